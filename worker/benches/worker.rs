@@ -0,0 +1,289 @@
+// Timings for the choices made in the worker's hot paths. Run with `cargo bench`, or `cargo bench -- backends` to only
+// run the benchmarks with "backends" in their name.
+use std::{
+    collections::hash_map::RandomState, future::Future, pin::Pin, sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    time::{Duration, Instant},
+};
+use rsky_lexicon::app::bsky::{feed::Post, richtext::Features};
+use rustc_hash::FxBuildHasher;
+use serde_json::json;
+use worker::{
+    bulk_search::{BulkSearchTree, MatchOptions, SearchBackend},
+    delivery_pool::DeliveryPool,
+    matcher::{MatchMode, Matcher},
+    post_text::{searchable_text, FIELD_SEPARATOR},
+};
+
+// Defines a benchmark by its name, the worker threads its runtime needs, and what it runs.
+type Bench = (&'static str, usize, fn() -> Pin<Box<dyn Future<Output = ()>>>);
+
+const BENCHES: [Bench; 8] = [
+    ("hot_branch", 1, || Box::pin(hot_branch())),
+    ("first_bytes", 1, || Box::pin(first_bytes())),
+    ("hashers", 1, || Box::pin(hashers())),
+    ("any_match", 1, || Box::pin(any_match())),
+    ("backends", 1, || Box::pin(backends())),
+    ("match_modes", 8, || Box::pin(match_modes())),
+    ("ingestion_under_delivery_load", 2, || Box::pin(ingestion_under_delivery_load())),
+    ("searchable_text", 1, || Box::pin(build_searchable_text())),
+];
+
+fn main() {
+    // Cargo passes flags like --bench, so the filter is the first argument which isn't one.
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-')).unwrap_or_default();
+    for (name, threads, bench) in BENCHES.into_iter().filter(|(name, ..)| name.contains(filter.as_str())) {
+        println!("{name}:");
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(threads).enable_all().build().unwrap();
+        runtime.block_on(bench());
+    }
+}
+
+// Builds a tree with the backend of the given number of items with 3 phrases each out of made up words.
+async fn made_up_tree(backend: SearchBackend, items: usize, words: u32) -> (BulkSearchTree<usize>, Vec<String>) {
+    let words: Vec<String> = (0..words)
+        .map(|i| {
+            let word = ["red", "blue", "rust", "sky", "pan", "crab"][i as usize % 6];
+            format!("{word}{}", i * 7919 % 1_000_003)
+        })
+        .collect();
+    let tree = BulkSearchTree::new_with_backend(MatchOptions::default(), backend);
+    for i in 0..items {
+        for j in 0..3 {
+            tree.add_item(&words[(i * 31 + j * 17) % words.len()], i).await;
+        }
+    }
+    (tree, words)
+}
+
+// Builds a tree of 5000 items with 3 phrases each out of 2000 made up words.
+async fn realistic_tree() -> (BulkSearchTree<usize>, Vec<String>) {
+    made_up_tree(SearchBackend::Tree, 5000, 2000).await
+}
+
+// Makes 1000 posts of about 300 bytes, each with a few of the words in.
+fn realistic_posts(words: &[String]) -> Vec<String> {
+    let filler = "just posting about my day and the weather, nothing to see here. ";
+    (0..1000usize)
+        .map(|i| {
+            let (a, b, c) = (&words[i % 2000], &words[(i * 3) % 2000], &words[(i * 11) % 2000]);
+            format!("{filler}{a} {filler}{b} {filler}{c}")
+        })
+        .collect()
+}
+
+// Times adding, searching for, and removing many items with the same phrase.
+async fn hot_branch() {
+    let tree = BulkSearchTree::new();
+
+    let start = Instant::now();
+    for item in 0..10_000u32 {
+        tree.add_item("crypto", item).await;
+    }
+    println!("  adding 10000 items to one phrase: {:?}", start.elapsed());
+
+    let text = "crypto is up, crypto is down, who even knows with crypto ".repeat(20);
+    let start = Instant::now();
+    for _ in 0..100 {
+        assert_eq!(tree.find_all_matches(&text).await.len(), 10_000);
+    }
+    println!("  100 searches hitting the phrase 60 times each: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    for item in 0..10_000u32 {
+        tree.remove_item("crypto", &item).await;
+    }
+    println!("  removing 10000 items from one phrase: {:?}", start.elapsed());
+}
+
+// Compares searching text which can't match with and without the first byte check.
+async fn first_bytes() {
+    // Cashtags are a common case of phrases which share a first byte most posts don't have.
+    let tree = BulkSearchTree::new();
+    for i in 0..5000u32 {
+        tree.add_item(&format!("${}", i * 7919 % 10007), i).await;
+    }
+    let filler = "just posting about my day and the weather, nothing to see here. ";
+    let posts: Vec<String> = (0..100_000usize).map(|i| format!("{filler}{i} {filler}")).collect();
+    for filtered in [true, false] {
+        if !filtered {
+            tree.disable_first_byte_check();
+        }
+        let start = Instant::now();
+        for post in &posts {
+            assert!(tree.find_all_matches(post).await.is_empty());
+        }
+        let check = if filtered { "on" } else { "off" };
+        println!("  first byte check {check}, 100000 searches: {:?}", start.elapsed());
+    }
+}
+
+// Compares the default SipHash hasher with FxHash for the per search sets over a realistic tree.
+async fn hashers() {
+    let (tree, words) = realistic_tree().await;
+    let posts = realistic_posts(&words);
+
+    let start = Instant::now();
+    for post in &posts {
+        tree.find_all_matches_with::<RandomState>(post).await;
+    }
+    println!("  SipHash: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    for post in &posts {
+        tree.find_all_matches_with::<FxBuildHasher>(post).await;
+    }
+    println!("  FxHash: {:?}", start.elapsed());
+}
+
+// Compares any_match with find_all_matches over posts where almost nothing matches, which is most of the firehose.
+async fn any_match() {
+    let (tree, words) = realistic_tree().await;
+
+    // One post in ten has a matching word, at the start.
+    let filler = "just posting about my day and the weather, nothing to see here. ".repeat(4);
+    let posts: Vec<String> = (0..1000usize)
+        .map(|i| if i % 10 == 0 { format!("{} {filler}", words[i % 2000]) } else { filler.clone() })
+        .collect();
+
+    let start = Instant::now();
+    let full = futures::future::join_all(posts.iter().map(|post| tree.find_all_matches(post))).await;
+    println!("  find_all_matches: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let any = futures::future::join_all(posts.iter().map(|post| tree.any_match(post))).await;
+    println!("  any_match: {:?}", start.elapsed());
+    assert_eq!(full.iter().map(|items| !items.is_empty()).collect::<Vec<_>>(), any);
+}
+
+// Compares the two backends over many phrases.
+async fn backends() {
+    for (items, words) in [(5000, 2000), (200_000, 100_000)] {
+        let filler = "just posting about my day and the weather, nothing to see here. ";
+        let mut results = Vec::new();
+        for backend in [SearchBackend::Tree, SearchBackend::AhoCorasick] {
+            let start = Instant::now();
+            let (tree, words) = made_up_tree(backend, items, words).await;
+            println!("  {backend:?} with {} phrases, building: {:?}", words.len(), start.elapsed());
+
+            // The first search builds the automaton, so it isn't timed.
+            let posts: Vec<String> = (0..1000usize)
+                .map(|i| {
+                    let (a, b, c) = (&words[i % 2000], &words[i * 7 % 2000], &words[i * 11 % 2000]);
+                    format!("{filler}{a} {filler}{b} {filler}{c}")
+                })
+                .collect();
+            tree.find_all_matches(&words[0]).await;
+            let start = Instant::now();
+            let mut found = Vec::new();
+            for post in &posts {
+                let mut matches = tree.find_all_matches(post).await;
+                matches.sort();
+                found.push(matches);
+            }
+            println!("  {backend:?} with {} phrases, 1000 searches: {:?}", words.len(), start.elapsed());
+            results.push(found);
+        }
+        assert_eq!(results[0], results[1]);
+    }
+}
+
+// Compares the shared tree with the actor when many workers match at once.
+async fn match_modes() {
+    let (tree, words) = realistic_tree().await;
+    let tree: &'static BulkSearchTree<usize> = Box::leak(Box::new(tree));
+    let posts: &'static [String] = Box::leak(realistic_posts(&words).into_boxed_slice());
+    for mode in [MatchMode::Shared, MatchMode::Actor] {
+        let matcher: &'static Matcher<usize> = Box::leak(Box::new(Matcher::new(mode, tree, || {}).unwrap()));
+        let start = Instant::now();
+        let workers: Vec<_> = (0..64).map(|worker| tokio::spawn(async move {
+            for post in posts.iter().skip(worker).step_by(8) {
+                matcher.find_all_matches(post).await;
+            }
+        })).collect();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+        println!("  {mode:?}: {:.0} posts/s", (64 * posts.len() / 8) as f64 / start.elapsed().as_secs_f64());
+    }
+}
+
+// Busy waits to simulate CPU bound work.
+fn spin(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        std::hint::spin_loop();
+    }
+}
+
+// Counts how many simulated firehose messages get decoded in a second while a flood of CPU heavy deliveries runs,
+// either on the main runtime or on the pool.
+async fn ingestion_under_delivery_load() {
+    async fn ingest() -> usize {
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            let decoded = decoded.clone();
+            tokio::spawn(async move {
+                spin(Duration::from_micros(50));
+                decoded.fetch_add(1, Ordering::Relaxed);
+            }).await.unwrap();
+        }
+        decoded.load(Ordering::Relaxed)
+    }
+
+    println!("  no delivery load: {} messages/s", ingest().await);
+
+    let deliveries: Vec<_> = (0..10_000).map(|_| tokio::spawn(async { spin(Duration::from_millis(1)) })).collect();
+    println!("  deliveries on the main runtime: {} messages/s", ingest().await);
+
+    // Let the main runtime drain before trying the pool.
+    for delivery in deliveries {
+        delivery.await.unwrap();
+    }
+    let pool = DeliveryPool::new(2, 10_000).unwrap();
+    for _ in 0..10_000 {
+        pool.spawn(0, async { spin(Duration::from_millis(1)) }).await;
+    }
+    println!("  deliveries on the pool: {} messages/s", ingest().await);
+}
+
+// Compares building the searchable text by copying each field, joining, and lowercasing with building it in one buffer.
+async fn build_searchable_text() {
+    let posts: Vec<Post> = (0..100_000).map(|i| serde_json::from_value(json!({
+        "text": format!("Post number {i} about my Day and the Weather, with a link and a #tag in it."),
+        "createdAt": "2024-11-20T00:00:00.000Z",
+        "facets": [
+            {
+                "index": {"byteStart": 0, "byteEnd": 4},
+                "features": [{"$type": "app.bsky.richtext.facet#link", "uri": "https://example.com/Some/Path"}],
+            },
+            {
+                "index": {"byteStart": 5, "byteEnd": 9},
+                "features": [{"$type": "app.bsky.richtext.facet#tag", "tag": "Tag"}],
+            },
+        ],
+    })).unwrap()).collect();
+
+    let start = Instant::now();
+    for post in &posts {
+        let mut fields = vec![post.text.clone()];
+        for facet in post.facets.iter().flatten() {
+            for feature in &facet.features {
+                if let Features::Link(link) = feature {
+                    fields.push(link.uri.clone());
+                } else if let Features::Tag(tag) = feature {
+                    fields.push(format!("#{}", tag.tag));
+                }
+            }
+        }
+        std::hint::black_box(fields.join(&FIELD_SEPARATOR.to_string()).to_lowercase());
+    }
+    println!("  copy, join, and lowercase: {:?}", start.elapsed());
+
+    let options = MatchOptions::default();
+    let start = Instant::now();
+    for post in &posts {
+        std::hint::black_box(options.normalize(&searchable_text(post, false)).len());
+    }
+    println!("  single buffer: {:?}", start.elapsed());
+}
@@ -0,0 +1,3 @@
+-- Whether users get posts which are replies.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS replies BOOLEAN NOT NULL DEFAULT TRUE;
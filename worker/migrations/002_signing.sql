@@ -0,0 +1,4 @@
+-- How deliveries are signed, and the HMAC secret.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS signing TEXT NOT NULL DEFAULT 'ed25519',
+    ADD COLUMN IF NOT EXISTS secret TEXT;
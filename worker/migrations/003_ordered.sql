@@ -0,0 +1,3 @@
+-- Deliveries sent one at a time in firehose order.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS ordered BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- Handles matched when they are mentioned in plain text.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS handle TEXT;
@@ -0,0 +1,3 @@
+-- Signed notices sent before a user is evicted.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS notify_eviction BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- Delivery priority when the in-flight limit is reached.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS priority INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Endpoints delivered to along with the primary one.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS extra_endpoints TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,3 @@
+-- The old key deliveries are also signed with during a key rotation.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS previous_private_key TEXT;
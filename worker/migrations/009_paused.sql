@@ -0,0 +1,4 @@
+-- Users paused while their endpoints are down, and when to probe them next.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS paused BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN IF NOT EXISTS paused_until BIGINT;
@@ -0,0 +1,3 @@
+-- DIDs users can still be found by after moving to a new one.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS previous_dids TEXT[] NOT NULL DEFAULT '{}';
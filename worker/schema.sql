@@ -1,7 +1,19 @@
 CREATE TABLE users (
     private_key TEXT PRIMARY KEY,
     did TEXT,
-    endpoint TEXT NOT NULL
+    endpoint TEXT NOT NULL,
+    replies BOOLEAN NOT NULL DEFAULT TRUE,
+    signing TEXT NOT NULL DEFAULT 'ed25519',
+    secret TEXT,
+    ordered BOOLEAN NOT NULL DEFAULT FALSE,
+    handle TEXT,
+    notify_eviction BOOLEAN NOT NULL DEFAULT FALSE,
+    priority INTEGER NOT NULL DEFAULT 0,
+    extra_endpoints TEXT[] NOT NULL DEFAULT '{}',
+    previous_private_key TEXT,
+    paused BOOLEAN NOT NULL DEFAULT FALSE,
+    paused_until BIGINT,
+    previous_dids TEXT[] NOT NULL DEFAULT '{}'
 );
 
 CREATE TABLE phrases (
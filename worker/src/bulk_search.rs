@@ -0,0 +1,1462 @@
+use std::{
+    borrow::Cow, collections::{HashMap, HashSet}, hash::{BuildHasher, Hash}, str::FromStr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use aho_corasick::AhoCorasick;
+use rustc_hash::FxBuildHasher;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, RwLockWriteGuard};
+use tracing::warn;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization as _};
+
+// Defines how phrases and text are compared. Phrases and text always go through the same normalization, so they can be
+// compared byte for byte.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchOptions {
+    // Ignore case, so "Rust" matches "rust".
+    pub case_insensitive: bool,
+
+    // Ignore accents and other combining marks, so "cafe" matches "café".
+    pub diacritic_insensitive: bool,
+
+    // Only match phrases with a non-alphanumeric character or the edge of the text either side of them.
+    pub whole_word: bool,
+
+    // The fewest characters a phrase can have once normalized.
+    pub min_length: usize,
+
+    // The most bytes a phrase can have once normalized. Each byte can be a branch, so this bounds how deep the tree
+    // gets and how far a search can walk down it.
+    pub max_bytes: usize,
+
+    // Let phrases match part of an emoji sequence, so "👍" matches "👍🏽" and "👨" matches "👨‍👩‍👧".
+    pub emoji_components: bool,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        Self {
+            case_insensitive: true, diacritic_insensitive: false, whole_word: false, min_length: 1, max_bytes: 512,
+            emoji_components: false,
+        }
+    }
+}
+
+impl MatchOptions {
+    // Normalizes a phrase or some text for matching.
+    pub fn normalize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        if !text.is_ascii() && text.contains(is_variation_selector) {
+            text = Cow::Owned(text.replace(is_variation_selector, ""));
+        }
+        if self.diacritic_insensitive && !text.is_ascii() {
+            // Split the accents off the characters, drop them, and put anything else which was split back together.
+            text = Cow::Owned(text.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect());
+        }
+        if self.case_insensitive {
+            // Most text is ASCII, which can be lowercased without working out the Unicode rules.
+            if text.is_ascii() {
+                if text.bytes().any(|b| b.is_ascii_uppercase()) {
+                    text.to_mut().make_ascii_lowercase();
+                }
+            } else {
+                text = Cow::Owned(fold_case(&text));
+            }
+        }
+        text
+    }
+}
+
+// Lowercases non-ASCII text. This is Rust's Unicode lowercasing, except that final sigma (ς) is folded to σ.
+// Lowercasing picks the sigma from the letters around it, so without this a phrase and a post could lowercase the same
+// word differently. Everything else is left as lowercasing leaves it:
+// - ß is already lowercase, so "straße" does not match "strasse".
+// - İ lowercases to i followed by a combining dot, so "İstanbul" only matches "istanbul" with diacritic_insensitive.
+// - I lowercases to i and ı stays as it is, so "ı" and "i" never match each other.
+fn fold_case(text: &str) -> String {
+    let mut lower = text.to_lowercase();
+    if lower.contains('ς') {
+        // Both sigmas are 2 bytes, so this doesn't move anything else.
+        lower = lower.replace('ς', "σ");
+    }
+    lower
+}
+
+// Checks if the bytes start with a letter or number. Used to find the edges of words.
+fn starts_with_word_char(bytes: &[u8]) -> bool {
+    bytes.utf8_chunks().next()
+        .and_then(|chunk| chunk.valid().chars().next())
+        .is_some_and(char::is_alphanumeric)
+}
+
+// Checks if a character asks for the emoji or text style of the one before it. Keyboards differ in whether they send
+// one, so "❤" and "❤️" are the same thing typed two ways, and these are dropped when normalizing.
+fn is_variation_selector(c: char) -> bool {
+    matches!(c, '\u{FE0E}' | '\u{FE0F}')
+}
+
+// Checks if a character is a flag letter. Flags are pairs of these, so they can only be split between pairs.
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+// Checks if a character attaches to the emoji before it: a skin tone, a keycap, a subdivision flag tag, or a zero width
+// joiner. Variation selectors would be too, but they are gone once the text is normalized.
+fn extends_emoji(c: char) -> bool {
+    matches!(c, '\u{200D}' | '\u{20E3}' | '\u{1F3FB}'..='\u{1F3FF}' | '\u{E0020}'..='\u{E007F}')
+}
+
+// Checks if the character boundary at i is inside an emoji sequence, so a match starting or ending there would only
+// be part of the emoji.
+fn splits_emoji(text: &str, i: usize) -> bool {
+    let (before, after) = text.split_at(i);
+    let (Some(prev), Some(next)) = (before.chars().next_back(), after.chars().next()) else {
+        return false;
+    };
+    if extends_emoji(next) || prev == '\u{200D}' {
+        return true;
+    }
+
+    // Between flag letters, this is only the middle of a flag if an odd number of them come before it.
+    is_regional_indicator(next) && before.chars().rev().take_while(|&c| is_regional_indicator(c)).count() % 2 == 1
+}
+
+// Checks if a match can start at byte i of the normalized text.
+fn is_match_start(text: &str, i: usize, options: &MatchOptions) -> bool {
+    // Phrases are whole characters, so they can only ever match from the start of one.
+    if !text.is_char_boundary(i) {
+        return false;
+    }
+    if options.whole_word && text[..i].chars().next_back().is_some_and(char::is_alphanumeric) {
+        return false;
+    }
+    options.emoji_components || !splits_emoji(text, i)
+}
+
+// Checks if a match can end at byte i of the normalized text.
+fn is_match_end(text: &str, i: usize, options: &MatchOptions) -> bool {
+    if options.whole_word && starts_with_word_char(&text.as_bytes()[i..]) {
+        return false;
+    }
+    options.emoji_components || !text.is_char_boundary(i) || !splits_emoji(text, i)
+}
+
+// How many items a branch can have before we index them. Most phrases only have a handful of items, where scanning a
+// small Vec is faster and smaller than a HashMap.
+const HOT_BRANCH_THRESHOLD: usize = 32;
+
+// Defines the items in a branch. Hot phrases can have thousands of items, so once a branch passes the threshold this
+// keeps an index of where each item is, making adding and removing them O(1) rather than a scan. The index costs
+// roughly a copy of each item and 8 bytes more plus the HashMap overhead, which is why small branches go without it.
+struct BranchItems<T> {
+    items: Vec<T>,
+    positions: Option<HashMap<T, usize>>,
+
+    // How many searches this phrase has matched for each item, in the same order as the items.
+    matches: Vec<AtomicU64>,
+}
+
+impl<T> Default for BranchItems<T> {
+    fn default() -> Self {
+        Self { items: Vec::new(), positions: None, matches: Vec::new() }
+    }
+}
+
+impl<T: Clone + Eq + Hash> BranchItems<T> {
+    fn single(item: T) -> Self {
+        Self { items: vec![item], positions: None, matches: vec![AtomicU64::new(0)] }
+    }
+
+    // Checks if this is a hot branch.
+    #[cfg(test)]
+    fn is_hot(&self) -> bool {
+        self.positions.is_some()
+    }
+
+    // Finds where an item is in the branch.
+    fn index_of(&self, item: &T) -> Option<usize> {
+        match &self.positions {
+            Some(positions) => positions.get(item).copied(),
+            None => self.items.iter().position(|i| i == item),
+        }
+    }
+
+    // Adds an item. Returns false if it is already in the branch.
+    fn insert(&mut self, item: T) -> bool {
+        if self.index_of(&item).is_some() {
+            return false;
+        }
+        match &mut self.positions {
+            Some(positions) => {
+                positions.insert(item.clone(), self.items.len());
+            }
+            None => {
+                if self.items.len() >= HOT_BRANCH_THRESHOLD {
+                    let mut positions: HashMap<T, usize> =
+                        self.items.iter().enumerate().map(|(i, existing)| (existing.clone(), i)).collect();
+                    positions.insert(item.clone(), self.items.len());
+                    self.positions = Some(positions);
+                }
+            }
+        }
+        self.items.push(item);
+        self.matches.push(AtomicU64::new(0));
+        true
+    }
+
+    // Adds an item, or puts it in place of the equal item already in the branch with its match count starting again.
+    // Returns false if it replaced one.
+    fn replace(&mut self, item: T) -> bool {
+        let Some(index) = self.index_of(&item) else {
+            return self.insert(item);
+        };
+
+        // The index is keyed by the old item, so it has to be swapped out too.
+        if let Some(positions) = &mut self.positions {
+            positions.remove(&item);
+            positions.insert(item.clone(), index);
+        }
+        self.items[index] = item;
+        self.matches[index] = AtomicU64::new(0);
+        false
+    }
+
+    // Removes an item. The order of the items is not kept. Returns false if the item is not in the branch.
+    fn remove(&mut self, item: &T) -> bool {
+        let Some(index) = self.index_of(item) else {
+            return false;
+        };
+        if let Some(positions) = &mut self.positions {
+            positions.remove(item);
+        }
+        self.items.swap_remove(index);
+        self.matches.swap_remove(index);
+
+        // Point the index at the item that was moved into the gap.
+        if let (Some(positions), Some(moved)) = (&mut self.positions, self.items.get(index)) {
+            positions.insert(moved.clone(), index);
+        }
+        true
+    }
+
+    // Counts a search matching this phrase for every item in the branch, and adds the items which haven't been found
+    // yet to the result.
+    fn take_match<'a, S: BuildHasher>(&'a self, consumed_items: &mut HashSet<&'a T, S>, items: &mut Vec<T>) {
+        for matches in &self.matches {
+            matches.fetch_add(1, Ordering::Relaxed);
+        }
+        items.extend(self.iter().filter(|item| consumed_items.insert(*item)).cloned());
+    }
+
+    // Gets how many searches this phrase has matched for an item.
+    fn match_count(&self, item: &T) -> Option<u64> {
+        self.index_of(item).map(|index| self.matches[index].load(Ordering::Relaxed))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.items.iter()
+    }
+}
+
+struct BulkSearchBranch<T> {
+    // A mapping of path chunks to the next branch. This is a option to allow for splits,
+    // but will always be Some.
+    mapping: Vec<Option<(Vec<u8>, BulkSearchBranch<T>)>>,
+
+    // A list of items in this branch.
+    items: BranchItems<T>,
+}
+
+impl<T> Default for BulkSearchBranch<T> {
+    fn default() -> Self {
+        Self { mapping: Vec::new(), items: BranchItems::default() }
+    }
+}
+
+// Recurse through each branch that is relevant to the remaining path. Adds any items from it to the result.
+// visited tracks the branches we have already taken items from, so a phrase which is in the text many times only has
+// its item list checked and its match counted once. Items are only added where a match can end under the options, such
+// as the end of a word if whole_word is set.
+fn walk_branch<'a, T: Clone + Eq + Hash, S: BuildHasher>(
+    mut branch: &'a BulkSearchBranch<T>, mut remaining_path: &[u8], text: &str, options: &MatchOptions,
+    consumed_items: &mut HashSet<&'a T, S>, visited: &mut HashSet<*const BulkSearchBranch<T>, S>, items: &mut Vec<T>,
+) {
+'outer:
+    loop {
+        // Add any items in this branch to the result.
+        if !branch.items.is_empty() && is_match_end(text, text.len() - remaining_path.len(), options)
+            && visited.insert(branch as *const _)
+        {
+            branch.items.take_match(consumed_items, items);
+        }
+
+        // If we have no more path left then we are done.
+        if remaining_path.is_empty() {
+            return;
+        }
+
+        // Go through each tree node in the current branch.
+        for node_opt in branch.mapping.iter() {
+            // This will never be None.
+            let node = node_opt.as_ref().unwrap();
+
+            // Check if the remaining path starts with this chunk.
+            if remaining_path.starts_with(&node.0) {
+                // Take the length of the path chunk and remove it from the remaining path.
+                remaining_path = &remaining_path[node.0.len()..];
+
+                // Recurse into the next branch.
+                branch = &node.1;
+                continue 'outer;
+            }
+        }
+
+        // If we get here then we have no more branches to recurse into.
+        break;
+    }
+}
+
+// Walks the branches for the remaining path like walk_branch, but stops at the first one with items in it.
+fn reaches_items<T: Clone + Eq + Hash>(
+    mut branch: &BulkSearchBranch<T>, mut remaining_path: &[u8], text: &str, options: &MatchOptions,
+) -> bool {
+    'outer:
+    loop {
+        if !branch.items.is_empty() && is_match_end(text, text.len() - remaining_path.len(), options) {
+            return true;
+        }
+        for node in branch.mapping.iter().flatten() {
+            if remaining_path.starts_with(&node.0) {
+                remaining_path = &remaining_path[node.0.len()..];
+                branch = &node.1;
+                continue 'outer;
+            }
+        }
+        return false;
+    }
+}
+
+// Splits a node by creating a new branch with no items at the split point, which the rest of the node hangs off.
+fn split_node<T>(node_opt: &mut Option<(Vec<u8>, BulkSearchBranch<T>)>, split_at: usize) {
+    let (path, branch) = node_opt.take().unwrap();
+
+    let junction_branch = BulkSearchBranch {
+        mapping: vec![
+            // Everything after the split point and the old branch.
+            Some((path[split_at..].to_vec(), branch)),
+        ],
+        items: BranchItems::default(),
+    };
+
+    // Replace the node with the junction branch.
+    node_opt.replace((path[..split_at].to_vec(), junction_branch));
+}
+
+// Writes to a branch by recursing through and then splitting if needed. Returns true if the item was added. No two
+// nodes in a branch start with the same byte, since searches only follow the first node which fits the text.
+fn write_branch<T: Clone + Eq + Hash>(
+    mut branch: &mut BulkSearchBranch<T>, mut remaining_path: &[u8], item: T, replace: bool,
+) -> bool {
+    loop {
+        // If we have no more path left then we are done.
+        if remaining_path.is_empty() {
+            return if replace { branch.items.replace(item) } else { branch.items.insert(item) };
+        }
+
+        // Find the node which starts the same way as the remaining path. If there isn't one, create it.
+        let Some(index) = branch.mapping.iter().position(|node| node.as_ref().unwrap().0[0] == remaining_path[0]) else {
+            let new_node = BulkSearchBranch {
+                mapping: vec![],
+                items: BranchItems::single(item),
+            };
+            branch.mapping.push(Some((remaining_path.to_vec(), new_node)));
+            return true;
+        };
+
+        // Split the node where it stops being the same as the remaining path, and recurse into the first half.
+        let node_opt = &mut branch.mapping[index];
+        let path = &node_opt.as_ref().unwrap().0;
+        let shared = path.iter().zip(remaining_path).take_while(|(a, b)| a == b).count();
+        if shared < path.len() {
+            split_node(node_opt, shared);
+        }
+        remaining_path = &remaining_path[shared..];
+        branch = &mut node_opt.as_mut().unwrap().1;
+    }
+}
+
+// Find a mutable branch that matches EXACTLY the remaining path.
+fn find_mut_branch<'a, T>(
+    mut branch: &'a mut BulkSearchBranch<T>, mut remaining_path: &[u8],
+) -> Option<&'a mut BulkSearchBranch<T>> {
+'outer:
+    loop {
+        // If we have no more path left then we are done.
+        if remaining_path.is_empty() {
+            return Some(branch);
+        }
+
+        // Go through each tree node in the current branch.
+        for node_opt in branch.mapping.iter_mut() {
+            // This will never be None.
+            let node = node_opt.as_mut().unwrap();
+
+            // Check if the remaining path starts with this chunk.
+            if remaining_path.starts_with(&node.0) {
+                // Take the length of the path chunk and remove it from the remaining path.
+                remaining_path = &remaining_path[node.0.len()..];
+
+                // Recurse into the next branch.
+                branch = &mut node.1;
+                continue 'outer;
+            }
+        }
+
+        // If we get here then we have no more branches to recurse into. Return None.
+        return None;
+    }
+}
+
+// Find a branch that matches EXACTLY the remaining path.
+fn find_branch<'a, T>(
+    mut branch: &'a BulkSearchBranch<T>, mut remaining_path: &[u8],
+) -> Option<&'a BulkSearchBranch<T>> {
+'outer:
+    loop {
+        if remaining_path.is_empty() {
+            return Some(branch);
+        }
+        for node_opt in branch.mapping.iter() {
+            // This will never be None.
+            let node = node_opt.as_ref().unwrap();
+            if remaining_path.starts_with(&node.0) {
+                remaining_path = &remaining_path[node.0.len()..];
+                branch = &node.1;
+                continue 'outer;
+            }
+        }
+        return None;
+    }
+}
+
+// Defines the phrases as an Aho-Corasick automaton, which finds every phrase in some text in one pass over it however
+// many phrases there are. An automaton can't be changed once it is built, so adding a phrase or removing the last item
+// from one throws it away, and it is built again from every phrase once the batch of changes is done, before the write
+// lock is let go.
+struct PhraseAutomaton<T> {
+    // The phrases once normalized and the items which want each of them. A phrase's index is its pattern ID.
+    phrases: Vec<Vec<u8>>,
+    items: Vec<BranchItems<T>>,
+
+    // Where each phrase is in the list.
+    indexes: HashMap<Vec<u8>, usize>,
+
+    // None from when the phrases change until it is built again.
+    automaton: Option<AhoCorasick>,
+}
+
+impl<T> Default for PhraseAutomaton<T> {
+    fn default() -> Self {
+        Self { phrases: Vec::new(), items: Vec::new(), indexes: HashMap::new(), automaton: None }
+    }
+}
+
+impl<T: Clone + Eq + Hash> PhraseAutomaton<T> {
+    // Adds an item to a phrase, replacing an equal item if asked to. Returns false if the item already has it.
+    fn insert(&mut self, phrase: &[u8], item: T, replace: bool) -> bool {
+        if let Some(&index) = self.indexes.get(phrase) {
+            let items = &mut self.items[index];
+            return if replace { items.replace(item) } else { items.insert(item) };
+        }
+        self.indexes.insert(phrase.to_vec(), self.phrases.len());
+        self.phrases.push(phrase.to_vec());
+        self.items.push(BranchItems::single(item));
+        self.automaton = None;
+        true
+    }
+
+    // Removes an item from a phrase. Phrases nobody has any more are dropped, moving the last phrase into the gap.
+    // Returns None if the phrase is not there, otherwise whether the item had it.
+    fn remove(&mut self, phrase: &[u8], item: &T) -> Option<bool> {
+        let &index = self.indexes.get(phrase)?;
+        let removed = self.items[index].remove(item);
+        if self.items[index].is_empty() {
+            self.indexes.remove(phrase);
+            self.phrases.swap_remove(index);
+            self.items.swap_remove(index);
+            if let Some(moved) = self.phrases.get(index).and_then(|moved| self.indexes.get_mut(moved)) {
+                *moved = index;
+            }
+            self.automaton = None;
+        }
+        Some(removed)
+    }
+
+    // Gets the items which want a phrase.
+    fn get(&self, phrase: &[u8]) -> Option<&BranchItems<T>> {
+        self.indexes.get(phrase).map(|&index| &self.items[index])
+    }
+
+    // Finds every phrase in the normalized text where a match can start and end under the options, as indexes into
+    // the phrases. A phrase is there once for each time it is in the text.
+    fn find<'a>(&'a self, text: &'a str, options: &'a MatchOptions) -> impl Iterator<Item = usize> + 'a {
+        self.automaton.iter()
+            .flat_map(move |automaton| automaton.find_overlapping_iter(text))
+            .filter(|found| is_match_start(text, found.start(), options) && is_match_end(text, found.end(), options))
+            .map(|found| found.pattern().as_usize())
+    }
+
+    // Builds the automaton from every phrase if they changed since it was last built.
+    fn build(&mut self) -> Result<(), aho_corasick::BuildError> {
+        if self.automaton.is_none() {
+            self.automaton = Some(AhoCorasick::new(&self.phrases)?);
+        }
+        Ok(())
+    }
+
+    // Moves the phrases into the first byte branches of a tree, keeping their items and match counts.
+    fn into_tree(self) -> Vec<BulkSearchBranch<T>> {
+        let mut first_byte_branches = first_byte_branches();
+        for (phrase, items) in self.phrases.into_iter().zip(self.items) {
+            let branch = &mut first_byte_branches[phrase[0] as usize];
+            write_branch(branch, &phrase[1..], items.items[0].clone(), false);
+            find_mut_branch(branch, &phrase[1..]).unwrap().items = items;
+        }
+        first_byte_branches
+    }
+}
+
+// Creates an empty branch for each possible first byte of a phrase.
+fn first_byte_branches<T>() -> Vec<BulkSearchBranch<T>> {
+    (0..=u8::MAX).map(|_| BulkSearchBranch::default()).collect()
+}
+
+// Builds the automaton again if the phrases changed. The automaton can only fail to build with more phrases than fit in
+// a pattern ID, but if it does the phrases are moved into a tree so they are still matched.
+fn build_automaton<T: Clone + Eq + Hash>(index: &mut PhraseIndex<T>) {
+    let PhraseIndex::Automaton(automaton) = index else {
+        return;
+    };
+    let Err(error) = automaton.build() else {
+        return;
+    };
+    warn!(%error, phrases = automaton.phrases.len(), "Failed to build the phrase automaton. Falling back to the tree");
+    fall_back_to_tree(index);
+}
+
+// Swaps the automaton for a tree of the same phrases.
+fn fall_back_to_tree<T: Clone + Eq + Hash>(index: &mut PhraseIndex<T>) {
+    if let PhraseIndex::Automaton(automaton) = std::mem::replace(index, PhraseIndex::Tree(Vec::new())) {
+        *index = PhraseIndex::Tree(automaton.into_tree());
+    }
+}
+
+// Defines how a tree finds the phrases in some text.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SearchBackend {
+    // Walks a radix tree of the phrases from each place in the text a match can start. Phrases are cheap to add and
+    // remove.
+    #[default]
+    Tree,
+
+    // Runs an Aho-Corasick automaton of the phrases over the text once, which is faster with a lot of phrases. Changing
+    // the phrases means building the automaton again from every phrase, so this suits phrases which rarely change.
+    AhoCorasick,
+}
+
+impl FromStr for SearchBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tree" => Ok(SearchBackend::Tree),
+            "aho_corasick" => Ok(SearchBackend::AhoCorasick),
+            _ => Err("expected tree or aho_corasick".to_string()),
+        }
+    }
+}
+
+// Defines where the phrases are kept for the backend.
+enum PhraseIndex<T> {
+    // The branches for each possible first byte of a phrase.
+    Tree(Vec<BulkSearchBranch<T>>),
+    Automaton(PhraseAutomaton<T>),
+}
+
+// Defines a census of what is in the tree.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct TreeStats {
+    // How many branches there are below the first byte ones, including ones which only exist to split a path. This is
+    // always 0 with the Aho-Corasick backend, which has no branches.
+    pub branches: usize,
+
+    // How many distinct phrases at least one item has.
+    pub phrases: usize,
+
+    // How many item and phrase pairs there are, so a phrase held by two items counts twice.
+    pub entries: usize,
+}
+
+// Adds the items in a branch and everything under it to the stats.
+fn count_branch<T: Clone + Eq + Hash>(branch: &BulkSearchBranch<T>, stats: &mut TreeStats) {
+    if !branch.items.is_empty() {
+        stats.phrases += 1;
+        stats.entries += branch.items.items.len();
+    }
+    for (_, child) in branch.mapping.iter().flatten() {
+        stats.branches += 1;
+        count_branch(child, stats);
+    }
+}
+
+// Defines which bytes phrases start with, as a bitmap alongside how many phrase and item pairs start with each byte.
+// Most posts match nothing, and text without any of these bytes can't match anything, so it is skipped without taking
+// the lock or walking the tree. The counts are only changed with the tree's write lock held.
+struct FirstBytes {
+    bits: [AtomicU64; 4],
+    counts: [AtomicUsize; 256],
+}
+
+impl Default for FirstBytes {
+    fn default() -> Self {
+        Self { bits: Default::default(), counts: std::array::from_fn(|_| AtomicUsize::new(0)) }
+    }
+}
+
+impl FirstBytes {
+    // Counts a phrase and item pair starting with the byte.
+    fn add(&self, byte: u8) {
+        if self.counts[byte as usize].fetch_add(1, Ordering::Relaxed) == 0 {
+            self.bits[byte as usize / 64].fetch_or(1 << (byte % 64), Ordering::Relaxed);
+        }
+    }
+
+    // Stops counting a phrase and item pair starting with the byte.
+    fn remove(&self, byte: u8) {
+        if self.counts[byte as usize].fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.bits[byte as usize / 64].fetch_and(!(1 << (byte % 64)), Ordering::Relaxed);
+        }
+    }
+
+    // Marks every byte as starting a phrase, which turns the check off.
+    fn fill(&self) {
+        for bits in &self.bits {
+            bits.store(u64::MAX, Ordering::Relaxed);
+        }
+    }
+
+    // Checks if any byte of the text starts a phrase. The bitmap is copied out first so each byte is only a shift and
+    // a mask.
+    fn any_in(&self, text: &[u8]) -> bool {
+        let bits = self.bits.each_ref().map(|bits| bits.load(Ordering::Relaxed));
+        text.iter().any(|&byte| bits[byte as usize / 64] & (1 << (byte % 64)) != 0)
+    }
+}
+
+// Defines a tree of phrases, each with the items which want it. Items are anything which can be told apart, like an ID
+// or an Arc of whatever should be told about a match. Searching a text gives back every item with a phrase in it.
+pub struct BulkSearchTree<T> {
+    index: RwLock<PhraseIndex<T>>,
+    first_bytes: FirstBytes,
+    options: MatchOptions,
+}
+
+impl<T: Clone + Eq + Hash> Default for BulkSearchTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone + Eq + Hash> BulkSearchTree<T> {
+    // Creates a tree with the default options.
+    pub fn new() -> Self {
+        Self::new_with_options(MatchOptions::default())
+    }
+
+    // Creates a tree which matches using the given options.
+    pub fn new_with_options(options: MatchOptions) -> Self {
+        Self::new_with_backend(options, SearchBackend::Tree)
+    }
+
+    // Creates a tree which matches using the given options and finds phrases with the given backend.
+    pub fn new_with_backend(options: MatchOptions, backend: SearchBackend) -> Self {
+        let index = match backend {
+            // Create the first byte branches.
+            SearchBackend::Tree => PhraseIndex::Tree(first_byte_branches()),
+            SearchBackend::AhoCorasick => PhraseIndex::Automaton(PhraseAutomaton::default()),
+        };
+        Self { index: RwLock::new(index), first_bytes: FirstBytes::default(), options }
+    }
+
+    // Finds all items that match within the given text.
+    pub async fn find_all_matches(&self, text: &str) -> Vec<T> {
+        // The sets only live for one search and are mostly keyed by pointers, so a fast hasher is fine.
+        self.find_all_matches_with::<FxBuildHasher>(text).await
+    }
+
+    // Finds all items that match within the given text, using the given hasher for the sets of seen items and branches.
+    // This is only public so the benchmarks can compare hashers.
+    pub async fn find_all_matches_with<S: BuildHasher + Default>(&self, text: &str) -> Vec<T> {
+        // Normalize the text the same way as the phrases and turn it into bytes. We think like a robot.
+        let normalized = self.options.normalize(text);
+        let text = normalized.as_bytes();
+
+        // Skip text which can't match before doing anything else.
+        if !self.first_bytes.any_in(text) {
+            return Vec::new();
+        }
+
+        // Read the phrases.
+        let index = self.index.read().await;
+
+        // Defines all the items we have found so far and a set so we can efficiently check if we already have them.
+        let mut items = Vec::new();
+        let mut consumed_items = HashSet::with_hasher(S::default());
+
+        let first_byte_branches = match &*index {
+            PhraseIndex::Tree(first_byte_branches) => first_byte_branches,
+            PhraseIndex::Automaton(automaton) => {
+                // Take the items from each phrase the first time it is found.
+                let mut visited = HashSet::with_hasher(S::default());
+                for found in automaton.find(&normalized, &self.options) {
+                    if visited.insert(found) {
+                        automaton.items[found].take_match(&mut consumed_items, &mut items);
+                    }
+                }
+                return items;
+            }
+        };
+        let mut visited = HashSet::with_hasher(S::default());
+
+        // Iterate over each byte in the text and make a cursor for each iteration.
+        for (i, &byte) in text.iter().enumerate() {
+            // Skip anywhere a match can't start, like straight after a letter for whole words or inside an emoji.
+            if !is_match_start(&normalized, i, &self.options) {
+                continue;
+            }
+            let cursor_after = &text[i + 1..];
+
+            // SAFETY: We can avoid a bounds check here because we know all bytes are initialized.
+            let branch = unsafe { first_byte_branches.get_unchecked(byte as usize) };
+
+            // Walk the branch.
+            walk_branch(
+                branch, cursor_after, &normalized, &self.options, &mut consumed_items, &mut visited, &mut items,
+            );
+        }
+
+        // Return the items we found.
+        items
+    }
+
+    // Stops skipping text which no phrase can start in. The check is never wrong, so this is only for the benchmarks to
+    // measure what it saves.
+    pub fn disable_first_byte_check(&self) {
+        self.first_bytes.fill();
+    }
+
+    // Checks if any item matches within the given text. This stops at the first match and doesn't build the set of
+    // items, so it is a cheap way to skip text nobody wants. Unlike find_all_matches, matches aren't counted.
+    pub async fn any_match(&self, text: &str) -> bool {
+        let normalized = self.options.normalize(text);
+        let text = normalized.as_bytes();
+        if !self.first_bytes.any_in(text) {
+            return false;
+        }
+        let index = self.index.read().await;
+        let first_byte_branches = match &*index {
+            PhraseIndex::Tree(first_byte_branches) => first_byte_branches,
+            PhraseIndex::Automaton(automaton) => return automaton.find(&normalized, &self.options).next().is_some(),
+        };
+        text.iter().enumerate().any(|(i, &byte)| {
+            let branch = &first_byte_branches[byte as usize];
+            is_match_start(&normalized, i, &self.options)
+                && reaches_items(branch, &text[i + 1..], &normalized, &self.options)
+        })
+    }
+
+    // Checks if a phrase can be added to the tree. Phrases which are blank, too short or too long once normalized
+    // can't be.
+    pub fn accepts(&self, phrase: &str) -> bool {
+        let phrase = self.options.normalize(phrase);
+        !phrase.is_empty() && phrase.chars().count() >= self.options.min_length
+            && phrase.len() <= self.options.max_bytes
+    }
+
+    // Checks if a phrase is over the maximum length once normalized.
+    pub fn too_long(&self, phrase: &str) -> bool {
+        self.options.normalize(phrase).len() > self.options.max_bytes
+    }
+
+    // Gets the most bytes a phrase can have once normalized.
+    pub fn max_bytes(&self) -> usize {
+        self.options.max_bytes
+    }
+
+    // Checks if two phrases are the same once normalized, so they would be the same place in the tree.
+    pub fn same_phrase(&self, a: &str, b: &str) -> bool {
+        self.options.normalize(a) == self.options.normalize(b)
+    }
+
+    // Adds an item to a tree branch. Return false if the text is blank, too short or too long, or the item already has
+    // the phrase.
+    pub async fn add_item(&self, subtext: &str, item: T) -> bool {
+        self.batch().await.add_item(subtext, item)
+    }
+
+    // Adds an item to a tree branch like add_item, but if an equal item already has the phrase it is swapped for this
+    // one. This lets a changed copy of an item take over its phrases without them ever being missing from the tree.
+    // Returns false if the text is blank, too short or too long.
+    pub async fn replace_item(&self, subtext: &str, item: T) -> bool {
+        self.batch().await.replace_item(subtext, item)
+    }
+
+    // Starts a batch of changes to the tree. Searches wait until the batch is dropped, and with the Aho-Corasick
+    // backend the automaton is only built once then, so loading many phrases in a batch doesn't build it for each one.
+    pub async fn batch(&self) -> TreeBatch<'_, T> {
+        TreeBatch { tree: self, index: self.index.write().await }
+    }
+
+    // Gets how many searches each of the phrases has matched for the item since it was added with them. Phrases which
+    // the item doesn't have in the tree are left out.
+    pub async fn match_counts(&self, item: &T, phrases: impl IntoIterator<Item = String>) -> HashMap<String, u64> {
+        let index = self.index.read().await;
+        let mut counts = HashMap::new();
+        for phrase in phrases {
+            let subtext = self.options.normalize(&phrase);
+            let Some((&first, rest_path)) = subtext.as_bytes().split_first() else {
+                continue;
+            };
+            let phrase_items = match &*index {
+                PhraseIndex::Tree(first_byte_branches) => {
+                    find_branch(&first_byte_branches[first as usize], rest_path).map(|branch| &branch.items)
+                }
+                PhraseIndex::Automaton(automaton) => automaton.get(subtext.as_bytes()),
+            };
+            let count = phrase_items.and_then(|phrase_items| phrase_items.match_count(item));
+            if let Some(count) = count {
+                counts.insert(phrase, count);
+            }
+        }
+        counts
+    }
+
+    // Finds which of the given phrases are in the text, matching them the same way as the tree. This builds a tree for
+    // just those phrases, so it is for explaining a match rather than the hot path.
+    pub async fn phrases_in(&self, text: &str, phrases: impl IntoIterator<Item = String>) -> Vec<String> {
+        let tree = BulkSearchTree::new_with_options(self.options);
+        for phrase in phrases {
+            tree.add_item(&phrase.clone(), phrase).await;
+        }
+        tree.find_all_matches(text).await
+    }
+
+    // Counts what is in the tree. This walks every branch, so it is for operators rather than the hot path.
+    pub async fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        match &*self.index.read().await {
+            PhraseIndex::Tree(first_byte_branches) => {
+                for branch in first_byte_branches.iter() {
+                    count_branch(branch, &mut stats);
+                }
+            }
+            PhraseIndex::Automaton(automaton) => {
+                stats.phrases = automaton.phrases.len();
+                stats.entries = automaton.items.iter().map(|phrase_items| phrase_items.items.len()).sum();
+            }
+        }
+        stats
+    }
+
+    // Removes an item from a tree branch. Returns false if the phrase is not in the tree.
+    pub async fn remove_item(&self, subtext: &str, item: &T) -> bool {
+        self.batch().await.remove_item(subtext, item)
+    }
+}
+
+// Defines a batch of changes to a tree, made under one write lock. Anything the changes need is built when the batch is
+// dropped.
+pub struct TreeBatch<'a, T: Clone + Eq + Hash> {
+    tree: &'a BulkSearchTree<T>,
+    index: RwLockWriteGuard<'a, PhraseIndex<T>>,
+}
+
+impl<T: Clone + Eq + Hash> TreeBatch<'_, T> {
+    // Adds an item to a tree branch, like BulkSearchTree::add_item.
+    pub fn add_item(&mut self, subtext: &str, item: T) -> bool {
+        self.write_item(subtext, item, false).unwrap_or(false)
+    }
+
+    // Adds or swaps in an item for a tree branch, like BulkSearchTree::replace_item.
+    pub fn replace_item(&mut self, subtext: &str, item: T) -> bool {
+        self.write_item(subtext, item, true).is_some()
+    }
+
+    // Writes an item to a tree branch. Returns None if the text can't be added, otherwise whether the item is new to
+    // the phrase.
+    fn write_item(&mut self, subtext: &str, item: T, replace: bool) -> Option<bool> {
+        // If the text is blank, too short or too long then we can't add the item.
+        if !self.tree.accepts(subtext) {
+            if self.tree.too_long(subtext) {
+                warn!(max_bytes = self.tree.options.max_bytes, "Not adding a phrase which is too long");
+            }
+            return None;
+        }
+        let subtext = self.tree.options.normalize(subtext);
+
+        // Turn the subtext into bytes.
+        let subtext = subtext.as_bytes();
+        let added = match &mut *self.index {
+            PhraseIndex::Tree(first_byte_branches) => {
+                // SAFETY: We can avoid a bounds check here because we know all bytes are initialized.
+                let branch = unsafe { first_byte_branches.get_unchecked_mut(subtext[0] as usize) };
+
+                // Get the rest of the path and then write to the branch.
+                let rest_path = &subtext[1..];
+                write_branch(branch, rest_path, item, replace)
+            }
+            PhraseIndex::Automaton(automaton) => automaton.insert(subtext, item, replace),
+        };
+        if added {
+            self.tree.first_bytes.add(subtext[0]);
+        }
+        Some(added)
+    }
+
+    // Removes an item from a tree branch, like BulkSearchTree::remove_item.
+    pub fn remove_item(&mut self, subtext: &str, item: &T) -> bool {
+        // Normalize the subtext the same way as when it was added and turn it into bytes.
+        let subtext = self.tree.options.normalize(subtext);
+        let subtext = subtext.as_bytes();
+
+        // Bail if the text is blank.
+        if subtext.is_empty() {
+            return false;
+        }
+        let removed = match &mut *self.index {
+            PhraseIndex::Tree(first_byte_branches) => {
+                // SAFETY: We can avoid a bounds check here because we know all bytes are initialized.
+                let branch = unsafe { first_byte_branches.get_unchecked_mut(subtext[0] as usize) };
+
+                // Get the rest of the path and then delete the item from the branch.
+                let rest_path = &subtext[1..];
+                find_mut_branch(branch, rest_path).map(|branch| branch.items.remove(item))
+            }
+            PhraseIndex::Automaton(automaton) => automaton.remove(subtext, item),
+        };
+        if removed == Some(true) {
+            self.tree.first_bytes.remove(subtext[0]);
+        }
+        removed.is_some()
+    }
+}
+
+impl<T: Clone + Eq + Hash> Drop for TreeBatch<'_, T> {
+    // Builds the automaton again if the batch changed the phrases, before searches can see them.
+    fn drop(&mut self) {
+        build_automaton(&mut self.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Gets the matches for some text in a set, since the order they are found in doesn't matter.
+    async fn matches(tree: &BulkSearchTree<u32>, text: &str) -> HashSet<u32> {
+        tree.find_all_matches(text).await.into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn test_add_and_find_item() {
+        let tree = BulkSearchTree::new();
+        tree.add_item("hello", 1).await;
+        tree.add_item("world", 1).await;
+        tree.add_item("ab", 2).await;
+
+        assert_eq!(tree.find_all_matches("hello world").await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_finds() {
+        let tree = BulkSearchTree::new();
+        tree.add_item("hello", 1).await;
+        tree.add_item("world", 1).await;
+        tree.add_item("hello", 2).await;
+
+        let found = tree.find_all_matches("hello world").await;
+        assert_eq!(found.len(), 2);
+        assert_eq!(found.into_iter().collect::<HashSet<_>>(), HashSet::from([1, 2]));
+    }
+
+    #[tokio::test]
+    async fn test_intersecting_phrases() {
+        let tree = BulkSearchTree::new();
+        tree.add_item("hello", 1).await;
+        tree.add_item("world", 1).await;
+        tree.add_item("or", 2).await;
+
+        let found = tree.find_all_matches("hello world").await;
+        assert_eq!(found.len(), 2);
+        assert_eq!(found.into_iter().collect::<HashSet<_>>(), HashSet::from([1, 2]));
+    }
+
+    #[tokio::test]
+    async fn test_phrases_sharing_a_start() {
+        // The phrases branch off each other part way through, so the tree has to split where they do.
+        let tree = BulkSearchTree::new();
+        tree.add_item("abc", 1).await;
+        tree.add_item("abd", 2).await;
+        tree.add_item("ab", 3).await;
+        assert_eq!(matches(&tree, "abd").await, HashSet::from([2, 3]));
+        assert_eq!(matches(&tree, "abc").await, HashSet::from([1, 3]));
+        assert_eq!(matches(&tree, "abe").await, HashSet::from([3]));
+    }
+
+    #[tokio::test]
+    async fn test_owned_payloads() {
+        // Items can be anything which can be told apart, not just numbers.
+        let tree = BulkSearchTree::new();
+        assert!(tree.add_item("red panda", "alice".to_string()).await);
+        assert!(tree.add_item("panda", "bob".to_string()).await);
+        assert!(!tree.add_item("red panda", "alice".to_string()).await);
+        let mut found = tree.find_all_matches("a red panda").await;
+        found.sort();
+        assert_eq!(found, vec!["alice".to_string(), "bob".to_string()]);
+    }
+
+    #[test]
+    fn test_hot_branch_items() {
+        let mut items = BranchItems::default();
+        let created: Vec<usize> = (0..HOT_BRANCH_THRESHOLD * 2).collect();
+        for &item in &created {
+            assert!(items.insert(item));
+        }
+        assert!(items.is_hot());
+        assert!(!items.insert(created[0]));
+
+        // Removing swaps the last item into the gap, so the index has to follow it.
+        items.remove(&created[3]);
+        items.remove(&created[3]);
+        assert_eq!(items.iter().count(), created.len() - 1);
+        let last = created.last().unwrap();
+        items.remove(last);
+        assert!(items.insert(*last));
+        assert!(!items.insert(*last));
+        for (i, item) in items.iter().enumerate() {
+            assert_eq!(items.positions.as_ref().unwrap()[item], i);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hot_branch_repeated_phrase() {
+        let tree = BulkSearchTree::new();
+        let count = HOT_BRANCH_THRESHOLD * 2;
+        for item in 0..count {
+            tree.add_item("crypto", item).await;
+        }
+        tree.add_item("cryptography", count).await;
+
+        let found = tree.find_all_matches("crypto crypto cryptography").await;
+        assert_eq!(found.len(), count + 1);
+    }
+
+    #[tokio::test]
+    async fn test_first_bytes() {
+        for backend in [SearchBackend::Tree, SearchBackend::AhoCorasick] {
+            let tree = BulkSearchTree::new_with_backend(MatchOptions::default(), backend);
+            tree.add_item("$rust", 1).await;
+            tree.add_item("$rust", 2).await;
+            tree.add_item("panda", 1).await;
+            assert!(tree.first_bytes.any_in(b"$"));
+            assert!(!tree.first_bytes.any_in(b"rust is nice"));
+            assert!(tree.first_bytes.any_in("Panda".to_lowercase().as_bytes()));
+
+            // Adding or replacing an item a phrase already has doesn't count it twice.
+            assert!(!tree.add_item("$Rust", 1).await);
+            assert!(tree.replace_item("$rust", 1).await);
+            tree.remove_item("$rust", &1).await;
+            assert!(tree.first_bytes.any_in(b"$"));
+            assert_eq!(matches(&tree, "$rust").await, HashSet::from([2]));
+
+            // Removing an item which doesn't have the phrase leaves the count alone.
+            tree.remove_item("$rust", &3).await;
+            tree.remove_item("$rust", &2).await;
+            assert!(!tree.first_bytes.any_in(b"$"));
+            assert!(!tree.any_match("$rust").await);
+            assert!(tree.any_match("a panda").await);
+
+            // Once the byte is gone, phrases starting with it are matched again when they come back.
+            tree.add_item("$crab", 3).await;
+            assert_eq!(matches(&tree, "$crab and a panda").await, HashSet::from([1, 3]));
+        }
+    }
+
+    // Checks the backends find the same items in each text and have the same counts for the items.
+    async fn assert_same_matches(
+        tree: &BulkSearchTree<u32>, automaton: &BulkSearchTree<u32>, texts: &[&str], items: u32,
+    ) {
+        for text in texts {
+            assert_eq!(matches(tree, text).await, matches(automaton, text).await, "{text:?} with {:?}", tree.options);
+            assert_eq!(tree.any_match(text).await, automaton.any_match(text).await, "{text:?} with {:?}", tree.options);
+        }
+        for item in 0..items {
+            let phrases = PHRASES.map(str::to_string);
+            assert_eq!(tree.match_counts(&item, phrases.clone()).await, automaton.match_counts(&item, phrases).await);
+        }
+        let (tree_stats, automaton_stats) = (tree.stats().await, automaton.stats().await);
+        assert_eq!((tree_stats.phrases, tree_stats.entries), (automaton_stats.phrases, automaton_stats.entries));
+    }
+
+    // Phrases which overlap each other, and some which normalize to the same thing with some options.
+    const PHRASES: [&str; 18] = [
+        "hello", "hel", "he", "world", "or", "Café", "cafe", "red panda", "panda", "pandas", "👍", "👍🏽", "🇸🇬", "👨",
+        "👨\u{200D}👩\u{200D}👧", "straße", "σοφία", "a",
+    ];
+
+    #[tokio::test]
+    async fn test_backends_match_the_same() {
+        let texts = [
+            "hello world", "HELLO WORLD", "a red panda eating bamboo", "i love Cafés", "i love cafe so much", "nice 👍🏽",
+            "nice 👍👍🏽", "🇺🇸🇸🇬 🇺🇸🇬🇧", "hi 👨\u{200D}👩\u{200D}👧 all", "STRAßE", "ΣΟΦΊΑ", "", "nothing here",
+            "redpandas and pandas, panda!",
+        ];
+        let items = HOT_BRANCH_THRESHOLD as u32 * 2;
+        for case_insensitive in [false, true] {
+            for diacritic_insensitive in [false, true] {
+                for whole_word in [false, true] {
+                    for emoji_components in [false, true] {
+                        let options = MatchOptions {
+                            case_insensitive, diacritic_insensitive, whole_word, emoji_components,
+                            ..MatchOptions::default()
+                        };
+                        let tree = BulkSearchTree::new_with_backend(options, SearchBackend::Tree);
+                        let automaton = BulkSearchTree::new_with_backend(options, SearchBackend::AhoCorasick);
+
+                        // Each phrase goes to a few items, and "a" goes to all of them to make its branch hot.
+                        for (i, phrase) in PHRASES.iter().enumerate() {
+                            let holders = if *phrase == "a" { items } else { i as u32 % 3 + 1 };
+                            for item in (0..holders).map(|holder| (holder + i as u32) % items) {
+                                assert_eq!(tree.add_item(phrase, item).await, automaton.add_item(phrase, item).await);
+                            }
+                        }
+                        assert_same_matches(&tree, &automaton, &texts, items).await;
+
+                        // Removing every item from a phrase drops it from the automaton, and adding one back brings it
+                        // back.
+                        for item in [8, 9, 10, 0] {
+                            let phrase = if item == 0 { "a" } else { "panda" };
+                            assert!(tree.remove_item(phrase, &item).await);
+                            assert!(automaton.remove_item(phrase, &item).await);
+                        }
+                        assert!(automaton.match_counts(&8, ["panda".to_string()]).await.is_empty());
+                        assert_same_matches(&tree, &automaton, &texts, items).await;
+                        assert!(tree.add_item("panda", 8).await);
+                        assert!(automaton.add_item("panda", 8).await);
+                        assert_same_matches(&tree, &automaton, &texts, items).await;
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_builds_once() {
+        let tree = BulkSearchTree::new_with_backend(MatchOptions::default(), SearchBackend::AhoCorasick);
+        tree.add_item("<0>", 0).await;
+
+        // Loading many phrases in a batch leaves the automaton unbuilt until the batch is done.
+        let mut batch = tree.batch().await;
+        for i in 1..20_000 {
+            assert!(batch.add_item(&format!("<{i}>"), i));
+        }
+        assert!(batch.remove_item("<0>", &0));
+        assert!(matches!(&*batch.index, PhraseIndex::Automaton(automaton) if automaton.automaton.is_none()));
+        drop(batch);
+        let index = tree.index.read().await;
+        assert!(matches!(&*index, PhraseIndex::Automaton(automaton) if automaton.automaton.is_some()));
+        drop(index);
+        assert_eq!(tree.stats().await.phrases, 19_999);
+        assert_eq!(matches(&tree, "<0> and <12345>").await, HashSet::from([12345]));
+    }
+
+    #[tokio::test]
+    async fn test_automaton_falls_back_to_tree() {
+        let tree = BulkSearchTree::new_with_backend(MatchOptions::default(), SearchBackend::AhoCorasick);
+        tree.add_item("red panda", 1).await;
+        tree.add_item("red panda", 2).await;
+        tree.add_item("red fox", 3).await;
+        assert_eq!(matches(&tree, "a red panda").await, HashSet::from([1, 2]));
+
+        // The phrases, their items and their match counts all move into the tree.
+        fall_back_to_tree(&mut *tree.index.write().await);
+        assert!(matches!(*tree.index.read().await, PhraseIndex::Tree(_)));
+        assert_eq!(matches(&tree, "a red panda and a red fox").await, HashSet::from([1, 2, 3]));
+        assert_eq!(tree.match_counts(&1, ["red panda".to_string()]).await["red panda"], 2);
+        assert!(tree.remove_item("red fox", &3).await);
+        assert!(matches(&tree, "a red fox").await.is_empty());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let options = MatchOptions::default();
+        assert!(matches!(options.normalize("already lower"), Cow::Borrowed(_)));
+        assert_eq!(options.normalize("Hello WORLD"), "hello world");
+        assert_eq!(options.normalize("CAFÉ"), "café");
+        assert_eq!(options.normalize("I \u{2764}\u{FE0F} it\u{FE0E}"), "i \u{2764} it");
+
+        let options = MatchOptions { case_insensitive: false, diacritic_insensitive: true, ..MatchOptions::default() };
+        assert_eq!(options.normalize("Café Ångström"), "Cafe Angstrom");
+        assert_eq!(options.normalize("한국어"), "한국어");
+    }
+
+    #[tokio::test]
+    async fn test_casing_edge_cases() {
+        let default = BulkSearchTree::new();
+        let options = MatchOptions { diacritic_insensitive: true, ..MatchOptions::default() };
+        let diacritics = BulkSearchTree::new_with_options(options);
+        for phrase in ["straße", "İstanbul", "ıspanak", "οδός", "σοφία"] {
+            assert!(default.add_item(phrase, 1).await);
+            assert!(diacritics.add_item(phrase, 1).await);
+        }
+
+        // Each text, and whether it matches with the default options and with diacritic_insensitive.
+        let texts = [
+            ("STRASSE", false, false),
+            ("Straße", true, true),
+            ("İSTANBUL", true, true),
+            ("istanbul", false, true),
+            ("ISPANAK", false, false),
+            ("ıspanak", true, true),
+            ("ΟΔΌΣ", true, true),
+            ("ΟΔΟΣ", false, true),
+            ("οδόσ", true, true),
+            ("ΣΟΦΊΑ", true, true),
+            ("ςοφία", true, true),
+        ];
+        for (text, default_matches, diacritics_matches) in texts {
+            assert_eq!(!default.find_all_matches(text).await.is_empty(), default_matches, "{text:?} with the defaults");
+            let matches = !diacritics.find_all_matches(text).await.is_empty();
+            assert_eq!(matches, diacritics_matches, "{text:?} without diacritics");
+        }
+
+        // Phrases and text go through the same normalization, so they are byte for byte the same.
+        let options = MatchOptions::default();
+        assert_eq!(options.normalize("ΟΔΌΣ"), "οδόσ");
+        assert_eq!(options.normalize("İ").as_bytes(), "i\u{307}".as_bytes());
+        assert_eq!(options.normalize("ẞ"), "ß");
+    }
+
+    #[tokio::test]
+    async fn test_match_option_combinations() {
+        // Each text, and whether it needs case or diacritic insensitivity, or partial words to match "Café".
+        let texts = [
+            ("i love Café so much", false, false, false),
+            ("i love CAFÉ so much", true, false, false),
+            ("i love Cafe so much", false, true, false),
+            ("i love CAFE so much", true, true, false),
+            ("i love Cafés so much", false, false, true),
+            ("i love theCafé so much", false, false, true),
+            ("i love CAFES so much", true, true, true),
+        ];
+        for case_insensitive in [false, true] {
+            for diacritic_insensitive in [false, true] {
+                for whole_word in [false, true] {
+                    let options = MatchOptions {
+                        case_insensitive, diacritic_insensitive, whole_word, ..MatchOptions::default()
+                    };
+                    let tree = BulkSearchTree::new_with_options(options);
+                    assert!(tree.add_item("Café", 1).await);
+
+                    for (text, needs_case, needs_diacritic, partial_word) in texts {
+                        let expected = (case_insensitive || !needs_case)
+                            && (diacritic_insensitive || !needs_diacritic)
+                            && (!whole_word || !partial_word);
+                        let matched = !tree.find_all_matches(text).await.is_empty();
+                        assert_eq!(matched, expected, "{text:?} with {options:?}");
+                    }
+
+                    // Removing goes through the same normalization.
+                    assert!(tree.remove_item("Café", &1).await);
+                    assert!(tree.find_all_matches("Café").await.is_empty());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_phrase() {
+        // Phrases come out of Postgres as they were typed, so the tree has to do the lowercasing itself.
+        let tree = BulkSearchTree::new();
+        assert!(tree.add_item("RuSt Lang", 1).await);
+        assert_eq!(tree.find_all_matches("Learning rUST lANG today").await.len(), 1);
+        assert!(tree.remove_item("rust LANG", &1).await);
+        assert!(tree.find_all_matches("Learning rUST lANG today").await.is_empty());
+
+        // With case sensitivity on, only the exact casing matches.
+        let options = MatchOptions { case_insensitive: false, ..MatchOptions::default() };
+        let tree = BulkSearchTree::new_with_options(options);
+        assert!(tree.add_item("RuSt Lang", 1).await);
+        assert!(tree.find_all_matches("Learning rUST lANG today").await.is_empty());
+        assert_eq!(tree.find_all_matches("Learning RuSt Lang today").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_whole_word_edges() {
+        let tree = BulkSearchTree::new_with_options(MatchOptions { whole_word: true, ..MatchOptions::default() });
+        tree.add_item("red panda", 1).await;
+        assert!(!tree.find_all_matches("red panda").await.is_empty());
+        assert!(!tree.find_all_matches("a red panda!").await.is_empty());
+        assert!(tree.find_all_matches("red pandas").await.is_empty());
+        assert!(tree.find_all_matches("fred panda").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_any_match() {
+        let tree = BulkSearchTree::new_with_options(MatchOptions { whole_word: true, ..MatchOptions::default() });
+        assert!(!tree.any_match("a red panda").await);
+        tree.add_item("red panda", 1).await;
+        tree.add_item("panda", 1).await;
+        assert!(tree.any_match("a red panda").await);
+        assert!(tree.any_match("A RED PANDA").await);
+        assert!(!tree.any_match("red pandas").await);
+        assert!(!tree.any_match("").await);
+
+        // Matches aren't counted, since nothing is delivered.
+        let phrases = ["red panda".to_string(), "panda".to_string()];
+        assert_eq!(tree.match_counts(&1, phrases).await.values().sum::<u64>(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_emoji_sequences() {
+        let tree = BulkSearchTree::new();
+        let (family, man, thumbs, toned_thumbs, flag) = (1, 2, 3, 4, 5);
+        tree.add_item("👨\u{200D}👩\u{200D}👧", family).await;
+        tree.add_item("👨", man).await;
+        tree.add_item("👍", thumbs).await;
+        tree.add_item("👍🏽", toned_thumbs).await;
+        tree.add_item("🇸🇬", flag).await;
+        let expect = |items: &[u32]| items.iter().copied().collect::<HashSet<_>>();
+
+        // Sequences only match in full, and not when they are part of a longer sequence.
+        assert_eq!(matches(&tree, "hi 👨\u{200D}👩\u{200D}👧 all").await, expect(&[family]));
+        assert_eq!(matches(&tree, "hi 👨\u{200D}👩\u{200D}👧\u{200D}👦 all").await, expect(&[]));
+        assert_eq!(matches(&tree, "hi 👩\u{200D}👨 all").await, expect(&[]));
+        assert_eq!(matches(&tree, "hi 👨 all").await, expect(&[man]));
+        assert_eq!(matches(&tree, "👨👨\u{200D}👩\u{200D}👧").await, expect(&[man, family]));
+
+        // Skin tones are part of the emoji, so a toned emoji isn't the untoned one.
+        assert_eq!(matches(&tree, "nice 👍🏽").await, expect(&[toned_thumbs]));
+        assert_eq!(matches(&tree, "nice 👍🏿").await, expect(&[]));
+        assert_eq!(matches(&tree, "nice 👍👍🏽").await, expect(&[thumbs, toned_thumbs]));
+
+        // Variation selectors are dropped, so an emoji matches whether or not the keyboard sent one with it.
+        assert_eq!(matches(&tree, "nice 👍\u{FE0F}").await, expect(&[thumbs]));
+        assert_eq!(matches(&tree, "nice 👍\u{FE0F}🏽").await, expect(&[toned_thumbs]));
+        tree.add_item("\u{2764}", 6).await;
+        tree.add_item("\u{2665}\u{FE0F}", 7).await;
+        assert_eq!(matches(&tree, "i \u{2764}\u{FE0F} it").await, expect(&[6]));
+        assert_eq!(matches(&tree, "i \u{2665} it").await, expect(&[7]));
+
+        // Flags are pairs of letters, so the letters across two flags aren't a flag.
+        assert_eq!(matches(&tree, "🇸🇬").await, expect(&[flag]));
+        assert_eq!(matches(&tree, "🇺🇸🇬🇧").await, expect(&[]));
+        assert_eq!(matches(&tree, "🇺🇸🇸🇬").await, expect(&[flag]));
+
+        // Matching parts of sequences can be turned on.
+        let tree = BulkSearchTree::new_with_options(MatchOptions { emoji_components: true, ..MatchOptions::default() });
+        tree.add_item("👨", man).await;
+        tree.add_item("👍", thumbs).await;
+        tree.add_item("🇸🇬", flag).await;
+        assert_eq!(matches(&tree, "hi 👨\u{200D}👩\u{200D}👧 all").await, expect(&[man]));
+        assert_eq!(matches(&tree, "nice 👍🏽").await, expect(&[thumbs]));
+        assert_eq!(matches(&tree, "🇺🇸🇬🇧").await, expect(&[flag]));
+    }
+
+    #[tokio::test]
+    async fn test_min_length() {
+        let tree = BulkSearchTree::new_with_options(MatchOptions { min_length: 3, ..MatchOptions::default() });
+        assert!(!tree.add_item("ab", 1).await);
+        assert!(!tree.add_item("éé", 1).await);
+        assert!(tree.add_item("abc", 1).await);
+    }
+
+    #[tokio::test]
+    async fn test_max_bytes() {
+        let tree = BulkSearchTree::new_with_options(MatchOptions { max_bytes: 8, ..MatchOptions::default() });
+
+        // The limit is on bytes once normalized, so uppercase counts the same and multi-byte characters count more.
+        assert!(tree.add_item("RED PAND", 1).await);
+        assert!(!tree.too_long("red pand"));
+        assert!(tree.too_long("red panda"));
+        assert!(!tree.add_item("red panda", 1).await);
+        assert!(!tree.add_item("ééééé", 1).await);
+        assert!(tree.add_item("éééé", 1).await);
+        assert_eq!(tree.find_all_matches("a red panda").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_match_counts() {
+        let tree = BulkSearchTree::new();
+        tree.add_item("Red Panda", 1).await;
+        tree.add_item("bamboo", 1).await;
+
+        // A phrase counts once per search, however many times it is in the text.
+        tree.find_all_matches("a red panda eating bamboo").await;
+        tree.find_all_matches("red panda, red panda!").await;
+        let phrases = ["Red Panda".to_string(), "bamboo".to_string(), "not added".to_string()];
+        let counts = tree.match_counts(&1, phrases).await;
+        assert_eq!(counts, HashMap::from([("Red Panda".to_string(), 2), ("bamboo".to_string(), 1)]));
+
+        // Items added later start from zero, and items without a phrase don't get a count for it.
+        tree.add_item("red panda", 2).await;
+        let counts = tree.match_counts(&2, ["red panda".to_string()]).await;
+        assert_eq!(counts, HashMap::from([("red panda".to_string(), 0)]));
+        assert!(tree.match_counts(&3, ["red panda".to_string()]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_phrases_in() {
+        let options = MatchOptions { whole_word: true, ..MatchOptions::default() };
+        let tree: BulkSearchTree<u64> = BulkSearchTree::new_with_options(options);
+        let phrases = ["Red Panda", "bamboo", "pan", "tree"].map(str::to_string);
+
+        // Phrases are matched with the tree's options and kept as they were given.
+        let mut found = tree.phrases_in("a red panda eating bamboo", phrases).await;
+        found.sort();
+        assert_eq!(found, vec!["Red Panda", "bamboo"]);
+        assert!(tree.find_all_matches("a red panda").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_remove_item() {
+        let tree = BulkSearchTree::new();
+        tree.add_item("hello", 1).await;
+        tree.add_item("hello", 2).await;
+        assert!(tree.remove_item("hello", &1).await);
+        assert_eq!(tree.find_all_matches("hello").await, vec![2]);
+        assert!(!tree.remove_item("goodbye", &2).await);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let tree = BulkSearchTree::new();
+        assert_eq!(tree.stats().await, TreeStats::default());
+
+        tree.add_item("hello", 1).await;
+        tree.add_item("help", 1).await;
+        tree.add_item("hello", 2).await;
+        tree.add_item("x", 2).await;
+
+        // After the "h", "hello" and "help" share "el" and then split into "lo" and "p".
+        assert_eq!(tree.stats().await, TreeStats { branches: 3, phrases: 3, entries: 4 });
+
+        tree.remove_item("hello", &2).await;
+        assert_eq!(tree.stats().await.entries, 3);
+    }
+}
@@ -1,336 +1,614 @@
-use std::{collections::HashSet, sync::{atomic::{AtomicU64, AtomicI64, Ordering}, Arc}};
+use std::{fmt::Display, hash::{Hash, Hasher}, str::FromStr, sync::{atomic::AtomicUsize, Arc, Mutex}};
+#[cfg(any(feature = "firehose", feature = "http"))]
+use std::sync::atomic::{AtomicBool, AtomicI64};
+#[cfg(feature = "firehose")]
+use std::sync::atomic::{AtomicU32, Ordering};
+use crypto::{digest::Digest, sha2::Sha256};
 use hex::FromHexError;
-use tokio::sync::RwLock;
 
-// Defines a global ID counter for users.
-static USER_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+pub use worker::bulk_search::{MatchOptions, SearchBackend};
 
-pub struct User {
-    // Internally used to manage the tree users fast. Nothing to do with bsky.
-    pub id: u64,
+// Defines the tree the worker matches posts with. It holds the users themselves, so a match gives back who to deliver
+// to.
+pub type BulkSearchTree = worker::bulk_search::BulkSearchTree<Arc<User>>;
 
-    pub did: Option<String>,
-    pub phrases: Vec<String>,
-    pub endpoint: String,
-    pub private_key: Vec<u8>,
-    pub user_downtime_started: AtomicI64,
+// Defines a batch of changes to the tree the worker matches posts with.
+pub type TreeBatch<'a> = worker::bulk_search::TreeBatch<'a, Arc<User>>;
+
+// Defines how the worker matches posts against its tree.
+#[cfg(feature = "firehose")]
+pub type Matcher<'a> = worker::matcher::Matcher<'a, Arc<User>>;
+
+// Gets the ID for a user from their private key. This is the first 8 bytes of the SHA-256 of the key, so the same user
+// always gets the same ID, even across restarts, and the ID doesn't give away the key.
+fn stable_user_id(private_key: &[u8]) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.input(private_key);
+    let mut digest = [0; 32];
+    hasher.result(&mut digest);
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
 }
 
-impl User {
-    pub fn new(
-        did: Option<String>, endpoint: String, private_key: String,
-    ) -> Result<Self, FromHexError> {
-        let private_key = hex::decode(private_key)?;
-        Ok(Self {
-            id: USER_ID_COUNTER.fetch_add(1, Ordering::Relaxed),
-            did, phrases: vec![], endpoint, private_key, user_downtime_started: AtomicI64::new(0),
-        })
+// The length of an Ed25519 private key in bytes.
+pub const PRIVATE_KEY_LENGTH: usize = 32;
+
+// Defines why a user could not be created.
+#[derive(Debug)]
+pub enum UserError {
+    InvalidHex(FromHexError),
+    BadKeyLength(usize),
+    EmptyEndpoint,
+    InvalidEndpoint(String),
+    UnsupportedSigning(String),
+}
+
+impl Display for UserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserError::InvalidHex(error) => write!(f, "private key is not valid hex: {error}"),
+            UserError::BadKeyLength(length) => {
+                write!(f, "private key must be {PRIVATE_KEY_LENGTH} bytes, got {length}")
+            }
+            UserError::EmptyEndpoint => write!(f, "endpoint is empty"),
+            UserError::InvalidEndpoint(reason) => write!(f, "endpoint is not valid: {reason}"),
+            UserError::UnsupportedSigning(reason) => write!(f, "signing settings are not valid: {reason}"),
+        }
     }
 }
 
-#[derive(Default)]
-struct BulkSearchBranch {
-    // A mapping of path chunks to the next branch. This is a option to allow for splits,
-    // but will always be Some.
-    mapping: Vec<Option<(Vec<u8>, BulkSearchBranch)>>,
+impl From<FromHexError> for UserError {
+    fn from(error: FromHexError) -> Self {
+        UserError::InvalidHex(error)
+    }
+}
 
-    // A list of users in this branch.
-    users: Vec<Arc<User>>,
+// Makes sure the endpoint is a HTTP(S) URL with a host.
+fn validate_endpoint(endpoint: &str) -> Result<(), UserError> {
+    if endpoint.trim().is_empty() {
+        return Err(UserError::EmptyEndpoint);
+    }
+    let url = url::Url::parse(endpoint).map_err(|error| UserError::InvalidEndpoint(error.to_string()))?;
+    if !matches!(url.scheme(), "https" | "http") {
+        return Err(UserError::InvalidEndpoint(format!("unsupported scheme {:?}", url.scheme())));
+    }
+    match url.host_str() {
+        Some(host) if !host.is_empty() => Ok(()),
+        _ => Err(UserError::InvalidEndpoint("no host".to_string())),
+    }
 }
 
-// Recurse through each branch that is relevant to the remaining path. Adds any users from it to the result.
-fn walk_branch(
-    mut branch: &BulkSearchBranch, mut remaining_path: &[u8], consumed_users: &mut HashSet<u64>,
-    users: &mut Vec<Arc<User>>,
-) {
-'outer:
-    loop {
-        // Add any users in this branch to the result.
-        users.extend(branch.users.iter().filter(|user| {
-            let was_uniq = consumed_users.insert(user.id);
-            was_uniq
-        }).cloned());
-
-        // If we have no more path left then we are done.
-        if remaining_path.is_empty() {
-            return;
-        }
+// Defines what marking one of a user's endpoints as dead did.
+#[cfg(feature = "firehose")]
+#[derive(Debug, PartialEq)]
+pub enum EndpointDeath {
+    // Something else already marked it dead, so there is nothing to do.
+    AlreadyDead,
 
-        // Go through each tree node in the current branch.
-        for node_opt in branch.mapping.iter() {
-            // This will never be None.
-            let node = node_opt.as_ref().unwrap();
+    // The user has other endpoints which are still delivered to.
+    OthersLeft,
 
-            // Check if the remaining path starts with this chunk.
-            if remaining_path.starts_with(&node.0) {
-                // Take the length of the path chunk and remove it from the remaining path.
-                remaining_path = &remaining_path[node.0.len()..];
+    // It was the user's last endpoint, so the user should go.
+    Last,
+}
 
-                // Recurse into the next branch.
-                branch = &node.1;
-                continue 'outer;
-            }
-        }
+// Defines how deliveries to a user are signed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SigningMode {
+    Ed25519,
+    Hmac,
+    Both,
+}
 
-        // If we get here then we have no more branches to recurse into.
-        break;
+impl SigningMode {
+    pub fn ed25519(self) -> bool {
+        matches!(self, SigningMode::Ed25519 | SigningMode::Both)
     }
-}
 
-// Splits a node by creating a new branch and adding the user to the junction.
-fn split_node(
-    node_opt: &mut Option<(Vec<u8>, BulkSearchBranch)>, split_at: usize, user: Arc<User>,
-) {
-    let (path, branch) = node_opt.take().unwrap();
-
-    let junction_branch = BulkSearchBranch {
-        mapping: vec![
-            // Everything after the split point and the old branch.
-            Some((path[split_at..].to_vec(), branch)),
-        ],
-        users: vec![user],
-    };
-
-    // Replace the node with the junction branch.
-    node_opt.replace((path[..split_at].to_vec(), junction_branch));
+    pub fn hmac(self) -> bool {
+        matches!(self, SigningMode::Hmac | SigningMode::Both)
+    }
 }
 
-// Writes to a branch by recursing through and then splitting if needed. Returns true if the user was added.
-fn write_branch(mut branch: &mut BulkSearchBranch, mut remaining_path: &[u8], user: Arc<User>) -> bool {
-'outer:
-    loop {
-        // SAFETY: In this context, a unsafe reference copy is safe and based even though it violates Rust's safety guarantees.
-        // This is because we will never re-mutably borrow the branch after this point and we know it will stay alive.
-        let unsafe_ref = unsafe { &mut *(&mut *branch as *mut BulkSearchBranch) };
-
-        // If we have no more path left then we are done.
-        if remaining_path.is_empty() {
-            let unique = unsafe_ref.users.iter().find(|u| u.id == user.id).is_none();
-            if unique {
-                unsafe_ref.users.push(user);
-            }
-            return unique;
-        }
+impl FromStr for SigningMode {
+    type Err = UserError;
 
-        for node_opt in unsafe_ref.mapping.iter_mut() {
-            // SAFETY: This copy is ONLY used in the context of a node split.
-            let node_opt_2 = unsafe { &mut *(&mut *node_opt as *mut _) };
-
-            // This will never be None.
-            let node = node_opt.as_mut().unwrap();
-
-            // Find out if this chunk is smaller than the remaining path.
-            let smaller = node.0.len() <= remaining_path.len();
-
-            if smaller {
-                // If we start with this chunk then we should recurse into the next branch.
-                if remaining_path.starts_with(&node.0) {
-                    remaining_path = &remaining_path[node.0.len()..];
-                    branch = &mut node.1;
-                    continue 'outer;
-                }
-            } else if node.0.starts_with(remaining_path) {
-                // We need to split this node.
-                split_node(node_opt_2, remaining_path.len(), user);
-                return true;
-            }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(SigningMode::Ed25519),
+            "hmac" => Ok(SigningMode::Hmac),
+            "both" => Ok(SigningMode::Both),
+            _ => Err(UserError::UnsupportedSigning(format!("unknown signing mode {s:?}"))),
         }
-
-        // If no other node matched then we need to create a new node.
-        let new_node = BulkSearchBranch {
-            mapping: vec![],
-            users: vec![user.clone()],
-        };
-        branch.mapping.push(Some((remaining_path.to_vec(), new_node)));
-        return true;
     }
 }
 
-// Find a mutable branch that matches EXACTLY the remaining path.
-fn find_mut_branch<'a>(mut branch: &'a mut BulkSearchBranch, mut remaining_path: &[u8]) -> Option<&'a mut BulkSearchBranch> {
-'outer:
-    loop {
-        // If we have no more path left then we are done.
-        if remaining_path.is_empty() {
-            return Some(branch);
-        }
+// Defines one of the URLs a user gets deliveries at, and how delivering to it has been going.
+pub struct Endpoint {
+    pub url: String,
 
-        // Go through each tree node in the current branch.
-        for node_opt in branch.mapping.iter_mut() {
-            // This will never be None.
-            let node = node_opt.as_mut().unwrap();
+    // When the endpoint started failing in milliseconds since the epoch, or 0 if it is up.
+    #[cfg(any(feature = "firehose", feature = "http"))]
+    pub downtime_started: AtomicI64,
 
-            // Check if the remaining path starts with this chunk.
-            if remaining_path.starts_with(&node.0) {
-                // Take the length of the path chunk and remove it from the remaining path.
-                remaining_path = &remaining_path[node.0.len()..];
+    // When the last successful delivery was in milliseconds since the epoch, or 0 if there has not been one.
+    #[cfg(any(feature = "firehose", feature = "http"))]
+    pub last_success: AtomicI64,
 
-                // Recurse into the next branch.
-                branch = &mut node.1;
-                continue 'outer;
-            }
+    // Set once the endpoint is broken for good. Dead endpoints aren't delivered to until the user is reloaded.
+    #[cfg(any(feature = "firehose", feature = "http"))]
+    pub dead: AtomicBool,
+
+    // When the endpoint asked us to wait until with a Retry-After in milliseconds since the epoch, or 0.
+    #[cfg(feature = "firehose")]
+    pub retry_after_until: AtomicI64,
+
+    // How many 429s with a Retry-After the endpoint has returned in a row.
+    #[cfg(feature = "firehose")]
+    pub retry_after_count: AtomicU32,
+}
+
+impl Endpoint {
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            #[cfg(any(feature = "firehose", feature = "http"))]
+            downtime_started: AtomicI64::new(0),
+            #[cfg(any(feature = "firehose", feature = "http"))]
+            last_success: AtomicI64::new(0),
+            #[cfg(any(feature = "firehose", feature = "http"))]
+            dead: AtomicBool::new(false),
+            #[cfg(feature = "firehose")]
+            retry_after_until: AtomicI64::new(0),
+            #[cfg(feature = "firehose")]
+            retry_after_count: AtomicU32::new(0),
         }
+    }
+
+    // Checks the host is on the allowlist. Every host is allowed when there is no allowlist. Both sides are normalized,
+    // so the same host written differently still matches.
+    pub fn host_allowed(&self, allowlist: Option<&[String]>) -> bool {
+        let Some(allowlist) = allowlist else {
+            return true;
+        };
+        let Some(host) = url::Url::parse(&self.url).ok().and_then(|url| url.host_str().and_then(normalize_host)) else {
+            return false;
+        };
+        allowlist.iter().any(|allowed| normalize_host(allowed).is_some_and(|allowed| allowed == host))
+    }
+}
 
-        // If we get here then we have no more branches to recurse into. Return None.
+// Normalizes a hostname the way URL hosts are written: lowercase, punycode for international names and no trailing
+// dot. Returns None if it is not a valid hostname.
+pub fn normalize_host(host: &str) -> Option<String> {
+    let host = host.trim().trim_end_matches('.');
+    if host.is_empty() {
         return None;
     }
+    url::Host::parse(host).ok().map(|host| host.to_string())
+}
+
+pub struct User {
+    // Internally used to manage the tree users fast. Nothing to do with bsky. Derived from the private key, so it is
+    // stable across restarts.
+    pub id: u64,
+
+    pub did: Option<String>,
+
+    // DIDs the user had before this one. Mentions of these are still sent to the user, unless another user now has one
+    // as their current DID.
+    pub previous_dids: Vec<String>,
+
+    // The phrases the user is matched on, as they were typed. The tree only holds them normalized, so these are what is
+    // reported back. These can change while the user is loaded, so they are behind a lock.
+    phrases: Mutex<Vec<String>>,
+
+    // Where deliveries are sent. Every delivery goes to each endpoint which isn't dead, and the first is the primary.
+    pub endpoints: Vec<Endpoint>,
+
+    // How many endpoints aren't dead. The user is evicted when this reaches 0.
+    live_endpoints: AtomicUsize,
+
+    pub private_key: [u8; PRIVATE_KEY_LENGTH],
+
+    // The key the user had before rotating to this one. While it is set, deliveries are signed with both so receivers
+    // can move over to the new public key in their own time.
+    pub previous_key: Option<[u8; PRIVATE_KEY_LENGTH]>,
+
+    // If false, the user is not told about posts which are replies.
+    pub replies: bool,
+
+    // If true, deliveries to the user are sent one at a time in the order they were queued.
+    pub ordered: bool,
+
+    // If true, the user is sent a notice before they are evicted.
+    pub notify_eviction: bool,
+
+    // When the delivery pool is full, deliveries to users with a higher priority are started first.
+    pub priority: i32,
+
+    // How deliveries are signed, and the shared secret used for HMAC signatures.
+    pub signing: SigningMode,
+    pub secret: Option<String>,
+
+    // The user's handle without the "@", matched like a phrase when it is written out in post text. This is whatever
+    // it was when the user was loaded, so it goes stale if they change their handle.
+    pub handle: Option<String>,
 }
 
-pub struct BulkSearchTree {
-    first_byte: RwLock<Vec<BulkSearchBranch>>,
+// Users are told apart by their ID, so the tree holds each user once per phrase however many copies it is given.
+impl PartialEq for User {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
 }
 
-impl BulkSearchTree {
-    pub fn new() -> Self {
-        // Create the first byte branches.
-        let vec_items = (0..=u8::MAX).map(|_| BulkSearchBranch::default()).collect();
-        let first_byte = RwLock::new(vec_items);
+impl Eq for User {}
 
-        Self { first_byte }
+impl Hash for User {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
     }
+}
 
-    // Finds all users that match witin the given text.
-    pub async fn find_all_matches(&self, text: &str) -> Vec<Arc<User>> {
-        // Turn it into bytes. We think like a robot.
-        let text = text.as_bytes();
+// Pads a short hex key out to a full private key by repeating it, so tests can tell users apart at a glance.
+#[cfg(test)]
+pub fn test_key(key: &str) -> String {
+    key.repeat(PRIVATE_KEY_LENGTH * 2 / key.len())
+}
+
+// Decodes a hex encoded private key, checking it is the right length.
+pub fn parse_private_key(private_key: &str) -> Result<[u8; PRIVATE_KEY_LENGTH], UserError> {
+    let private_key = hex::decode(private_key)?;
+    private_key.as_slice().try_into().map_err(|_| UserError::BadKeyLength(private_key.len()))
+}
 
-        // Read the first byte branches.
-        let first_byte_branches = self.first_byte.read().await;
+// Derives the Ed25519 public key receivers verify deliveries with from a private key.
+pub fn public_key(private_key: &[u8; PRIVATE_KEY_LENGTH]) -> [u8; ed25519_dalek::PUBLIC_KEY_LENGTH] {
+    ed25519_dalek::SigningKey::from_bytes(private_key).verifying_key().to_bytes()
+}
 
-        // Defines all the users we have found so far and a set so we can efficiently check if we already have them.
-        let mut users = Vec::new();
-        let mut consumed_users = HashSet::new();
+impl User {
+    pub fn new(
+        did: Option<String>, endpoint: String, private_key: String,
+    ) -> Result<Self, UserError> {
+        let private_key = parse_private_key(&private_key)?;
+        validate_endpoint(&endpoint)?;
+        Ok(Self {
+            id: stable_user_id(&private_key),
+            did, previous_dids: vec![], phrases: Mutex::default(),
+            endpoints: vec![Endpoint::new(endpoint)], live_endpoints: AtomicUsize::new(1),
+            private_key, previous_key: None,
+            replies: true, ordered: false, notify_eviction: false, priority: 0,
+            signing: SigningMode::Ed25519, secret: None,
+            handle: None,
+        })
+    }
 
-        // Iterate over each byte in the text and make a cursor for each iteration.
-        for (i, &byte) in text.iter().enumerate() {
-            let cursor_after = &text[i + 1..];
+    // Adds another endpoint which gets every delivery. This is for building the user before they are shared.
+    pub fn add_endpoint(&mut self, endpoint: String) -> Result<(), UserError> {
+        validate_endpoint(&endpoint)?;
+        self.endpoints.push(Endpoint::new(endpoint));
+        *self.live_endpoints.get_mut() += 1;
+        Ok(())
+    }
 
-            // SAFETY: We can avoid a bounds check here because we know all bytes are initialized.
-            let branch = unsafe { first_byte_branches.get_unchecked(byte as usize) };
+    // Sets the key the user is rotating away from, if they are in the middle of a rotation.
+    pub fn set_previous_key(&mut self, previous_key: Option<String>) -> Result<(), UserError> {
+        self.previous_key = previous_key.as_deref().map(parse_private_key).transpose()?;
+        Ok(())
+    }
 
-            // Walk the branch.
-            walk_branch(branch, &cursor_after, &mut consumed_users, &mut users);
+    // Marks an endpoint as dead. Only one call sees it was the last endpoint which wasn't, even if several endpoints
+    // die at the same time.
+    #[cfg(feature = "firehose")]
+    pub fn mark_endpoint_dead(&self, index: usize) -> EndpointDeath {
+        if self.endpoints[index].dead.swap(true, Ordering::Relaxed) {
+            return EndpointDeath::AlreadyDead;
         }
+        if self.live_endpoints.fetch_sub(1, Ordering::Relaxed) == 1 {
+            EndpointDeath::Last
+        } else {
+            EndpointDeath::OthersLeft
+        }
+    }
 
-        // Return the users we found.
-        users
+    // Rejects plaintext http:// endpoints unless they are allowed, since anyone on the path could read or tamper with
+    // the deliveries.
+    pub fn require_https(&self, allow_insecure: bool) -> Result<(), UserError> {
+        let insecure = self.endpoints.iter()
+            .any(|endpoint| url::Url::parse(&endpoint.url).is_ok_and(|url| url.scheme() == "http"));
+        if insecure && !allow_insecure {
+            return Err(UserError::InvalidEndpoint("http:// endpoints are not allowed".to_string()));
+        }
+        Ok(())
     }
 
-    // Adds a user to a tree branch. Return false if the text is blank or the user is already in the tree.
-    pub async fn add_item(&self, subtext: &str, user: Arc<User>) -> bool {
-        // If the text is blank then we can't add the user.
-        if subtext.is_empty() {
-            return false;
+    // Rejects users with an endpoint whose host isn't on the allowlist, if there is one.
+    pub fn require_allowed_host(&self, allowlist: Option<&[String]>) -> Result<(), UserError> {
+        if !self.endpoints.iter().all(|endpoint| endpoint.host_allowed(allowlist)) {
+            return Err(UserError::InvalidEndpoint("host is not on ENDPOINT_HOST_ALLOWLIST".to_string()));
         }
+        Ok(())
+    }
+
+    // Gets every DID the user can be found by, their current one first.
+    pub fn dids(&self) -> impl Iterator<Item = &str> {
+        self.did.iter().chain(&self.previous_dids).map(String::as_str)
+    }
 
-        // Turn the subtext into bytes.
-        let subtext = subtext.as_bytes();
+    // Gets a copy of the user's phrases.
+    pub fn phrases(&self) -> Vec<String> {
+        self.phrases.lock().unwrap().clone()
+    }
 
-        // Write lock the first byte branches.
-        let mut first_byte_branches = self.first_byte.write().await;
+    // Replaces the user's phrases. This is for building the user before they are shared.
+    pub fn set_phrases(&mut self, phrases: Vec<String>) {
+        *self.phrases.get_mut().unwrap() = phrases;
+    }
 
-        // SAFETY: We can avoid a bounds check here because we know all bytes are initialized.
-        let branch = unsafe { first_byte_branches.get_unchecked_mut(subtext[0] as usize) };
+    // Keeps only the phrases which pass the check. This is for building the user before they are shared.
+    pub fn retain_phrases(&mut self, check: impl FnMut(&String) -> bool) {
+        self.phrases.get_mut().unwrap().retain(check);
+    }
 
-        // Get the rest of the path and then write to the branch.
-        let rest_path = &subtext[1..];
-        write_branch(branch, rest_path, user)
+    // Gets how many phrases the user has.
+    #[cfg(feature = "http")]
+    pub fn phrase_count(&self) -> usize {
+        self.phrases.lock().unwrap().len()
     }
 
-    // Removes a user from the tree. Returns false if the user is not in the tree.
-    pub async fn remove_item(&self, subtext: &str, user: Arc<User>) -> bool {
-        // Turn the subtext into bytes.
-        let subtext = subtext.as_bytes();
+    // Adds a phrase to the user's list. This does not add them to the tree.
+    #[cfg(feature = "http")]
+    pub fn add_phrase(&self, phrase: &str) {
+        self.phrases.lock().unwrap().push(phrase.to_string());
+    }
 
-        // Bail if the text is blank.
-        if subtext.is_empty() {
+    // Removes a phrase from the user's list. Returns false if they did not have it. This does not remove them from the
+    // tree.
+    #[cfg(feature = "http")]
+    pub fn remove_phrase(&self, phrase: &str) -> bool {
+        let mut phrases = self.phrases.lock().unwrap();
+        let Some(index) = phrases.iter().position(|existing| existing == phrase) else {
             return false;
-        }
+        };
+        phrases.remove(index);
+        true
+    }
 
-        // Write lock the first byte branches.
-        let mut first_byte_branches = self.first_byte.write().await;
+    // Sets the user's handle. A leading "@" is dropped, and an empty handle is the same as none.
+    pub fn set_handle(&mut self, handle: Option<String>) {
+        self.handle = handle
+            .map(|handle| handle.trim().trim_start_matches('@').to_string())
+            .filter(|handle| !handle.is_empty());
+    }
 
-        // SAFETY: We can avoid a bounds check here because we know all bytes are initialized.
-        let branch = unsafe { first_byte_branches.get_unchecked_mut(subtext[0] as usize) };
+    // Gets the phrase matching a plain text mention of the user's handle, like "@alice.bsky.social".
+    pub fn handle_phrase(&self) -> Option<String> {
+        self.handle.as_ref().map(|handle| format!("@{handle}"))
+    }
 
-        // Get the rest of the path and then delete the user from the branch.
-        let rest_path = &subtext[1..];
-        let branch = find_mut_branch(branch, rest_path);
-        if let Some(branch) = branch {
-            branch.users.retain(|u| u.id != user.id);
-            true
-        } else {
-            false
+    // Sets how deliveries are signed. HMAC signing needs a secret.
+    pub fn set_signing(&mut self, signing: SigningMode, secret: Option<String>) -> Result<(), UserError> {
+        if signing.hmac() && secret.as_deref().is_none_or(str::is_empty) {
+            return Err(UserError::UnsupportedSigning("HMAC signing needs a secret".to_string()));
         }
+        self.signing = signing;
+        self.secret = secret;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::Arc;
+    use std::collections::{HashMap, HashSet};
 
-    fn create_user(did: &str, endpoint: &str) -> Arc<User> {
-        Arc::new(User::new(
-            Some(did.to_string()),
-            endpoint.to_string(),
-            "aa".to_string(),
-        ).unwrap())
+    #[tokio::test]
+    async fn test_users_in_tree() {
+        let tree = BulkSearchTree::new();
+        let user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        let other = Arc::new(User::new(None, "https://example.com".to_string(), test_key("bb")).unwrap());
+        assert!(tree.add_item("hello", user.clone()).await);
+        assert!(tree.add_item("world", other.clone()).await);
+
+        // A user loaded again from the same key is the same user, even though it is a different Arc.
+        let reloaded = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        assert!(!tree.add_item("hello", reloaded.clone()).await);
+        assert!(tree.add_item("world", reloaded).await);
+
+        let mut ids: Vec<u64> = tree.find_all_matches("hello world").await.iter().map(|user| user.id).collect();
+        ids.sort();
+        let mut expected = vec![user.id, other.id];
+        expected.sort();
+        assert_eq!(ids, expected);
     }
 
     #[tokio::test]
-    async fn test_add_and_find_user() {
-        let tree = BulkSearchTree::new();
-        let user1 = create_user("did:example:123", "http://example.com");
-        tree.add_item("hello", user1.clone()).await;
-        tree.add_item("world", user1.clone()).await;
-        let user2 = create_user("did:example:456", "http://example.com");
-        tree.add_item("ab", user2.clone()).await;
+    async fn test_replacing_users() {
+        for backend in [SearchBackend::Tree, SearchBackend::AhoCorasick] {
+            // Enough users share the phrase for its users to be indexed.
+            let tree = BulkSearchTree::new_with_backend(MatchOptions::default(), backend);
+            let users: Vec<Arc<User>> = (0..40)
+                .map(|i| User::new(None, "https://example.com".to_string(), test_key(&format!("{i:02x}"))).unwrap())
+                .map(Arc::new)
+                .collect();
+            for user in &users {
+                assert!(tree.add_item("hello", user.clone()).await);
+            }
+            tree.find_all_matches("hello").await;
+
+            // The reloaded copy takes the place of the old one, with its matches counted from the reload.
+            let reloaded = Arc::new(User::new(None, "https://example.net".to_string(), test_key("05")).unwrap());
+            assert!(tree.replace_item("hello", reloaded.clone()).await);
+            assert!(tree.replace_item("world", reloaded.clone()).await);
+            assert!(!tree.replace_item("", reloaded.clone()).await);
+            assert_eq!(tree.match_counts(&reloaded, ["hello".to_string()]).await["hello"], 0);
+            let matches = tree.find_all_matches("hello world").await;
+            assert_eq!(matches.len(), 40);
+            let found = matches.iter().find(|user| user.id == reloaded.id).unwrap();
+            assert!(Arc::ptr_eq(found, &reloaded));
+
+            // The old copy isn't held on to by the tree any more.
+            assert_eq!(Arc::strong_count(&users[5]), 1);
+            assert!(tree.remove_item("hello", &reloaded).await);
+            assert_eq!(tree.find_all_matches("hello").await.len(), 39);
+        }
+    }
 
-        let matches = tree.find_all_matches("hello world").await;
-        assert_eq!(matches.len(), 1);
-        assert_eq!(matches[0].id, user1.id);
+    #[test]
+    fn test_bad_private_keys() {
+        let result = User::new(None, "https://example.com".to_string(), "not hex".to_string());
+        assert!(matches!(result, Err(UserError::InvalidHex(_))));
+        let result = User::new(None, "https://example.com".to_string(), String::from("aa"));
+        assert!(matches!(result, Err(UserError::BadKeyLength(1))));
+        let result = User::new(None, "https://example.com".to_string(), "aa".repeat(33));
+        assert!(matches!(result, Err(UserError::BadKeyLength(33))));
+
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        assert!(matches!(user.set_previous_key(Some("bb".repeat(31))), Err(UserError::BadKeyLength(31))));
+        user.set_previous_key(Some(test_key("bb"))).unwrap();
+        assert_eq!(user.previous_key, Some([0xbb; PRIVATE_KEY_LENGTH]));
+        user.set_previous_key(None).unwrap();
+        assert_eq!(user.previous_key, None);
     }
 
-    #[tokio::test]
-    async fn test_multiple_finds() {
-        let tree = BulkSearchTree::new();
-        let user1 = create_user("did:example:123", "http://example.com");
-        tree.add_item("hello", user1.clone()).await;
-        tree.add_item("world", user1.clone()).await;
-        let user2 = create_user("did:example:456", "http://example.com");
-        tree.add_item("hello", user2.clone()).await;
+    #[test]
+    fn test_empty_endpoint() {
+        for endpoint in ["", "  "] {
+            let result = User::new(None, endpoint.to_string(), test_key("aa"));
+            assert!(matches!(result, Err(UserError::EmptyEndpoint)));
+        }
+    }
 
-        let matches = tree.find_all_matches("hello world").await;
-        assert_eq!(matches.len(), 2);
-        assert!(matches.iter().any(|u| u.id == user1.id));
-        assert!(matches.iter().any(|u| u.id == user2.id));
+    #[test]
+    fn test_valid_endpoint() {
+        assert!(User::new(None, "https://example.com/webhook".to_string(), test_key("aa")).is_ok());
     }
 
-    #[tokio::test]
-    async fn test_intersecting_phrases() {
-        let tree = BulkSearchTree::new();
-        let user1 = create_user("did:example:123", "http://example.com");
-        tree.add_item("hello", user1.clone()).await;
-        tree.add_item("world", user1.clone()).await;
-        let user2 = create_user("did:example:456", "http://example.com");
-        tree.add_item("or", user2.clone()).await;
+    #[test]
+    fn test_schemeless_endpoint() {
+        let result = User::new(None, "example.com/webhook".to_string(), test_key("aa"));
+        assert!(matches!(result, Err(UserError::InvalidEndpoint(_))));
 
-        let matches = tree.find_all_matches("hello world").await;
-        assert_eq!(matches.len(), 2);
-        assert!(matches.iter().any(|u| u.id == user1.id));
-        assert!(matches.iter().any(|u| u.id == user2.id));
+        let result = User::new(None, "ftp://example.com/webhook".to_string(), test_key("aa"));
+        assert!(matches!(result, Err(UserError::InvalidEndpoint(_))));
     }
 
-    #[tokio::test]
-    async fn test_remove_user() {
-        let tree = BulkSearchTree::new();
-        let user = create_user("did:example:123", "http://example.com");
+    #[test]
+    fn test_hostless_endpoint() {
+        let result = User::new(None, "https://".to_string(), test_key("aa"));
+        assert!(matches!(result, Err(UserError::InvalidEndpoint(_))));
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        // Loading the same users again, like after a restart, gives them the same IDs.
+        let keys = ["aa", "bb", "0123456789abcdef"];
+        let load = || keys.map(|key| User::new(None, "https://example.com".to_string(), test_key(key)).unwrap().id);
+        let first = load();
+        assert_eq!(first, load());
+        assert_eq!(first[0], 0xe0e7_7a50_7412_b120);
+        assert_eq!(first[1], 0x4ca1_4526_b275_1b64);
+        assert_eq!(first[2], 0x4884_fdaa_fea4_7c29);
+
+        // IDs are worked out from the key bytes the same way as before keys had to be a full length.
+        assert_eq!(stable_user_id(&[0xaa]), 0xbcee_f655_b5a0_3491);
+
+        // Different keys get different IDs.
+        assert_eq!(first.iter().collect::<HashSet<_>>().len(), keys.len());
+    }
+
+    #[test]
+    fn test_insecure_endpoint() {
+        let user = User::new(None, "http://example.com/webhook".to_string(), test_key("aa")).unwrap();
+        assert!(matches!(user.require_https(false), Err(UserError::InvalidEndpoint(_))));
+        assert!(user.require_https(true).is_ok());
 
-        tree.add_item("hello", user.clone()).await;
-        assert!(tree.remove_item("hello", user.clone()).await);
+        let user = User::new(None, "https://example.com/webhook".to_string(), test_key("aa")).unwrap();
+        assert!(user.require_https(false).is_ok());
+    }
+
+    #[test]
+    fn test_endpoint_host_allowlist() {
+        let allowlist = vec!["hooks.example.com".to_string()];
+        let user = User::new(None, "https://Hooks.Example.com./webhook".to_string(), test_key("aa")).unwrap();
+        assert!(user.require_allowed_host(Some(&allowlist)).is_ok());
+
+        // Other hosts, including subdomains of allowed ones, are rejected.
+        for endpoint in ["https://example.com/webhook", "https://evil.hooks.example.com/webhook"] {
+            let user = User::new(None, endpoint.to_string(), test_key("aa")).unwrap();
+            assert!(matches!(user.require_allowed_host(Some(&allowlist)), Err(UserError::InvalidEndpoint(_))));
+            assert!(user.require_allowed_host(None).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_endpoint_host_allowlist_normalized() {
+        let user = User::new(None, "https://Bücher.Example./webhook".to_string(), test_key("aa")).unwrap();
+        for allowed in ["bücher.example", "BÜCHER.example.", "xn--bcher-kva.example", " XN--BCHER-KVA.EXAMPLE "] {
+            assert!(user.require_allowed_host(Some(&[allowed.to_string()])).is_ok(), "{allowed}");
+        }
+        assert!(user.require_allowed_host(Some(&["bucher.example".to_string()])).is_err());
+
+        let user = User::new(None, "https://[::1]:8080/webhook".to_string(), test_key("aa")).unwrap();
+        assert!(user.require_allowed_host(Some(&["[0:0::1]".to_string()])).is_ok());
+    }
 
-        let matches = tree.find_all_matches("hello").await;
-        assert!(matches.is_empty());
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("Hooks.Example.COM.").as_deref(), Some("hooks.example.com"));
+        assert_eq!(normalize_host("bücher.example").as_deref(), Some("xn--bcher-kva.example"));
+        assert_eq!(normalize_host("[::1]").as_deref(), Some("[::1]"));
+        assert_eq!(normalize_host("127.000.0.1").as_deref(), Some("127.0.0.1"));
+        assert_eq!(normalize_host(""), None);
+        assert_eq!(normalize_host("."), None);
+        assert_eq!(normalize_host("bad host"), None);
+    }
+
+    #[test]
+    fn test_extra_endpoints() {
+        let mut user = User::new(None, "https://a.example/webhook".to_string(), test_key("aa")).unwrap();
+        user.add_endpoint("http://b.example/webhook".to_string()).unwrap();
+        assert!(matches!(user.add_endpoint(String::new()), Err(UserError::EmptyEndpoint)));
+        assert_eq!(user.endpoints.len(), 2);
+
+        // Every endpoint is held to the same rules as the primary.
+        assert!(user.require_https(false).is_err());
+        assert!(user.require_allowed_host(Some(&["a.example".to_string()])).is_err());
+        assert!(user.require_allowed_host(Some(&["a.example".to_string(), "b.example".to_string()])).is_ok());
+
+        // Only the last endpoint to die says the user should go, and only once.
+        assert_eq!(user.mark_endpoint_dead(1), EndpointDeath::OthersLeft);
+        assert_eq!(user.mark_endpoint_dead(1), EndpointDeath::AlreadyDead);
+        assert_eq!(user.mark_endpoint_dead(0), EndpointDeath::Last);
+        assert_eq!(user.mark_endpoint_dead(0), EndpointDeath::AlreadyDead);
+    }
+
+    #[test]
+    fn test_signing_needs_secret() {
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        assert!(matches!(user.set_signing(SigningMode::Hmac, None), Err(UserError::UnsupportedSigning(_))));
+        let result = user.set_signing(SigningMode::Both, Some(String::new()));
+        assert!(matches!(result, Err(UserError::UnsupportedSigning(_))));
+        assert!(user.set_signing(SigningMode::Ed25519, None).is_ok());
+        assert!(user.set_signing(SigningMode::Hmac, Some("hunter2".to_string())).is_ok());
+        assert_eq!(user.signing, SigningMode::Hmac);
+        assert!("rot13".parse::<SigningMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_match_counts() {
+        let tree = BulkSearchTree::new();
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        user.set_phrases(vec!["Red Panda".to_string(), "bamboo".to_string(), "not added".to_string()]);
+        let user = Arc::new(user);
+        tree.add_item("Red Panda", user.clone()).await;
+        tree.add_item("bamboo", user.clone()).await;
+
+        // Counts are reported against the phrases as the user typed them.
+        tree.find_all_matches("a red panda eating bamboo").await;
+        tree.find_all_matches("red panda, red panda!").await;
+        let counts = tree.match_counts(&user, user.phrases()).await;
+        assert_eq!(counts, HashMap::from([("Red Panda".to_string(), 2), ("bamboo".to_string(), 1)]));
     }
 }
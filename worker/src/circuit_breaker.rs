@@ -0,0 +1,136 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+// Defines the state of the circuit for a single endpoint. Endpoints with no entry are closed with no failures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BreakerState {
+    // Requests flow normally. Tracks the consecutive failures so far.
+    Closed { failures: u32 },
+
+    // Requests are short-circuited until the cooldown is over.
+    Open { until: Instant },
+
+    // A single probe request is in flight to test if the endpoint recovered. If the probe never reports back,
+    // another is allowed after the cooldown.
+    HalfOpen { probe_started: Instant },
+}
+
+// Defines a circuit breaker for each endpoint so we stop hammering hosts which are down.
+pub struct CircuitBreakers {
+    threshold: u32,
+    cooldown: Duration,
+    endpoints: Mutex<HashMap<String, BreakerState>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self { threshold, cooldown, endpoints: Mutex::new(HashMap::new()) }
+    }
+
+    // Checks if a request to the endpoint should be attempted. If the circuit has cooled down, this lets a single
+    // probe through.
+    pub fn allow(&self, endpoint: &str, now: Instant) -> bool {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let Some(state) = endpoints.get_mut(endpoint) else {
+            return true;
+        };
+        let probe_due = match *state {
+            BreakerState::Closed { .. } => return true,
+            BreakerState::Open { until } => now >= until,
+            BreakerState::HalfOpen { probe_started } => now >= probe_started + self.cooldown,
+        };
+        if probe_due {
+            *state = BreakerState::HalfOpen { probe_started: now };
+        }
+        probe_due
+    }
+
+    // Records a successful request, closing the circuit.
+    pub fn record_success(&self, endpoint: &str) {
+        self.endpoints.lock().unwrap().remove(endpoint);
+    }
+
+    // Records a failed request, opening the circuit if we hit the threshold or the probe failed.
+    pub fn record_failure(&self, endpoint: &str, now: Instant) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let state = endpoints.entry(endpoint.to_string()).or_insert(BreakerState::Closed { failures: 0 });
+        *state = match *state {
+            BreakerState::Closed { failures } if failures + 1 < self.threshold => {
+                BreakerState::Closed { failures: failures + 1 }
+            }
+            _ => BreakerState::Open { until: now + self.cooldown },
+        };
+    }
+
+    #[cfg(test)]
+    fn state(&self, endpoint: &str) -> Option<BreakerState> {
+        self.endpoints.lock().unwrap().get(endpoint).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENDPOINT: &str = "https://example.com/webhook";
+
+    #[test]
+    fn test_full_cycle() {
+        let breakers = CircuitBreakers::new(3, Duration::from_secs(30));
+        let now = Instant::now();
+
+        // Closed: failures below the threshold still let requests through.
+        assert!(breakers.allow(ENDPOINT, now));
+        breakers.record_failure(ENDPOINT, now);
+        breakers.record_failure(ENDPOINT, now);
+        assert_eq!(breakers.state(ENDPOINT), Some(BreakerState::Closed { failures: 2 }));
+        assert!(breakers.allow(ENDPOINT, now));
+
+        // Open: the third failure trips the breaker.
+        breakers.record_failure(ENDPOINT, now);
+        assert!(matches!(breakers.state(ENDPOINT), Some(BreakerState::Open { .. })));
+        assert!(!breakers.allow(ENDPOINT, now + Duration::from_secs(29)));
+
+        // Half open: after the cooldown a single probe is allowed.
+        let probe_time = now + Duration::from_secs(30);
+        assert!(breakers.allow(ENDPOINT, probe_time));
+        assert_eq!(breakers.state(ENDPOINT), Some(BreakerState::HalfOpen { probe_started: probe_time }));
+        assert!(!breakers.allow(ENDPOINT, probe_time));
+
+        // Closed: the probe succeeded.
+        breakers.record_success(ENDPOINT);
+        assert_eq!(breakers.state(ENDPOINT), None);
+        assert!(breakers.allow(ENDPOINT, probe_time));
+    }
+
+    #[test]
+    fn test_failed_probe_reopens() {
+        let breakers = CircuitBreakers::new(1, Duration::from_secs(30));
+        let now = Instant::now();
+        breakers.record_failure(ENDPOINT, now);
+        let probe_time = now + Duration::from_secs(30);
+        assert!(breakers.allow(ENDPOINT, probe_time));
+        breakers.record_failure(ENDPOINT, probe_time);
+        assert_eq!(breakers.state(ENDPOINT), Some(BreakerState::Open { until: probe_time + Duration::from_secs(30) }));
+        assert!(!breakers.allow(ENDPOINT, probe_time + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_lost_probe_is_retried() {
+        let breakers = CircuitBreakers::new(1, Duration::from_secs(30));
+        let now = Instant::now();
+        breakers.record_failure(ENDPOINT, now);
+        assert!(breakers.allow(ENDPOINT, now + Duration::from_secs(30)));
+        assert!(!breakers.allow(ENDPOINT, now + Duration::from_secs(59)));
+        assert!(breakers.allow(ENDPOINT, now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let breakers = CircuitBreakers::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+        breakers.record_failure(ENDPOINT, now);
+        breakers.record_success(ENDPOINT);
+        breakers.record_failure(ENDPOINT, now);
+        assert!(breakers.allow(ENDPOINT, now));
+    }
+}
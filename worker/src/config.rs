@@ -0,0 +1,923 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+#[cfg(feature = "http")]
+use std::net::SocketAddr;
+#[cfg(feature = "postgres")]
+use deadpool_postgres::{PoolConfig, Timeouts};
+use serde::de::DeserializeOwned;
+use crate::{bulk_search_tree::{normalize_host, MatchOptions, SearchBackend}, store::{SeedUser, StoreKind}};
+#[cfg(feature = "firehose")]
+use crate::delivery::PayloadProfile;
+#[cfg(feature = "firehose")]
+use worker::matcher::MatchMode;
+
+// The shortest HTTP key we will accept. The key guards every mutating endpoint, so it must not be guessable.
+#[cfg(feature = "http")]
+pub const MIN_HTTP_KEY_LENGTH: usize = 32;
+
+// The headers on deliveries which can't be set with DELIVERY_HEADERS.
+const RESERVED_DELIVERY_HEADERS: &[&str] = &[
+    "Content-Type", "Content-Encoding", "Content-Length", "Host", "User-Agent", "X-Signature-Ed25519",
+    "X-Signature-Ed25519-Previous", "X-Signature-HMAC", "X-Signature-Timestamp",
+];
+
+// Defines a token bucket rate limit.
+#[cfg(feature = "firehose")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+// Defines the configuration for the worker. This is read once at startup.
+pub struct Config {
+    pub store: StoreKind,
+
+    // The users the memory store starts with.
+    pub memory_store_users: Vec<SeedUser>,
+    #[cfg(feature = "postgres")]
+    pub pg_connection_string: String,
+    #[cfg(feature = "postgres")]
+    pub pg_pool: PoolConfig,
+    #[cfg(feature = "postgres")]
+    pub pg_warmup_connections: usize,
+    #[cfg(feature = "postgres")]
+    pub pg_warmup_timeout: Duration,
+    pub worker_threads: Option<usize>,
+    pub max_blocking_threads: Option<usize>,
+    #[cfg(feature = "http")]
+    pub http_key: String,
+    #[cfg(feature = "http")]
+    pub http_addr: SocketAddr,
+    #[cfg(feature = "http")]
+    pub reject_changes_while_disconnected: bool,
+    #[cfg(feature = "firehose")]
+    pub user_rate_limit: Option<RateLimitConfig>,
+    #[cfg(feature = "firehose")]
+    pub host_rate_limit: Option<RateLimitConfig>,
+    #[cfg(feature = "firehose")]
+    pub circuit_breaker_threshold: u32,
+    #[cfg(feature = "firehose")]
+    pub circuit_breaker_cooldown: Duration,
+    pub allow_internal_endpoints: bool,
+    pub allow_insecure_endpoints: bool,
+    pub endpoint_host_allowlist: Option<Vec<String>>,
+    #[cfg(feature = "firehose")]
+    pub dry_run: bool,
+    #[cfg(feature = "firehose")]
+    pub eviction_downtime: Duration,
+    #[cfg(feature = "firehose")]
+    pub eviction_statuses: Vec<u16>,
+    #[cfg(feature = "firehose")]
+    pub retry_after_eviction_threshold: u32,
+    pub pause_probe_interval: Duration,
+    pub success_statuses: Vec<u16>,
+    pub delivery_user_agent: String,
+    pub delivery_headers: Vec<(String, String)>,
+    pub compress_deliveries: bool,
+    #[cfg(feature = "firehose")]
+    pub payload_profile: PayloadProfile,
+    pub max_delivery_bytes: usize,
+    #[cfg(feature = "firehose")]
+    pub delivery_threads: usize,
+    #[cfg(feature = "firehose")]
+    pub max_in_flight_deliveries: usize,
+    #[cfg(feature = "firehose")]
+    pub firehose_relays: Vec<String>,
+    #[cfg(feature = "firehose")]
+    pub firehose_relay_max_failures: u32,
+    #[cfg(feature = "firehose")]
+    pub firehose_workers: usize,
+    #[cfg(feature = "firehose")]
+    pub firehose_queue_depth: usize,
+    #[cfg(feature = "firehose")]
+    pub firehose_ping_interval: Option<Duration>,
+    #[cfg(feature = "firehose")]
+    pub firehose_ping_timeout: Duration,
+    pub match_options: MatchOptions,
+    #[cfg(feature = "firehose")]
+    pub match_mode: MatchMode,
+    pub match_backend: SearchBackend,
+    #[cfg(feature = "firehose")]
+    pub mask_facets: bool,
+    #[cfg(feature = "firehose")]
+    pub max_recipients_per_post: Option<usize>,
+    #[cfg(feature = "http")]
+    pub max_phrases_per_user: Option<usize>,
+    #[cfg(feature = "firehose")]
+    pub quote_cache_size: usize,
+    #[cfg(feature = "firehose")]
+    pub dedupe_window: Duration,
+    #[cfg(feature = "firehose")]
+    pub dedupe_cache_size: usize,
+    #[cfg(feature = "firehose")]
+    pub max_post_age: Option<Duration>,
+    #[cfg(feature = "firehose")]
+    pub max_post_text_bytes: Option<usize>,
+}
+
+// Defines everything that was wrong with the configuration.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "The worker is not configured correctly:")?;
+        for error in &self.0 {
+            writeln!(f, "  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+// Reads settings from a lookup function, collecting errors so they can all be reported at once.
+struct Reader<F> {
+    get: F,
+    errors: Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Reader<F> {
+    // Reads a setting that must be set and not blank.
+    #[cfg(any(feature = "postgres", feature = "http"))]
+    fn required(&mut self, name: &str) -> String {
+        match (self.get)(name) {
+            Some(value) if !value.is_empty() => value,
+            _ => {
+                self.errors.push(format!("{name} must be set"));
+                String::new()
+            }
+        }
+    }
+
+    // Reads a setting, falling back to the default if it is not set.
+    fn string_or(&mut self, name: &str, default: &str) -> String {
+        (self.get)(name).unwrap_or_else(|| default.to_string())
+    }
+
+    // Parses a setting, falling back to the default if it is not set.
+    fn parse_or<T: FromStr>(&mut self, name: &str, default: T) -> T
+    where
+        T::Err: Display,
+    {
+        match (self.get)(name) {
+            None => default,
+            Some(value) => match value.parse::<T>() {
+                Ok(value) => value,
+                Err(error) => {
+                    self.errors.push(format!("{name} is invalid ({value:?}): {error}"));
+                    default
+                }
+            },
+        }
+    }
+
+    // Parses a positive integer setting if it is set.
+    fn positive(&mut self, name: &str) -> Option<u64> {
+        let value = (self.get)(name)?;
+        match value.parse::<u64>() {
+            Ok(0) | Err(_) => {
+                self.errors.push(format!("{name} must be a positive integer, got {value:?}"));
+                None
+            }
+            Ok(value) => Some(value),
+        }
+    }
+
+    // Parses a positive number setting if it is set.
+    #[cfg(feature = "firehose")]
+    fn positive_f64(&mut self, name: &str) -> Option<f64> {
+        let value = (self.get)(name)?;
+        match value.parse::<f64>() {
+            Ok(parsed) if parsed.is_finite() && parsed > 0.0 => Some(parsed),
+            _ => {
+                self.errors.push(format!("{name} must be a positive number, got {value:?}"));
+                None
+            }
+        }
+    }
+
+    // Parses a comma separated list of HTTP status codes, falling back to the default if it is not set. An empty value
+    // is an empty list.
+    fn status_list(&mut self, name: &str, default: &[u16]) -> Vec<u16> {
+        let Some(value) = (self.get)(name) else {
+            return default.to_vec();
+        };
+        let mut statuses = vec![];
+        for part in value.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.parse::<u16>() {
+                Ok(status) if (100..=599).contains(&status) => statuses.push(status),
+                _ => {
+                    self.errors.push(format!(
+                        "{name} must be a comma separated list of HTTP status codes, got {value:?}",
+                    ));
+                    return default.to_vec();
+                }
+            }
+        }
+        statuses
+    }
+
+    // Reads a comma separated list of hostnames if it is set. Hostnames are normalized the way URL hosts are written.
+    fn hostnames(&mut self, name: &str) -> Option<Vec<String>> {
+        let value = (self.get)(name)?;
+        let mut hostnames = vec![];
+        for hostname in value.split(',').map(str::trim).filter(|hostname| !hostname.is_empty()) {
+            match normalize_host(hostname) {
+                Some(hostname) => hostnames.push(hostname),
+                None => self.errors.push(format!("{name} has an invalid hostname {hostname:?}")),
+            }
+        }
+        if hostnames.is_empty() {
+            self.errors.push(format!("{name} must have at least one hostname if it is set"));
+        }
+        Some(hostnames)
+    }
+
+    // Reads a comma separated list of websocket URLs, falling back to the default if it is not set.
+    #[cfg(feature = "firehose")]
+    fn websocket_urls(&mut self, name: &str, default: &str) -> Vec<String> {
+        let value = self.string_or(name, default);
+        let urls: Vec<String> = value.split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_string)
+            .collect();
+        if urls.is_empty() {
+            self.errors.push(format!("{name} must have at least one URL"));
+        }
+        for url in &urls {
+            match url::Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "ws" | "wss") => {}
+                _ => self.errors.push(format!("{name} must only contain ws:// or wss:// URLs, got {url:?}")),
+            }
+        }
+        urls
+    }
+
+    // Reads a comma separated list of "Name: value" headers. Headers which we set ourselves can't be overridden.
+    fn headers(&mut self, name: &str, reserved: &[&str]) -> Vec<(String, String)> {
+        let Some(value) = (self.get)(name) else {
+            return vec![];
+        };
+        let mut headers = vec![];
+        for part in value.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let Some((header, header_value)) = part.split_once(':') else {
+                self.errors.push(format!(
+                    "{name} must be a comma separated list of \"Name: value\" headers, got {part:?}",
+                ));
+                continue;
+            };
+            let (header, header_value) = (header.trim(), header_value.trim());
+            if reqwest::header::HeaderName::from_bytes(header.as_bytes()).is_err()
+                || reqwest::header::HeaderValue::from_str(header_value).is_err()
+            {
+                self.errors.push(format!("{name} has an invalid header: {part:?}"));
+            } else if reserved.iter().any(|reserved| reserved.eq_ignore_ascii_case(header)) {
+                self.errors.push(format!("{name} can't set the {header} header"));
+            } else {
+                headers.push((header.to_string(), header_value.to_string()));
+            }
+        }
+        headers
+    }
+
+    // Reads a JSON setting, either from the setting itself or from the file at {name}_FILE, falling back to the
+    // default if neither is set.
+    fn json_or_default<T: DeserializeOwned + Default>(&mut self, name: &str) -> T {
+        let file_name = format!("{name}_FILE");
+        let json = match ((self.get)(name), (self.get)(&file_name)) {
+            (None, None) => return T::default(),
+            (Some(_), Some(_)) => {
+                self.errors.push(format!("Only one of {name} and {file_name} can be set"));
+                return T::default();
+            }
+            (Some(json), None) => json,
+            (None, Some(path)) => match std::fs::read_to_string(&path) {
+                Ok(json) => json,
+                Err(error) => {
+                    self.errors.push(format!("{file_name} could not be read ({path:?}): {error}"));
+                    return T::default();
+                }
+            },
+        };
+        serde_json::from_str(&json).unwrap_or_else(|error| {
+            self.errors.push(format!("{name} is not valid: {error}"));
+            T::default()
+        })
+    }
+
+    // Reads a rate limit from {prefix}_PER_SECOND and {prefix}_BURST. The burst defaults to the rate.
+    #[cfg(feature = "firehose")]
+    fn rate_limit(&mut self, prefix: &str) -> Option<RateLimitConfig> {
+        let per_second = self.positive_f64(&format!("{prefix}_PER_SECOND"));
+        let burst = self.positive_f64(&format!("{prefix}_BURST"));
+        match (per_second, burst) {
+            (Some(per_second), burst) => {
+                Some(RateLimitConfig { per_second, burst: burst.unwrap_or(per_second).max(1.0) })
+            }
+            (None, Some(_)) => {
+                self.errors.push(format!("{prefix}_BURST is set without {prefix}_PER_SECOND"));
+                None
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+impl Config {
+    // Reads the config from the environment.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    // Reads the config using the given lookup function. Split out so it can be tested without touching the environment.
+    pub fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut reader = Reader { get, errors: vec![] };
+
+        // Store settings. The connection string is only needed when users are kept in Postgres.
+        let store = reader.parse_or("STORE", StoreKind::default());
+        let memory_store_users: Vec<SeedUser> = match store {
+            StoreKind::Memory => reader.json_or_default("MEMORY_STORE_USERS"),
+            #[cfg(feature = "postgres")]
+            StoreKind::Postgres => vec![],
+        };
+        #[cfg(feature = "postgres")]
+        let (pg_connection_string, pg_pool, pg_warmup_connections, pg_warmup_timeout) = {
+            let pg_connection_string = match store {
+                StoreKind::Postgres => reader.required("PG_CONNECTION_STRING"),
+                StoreKind::Memory => reader.string_or("PG_CONNECTION_STRING", ""),
+            };
+            let mut pg_pool = PoolConfig::default();
+            if let Some(max_size) = reader.positive("PG_POOL_MAX_SIZE") {
+                pg_pool.max_size = max_size as usize;
+            }
+            pg_pool.timeouts = Timeouts {
+                wait: reader.positive("PG_POOL_WAIT_TIMEOUT_MS").map(Duration::from_millis),
+                create: reader.positive("PG_POOL_CREATE_TIMEOUT_MS").map(Duration::from_millis),
+                recycle: None,
+            };
+            let pg_warmup_connections = reader.parse_or::<usize>("PG_WARMUP_CONNECTIONS", 1);
+            if pg_warmup_connections > pg_pool.max_size {
+                reader.errors.push(format!(
+                    "PG_WARMUP_CONNECTIONS ({pg_warmup_connections}) can't be more than the pool size ({})",
+                    pg_pool.max_size,
+                ));
+            }
+            let pg_warmup_timeout = Duration::from_millis(reader.positive("PG_WARMUP_TIMEOUT_MS").unwrap_or(30_000));
+            (pg_connection_string, pg_pool, pg_warmup_connections, pg_warmup_timeout)
+        };
+
+        // Runtime settings. Tokio picks these based on the CPU count when they aren't set.
+        let worker_threads = reader.positive("WORKER_THREADS").map(|threads| threads as usize);
+        let max_blocking_threads = reader.positive("MAX_BLOCKING_THREADS").map(|threads| threads as usize);
+
+        // HTTP settings.
+        #[cfg(feature = "http")]
+        let (http_key, http_addr, reject_changes_while_disconnected) = {
+            let http_key = reader.required("HTTP_KEY");
+            if !http_key.is_empty() && http_key.len() < MIN_HTTP_KEY_LENGTH {
+                reader.errors.push(format!("HTTP_KEY must be at least {MIN_HTTP_KEY_LENGTH} bytes long"));
+            }
+            let host = reader.string_or("HOST", "0.0.0.0");
+            let port = reader.parse_or::<u16>("PORT", 6969);
+            let http_addr = match format!("{host}:{port}").parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(_) => {
+                    reader.errors.push(format!("HOST is not a valid IP address: {host:?}"));
+                    SocketAddr::from(([0, 0, 0, 0], port))
+                }
+            };
+            let reject_changes_while_disconnected = reader.parse_or("REJECT_CHANGES_WHILE_DISCONNECTED", false);
+            (http_key, http_addr, reject_changes_while_disconnected)
+        };
+
+        // Delivery settings.
+        #[cfg(feature = "firehose")]
+        let user_rate_limit = reader.rate_limit("RATE_LIMIT_USER");
+        #[cfg(feature = "firehose")]
+        let host_rate_limit = reader.rate_limit("RATE_LIMIT_HOST");
+        #[cfg(feature = "firehose")]
+        let circuit_breaker_threshold = reader.positive("CIRCUIT_BREAKER_THRESHOLD").unwrap_or(5) as u32;
+        #[cfg(feature = "firehose")]
+        let circuit_breaker_cooldown =
+            Duration::from_millis(reader.positive("CIRCUIT_BREAKER_COOLDOWN_MS").unwrap_or(60_000));
+        let allow_internal_endpoints = reader.parse_or("ALLOW_INTERNAL_ENDPOINTS", false);
+        let allow_insecure_endpoints = reader.parse_or("ALLOW_INSECURE_ENDPOINTS", false);
+        let endpoint_host_allowlist = reader.hostnames("ENDPOINT_HOST_ALLOWLIST");
+        #[cfg(feature = "firehose")]
+        let dry_run = reader.parse_or("DRY_RUN", false);
+        let delivery_user_agent =
+            reader.string_or("DELIVERY_USER_AGENT", concat!("bluehook/", env!("CARGO_PKG_VERSION")));
+        let delivery_headers = reader.headers("DELIVERY_HEADERS", RESERVED_DELIVERY_HEADERS);
+        let compress_deliveries = reader.parse_or("COMPRESS_DELIVERIES", false);
+        #[cfg(feature = "firehose")]
+        let payload_profile = reader.parse_or("PAYLOAD_PROFILE", PayloadProfile::Full);
+        let max_delivery_bytes = reader.positive("MAX_DELIVERY_BYTES").unwrap_or(1024 * 1024) as usize;
+        #[cfg(feature = "firehose")]
+        let delivery_threads = reader.positive("DELIVERY_THREADS").unwrap_or(2) as usize;
+        #[cfg(feature = "firehose")]
+        let max_in_flight_deliveries = reader.positive("MAX_IN_FLIGHT_DELIVERIES").unwrap_or(1024) as usize;
+
+        // Firehose settings.
+        #[cfg(feature = "firehose")]
+        let firehose_relays = reader.websocket_urls("FIREHOSE_RELAYS", "wss://bsky.network");
+        #[cfg(feature = "firehose")]
+        let firehose_relay_max_failures = reader.positive("FIREHOSE_RELAY_MAX_FAILURES").unwrap_or(3) as u32;
+        #[cfg(feature = "firehose")]
+        let firehose_workers = reader.positive("FIREHOSE_WORKERS").unwrap_or(8) as usize;
+        #[cfg(feature = "firehose")]
+        let firehose_queue_depth = reader.positive("FIREHOSE_QUEUE_DEPTH").unwrap_or(1024) as usize;
+        #[cfg(feature = "firehose")]
+        let firehose_ping_interval = match reader.parse_or::<u64>("FIREHOSE_PING_INTERVAL_MS", 30_000) {
+            0 => None,
+            interval => Some(Duration::from_millis(interval)),
+        };
+        #[cfg(feature = "firehose")]
+        let firehose_ping_timeout =
+            Duration::from_millis(reader.positive("FIREHOSE_PING_TIMEOUT_MS").unwrap_or(10_000));
+
+        // Matching settings.
+        let match_options: MatchOptions = reader.json_or_default("MATCH_OPTIONS");
+        #[cfg(feature = "firehose")]
+        let match_mode = reader.parse_or("MATCH_MODE", MatchMode::Shared);
+        let match_backend = reader.parse_or("MATCH_BACKEND", SearchBackend::Tree);
+        #[cfg(feature = "firehose")]
+        let mask_facets = reader.parse_or("MASK_FACETS", false);
+        #[cfg(feature = "firehose")]
+        let max_recipients_per_post = reader.positive("MAX_RECIPIENTS_PER_POST").map(|max| max as usize);
+        #[cfg(feature = "http")]
+        let max_phrases_per_user = reader.positive("MAX_PHRASES_PER_USER").map(|max| max as usize);
+        #[cfg(feature = "firehose")]
+        let quote_cache_size = reader.parse_or::<usize>("QUOTE_CACHE_SIZE", 10_000);
+        #[cfg(feature = "firehose")]
+        let dedupe_window = Duration::from_millis(reader.parse_or::<u64>("DEDUPE_WINDOW_MS", 60_000));
+        #[cfg(feature = "firehose")]
+        let dedupe_cache_size = reader.parse_or::<usize>("DEDUPE_CACHE_SIZE", 10_000);
+        #[cfg(feature = "firehose")]
+        let max_post_age = reader.positive("MAX_POST_AGE_SECONDS").map(Duration::from_secs);
+        #[cfg(feature = "firehose")]
+        let max_post_text_bytes = reader.positive("MAX_POST_TEXT_BYTES").map(|max| max as usize);
+
+        // Eviction settings.
+        #[cfg(feature = "firehose")]
+        let eviction_downtime =
+            Duration::from_millis(reader.positive("EVICTION_DOWNTIME_MS").unwrap_or(2 * 60 * 60 * 1000));
+        #[cfg(feature = "firehose")]
+        let eviction_statuses = reader.status_list("EVICTION_STATUSES", &[403]);
+        #[cfg(feature = "firehose")]
+        let retry_after_eviction_threshold = reader.positive("RETRY_AFTER_EVICTION_THRESHOLD").unwrap_or(5) as u32;
+        let pause_probe_interval =
+            Duration::from_millis(reader.positive("PAUSE_PROBE_INTERVAL_MS").unwrap_or(10 * 60 * 1000));
+        let success_statuses = reader.status_list("SUCCESS_STATUSES", &[]);
+
+        if !reader.errors.is_empty() {
+            return Err(ConfigError(reader.errors));
+        }
+        Ok(Self {
+            store, memory_store_users,
+            #[cfg(feature = "postgres")]
+            pg_connection_string,
+            #[cfg(feature = "postgres")]
+            pg_pool,
+            #[cfg(feature = "postgres")]
+            pg_warmup_connections,
+            #[cfg(feature = "postgres")]
+            pg_warmup_timeout,
+            worker_threads, max_blocking_threads,
+            #[cfg(feature = "http")]
+            http_key,
+            #[cfg(feature = "http")]
+            http_addr,
+            #[cfg(feature = "http")]
+            reject_changes_while_disconnected,
+            allow_internal_endpoints, allow_insecure_endpoints, endpoint_host_allowlist, pause_probe_interval,
+            success_statuses, delivery_user_agent, delivery_headers, compress_deliveries, max_delivery_bytes,
+            match_options, match_backend,
+            #[cfg(feature = "http")]
+            max_phrases_per_user,
+            #[cfg(feature = "firehose")]
+            delivery_threads,
+            #[cfg(feature = "firehose")]
+            max_in_flight_deliveries,
+            #[cfg(feature = "firehose")]
+            user_rate_limit,
+            #[cfg(feature = "firehose")]
+            host_rate_limit,
+            #[cfg(feature = "firehose")]
+            circuit_breaker_threshold,
+            #[cfg(feature = "firehose")]
+            circuit_breaker_cooldown,
+            #[cfg(feature = "firehose")]
+            dry_run,
+            #[cfg(feature = "firehose")]
+            eviction_downtime,
+            #[cfg(feature = "firehose")]
+            eviction_statuses,
+            #[cfg(feature = "firehose")]
+            retry_after_eviction_threshold,
+            #[cfg(feature = "firehose")]
+            payload_profile,
+            #[cfg(feature = "firehose")]
+            firehose_relays,
+            #[cfg(feature = "firehose")]
+            firehose_relay_max_failures,
+            #[cfg(feature = "firehose")]
+            firehose_workers,
+            #[cfg(feature = "firehose")]
+            firehose_queue_depth,
+            #[cfg(feature = "firehose")]
+            firehose_ping_interval,
+            #[cfg(feature = "firehose")]
+            firehose_ping_timeout,
+            #[cfg(feature = "firehose")]
+            match_mode,
+            #[cfg(feature = "firehose")]
+            mask_facets,
+            #[cfg(feature = "firehose")]
+            max_recipients_per_post,
+            #[cfg(feature = "firehose")]
+            quote_cache_size,
+            #[cfg(feature = "firehose")]
+            dedupe_window,
+            #[cfg(feature = "firehose")]
+            dedupe_cache_size,
+            #[cfg(feature = "firehose")]
+            max_post_age,
+            #[cfg(feature = "firehose")]
+            max_post_text_bytes,
+        })
+    }
+
+    // Builds a valid config for tests, with the given settings layered on top.
+    #[cfg(test)]
+    pub fn for_tests(env: &[(&str, &str)]) -> Self {
+        let env: std::collections::HashMap<_, _> = env.iter().cloned().collect();
+        Self::from_lookup(|name| match env.get(name) {
+            Some(value) => Some(value.to_string()),
+            None => match name {
+                "PG_CONNECTION_STRING" => Some("postgres://postgres@127.0.0.1:1/postgres".to_string()),
+                "HTTP_KEY" => Some("0123456789abcdef0123456789abcdef".to_string()),
+                _ => None,
+            },
+        }).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const HTTP_KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn config_from(env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let env: HashMap<_, _> = env.iter().cloned().collect();
+        Config::from_lookup(|name| env.get(name).map(|v| v.to_string()))
+    }
+
+    // Gets the error for a config which is fine apart from the one setting.
+    fn error_for(name: &str, value: &str) -> ConfigError {
+        config_from(&[("PG_CONNECTION_STRING", "postgres://localhost"), ("HTTP_KEY", HTTP_KEY), (name, value)])
+            .err()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_minimal_config() {
+        let config = config_from(&[("PG_CONNECTION_STRING", "postgres://localhost"), ("HTTP_KEY", HTTP_KEY)]).unwrap();
+        assert_eq!(config.http_key, HTTP_KEY);
+        assert_eq!(config.http_addr, "0.0.0.0:6969".parse().unwrap());
+        assert_eq!(config.pg_pool.max_size, PoolConfig::default().max_size);
+        assert_eq!(config.pg_pool.timeouts.wait, None);
+    }
+
+    #[test]
+    fn test_reject_changes_while_disconnected() {
+        assert!(!Config::for_tests(&[]).reject_changes_while_disconnected);
+        assert!(Config::for_tests(&[("REJECT_CHANGES_WHILE_DISCONNECTED", "true")]).reject_changes_while_disconnected);
+    }
+
+    #[test]
+    fn test_allow_internal_endpoints() {
+        assert!(!Config::for_tests(&[]).allow_internal_endpoints);
+        assert!(Config::for_tests(&[("ALLOW_INTERNAL_ENDPOINTS", "true")]).allow_internal_endpoints);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        assert!(!Config::for_tests(&[]).dry_run);
+        assert!(Config::for_tests(&[("DRY_RUN", "true")]).dry_run);
+    }
+
+    #[test]
+    fn test_max_recipients_per_post() {
+        assert_eq!(Config::for_tests(&[]).max_recipients_per_post, None);
+        assert_eq!(Config::for_tests(&[("MAX_RECIPIENTS_PER_POST", "100")]).max_recipients_per_post, Some(100));
+    }
+
+    #[test]
+    fn test_max_phrases_per_user() {
+        assert_eq!(Config::for_tests(&[]).max_phrases_per_user, None);
+        assert_eq!(Config::for_tests(&[("MAX_PHRASES_PER_USER", "50")]).max_phrases_per_user, Some(50));
+    }
+
+    #[test]
+    fn test_quote_cache_size() {
+        assert_eq!(Config::for_tests(&[]).quote_cache_size, 10_000);
+        assert_eq!(Config::for_tests(&[("QUOTE_CACHE_SIZE", "0")]).quote_cache_size, 0);
+    }
+
+    #[test]
+    fn test_dedupe_settings() {
+        let config = Config::for_tests(&[]);
+        assert_eq!((config.dedupe_window, config.dedupe_cache_size), (Duration::from_secs(60), 10_000));
+
+        let config = Config::for_tests(&[("DEDUPE_WINDOW_MS", "5000"), ("DEDUPE_CACHE_SIZE", "10")]);
+        assert_eq!((config.dedupe_window, config.dedupe_cache_size), (Duration::from_secs(5), 10));
+    }
+
+    #[test]
+    fn test_max_post_age() {
+        assert_eq!(Config::for_tests(&[]).max_post_age, None);
+        assert_eq!(Config::for_tests(&[("MAX_POST_AGE_SECONDS", "600")]).max_post_age, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn test_max_post_text_bytes() {
+        assert_eq!(Config::for_tests(&[]).max_post_text_bytes, None);
+        assert_eq!(Config::for_tests(&[("MAX_POST_TEXT_BYTES", "65536")]).max_post_text_bytes, Some(65_536));
+    }
+
+    #[test]
+    fn test_missing_values_are_aggregated() {
+        let error = config_from(&[]).err().unwrap();
+        assert_eq!(error.0.len(), 2);
+        assert!(error.0.iter().any(|e| e.contains("PG_CONNECTION_STRING")));
+        assert!(error.0.iter().any(|e| e.contains("HTTP_KEY")));
+    }
+
+    #[test]
+    fn test_malformed_values() {
+        let error = config_from(&[
+            ("PG_CONNECTION_STRING", "postgres://localhost"),
+            ("HTTP_KEY", HTTP_KEY),
+            ("PORT", "lots"),
+            ("HOST", "not a host"),
+            ("PG_POOL_MAX_SIZE", "0"),
+        ]).err().unwrap();
+        assert_eq!(error.0.len(), 3);
+    }
+
+    #[test]
+    fn test_short_http_key() {
+        let error = config_from(&[
+            ("PG_CONNECTION_STRING", "postgres://localhost"),
+            ("HTTP_KEY", "hunter2"),
+        ]).err().unwrap();
+        assert_eq!(error.0.len(), 1);
+        assert!(error.0[0].contains("HTTP_KEY"));
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let config = Config::for_tests(&[
+            ("RATE_LIMIT_USER_PER_SECOND", "2.5"),
+            ("RATE_LIMIT_HOST_PER_SECOND", "100"),
+            ("RATE_LIMIT_HOST_BURST", "500"),
+        ]);
+        assert_eq!(config.user_rate_limit, Some(RateLimitConfig { per_second: 2.5, burst: 2.5 }));
+        assert_eq!(config.host_rate_limit, Some(RateLimitConfig { per_second: 100.0, burst: 500.0 }));
+
+        let config = Config::for_tests(&[]);
+        assert_eq!(config.user_rate_limit, None);
+        assert_eq!(config.host_rate_limit, None);
+
+        assert_eq!(error_for("RATE_LIMIT_USER_BURST", "5").0.len(), 1);
+    }
+
+    #[test]
+    fn test_firehose_pings() {
+        let config = Config::for_tests(&[]);
+        assert_eq!(config.firehose_ping_interval, Some(Duration::from_secs(30)));
+        assert_eq!(config.firehose_ping_timeout, Duration::from_secs(10));
+
+        let config = Config::for_tests(&[("FIREHOSE_PING_INTERVAL_MS", "0"), ("FIREHOSE_PING_TIMEOUT_MS", "500")]);
+        assert_eq!(config.firehose_ping_interval, None);
+        assert_eq!(config.firehose_ping_timeout, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_allow_insecure_endpoints() {
+        assert!(!Config::for_tests(&[]).allow_insecure_endpoints);
+        assert!(Config::for_tests(&[("ALLOW_INSECURE_ENDPOINTS", "true")]).allow_insecure_endpoints);
+    }
+
+    #[test]
+    fn test_eviction_settings() {
+        let config = Config::for_tests(&[]);
+        assert_eq!(config.eviction_downtime, Duration::from_secs(2 * 60 * 60));
+        assert_eq!(config.eviction_statuses, vec![403]);
+        assert_eq!(config.retry_after_eviction_threshold, 5);
+        assert_eq!(config.pause_probe_interval, Duration::from_secs(10 * 60));
+
+        let config = Config::for_tests(&[
+            ("EVICTION_DOWNTIME_MS", "5000"), ("EVICTION_STATUSES", "429, 410"),
+            ("RETRY_AFTER_EVICTION_THRESHOLD", "2"), ("PAUSE_PROBE_INTERVAL_MS", "30000"),
+        ]);
+        assert_eq!(config.eviction_downtime, Duration::from_secs(5));
+        assert_eq!(config.eviction_statuses, vec![429, 410]);
+        assert_eq!(config.retry_after_eviction_threshold, 2);
+        assert_eq!(config.pause_probe_interval, Duration::from_secs(30));
+
+        let config = Config::for_tests(&[("EVICTION_STATUSES", "")]);
+        assert!(config.eviction_statuses.is_empty());
+
+        let config = Config::for_tests(&[("SUCCESS_STATUSES", "200,204")]);
+        assert_eq!(config.success_statuses, vec![200, 204]);
+
+        assert_eq!(error_for("EVICTION_STATUSES", "403,teapot").0.len(), 1);
+    }
+
+    #[test]
+    fn test_endpoint_host_allowlist() {
+        assert_eq!(Config::for_tests(&[]).endpoint_host_allowlist, None);
+        let allowlist = "Hooks.Example.com, partner.example., Bücher.example";
+        let config = Config::for_tests(&[("ENDPOINT_HOST_ALLOWLIST", allowlist)]);
+        let expected = ["hooks.example.com", "partner.example", "xn--bcher-kva.example"];
+        assert_eq!(config.endpoint_host_allowlist, Some(expected.map(str::to_string).to_vec()));
+        assert_eq!(error_for("ENDPOINT_HOST_ALLOWLIST", " , ").0.len(), 1);
+        assert_eq!(error_for("ENDPOINT_HOST_ALLOWLIST", "hooks.example.com, bad host").0.len(), 1);
+    }
+
+    #[test]
+    fn test_firehose_relays() {
+        let config = Config::for_tests(&[]);
+        assert_eq!(config.firehose_relays, vec!["wss://bsky.network"]);
+
+        let config = Config::for_tests(&[("FIREHOSE_RELAYS", "wss://a.example, wss://b.example")]);
+        assert_eq!(config.firehose_relays, vec!["wss://a.example", "wss://b.example"]);
+
+        assert_eq!(error_for("FIREHOSE_RELAYS", "https://a.example").0.len(), 1);
+    }
+
+    #[test]
+    fn test_delivery_headers() {
+        let config = Config::for_tests(&[]);
+        assert!(config.delivery_user_agent.starts_with("bluehook/"));
+        assert!(config.delivery_headers.is_empty());
+
+        let config = Config::for_tests(&[("DELIVERY_HEADERS", "X-Bluehook-Instance: prod, X-Team:search")]);
+        assert_eq!(config.delivery_headers, vec![
+            ("X-Bluehook-Instance".to_string(), "prod".to_string()),
+            ("X-Team".to_string(), "search".to_string()),
+        ]);
+
+        assert_eq!(error_for("DELIVERY_HEADERS", "x-signature-ed25519: forged, not a header").0.len(), 2);
+    }
+
+    #[test]
+    fn test_payload_profile() {
+        assert_eq!(Config::for_tests(&[]).payload_profile, PayloadProfile::Full);
+        assert_eq!(Config::for_tests(&[("PAYLOAD_PROFILE", "minimal")]).payload_profile, PayloadProfile::Minimal);
+
+        assert_eq!(error_for("PAYLOAD_PROFILE", "tiny").0.len(), 1);
+    }
+
+    #[test]
+    fn test_match_mode() {
+        assert_eq!(Config::for_tests(&[]).match_mode, MatchMode::Shared);
+        assert_eq!(Config::for_tests(&[("MATCH_MODE", "actor")]).match_mode, MatchMode::Actor);
+        assert!(!Config::for_tests(&[]).mask_facets);
+        assert!(Config::for_tests(&[("MASK_FACETS", "true")]).mask_facets);
+        assert_eq!(Config::for_tests(&[]).match_backend, SearchBackend::Tree);
+        assert_eq!(Config::for_tests(&[("MATCH_BACKEND", "aho_corasick")]).match_backend, SearchBackend::AhoCorasick);
+
+        assert_eq!(error_for("MATCH_MODE", "threaded").0.len(), 1);
+        assert_eq!(error_for("MATCH_BACKEND", "regex").0.len(), 1);
+    }
+
+    #[test]
+    fn test_store() {
+        assert_eq!(Config::for_tests(&[]).store, StoreKind::Postgres);
+
+        // The connection string is only needed for Postgres.
+        let config = config_from(&[("STORE", "memory"), ("HTTP_KEY", HTTP_KEY)]).unwrap();
+        assert_eq!(config.store, StoreKind::Memory);
+        let error = config_from(&[("STORE", "postgres"), ("HTTP_KEY", HTTP_KEY)]).err().unwrap();
+        assert!(error.0[0].contains("PG_CONNECTION_STRING"));
+
+        assert_eq!(error_for("STORE", "sqlite").0.len(), 1);
+    }
+
+    #[test]
+    fn test_memory_store_users() {
+        assert!(Config::for_tests(&[]).memory_store_users.is_empty());
+
+        let users = r#"[{"private_key": "aa", "endpoint": "https://a.example", "phrases": ["a"], "ordered": true}]"#;
+        let config = config_from(&[
+            ("STORE", "memory"), ("HTTP_KEY", HTTP_KEY), ("MEMORY_STORE_USERS", users),
+        ]).unwrap();
+        assert_eq!(config.memory_store_users.len(), 1);
+        let seed = &config.memory_store_users[0];
+        assert_eq!(seed.phrases, vec!["a"]);
+        assert!(seed.user.ordered);
+        assert!(seed.user.replies);
+        assert_eq!(seed.user.signing, "ed25519");
+
+        // They are only read for the memory store.
+        let config = Config::for_tests(&[("MEMORY_STORE_USERS", users)]);
+        assert!(config.memory_store_users.is_empty());
+
+        let missing_endpoint = r#"[{"private_key": "aa"}]"#;
+        let error = config_from(&[
+            ("STORE", "memory"), ("HTTP_KEY", HTTP_KEY), ("MEMORY_STORE_USERS", missing_endpoint),
+        ]).err().unwrap();
+        assert_eq!(error.0.len(), 1);
+    }
+
+    #[test]
+    fn test_pool_settings() {
+        let config = config_from(&[
+            ("PG_CONNECTION_STRING", "postgres://localhost"),
+            ("HTTP_KEY", HTTP_KEY),
+            ("PG_POOL_MAX_SIZE", "7"),
+            ("PG_POOL_WAIT_TIMEOUT_MS", "250"),
+            ("PG_POOL_CREATE_TIMEOUT_MS", "1000"),
+        ]).unwrap();
+        assert_eq!(config.pg_pool.max_size, 7);
+        assert_eq!(config.pg_pool.timeouts.wait, Some(Duration::from_millis(250)));
+        assert_eq!(config.pg_pool.timeouts.create, Some(Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn test_runtime_settings() {
+        let config = Config::for_tests(&[]);
+        assert_eq!((config.worker_threads, config.max_blocking_threads), (None, None));
+
+        let config = Config::for_tests(&[("WORKER_THREADS", "3"), ("MAX_BLOCKING_THREADS", "16")]);
+        assert_eq!((config.worker_threads, config.max_blocking_threads), (Some(3), Some(16)));
+
+        assert_eq!(error_for("WORKER_THREADS", "0").0.len(), 1);
+        assert_eq!(error_for("MAX_BLOCKING_THREADS", "many").0.len(), 1);
+    }
+
+    #[test]
+    fn test_pool_warmup_settings() {
+        let config = Config::for_tests(&[]);
+        assert_eq!((config.pg_warmup_connections, config.pg_warmup_timeout), (1, Duration::from_secs(30)));
+
+        let config = Config::for_tests(&[("PG_WARMUP_CONNECTIONS", "0"), ("PG_WARMUP_TIMEOUT_MS", "500")]);
+        assert_eq!((config.pg_warmup_connections, config.pg_warmup_timeout), (0, Duration::from_millis(500)));
+
+        // Warming up more connections than the pool holds would wait forever.
+        let result = config_from(&[
+            ("PG_CONNECTION_STRING", "postgres://localhost"),
+            ("HTTP_KEY", HTTP_KEY),
+            ("PG_POOL_MAX_SIZE", "2"),
+            ("PG_WARMUP_CONNECTIONS", "3"),
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_delivery_pool_settings() {
+        let config = Config::for_tests(&[]);
+        assert_eq!((config.delivery_threads, config.max_in_flight_deliveries), (2, 1024));
+
+        let config = Config::for_tests(&[("DELIVERY_THREADS", "4"), ("MAX_IN_FLIGHT_DELIVERIES", "64")]);
+        assert_eq!((config.delivery_threads, config.max_in_flight_deliveries), (4, 64));
+    }
+
+    #[test]
+    fn test_firehose_queue_settings() {
+        let config = Config::for_tests(&[]);
+        assert_eq!((config.firehose_workers, config.firehose_queue_depth), (8, 1024));
+
+        let config = Config::for_tests(&[("FIREHOSE_WORKERS", "2"), ("FIREHOSE_QUEUE_DEPTH", "16")]);
+        assert_eq!((config.firehose_workers, config.firehose_queue_depth), (2, 16));
+        assert_eq!(error_for("FIREHOSE_QUEUE_DEPTH", "0").0.len(), 1);
+    }
+
+    #[test]
+    fn test_match_options() {
+        assert_eq!(Config::for_tests(&[]).match_options, MatchOptions::default());
+
+        let config = Config::for_tests(&[("MATCH_OPTIONS", r#"{"whole_word": true, "min_length": 3}"#)]);
+        assert_eq!(config.match_options, MatchOptions { whole_word: true, min_length: 3, ..MatchOptions::default() });
+        let config = Config::for_tests(&[("MATCH_OPTIONS", r#"{"max_bytes": 64}"#)]);
+        assert_eq!(config.match_options.max_bytes, 64);
+
+        let path = std::env::temp_dir().join(format!("bluehook-match-options-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"case_insensitive": false}"#).unwrap();
+        let config = Config::for_tests(&[("MATCH_OPTIONS_FILE", path.to_str().unwrap())]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!config.match_options.case_insensitive);
+
+        let invalid = [
+            ("MATCH_OPTIONS", r#"{"wholeWord": true}"#),
+            ("MATCH_OPTIONS_FILE", "/nonexistent/options.json"),
+        ];
+        for (name, value) in invalid {
+            assert_eq!(error_for(name, value).0.len(), 1);
+        }
+    }
+}
@@ -0,0 +1,639 @@
+use std::{fmt::Display, io::Write, sync::Arc, time::Duration};
+#[cfg(feature = "firehose")]
+use std::str::FromStr;
+#[cfg(any(feature = "firehose", feature = "http"))]
+use std::sync::atomic::Ordering;
+use flate2::{write::GzEncoder, Compression};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+#[cfg(feature = "firehose")]
+use serde::Deserialize;
+use serde_json::json;
+use tracing::debug;
+#[cfg(feature = "firehose")]
+use worker::observer::{DeliveryObserver, DeliveryOutcome};
+use worker::signature::{sign, sign_hmac};
+use crate::{bulk_search_tree::User, config::Config, dns::SharedResolver};
+#[cfg(feature = "firehose")]
+use crate::metrics;
+#[cfg(any(feature = "firehose", feature = "http"))]
+use crate::ssrf;
+
+// Defines why a delivery could not be sent.
+#[derive(Debug)]
+pub enum DeliveryError {
+    // The payload was bigger than the configured maximum, so it was not sent.
+    Oversized(usize),
+    Http(reqwest::Error),
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::Oversized(size) => write!(f, "payload of {size} bytes is over the maximum size"),
+            DeliveryError::Http(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for DeliveryError {
+    fn from(error: reqwest::Error) -> Self {
+        DeliveryError::Http(error)
+    }
+}
+
+// Defines how much of a post is sent in its payload.
+#[cfg(feature = "firehose")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PayloadProfile {
+    // The whole post record, as it came off the firehose.
+    Full,
+    // Just the text, author, and when it was created. This leaves out embeds, facets and anything else heavy.
+    Minimal,
+}
+
+#[cfg(feature = "firehose")]
+impl FromStr for PayloadProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(PayloadProfile::Full),
+            "minimal" => Ok(PayloadProfile::Minimal),
+            _ => Err("expected full or minimal".to_string()),
+        }
+    }
+}
+
+// How long an eviction notice is given to be delivered. The endpoint is likely what is failing, so this is kept short.
+#[cfg(any(feature = "firehose", feature = "http"))]
+const EVICTION_NOTICE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Defines why a user was evicted.
+#[cfg(any(feature = "firehose", feature = "http"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionReason {
+    // The endpoint returned a status in EVICTION_STATUSES.
+    #[cfg(feature = "firehose")]
+    Status(u16),
+    // The endpoint has been failing for longer than EVICTION_DOWNTIME_MS.
+    #[cfg(feature = "firehose")]
+    Downtime,
+    // The endpoint's hostname no longer exists.
+    #[cfg(feature = "firehose")]
+    HostnameNotFound,
+    // The endpoint can't be parsed or has no host.
+    #[cfg(feature = "firehose")]
+    InvalidEndpoint,
+    // The endpoint resolves to an internal address.
+    #[cfg(feature = "firehose")]
+    InternalAddress,
+    // An operator evicted the user with POST /admin/evict-did/:did.
+    #[cfg(feature = "http")]
+    Admin,
+}
+
+#[cfg(any(feature = "firehose", feature = "http"))]
+impl EvictionReason {
+    // Gets the reason as it is sent in eviction notices.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "firehose")]
+            EvictionReason::Status(_) => "status",
+            #[cfg(feature = "firehose")]
+            EvictionReason::Downtime => "downtime",
+            #[cfg(feature = "firehose")]
+            EvictionReason::HostnameNotFound => "hostname_not_found",
+            #[cfg(feature = "firehose")]
+            EvictionReason::InvalidEndpoint => "invalid_endpoint",
+            #[cfg(feature = "firehose")]
+            EvictionReason::InternalAddress => "internal_address",
+            #[cfg(feature = "http")]
+            EvictionReason::Admin => "admin",
+        }
+    }
+
+    // Gets whether a user evicted for the reason is sent a notice. Users pointing at an internal address never are.
+    pub fn notifies(&self) -> bool {
+        match self {
+            #[cfg(feature = "firehose")]
+            EvictionReason::InternalAddress => false,
+            _ => true,
+        }
+    }
+
+    // Gets the label the reason is counted under in the evictions metric. Statuses are counted separately, since which
+    // one an endpoint returned says a lot about why it is gone.
+    pub fn metric_label(&self) -> String {
+        match self {
+            #[cfg(feature = "firehose")]
+            EvictionReason::Status(status) => status.to_string(),
+            #[cfg(feature = "firehose")]
+            EvictionReason::HostnameNotFound => "dns".to_string(),
+            reason => reason.as_str().to_string(),
+        }
+    }
+}
+
+// Builds the HTTP client used for deliveries, with the configured user agent and headers.
+pub fn new_client(config: &Config) -> reqwest::Client {
+    // The headers were validated when the config was read. Default headers never replace the ones set on a request,
+    // so these can't override the signature.
+    let mut headers = HeaderMap::new();
+    for (name, value) in &config.delivery_headers {
+        headers.insert(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+    }
+
+    // Redirects are not followed so they can't be used to get around the internal address check, and so a redirect is
+    // not mistaken for a successful delivery.
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(SharedResolver { allow_internal: config.allow_internal_endpoints }))
+        .user_agent(&config.delivery_user_agent)
+        .default_headers(headers)
+        .redirect(reqwest::redirect::Policy::none())
+        .build().unwrap()
+}
+
+// Gzips a payload.
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Builds the signed request for a payload to one of the user's endpoints. The signature is always over the
+// uncompressed body.
+fn build_request(
+    client: &reqwest::Client, config: &Config, user: &User, endpoint: &str, json: String, ts_seconds: i64,
+) -> Result<reqwest::Request, DeliveryError> {
+    if json.len() > config.max_delivery_bytes {
+        return Err(DeliveryError::Oversized(json.len()));
+    }
+    let mut builder = client.post(endpoint)
+        .header("Content-Type", "application/json")
+        .header("X-Signature-Timestamp", ts_seconds.to_string());
+    if user.signing.ed25519() {
+        builder = builder.header("X-Signature-Ed25519", sign(&user.private_key, ts_seconds, &json));
+        if let Some(previous_key) = &user.previous_key {
+            builder = builder.header("X-Signature-Ed25519-Previous", sign(previous_key, ts_seconds, &json));
+        }
+    }
+    if let (true, Some(secret)) = (user.signing.hmac(), &user.secret) {
+        builder = builder.header("X-Signature-HMAC", sign_hmac(secret, ts_seconds, &json));
+    }
+    builder = if config.compress_deliveries {
+        builder.header("Content-Encoding", "gzip").body(gzip(json.as_bytes()))
+    } else {
+        builder.body(json)
+    };
+    Ok(builder.build()?)
+}
+
+// Sends a signed payload to one of the user's endpoints.
+#[cfg(any(feature = "firehose", feature = "http"))]
+pub async fn send(
+    client: &reqwest::Client, config: &Config, user: &User, endpoint: &str, json: String, ts_seconds: i64,
+) -> Result<reqwest::Response, DeliveryError> {
+    let request = build_request(client, config, user, endpoint, json, ts_seconds)?;
+    Ok(client.execute(request).await?)
+}
+
+// Counts deliveries by outcome in the Prometheus metrics. This is the observer the worker runs with.
+#[cfg(feature = "firehose")]
+pub struct MetricsObserver;
+
+#[cfg(feature = "firehose")]
+impl DeliveryObserver for MetricsObserver {
+    fn observe(&self, _user_id: u64, _endpoint: &str, outcome: DeliveryOutcome, _latency: Duration) {
+        match outcome {
+            DeliveryOutcome::Success(status) | DeliveryOutcome::Failure(status) => {
+                metrics::DELIVERIES.inc(metrics::status_class(status));
+            }
+            DeliveryOutcome::Error => metrics::DELIVERIES.inc("error"),
+
+            // This is our problem rather than the endpoint's, so it isn't counted against them.
+            DeliveryOutcome::Oversized => {}
+        }
+    }
+}
+
+// The longest a Retry-After is honoured for, so an endpoint can't pause its deliveries indefinitely.
+#[cfg(feature = "firehose")]
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+// Parses a Retry-After header, which is either a number of seconds or a HTTP date. Dates in the past mean no wait.
+#[cfg(feature = "firehose")]
+pub fn parse_retry_after(value: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    let value = value.trim();
+    let wait = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            (date.with_timezone(&chrono::Utc) - now).to_std().unwrap_or(Duration::ZERO)
+        }
+    };
+    Some(wait.min(MAX_RETRY_AFTER))
+}
+
+// The biggest response body read for an ack. Receivers which don't send acks shouldn't cost us reading their bodies.
+#[cfg(feature = "firehose")]
+const MAX_ACK_BYTES: u64 = 1024;
+
+// Defines the ack a receiver can put in the body of a successful response.
+#[cfg(feature = "firehose")]
+#[derive(Deserialize)]
+struct Ack {
+    // How long to wait before sending the endpoint anything else.
+    next_after_ms: Option<u64>,
+}
+
+// Parses an ack from a response body, returning how long the receiver asked us to wait. Bodies which aren't an ack are
+// ignored, and the wait is capped the same as a Retry-After.
+#[cfg(feature = "firehose")]
+pub fn parse_ack(body: &[u8]) -> Option<Duration> {
+    let ack: Ack = serde_json::from_slice(body).ok()?;
+    let wait = Duration::from_millis(ack.next_after_ms?);
+    Some(wait.min(MAX_RETRY_AFTER)).filter(|wait| !wait.is_zero())
+}
+
+// Reads an ack from a successful response if the body is small enough to be one.
+#[cfg(feature = "firehose")]
+pub async fn read_ack(resp: reqwest::Response) -> Option<Duration> {
+    if !resp.content_length().is_some_and(|length| length > 0 && length <= MAX_ACK_BYTES) {
+        return None;
+    }
+    parse_ack(&resp.bytes().await.ok()?)
+}
+
+// Builds the payload telling a user they were evicted. Evictions for a status include the status.
+#[cfg(any(feature = "firehose", feature = "http"))]
+pub fn eviction_payload(reason: EvictionReason) -> String {
+    let status: Option<u16> = match reason {
+        #[cfg(feature = "firehose")]
+        EvictionReason::Status(status) => Some(status),
+        _ => None,
+    };
+    let mut payload = json!({
+        "type": "evicted",
+        "reason": reason.as_str(),
+    });
+    if let Some(status) = status {
+        payload["status"] = json!(status);
+    }
+    serde_json::to_string(&payload).unwrap()
+}
+
+// Sends a signed eviction notice to each of the user's endpoints at once. Dead endpoints are skipped, apart from the
+// one whose death evicted the user, and so are internal addresses unless they are allowed. This is best effort, so the
+// results are only logged.
+#[cfg(any(feature = "firehose", feature = "http"))]
+pub async fn send_eviction_notice(
+    client: &reqwest::Client, config: &Config, user: &User, reason: EvictionReason, last_endpoint: Option<usize>,
+) {
+    let ts_seconds = chrono::Utc::now().timestamp();
+    let endpoints = user.endpoints.iter().enumerate()
+        .filter(|&(index, endpoint)| Some(index) == last_endpoint || !endpoint.dead.load(Ordering::Relaxed));
+    futures::future::join_all(endpoints.map(|(_, endpoint)| async move {
+        // The delivery client only filters the addresses hostnames resolve to, so IP literals are checked here.
+        if !config.allow_internal_endpoints && ssrf::endpoint_ip_is_internal(&endpoint.url) {
+            debug!(user_id = user.id, "Not sending the eviction notice to an internal address");
+            return;
+        }
+        let result = match build_request(client, config, user, &endpoint.url, eviction_payload(reason), ts_seconds) {
+            Ok(mut request) => {
+                *request.timeout_mut() = Some(EVICTION_NOTICE_TIMEOUT);
+                client.execute(request).await.map_err(DeliveryError::from)
+            }
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(resp) => debug!(user_id = user.id, status = resp.status().as_u16(), "Sent the eviction notice"),
+            Err(error) => debug!(user_id = user.id, %error, "Failed to send the eviction notice"),
+        }
+    })).await;
+}
+
+// How long a paused user's endpoint is given to answer a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Builds the payload sent to check if a paused user's endpoint is back.
+pub fn probe_payload() -> String {
+    serde_json::to_string(&json!({"type": "probe"})).unwrap()
+}
+
+// Sends a signed probe to one of a paused user's endpoints, returning the status it answered with.
+pub async fn send_probe(client: &reqwest::Client, config: &Config, user: &User, endpoint: &str) -> Option<u16> {
+    let ts_seconds = chrono::Utc::now().timestamp();
+    let mut request = match build_request(client, config, user, endpoint, probe_payload(), ts_seconds) {
+        Ok(request) => request,
+        Err(error) => {
+            debug!(user_id = user.id, %error, "Failed to build the probe");
+            return None;
+        }
+    };
+    *request.timeout_mut() = Some(PROBE_TIMEOUT);
+    match client.execute(request).await {
+        Ok(resp) => Some(resp.status().as_u16()),
+        Err(error) => {
+            debug!(user_id = user.id, %error, "Failed to send the probe");
+            None
+        }
+    }
+}
+
+// The version of the post and repost payloads, sent as their `v` field. This goes up when a field is removed or changes
+// meaning, but not when one is added, so receivers should ignore fields they don't know.
+#[cfg(any(feature = "firehose", feature = "http"))]
+pub const PAYLOAD_VERSION: u32 = 1;
+
+// Builds a synthetic post payload for test deliveries. This has the same shape as a real phrase match.
+#[cfg(feature = "http")]
+pub fn test_payload() -> String {
+    serde_json::to_string(&json!({
+        "v": PAYLOAD_VERSION,
+        "bluehook": true,
+        "cid": "bafyreibluehooktest",
+        "uri": "at://did:plc:bluehook/app.bsky.feed.post/test",
+        "post": {
+            "$type": "app.bsky.feed.post",
+            "text": "This is a test delivery from bluehook.",
+            "createdAt": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        },
+        "is_reply": false,
+        "reply": null,
+        "reason": "phrase",
+        "test": true,
+    })).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bulk_search_tree::SigningMode;
+    use ed25519_dalek::{Signature, Verifier};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::collections::HashMap;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+    use worker::signature::verify_hmac;
+
+    // Accepts a single request, responding with a 204. Returns the headers and body it got.
+    async fn receive_one(listener: TcpListener) -> (HashMap<String, String>, String) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = vec![];
+        let mut chunk = [0; 4096];
+        let header_end = loop {
+            let n = socket.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+        let head = String::from_utf8(buf[..header_end].to_vec()).unwrap();
+        let headers: HashMap<String, String> = head.lines().skip(1)
+            .filter_map(|line| line.split_once(": "))
+            .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+            .collect();
+        let content_length: usize = headers["content-length"].parse().unwrap();
+        while buf.len() < header_end + content_length {
+            let n = socket.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        socket.write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+        (headers, String::from_utf8(buf[header_end..].to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_delivery_is_signed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/webhook", listener.local_addr().unwrap());
+        let receiver = tokio::spawn(receive_one(listener));
+
+        let private_key = [7u8; 32];
+        let user = User::new(None, endpoint, hex::encode(private_key)).unwrap();
+        let config = Config::for_tests(&[("DELIVERY_HEADERS", "X-Bluehook-Instance: test")]);
+        let resp = send(
+            &new_client(&config), &config, &user, &user.endpoints[0].url, test_payload(), 1_700_000_000,
+        ).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 204);
+
+        // Check the signature with the public key the receiver would have.
+        let (headers, body) = receiver.await.unwrap();
+        assert_eq!(headers["x-signature-timestamp"], "1700000000");
+        assert_eq!(headers["user-agent"], config.delivery_user_agent);
+        assert_eq!(headers["x-bluehook-instance"], "test");
+        let signature = Signature::from_slice(&hex::decode(&headers["x-signature-ed25519"]).unwrap()).unwrap();
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&private_key).verifying_key();
+        assert!(public_key.verify(format!("1700000000{body}").as_bytes(), &signature).is_ok());
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["test"], true);
+    }
+
+    #[tokio::test]
+    async fn test_hostnames_resolving_to_internal_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://localhost:{}/webhook", listener.local_addr().unwrap().port());
+        let user = User::new(None, endpoint, hex::encode([7u8; 32])).unwrap();
+
+        // The client won't connect to whatever the hostname resolves to when it is an internal address, whatever it
+        // resolved to when it was checked.
+        let config = Config::for_tests(&[]);
+        let result = send(
+            &new_client(&config), &config, &user, &user.endpoints[0].url, test_payload(), 1_700_000_000,
+        ).await;
+        assert!(result.is_err());
+
+        let receiver = tokio::spawn(receive_one(listener));
+        let config = Config::for_tests(&[("ALLOW_INTERNAL_ENDPOINTS", "true")]);
+        let resp = send(
+            &new_client(&config), &config, &user, &user.endpoints[0].url, test_payload(), 1_700_000_000,
+        ).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 204);
+        receiver.await.unwrap();
+    }
+
+    #[test]
+    fn test_eviction_metric_labels() {
+        assert_eq!(EvictionReason::Status(429).metric_label(), "429");
+        assert_eq!(EvictionReason::Status(403).metric_label(), "403");
+        assert_eq!(EvictionReason::HostnameNotFound.metric_label(), "dns");
+        assert_eq!(EvictionReason::Downtime.metric_label(), "downtime");
+        assert_eq!(EvictionReason::InternalAddress.metric_label(), "internal_address");
+        assert_eq!(EvictionReason::Admin.metric_label(), "admin");
+    }
+
+    #[tokio::test]
+    async fn test_eviction_notice() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/webhook", listener.local_addr().unwrap());
+        let receiver = tokio::spawn(receive_one(listener));
+
+        let private_key = [7u8; 32];
+        let user = User::new(None, endpoint, hex::encode(private_key)).unwrap();
+        let config = Config::for_tests(&[("ALLOW_INTERNAL_ENDPOINTS", "true")]);
+        send_eviction_notice(&new_client(&config), &config, &user, EvictionReason::Status(429), None).await;
+
+        // The notice is signed like any other delivery.
+        let (headers, body) = receiver.await.unwrap();
+        assert_eq!(body, eviction_payload(EvictionReason::Status(429)));
+        let timestamp = &headers["x-signature-timestamp"];
+        let signature = Signature::from_slice(&hex::decode(&headers["x-signature-ed25519"]).unwrap()).unwrap();
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&private_key).verifying_key();
+        assert!(public_key.verify(format!("{timestamp}{body}").as_bytes(), &signature).is_ok());
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json, json!({"type": "evicted", "reason": "status", "status": 429}));
+
+        let json: serde_json::Value = serde_json::from_str(&eviction_payload(EvictionReason::Downtime)).unwrap();
+        assert_eq!(json, json!({"type": "evicted", "reason": "downtime"}));
+    }
+
+    // Checks nothing has connected to the listener. Notices are sent before send_eviction_notice returns, so anything
+    // which was sent is already waiting to be accepted.
+    async fn nothing_received(listener: &TcpListener) -> bool {
+        tokio::time::timeout(Duration::from_millis(100), listener.accept()).await.is_err()
+    }
+
+    #[tokio::test]
+    async fn test_eviction_notice_skips_dead_and_internal_endpoints() {
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/webhook", internal.local_addr().unwrap());
+        let mut user = User::new(None, endpoint, hex::encode([7u8; 32])).unwrap();
+        user.add_endpoint(format!("http://{}/webhook", live.local_addr().unwrap())).unwrap();
+
+        // IP literals never go through the client's resolver, but internal ones are still skipped.
+        let config = Config::for_tests(&[]);
+        send_eviction_notice(&new_client(&config), &config, &user, EvictionReason::Status(403), None).await;
+        assert!(nothing_received(&internal).await);
+        assert!(nothing_received(&live).await);
+
+        // The first endpoint was killed for being internal and the second for a 403, which evicted the user. Only the
+        // second is told, even with internal addresses allowed.
+        user.mark_endpoint_dead(0);
+        user.mark_endpoint_dead(1);
+        let receiver = tokio::spawn(receive_one(live));
+        let config = Config::for_tests(&[("ALLOW_INTERNAL_ENDPOINTS", "true")]);
+        send_eviction_notice(&new_client(&config), &config, &user, EvictionReason::Status(403), Some(1)).await;
+        let (_, body) = receiver.await.unwrap();
+        assert_eq!(body, eviction_payload(EvictionReason::Status(403)));
+        assert!(nothing_received(&internal).await);
+    }
+
+    const TEST_ENDPOINT: &str = "https://example.com/webhook";
+
+    fn test_user() -> User {
+        User::new(None, TEST_ENDPOINT.to_string(), hex::encode([7u8; 32])).unwrap()
+    }
+
+    #[test]
+    fn test_compressed_request() {
+        let config = Config::for_tests(&[("COMPRESS_DELIVERIES", "true")]);
+        let json = test_payload();
+        let request = build_request(
+            &new_client(&config), &config, &test_user(), TEST_ENDPOINT, json.clone(), 1_700_000_000,
+        ).unwrap();
+        assert_eq!(request.headers()["Content-Encoding"], "gzip");
+
+        // The body decompresses to the payload and the signature is over the uncompressed payload.
+        let mut body = String::new();
+        GzDecoder::new(request.body().unwrap().as_bytes().unwrap()).read_to_string(&mut body).unwrap();
+        assert_eq!(body, json);
+        assert_eq!(request.headers()["X-Signature-Ed25519"], sign(&[7u8; 32], 1_700_000_000, &json).as_str());
+
+        let config = Config::for_tests(&[]);
+        let request = build_request(
+            &new_client(&config), &config, &test_user(), TEST_ENDPOINT, json.clone(), 1_700_000_000,
+        ).unwrap();
+        assert!(request.headers().get("Content-Encoding").is_none());
+        assert_eq!(request.body().unwrap().as_bytes().unwrap(), json.as_bytes());
+    }
+
+    #[test]
+    fn test_oversized_payload_is_skipped() {
+        let config = Config::for_tests(&[("MAX_DELIVERY_BYTES", "16")]);
+        let result = build_request(
+            &new_client(&config), &config, &test_user(), TEST_ENDPOINT, test_payload(), 1_700_000_000,
+        );
+        assert!(matches!(result, Err(DeliveryError::Oversized(_))));
+    }
+
+    #[test]
+    fn test_signing_modes() {
+        let config = Config::for_tests(&[]);
+        let json = test_payload();
+        let signed_with = |signing: SigningMode| {
+            let mut user = test_user();
+            user.set_signing(signing, Some("hunter2".to_string())).unwrap();
+            let request = build_request(
+                &new_client(&config), &config, &user, &user.endpoints[0].url, json.clone(), 1_700_000_000,
+            ).unwrap();
+            (request.headers().contains_key("X-Signature-Ed25519"), request.headers().get("X-Signature-HMAC").cloned())
+        };
+
+        assert_eq!(signed_with(SigningMode::Ed25519), (true, None));
+        let (ed25519, hmac) = signed_with(SigningMode::Hmac);
+        assert!(!ed25519);
+        assert!(verify_hmac("hunter2", 1_700_000_000, &json, hmac.unwrap().to_str().unwrap()));
+        let (ed25519, hmac) = signed_with(SigningMode::Both);
+        assert!(ed25519 && hmac.is_some());
+    }
+
+    #[test]
+    fn test_retry_after_seconds() {
+        let now = chrono::Utc::now();
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("999999", now), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_retry_after("-5", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_parse_ack() {
+        assert_eq!(parse_ack(br#"{"next_after_ms": 2000}"#), Some(Duration::from_secs(2)));
+        assert_eq!(parse_ack(br#"{"next_after_ms": 2000, "handled": 1}"#), Some(Duration::from_secs(2)));
+        assert_eq!(parse_ack(br#"{"next_after_ms": 99999999999}"#), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_ack(br#"{"next_after_ms": 0}"#), None);
+        assert_eq!(parse_ack(br#"{"next_after_ms": -5}"#), None);
+        assert_eq!(parse_ack(br#"{"ok": true}"#), None);
+        assert_eq!(parse_ack(b"OK"), None);
+        assert_eq!(parse_ack(b""), None);
+    }
+
+    #[test]
+    fn test_retry_after_date() {
+        let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT").unwrap().to_utc();
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:30:30 GMT", now), Some(Duration::from_secs(150)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("Thu, 22 Oct 2015 07:28:00 GMT", now), Some(MAX_RETRY_AFTER));
+    }
+
+    #[test]
+    fn test_key_rotation_signs_with_both_keys() {
+        let config = Config::for_tests(&[]);
+        let json = test_payload();
+        let mut user = test_user();
+        let request = build_request(
+            &new_client(&config), &config, &user, TEST_ENDPOINT, json.clone(), 1_700_000_000,
+        ).unwrap();
+        assert!(!request.headers().contains_key("X-Signature-Ed25519-Previous"));
+
+        // Each signature checks out against its own public key, and only its own.
+        user.set_previous_key(Some(hex::encode([9u8; 32]))).unwrap();
+        let request = build_request(
+            &new_client(&config), &config, &user, TEST_ENDPOINT, json.clone(), 1_700_000_000,
+        ).unwrap();
+        let signed = format!("1700000000{json}");
+        let signature_from = |header: &str| {
+            Signature::from_slice(&hex::decode(request.headers()[header].to_str().unwrap()).unwrap()).unwrap()
+        };
+        let current = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]).verifying_key();
+        let previous = ed25519_dalek::SigningKey::from_bytes(&[9u8; 32]).verifying_key();
+        assert!(current.verify(signed.as_bytes(), &signature_from("X-Signature-Ed25519")).is_ok());
+        assert!(previous.verify(signed.as_bytes(), &signature_from("X-Signature-Ed25519-Previous")).is_ok());
+        assert!(current.verify(signed.as_bytes(), &signature_from("X-Signature-Ed25519-Previous")).is_err());
+
+        // Users who don't sign with Ed25519 don't get either.
+        user.set_signing(SigningMode::Hmac, Some("hunter2".to_string())).unwrap();
+        let request = build_request(&new_client(&config), &config, &user, TEST_ENDPOINT, json, 1_700_000_000).unwrap();
+        assert!(!request.headers().contains_key("X-Signature-Ed25519-Previous"));
+    }
+}
@@ -0,0 +1,309 @@
+use std::{cmp::Ordering, collections::{BinaryHeap, HashMap}, future::Future, sync::{Arc, Mutex}};
+use futures::future::BoxFuture;
+use tokio::{runtime::Runtime, sync::{mpsc, oneshot}};
+
+// Defines a delivery waiting for a free slot. Higher priorities go first, then whoever has waited longest.
+struct Waiter {
+    priority: i32,
+    order: u64,
+    sender: oneshot::Sender<Permit>,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority).then(other.order.cmp(&self.order))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+// Defines the free slots and the deliveries waiting for one.
+struct PermitState {
+    available: usize,
+    waiters: BinaryHeap<Waiter>,
+    next_order: u64,
+}
+
+// Defines a limit on how many deliveries run at once. When a slot frees up, it goes to the highest priority delivery
+// waiting for one rather than the one which has waited longest.
+struct Permits {
+    state: Mutex<PermitState>,
+}
+
+impl Permits {
+    fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self { state: Mutex::new(PermitState { available: max, waiters: BinaryHeap::new(), next_order: 0 }) })
+    }
+
+    // Waits for a free slot.
+    async fn acquire(self: &Arc<Self>, priority: i32) -> Permit {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                return Permit { permits: Some(self.clone()) };
+            }
+            let (sender, receiver) = oneshot::channel();
+            let order = state.next_order;
+            state.next_order += 1;
+            state.waiters.push(Waiter { priority, order, sender });
+            receiver
+        };
+
+        // Waiters are only removed by handing them a permit, so the sender is never dropped without sending.
+        receiver.await.unwrap()
+    }
+
+    // Hands a freed slot to the highest priority waiter which is still waiting.
+    fn release(self: Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiters.pop() {
+            match waiter.sender.send(Permit { permits: Some(self.clone()) }) {
+                Ok(()) => return,
+                // The waiter gave up, so this permit was never handed out and mustn't release a slot when dropped.
+                Err(mut permit) => permit.permits = None,
+            }
+        }
+        state.available += 1;
+    }
+}
+
+// Defines a slot held by a running delivery. The slot is freed when this is dropped.
+struct Permit {
+    permits: Option<Arc<Permits>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(permits) = self.permits.take() {
+            permits.release();
+        }
+    }
+}
+
+// Defines a delivery waiting in a key's queue, with the priority it waits for a slot with.
+type OrderedDelivery = (i32, BoxFuture<'static, ()>);
+
+// How many deliveries can wait in the queue for one key before more are dropped.
+pub const ORDERED_QUEUE_CAPACITY: usize = 256;
+
+// Defines a separate runtime which webhook deliveries run on. This keeps slow deliveries (and the signing, compression
+// and TLS work they do) from starving the firehose decoding on the main runtime.
+pub struct DeliveryPool {
+    // This is only None while the pool is being dropped.
+    runtime: Option<Runtime>,
+    permits: Arc<Permits>,
+
+    // The queues for users whose deliveries are sent one at a time, by user ID.
+    ordered: Mutex<HashMap<u64, mpsc::Sender<OrderedDelivery>>>,
+}
+
+impl DeliveryPool {
+    // Creates a pool with the given number of threads which runs at most max_in_flight deliveries at once.
+    pub fn new(threads: usize, max_in_flight: usize) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .thread_name("delivery")
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            runtime: Some(runtime), permits: Permits::new(max_in_flight), ordered: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Runs a delivery on the pool. If the pool is full, this waits for a delivery to finish first. Deliveries with a
+    // higher priority are started first when several are waiting.
+    pub async fn spawn(&self, priority: i32, delivery: impl Future<Output = ()> + Send + 'static) {
+        let permit = self.permits.acquire(priority).await;
+        self.runtime.as_ref().unwrap().spawn(async move {
+            delivery.await;
+            drop(permit);
+        });
+    }
+
+    // Queues a delivery to run on the pool after every delivery queued before it with the same key has finished. This
+    // never waits, so a slow key can't hold up the caller. Each delivery waits for a free slot only once it is next in
+    // its key's queue. Returns false if the key already has ORDERED_QUEUE_CAPACITY deliveries waiting, in which case
+    // this one is dropped.
+    pub fn spawn_ordered(&self, key: u64, priority: i32, delivery: impl Future<Output = ()> + Send + 'static) -> bool {
+        let mut ordered = self.ordered.lock().unwrap();
+        let queue = ordered.entry(key).or_insert_with(|| {
+            // Start a task which runs the deliveries for this key in order. It stops when the queue is forgotten.
+            let (sender, mut receiver) = mpsc::channel::<OrderedDelivery>(ORDERED_QUEUE_CAPACITY);
+            let permits = self.permits.clone();
+            self.runtime.as_ref().unwrap().spawn(async move {
+                while let Some((priority, delivery)) = receiver.recv().await {
+                    let _permit = permits.acquire(priority).await;
+                    delivery.await;
+                }
+            });
+            sender
+        });
+        queue.try_send((priority, Box::pin(delivery))).is_ok()
+    }
+
+    // Stops keeping a queue for the key. Anything already queued is still delivered.
+    pub fn forget_ordered(&self, key: u64) {
+        self.ordered.lock().unwrap().remove(&key);
+    }
+
+    // Checks if the pool is keeping a queue for the key.
+    pub fn has_ordered(&self, key: u64) -> bool {
+        self.ordered.lock().unwrap().contains_key(&key)
+    }
+}
+
+impl Drop for DeliveryPool {
+    // Dropping a runtime blocks, which panics inside another runtime, so don't wait for in flight deliveries.
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_runs_on_pool_threads() {
+        let pool = DeliveryPool::new(1, 8).unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        pool.spawn(0, async move {
+            tx.send(std::thread::current().name().map(str::to_string)).unwrap();
+        }).await;
+        assert_eq!(rx.await.unwrap().as_deref(), Some("delivery"));
+    }
+
+    #[tokio::test]
+    async fn test_bounds_in_flight() {
+        let pool = DeliveryPool::new(1, 2).unwrap();
+        let (release, _) = tokio::sync::broadcast::channel::<()>(1);
+        for _ in 0..2 {
+            let mut released = release.subscribe();
+            pool.spawn(0, async move {
+                let _ = released.recv().await;
+            }).await;
+        }
+
+        // The third delivery has to wait for one of the first two to finish.
+        let third = pool.spawn(0, async {});
+        tokio::pin!(third);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut third).await.is_err());
+        release.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), third).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_dispatched_first() {
+        let pool = DeliveryPool::new(1, 1).unwrap();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        pool.spawn(0, async move {
+            let _ = released.await;
+        }).await;
+
+        // With the pool full, queue low priority deliveries and then a high priority one. The first poll of each
+        // spawn queues it for a slot, so they are queued in this order.
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut waiting = vec![];
+        for (name, priority) in [("low", 0), ("also low", 0), ("high", 10)] {
+            let tx = tx.clone();
+            let mut spawn = Box::pin(pool.spawn(priority, async move { tx.send(name).unwrap() }));
+            assert!(futures::poll!(&mut spawn).is_pending());
+            waiting.push(spawn);
+        }
+
+        // The high priority delivery goes first once there is room, then the rest in the order they were queued.
+        release.send(()).unwrap();
+        futures::future::join_all(waiting).await;
+        for expected in ["high", "low", "also low"] {
+            assert_eq!(rx.recv().await, Some(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gave_up_waiter_frees_its_slot() {
+        let pool = DeliveryPool::new(1, 1).unwrap();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        pool.spawn(0, async move {
+            let _ = released.await;
+        }).await;
+
+        // A delivery which stops waiting doesn't take the slot with it.
+        let mut gave_up = Box::pin(pool.spawn(5, async {}));
+        assert!(futures::poll!(&mut gave_up).is_pending());
+        drop(gave_up);
+        release.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), pool.spawn(0, async {})).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ordered_deliveries_keep_order() {
+        let pool = DeliveryPool::new(2, 8).unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        // The first delivery is slower, so it would finish second if they ran at the same time.
+        for (i, delay) in [(1, 50), (2, 0), (3, 10)] {
+            let tx = tx.clone();
+            assert!(pool.spawn_ordered(7, 0, async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                tx.send(i).unwrap();
+            }));
+        }
+        for expected in 1..=3 {
+            assert_eq!(rx.recv().await, Some(expected));
+        }
+
+        // A forgotten queue is started again on the next delivery.
+        assert!(pool.has_ordered(7));
+        pool.forget_ordered(7);
+        assert!(!pool.has_ordered(7));
+        let tx = tx.clone();
+        assert!(pool.spawn_ordered(7, 0, async move { tx.send(4).unwrap() }));
+        assert_eq!(rx.recv().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_ordered_key_does_not_block_others() {
+        let pool = DeliveryPool::new(1, 2).unwrap();
+        let (started, is_started) = tokio::sync::oneshot::channel();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        assert!(pool.spawn_ordered(7, 0, async move {
+            started.send(()).unwrap();
+            let _ = released.await;
+        }));
+        is_started.await.unwrap();
+
+        // The deliveries queued behind the stalled one don't hold a slot or make the caller wait, until the queue is
+        // full and the next is dropped.
+        for _ in 0..ORDERED_QUEUE_CAPACITY {
+            assert!(pool.spawn_ordered(7, 0, async {}));
+        }
+        assert!(!pool.spawn_ordered(7, 0, async {}));
+
+        // Other users still get their deliveries, ordered or not.
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let other = tx.clone();
+        assert!(pool.spawn_ordered(8, 0, async move { other.send("ordered").unwrap() }));
+        assert_eq!(tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap(), Some("ordered"));
+        tokio::time::timeout(Duration::from_secs(5), pool.spawn(0, async move { tx.send("unordered").unwrap() }))
+            .await
+            .unwrap();
+        assert_eq!(tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap(), Some("unordered"));
+        release.send(()).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use std::{io, net::SocketAddr};
+use dns_lookup::{getaddrinfo, AddrInfoHints, LookupError, LookupErrorKind, SockType};
+#[cfg(feature = "firehose")]
+use std::{future::Future, time::Duration};
+#[cfg(feature = "firehose")]
+use url::{Host, Url};
+use crate::ssrf::is_internal_ip;
+
+// How many times we look up a hostname before trusting the result, and the delay between each attempt.
+#[cfg(feature = "firehose")]
+const LOOKUP_ATTEMPTS: u32 = 3;
+#[cfg(feature = "firehose")]
+const LOOKUP_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+// Defines the outcome of resolving a hostname.
+#[cfg(feature = "firehose")]
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    // The hostname has at least one address.
+    Resolved,
+
+    // Every attempt confirmed the hostname does not exist or has no addresses.
+    NoRecords,
+
+    // The resolver failed in a way that may be temporary (for example SERVFAIL or a timeout).
+    Transient,
+}
+
+// Checks if a lookup error means the hostname definitely does not exist. lookup gives these the NotFound kind.
+#[cfg(feature = "firehose")]
+pub fn is_permanent_lookup_error(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::NotFound
+}
+
+// Turns a getaddrinfo error into an io::Error, with the NotFound kind if the hostname doesn't exist or has no
+// addresses. The C library translates its messages, so only the error code can be trusted for this.
+fn lookup_error(error: LookupError) -> io::Error {
+    match error.kind() {
+        LookupErrorKind::NoName | LookupErrorKind::NoData => {
+            io::Error::new(io::ErrorKind::NotFound, io::Error::from(error))
+        }
+        _ => error.into(),
+    }
+}
+
+// Runs the lookup until it resolves, retrying on failure. Only reports NoRecords if every attempt agreed.
+#[cfg(feature = "firehose")]
+pub async fn resolve_with_retry<F, Fut>(mut lookup: F) -> Resolution
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<Vec<SocketAddr>>>,
+{
+    let mut all_permanent = true;
+    for attempt in 0..LOOKUP_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(LOOKUP_RETRY_DELAY).await;
+        }
+        match lookup().await {
+            Ok(addrs) if !addrs.is_empty() => return Resolution::Resolved,
+            Ok(_) => {}
+            Err(error) if is_permanent_lookup_error(&error) => {}
+            Err(_) => all_permanent = false,
+        }
+    }
+    if all_permanent {
+        Resolution::NoRecords
+    } else {
+        Resolution::Transient
+    }
+}
+
+// Looks up the addresses for a hostname and port with the system resolver. Deliveries, the internal address check, and
+// the liveness check all resolve through this, so a hostname which resolves for one resolves for the others.
+pub async fn lookup(hostname: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let hostname = hostname.to_string();
+    let hints = AddrInfoHints { socktype: SockType::Stream.into(), ..AddrInfoHints::default() };
+    let found = tokio::task::spawn_blocking(move || {
+        getaddrinfo(Some(&hostname), None, Some(hints)).map_err(lookup_error)?.collect::<io::Result<Vec<_>>>()
+    }).await.map_err(io::Error::other)?;
+    Ok(found?.into_iter().map(|info| SocketAddr::new(info.sockaddr.ip(), port)).collect())
+}
+
+// Defines the resolver the delivery client connects with. Unless internal endpoints are allowed, internal addresses are
+// dropped from every lookup. This is what keeps deliveries off internal addresses, since a hostname could resolve to a
+// public address when it is checked and an internal one when it is connected to.
+pub struct SharedResolver {
+    pub allow_internal: bool,
+}
+
+impl reqwest::dns::Resolve for SharedResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let allow_internal = self.allow_internal;
+        Box::pin(async move {
+            // reqwest fills in the port itself.
+            let mut addrs = lookup(name.as_str(), 0).await?;
+            if !allow_internal && !addrs.is_empty() {
+                addrs.retain(|addr| !is_internal_ip(addr.ip()));
+                if addrs.is_empty() {
+                    let message = "hostname resolves to an internal address";
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, message).into());
+                }
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+// Resolves the hostname and port, retrying to make sure the result can be trusted.
+#[cfg(feature = "firehose")]
+pub async fn resolve_host(hostname: &str, port: u16) -> Resolution {
+    resolve_with_retry(|| lookup(hostname, port)).await
+}
+
+// Defines what needs checking to tell if the host of an endpoint still exists.
+#[cfg(feature = "firehose")]
+#[derive(Debug, PartialEq)]
+pub enum HostCheck {
+    // The endpoint has no host, so it can never be delivered to.
+    NoHost,
+
+    // The host is an IP address (IPv6 ones are in brackets in the URL), so there is nothing to look up.
+    IpAddress,
+
+    // The hostname and port to look up.
+    Lookup(String, u16),
+}
+
+#[cfg(feature = "firehose")]
+impl HostCheck {
+    // Works out what to check for an endpoint URL. The port defaults to the one for the scheme.
+    pub fn for_url(url: &Url) -> Self {
+        let port = url.port_or_known_default().unwrap_or(443);
+        match url.host() {
+            None => HostCheck::NoHost,
+            Some(Host::Ipv4(_) | Host::Ipv6(_)) => HostCheck::IpAddress,
+            Some(Host::Domain(hostname)) => HostCheck::Lookup(hostname.to_string(), port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn nxdomain() -> io::Error {
+        lookup_error(LookupError::new(libc::EAI_NONAME))
+    }
+
+    fn servfail() -> io::Error {
+        lookup_error(LookupError::new(libc::EAI_AGAIN))
+    }
+
+    #[test]
+    fn test_error_classification() {
+        assert!(is_permanent_lookup_error(&nxdomain()));
+        assert!(is_permanent_lookup_error(&lookup_error(LookupError::new(libc::EAI_NODATA))));
+        assert!(!is_permanent_lookup_error(&servfail()));
+        assert!(!is_permanent_lookup_error(&lookup_error(LookupError::new(libc::EAI_FAIL))));
+
+        // Only the code counts, so a message in another language, or one a future C library words differently, is
+        // still classified the same.
+        assert!(!is_permanent_lookup_error(&io::Error::other("Name or service not known")));
+    }
+
+    #[test]
+    fn test_host_checks() {
+        let check = |endpoint: &str| HostCheck::for_url(&Url::parse(endpoint).unwrap());
+        assert_eq!(check("https://[2001:db8::1]/webhook"), HostCheck::IpAddress);
+        assert_eq!(check("https://[2001:db8::1]:8443/webhook"), HostCheck::IpAddress);
+        assert_eq!(check("https://203.0.113.5:8443/webhook"), HostCheck::IpAddress);
+        assert_eq!(check("https://example.com:8443/webhook"), HostCheck::Lookup("example.com".to_string(), 8443));
+        assert_eq!(check("https://example.com/webhook"), HostCheck::Lookup("example.com".to_string(), 443));
+        assert_eq!(check("http://example.com/webhook"), HostCheck::Lookup("example.com".to_string(), 80));
+        assert_eq!(check("unix:/run/webhook.sock"), HostCheck::NoHost);
+    }
+
+    #[tokio::test]
+    async fn test_shared_resolver() {
+        use reqwest::dns::Resolve;
+        let resolver = SharedResolver { allow_internal: true };
+        let addrs: Vec<_> = resolver.resolve("localhost".parse().unwrap()).await.unwrap().collect();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+        assert!(!addrs.is_empty());
+        assert_eq!(resolve_host("localhost", 8443).await, Resolution::Resolved);
+
+        // Connections never see an internal address unless they are allowed.
+        let resolver = SharedResolver { allow_internal: false };
+        let error = resolver.resolve("localhost".parse().unwrap()).await.err().unwrap();
+        assert_eq!(error.to_string(), "hostname resolves to an internal address");
+    }
+
+    #[tokio::test]
+    async fn test_resolved() {
+        let resolution = resolve_with_retry(|| async { Ok(vec!["127.0.0.1:443".parse().unwrap()]) }).await;
+        assert_eq!(resolution, Resolution::Resolved);
+    }
+
+    #[tokio::test]
+    async fn test_confirmed_nxdomain() {
+        let attempts = AtomicU32::new(0);
+        let resolution = resolve_with_retry(|| async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(nxdomain())
+        }).await;
+        assert_eq!(resolution, Resolution::NoRecords);
+        assert_eq!(attempts.load(Ordering::Relaxed), LOOKUP_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn test_transient_then_resolved() {
+        let attempts = AtomicU32::new(0);
+        let resolution = resolve_with_retry(|| async {
+            if attempts.fetch_add(1, Ordering::Relaxed) == 0 {
+                Err(servfail())
+            } else {
+                Ok(vec!["127.0.0.1:443".parse().unwrap()])
+            }
+        }).await;
+        assert_eq!(resolution, Resolution::Resolved);
+    }
+
+    #[tokio::test]
+    async fn test_transient_is_not_eviction() {
+        let resolution = resolve_with_retry(|| async { Err(servfail()) }).await;
+        assert_eq!(resolution, Resolution::Transient);
+    }
+
+    #[tokio::test]
+    async fn test_mixed_failures_are_transient() {
+        let attempts = AtomicU32::new(0);
+        let resolution = resolve_with_retry(|| async {
+            if attempts.fetch_add(1, Ordering::Relaxed) == 1 {
+                Err(servfail())
+            } else {
+                Err(nxdomain())
+            }
+        }).await;
+        assert_eq!(resolution, Resolution::Transient);
+    }
+}
@@ -1,61 +1,781 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use deadpool_postgres::Pool;
+use std::{
+    collections::HashMap, fmt::Display, future::Future, net::SocketAddr, ops::Deref,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+};
+use serde::Deserialize;
+use serde_json::json;
 use tokio::sync::RwLock;
-use viz::{types::{Params, State}, Request, RequestExt, Result, Router, Server, ServiceMaker, StatusCode};
-use crate::{bulk_search_tree::{BulkSearchTree, User}, postgres::init_user};
+use tracing::{error, warn};
+use viz::{
+    header::{HeaderMap, HeaderValue, RETRY_AFTER}, types::{Params, State}, IntoResponse, Request, RequestExt, Response,
+    ResponseExt, Result, Router, Server, ServiceMaker, StatusCode,
+};
+use crate::{
+    bulk_search_tree::{BulkSearchTree, User, PRIVATE_KEY_LENGTH}, config::Config, delivery::{self, EvictionReason},
+    evict_user, metrics, ssrf,
+    store::{add_phrase, init_user, init_users, remove_phrase, LoadResult, PhraseError, StoreError}, HttpDelivery,
+};
 
+// Defines the state the HTTP server works with. Users are loaded into and evicted from the same local copy the firehose
+// delivers from, so this shares the delivery state.
 #[derive(Clone)]
 struct HTTPState {
-    pool: &'static Pool,
-    tree: &'static BulkSearchTree,
-    dids: &'static RwLock<HashMap<String, Arc<User>>>,
-    http_key: &'static str,
+    delivery: &'static HttpDelivery,
+    firehose_connected: &'static AtomicBool,
 }
 
-async fn private_key_handler(mut req: Request) -> Result<StatusCode> {
-    // Extract the key and HTTP state.
+impl Deref for HTTPState {
+    type Target = HttpDelivery;
+
+    fn deref(&self) -> &HttpDelivery {
+        self.delivery
+    }
+}
+
+// How long clients are told to wait before retrying a change turned away while the firehose is disconnected.
+const DISCONNECTED_RETRY_AFTER_SECS: u64 = 5;
+
+// Checks the authorization header against the HTTP key. Returns the status to respond with if it is not valid.
+fn check_auth(headers: &HeaderMap, http_key: &str) -> Option<StatusCode> {
+    // A missing header is an auth failure like any other.
+    let auth = match headers.get("Authorization") {
+        Some(auth) => auth,
+        None => return Some(StatusCode::UNAUTHORIZED),
+    };
+
+    // Reject headers with non-visible bytes since they can never be a valid key.
+    if auth.to_str().is_err() {
+        return Some(StatusCode::BAD_REQUEST);
+    }
+
+    // Check the raw bytes against the key in constant time.
+    if !crypto::util::fixed_time_eq(auth.as_bytes(), http_key.as_bytes()) {
+        return Some(StatusCode::UNAUTHORIZED);
+    }
+    None
+}
+
+// Checks the worker can take changes which add users or phrases. With REJECT_CHANGES_WHILE_DISCONNECTED, they are
+// turned away while the firehose is disconnected since nothing would be delivered. Returns the status to respond with
+// if they can't be taken.
+fn check_connected(config: &Config, firehose_connected: &AtomicBool) -> Option<StatusCode> {
+    if config.reject_changes_while_disconnected && !firehose_connected.load(Ordering::Relaxed) {
+        return Some(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    None
+}
+
+// Builds the response for a change turned away by check_connected, telling the client when to try again.
+fn retry_later(status: StatusCode) -> Response {
+    let mut resp = status.into_response();
+    resp.headers_mut().insert(RETRY_AFTER, HeaderValue::from(DISCONNECTED_RETRY_AFTER_SECS));
+    resp
+}
+
+// Checks a private key from a request looks like one before it goes anywhere near the store.
+fn valid_private_key(key: &str) -> bool {
+    key.len() == PRIVATE_KEY_LENGTH * 2 && key.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+// Extracts the HTTP state and the private key for a /:key route. The authorization header is checked first, then
+// whether the firehose is connected if the change adds anything, and then that the key looks like one. Gives back the
+// response to send instead if any of them fail.
+async fn key_route(req: &mut Request, adds: bool) -> Result<Result<(HTTPState, String), Response>> {
     let (State(state), Params(key)) = req.extract::<(State<HTTPState>, Params<String>)>().await?;
+    if let Some(status) = check_auth(req.headers(), &state.config.http_key) {
+        return Ok(Err(status.into_response()));
+    }
+    if adds {
+        if let Some(status) = check_connected(state.config, state.firehose_connected) {
+            return Ok(Err(retry_later(status)));
+        }
+    }
+    if !valid_private_key(&key) {
+        return Ok(Err(StatusCode::BAD_REQUEST.into_response()));
+    }
+    Ok(Ok((state, key)))
+}
+
+async fn private_key_handler(mut req: Request) -> Result<Response> {
+    // Extract the key and HTTP state, turning the request away if they can't be used.
+    let (state, key) = match key_route(&mut req, true).await? {
+        Ok(route) => route,
+        Err(resp) => return Ok(resp),
+    };
+
+    // Call the function to init a user from the pg file, and tell the caller if they weren't loaded.
+    let status = match init_user(state.config, state.store, state.tree, state.dids, state.keys, &key).await {
+        Ok(LoadResult::Loaded) => StatusCode::NO_CONTENT,
+        Ok(LoadResult::NotFound) => StatusCode::NOT_FOUND,
+        Ok(LoadResult::Invalid) => StatusCode::UNPROCESSABLE_ENTITY,
+        Ok(LoadResult::Paused) => StatusCode::CONFLICT,
+        Err(error) => {
+            error!(%error, "Failed to initialize the user");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    Ok(status.into_response())
+}
+
+async fn bulk_load_handler(mut req: Request) -> Result<Response> {
+    // Extract the HTTP state.
+    let State(state) = req.extract::<State<HTTPState>>().await?;
 
     // Check the authorization header.
-    let auth = match req.headers().get("Authorization") {
-        Some(auth) => auth,
-        None => return Ok(StatusCode::BAD_REQUEST),
+    if let Some(status) = check_auth(req.headers(), &state.config.http_key) {
+        return Ok(status.into_response());
+    }
+
+    // Check the firehose is connected, if that is required.
+    if let Some(status) = check_connected(state.config, state.firehose_connected) {
+        return Ok(retry_later(status));
+    }
+
+    // The body is a JSON array of private keys, which are checked the same way as the key of a /:key route.
+    let Ok(private_keys) = req.json::<Vec<String>>().await else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
     };
-    let auth = auth.to_str().unwrap();
+    if !private_keys.iter().all(|key| valid_private_key(key)) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
 
-    // Check the key in constant time.
-    if !crypto::util::fixed_time_eq(auth.as_bytes(), state.http_key.as_bytes()) {
-        return Ok(StatusCode::UNAUTHORIZED);
+    // Load the users and tell the caller what happened to each key.
+    match init_users(state.config, state.store, state.tree, state.dids, state.keys, &private_keys).await {
+        Ok(results) => Ok(Response::json(results)?),
+        Err(error) => {
+            error!(%error, "Failed to bulk load the users");
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
     }
+}
 
-    // Call the function to init a user from the pg file.
-    init_user(state.pool, state.tree, state.dids, &key).await;
+// Gets the live state of a loaded user by their private key. Returns None if the user is not loaded.
+async fn user_status(
+    keys: &RwLock<HashMap<String, Arc<User>>>, tree: &BulkSearchTree, key: &str,
+) -> Option<serde_json::Value> {
+    let user = keys.read().await.get(&key.to_lowercase()).cloned()?;
+    let endpoints: Vec<_> = user.endpoints.iter().map(|endpoint| json!({
+        "endpoint": endpoint.url,
+        "downtime_started": endpoint.downtime_started.load(Ordering::Relaxed),
+        "last_success": endpoint.last_success.load(Ordering::Relaxed),
+        "dead": endpoint.dead.load(Ordering::Relaxed),
+    })).collect();
 
-    // Return a 204.
-    Ok(StatusCode::NO_CONTENT)
+    // The top level downtime and last success are the primary endpoint's, like before users had more than one.
+    let primary = &user.endpoints[0];
+    Some(json!({
+        "loaded": true,
+        "phrase_count": user.phrase_count(),
+        "phrase_matches": tree.match_counts(&user, user.phrases()).await,
+        "did": user.did,
+        "previous_dids": user.previous_dids,
+        "user_downtime_started": primary.downtime_started.load(Ordering::Relaxed),
+        "last_success": primary.last_success.load(Ordering::Relaxed),
+        "endpoints": endpoints,
+    }))
 }
 
-pub async fn init_http_server(
-    pool: &'static Pool, tree: &'static BulkSearchTree, dids: &'static RwLock<HashMap<String, Arc<User>>>,
-) {
-    // Get the HTTP key.
-    let http_key = Box::leak(Box::new(std::env::var("HTTP_KEY").unwrap()));
+async fn status_handler(mut req: Request) -> Result<Response> {
+    // Extract the key and HTTP state, turning the request away if they can't be used.
+    let (state, key) = match key_route(&mut req, false).await? {
+        Ok(route) => route,
+        Err(resp) => return Ok(resp),
+    };
 
-    // Get the host to serve on.
-    let host = std::env::var("HOST").unwrap_or("0.0.0.0".to_string());
-    let port = std::env::var("PORT").unwrap_or("6969".to_string());
+    // Return the status, or a 404 if the user is not loaded.
+    match user_status(state.keys, state.tree, &key).await {
+        Some(status) => Ok(Response::json(status)?),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
 
-    // Turn the port into a u16.
-    let port = port.parse::<u16>().unwrap();
+// Gets the phrases held for a loaded user by their private key. Returns None if the user is not loaded.
+async fn user_phrases(keys: &RwLock<HashMap<String, Arc<User>>>, key: &str) -> Option<Vec<String>> {
+    let user = keys.read().await.get(&key.to_lowercase()).cloned()?;
+    Some(user.phrases())
+}
 
-    // Create the HTTP server.
-    let router = Router::new()
+async fn phrases_handler(mut req: Request) -> Result<Response> {
+    // Extract the key and HTTP state, turning the request away if they can't be used.
+    let (state, key) = match key_route(&mut req, false).await? {
+        Ok(route) => route,
+        Err(resp) => return Ok(resp),
+    };
+
+    // Return the phrases, or a 404 if the user is not loaded.
+    match user_phrases(state.keys, &key).await {
+        Some(phrases) => Ok(Response::json(phrases)?),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+// Defines the body of a request to change one of a user's phrases.
+#[derive(Deserialize)]
+struct PhraseBody {
+    phrase: String,
+}
+
+async fn add_phrase_handler(mut req: Request) -> Result<Response> {
+    // Extract the key and HTTP state, turning the request away if they can't be used.
+    let (state, key) = match key_route(&mut req, true).await? {
+        Ok(route) => route,
+        Err(resp) => return Ok(resp),
+    };
+
+    // Get the loaded user and the phrase to add.
+    let Some(user) = state.keys.read().await.get(&key.to_lowercase()).cloned() else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let Ok(body) = req.json::<PhraseBody>().await else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    // Add the phrase, telling the caller why if we couldn't.
+    let error = match add_phrase(state.config, state.store, state.tree, &user, &body.phrase).await {
+        Ok(()) => return Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(PhraseError::Store(error)) => {
+            error!(%error, "Failed to save the phrase");
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+        Err(error) => error,
+    };
+    let mut resp = Response::json(json!({ "error": error.to_string() }))?;
+    *resp.status_mut() = match error {
+        PhraseError::Duplicate => StatusCode::CONFLICT,
+        _ => StatusCode::UNPROCESSABLE_ENTITY,
+    };
+    Ok(resp)
+}
+
+async fn remove_phrase_handler(mut req: Request) -> Result<Response> {
+    // Extract the key and HTTP state, turning the request away if they can't be used. This isn't turned away while the
+    // firehose is disconnected, since removing a phrase only stops deliveries and it shouldn't match once it is back.
+    let (state, key) = match key_route(&mut req, false).await? {
+        Ok(route) => route,
+        Err(resp) => return Ok(resp),
+    };
+
+    // Get the loaded user and the phrase to remove.
+    let Some(user) = state.keys.read().await.get(&key.to_lowercase()).cloned() else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let Ok(body) = req.json::<PhraseBody>().await else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    // Remove the phrase, or 404 if the user doesn't have it.
+    let status = match remove_phrase(state.store, state.tree, &user, &body.phrase).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => {
+            error!(%error, "Failed to delete the phrase");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    Ok(status.into_response())
+}
+
+async fn test_delivery_handler(mut req: Request) -> Result<Response> {
+    // Extract the key and HTTP state, turning the request away if they can't be used.
+    let (state, key) = match key_route(&mut req, false).await? {
+        Ok(route) => route,
+        Err(resp) => return Ok(resp),
+    };
+
+    // Get the loaded user.
+    let Some(user) = state.keys.read().await.get(&key.to_lowercase()).cloned() else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    // Test deliveries go to the primary endpoint, and are held to the same rules as real ones.
+    let endpoint = &user.endpoints[0].url;
+    if !state.config.allow_internal_endpoints && ssrf::endpoint_is_internal(endpoint).await {
+        return Ok(StatusCode::UNPROCESSABLE_ENTITY.into_response());
+    }
+
+    // Send the test delivery and tell the caller what their endpoint said.
+    let ts_seconds = chrono::Utc::now().timestamp();
+    match delivery::send(
+        &state.http_client, state.config, &user, endpoint, delivery::test_payload(), ts_seconds,
+    ).await {
+        Ok(resp) => Ok(Response::json(json!({ "status": resp.status().as_u16() }))?),
+        Err(error) => {
+            let mut resp = Response::json(json!({ "error": error.to_string() }))?;
+            *resp.status_mut() = StatusCode::BAD_GATEWAY;
+            Ok(resp)
+        }
+    }
+}
+
+// Evicts the loaded user with the DID, the same way as when their endpoints break. Returns None if no user with the DID
+// is loaded.
+async fn evict_by_did(state: &HTTPState, did: &str) -> Option<Result<(), StoreError>> {
+    // Users are also matched by the DIDs they used to have, but only the user with the DID now is evicted.
+    let user = state.dids.read().await.get(did).filter(|user| user.did.as_deref() == Some(did)).cloned()?;
+    warn!(user_id = user.id, did, "Evicting user by DID");
+    metrics::EVICTIONS.inc(&EvictionReason::Admin.metric_label());
+    Some(evict_user(user, EvictionReason::Admin, None, state.delivery).await)
+}
+
+async fn evict_did_handler(mut req: Request) -> Result<StatusCode> {
+    // Extract the DID and HTTP state.
+    let (State(state), Params(did)) = req.extract::<(State<HTTPState>, Params<String>)>().await?;
+
+    // Check the authorization header.
+    if let Some(status) = check_auth(req.headers(), &state.config.http_key) {
+        return Ok(status);
+    }
+
+    // Evictions aren't turned away while the firehose is disconnected, since they only stop deliveries and an operator
+    // evicting a user wants them gone before it is back. The user is no longer matched even if deleting them from the
+    // store fails.
+    match evict_by_did(&state, &did).await {
+        Some(Ok(())) => Ok(StatusCode::NO_CONTENT),
+        Some(Err(error)) => {
+            error!(%error, "Failed to delete the evicted user from the store");
+            Ok(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        None => Ok(StatusCode::NOT_FOUND),
+    }
+}
+
+// Counts the loaded users and what is in the tree. Every loaded user has exactly one entry in keys, so its size is the
+// number of users.
+async fn admin_stats(state: &HTTPState) -> serde_json::Value {
+    let users = state.keys.read().await.len();
+    let dids = state.dids.read().await.len();
+    json!({
+        "users": users,
+        "dids": dids,
+        "tree": state.tree.stats().await,
+    })
+}
+
+async fn stats_handler(mut req: Request) -> Result<Response> {
+    // Extract the HTTP state.
+    let State(state) = req.extract::<State<HTTPState>>().await?;
+
+    // Check the authorization header.
+    if let Some(status) = check_auth(req.headers(), &state.config.http_key) {
+        return Ok(status.into_response());
+    }
+
+    Ok(Response::json(admin_stats(&state).await)?)
+}
+
+async fn metrics_handler(_req: Request) -> Result<Response> {
+    Ok(Response::with(metrics::render(), "text/plain; version=0.0.4"))
+}
+
+// Defines why the HTTP server could not start or stopped serving.
+#[derive(Debug)]
+pub enum HttpServerError {
+    // The address could not be bound, usually because something else is using the port.
+    Bind(SocketAddr, std::io::Error),
+    Serve(String),
+}
+
+impl Display for HttpServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpServerError::Bind(addr, error) => write!(f, "failed to bind to {addr}: {error}"),
+            HttpServerError::Serve(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+// Creates the router for the HTTP API.
+fn router(state: HTTPState) -> Router {
+    Router::new()
+        .get("/metrics", metrics_handler)
         .put("/:key", private_key_handler)
-        .with(State::new(HTTPState { pool, tree, dids, http_key }));
+        .post("/bulk-load", bulk_load_handler)
+        .get("/:key/status", status_handler)
+        .get("/:key/phrases", phrases_handler)
+        .post("/:key/phrases", add_phrase_handler)
+        .delete("/:key/phrases", remove_phrase_handler)
+        .post("/:key/test", test_delivery_handler)
+        .post("/admin/evict-did/:did", evict_did_handler)
+        .get("/admin/stats", stats_handler)
+        .with(State::new(state))
+}
+
+// Binds the HTTP server, returning the future which serves it. The address is bound before this returns, so a port
+// which is in use is an error for the caller rather than for whichever task runs the server.
+pub fn init_http_server(
+    delivery: &'static HttpDelivery, firehose_connected: &'static AtomicBool,
+) -> Result<impl Future<Output = Result<(), HttpServerError>>, HttpServerError> {
+    // Create the HTTP server.
+    let router = router(HTTPState { delivery, firehose_connected });
+
+    // Bind the address, then serve the router.
+    let addr = delivery.config.http_addr;
+    let listener = std::net::TcpListener::bind(addr).map_err(|error| HttpServerError::Bind(addr, error))?;
+    let server = Server::from_tcp(listener)
+        .map_err(|error| HttpServerError::Bind(addr, std::io::Error::other(error.to_string())))?;
+    Ok(async move {
+        server.serve(ServiceMaker::from(router)).await.map_err(|error| HttpServerError::Serve(error.to_string()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bulk_search_tree::{test_key, MatchOptions}, store::{insert_user, MemoryStore, UserRecord, UserStore},
+    };
+
+    #[test]
+    fn test_valid_auth() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", HeaderValue::from_static("hunter2"));
+        assert_eq!(check_auth(&headers, "hunter2"), None);
+    }
+
+    #[test]
+    fn test_wrong_auth() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", HeaderValue::from_static("hunter3"));
+        assert_eq!(check_auth(&headers, "hunter2"), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn test_missing_auth() {
+        let headers = HeaderMap::new();
+        assert_eq!(check_auth(&headers, "hunter2"), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn test_changes_while_disconnected() {
+        let connected = AtomicBool::new(false);
+
+        // Changes are taken regardless of the firehose by default.
+        let config = Config::for_tests(&[]);
+        assert_eq!(check_connected(&config, &connected), None);
+
+        // When they're only taken while connected, they're turned away until the firehose connects.
+        let config = Config::for_tests(&[("REJECT_CHANGES_WHILE_DISCONNECTED", "true")]);
+        assert_eq!(check_connected(&config, &connected), Some(StatusCode::SERVICE_UNAVAILABLE));
+        connected.store(true, Ordering::Relaxed);
+        assert_eq!(check_connected(&config, &connected), None);
+    }
+
+    #[tokio::test]
+    async fn test_user_status() {
+        let keys = RwLock::new(HashMap::new());
+        let tree = BulkSearchTree::new();
+        assert_eq!(user_status(&keys, &tree, &test_key("aabb")).await, None);
+
+        let mut user = User::new(
+            Some("did:plc:jake".to_string()), "https://example.com".to_string(), test_key("aabb"),
+        ).unwrap();
+        user.set_phrases(vec!["red panda".to_string(), "bamboo".to_string()]);
+        user.endpoints[0].last_success.store(1234, Ordering::Relaxed);
+        let user = Arc::new(user);
+        keys.write().await.insert(test_key("aabb"), user.clone());
+        for phrase in user.phrases() {
+            tree.add_item(&phrase, user.clone()).await;
+        }
+        tree.find_all_matches("a red panda").await;
+        tree.find_all_matches("another red panda").await;
+
+        let status = user_status(&keys, &tree, &test_key("AABB")).await.unwrap();
+        assert_eq!(status, json!({
+            "loaded": true,
+            "phrase_count": 2,
+            "phrase_matches": {"red panda": 2, "bamboo": 0},
+            "did": "did:plc:jake",
+            "previous_dids": [],
+            "user_downtime_started": 0,
+            "last_success": 1234,
+            "endpoints": [
+                {"endpoint": "https://example.com", "downtime_started": 0, "last_success": 1234, "dead": false},
+            ],
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_user_phrases() {
+        let keys = RwLock::new(HashMap::new());
+        let tree = BulkSearchTree::new_with_options(MatchOptions { min_length: 3, ..MatchOptions::default() });
+        assert_eq!(user_phrases(&keys, &test_key("aabb")).await, None);
+
+        // Phrases which are too short once normalized are not held.
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aabb")).unwrap();
+        user.set_phrases(vec!["Red Panda".to_string(), "ok".to_string(), "  ".to_string(), "bamboo".to_string()]);
+        insert_user(user, &tree, &RwLock::new(HashMap::new()), &keys).await;
+        assert_eq!(user_phrases(&keys, &test_key("AABB")).await.unwrap(), vec!["Red Panda", "bamboo"]);
+    }
+
+    // Builds the HTTP state with the given settings and nothing loaded, backed by the store.
+    fn test_state(env: &[(&str, &str)], store: &'static MemoryStore) -> HTTPState {
+        HTTPState {
+            delivery: Box::leak(Box::new(crate::test_delivery(env, store))),
+            firehose_connected: Box::leak(Box::new(AtomicBool::new(true))),
+        }
+    }
+
+    // Builds a user record for the key with a working endpoint.
+    fn test_record(key: &str) -> UserRecord {
+        UserRecord::new(test_key(key), "https://example.com".to_string())
+    }
+
+    // Builds the HTTP state with the given settings, with the user saved to its store with the phrases and loaded.
+    async fn loaded_state(
+        env: &[(&str, &str)], user: UserRecord, phrases: &[&str],
+    ) -> (HTTPState, &'static MemoryStore) {
+        let store: &'static MemoryStore = Box::leak(Box::default());
+        let state = test_state(env, store);
+        let private_key = user.private_key.clone();
+        store.insert(user, phrases);
+        init_user(state.config, state.store, state.tree, state.dids, state.keys, &private_key).await.unwrap();
+        (state, store)
+    }
+
+    // Serves the API for the state on a free port, returning its base URL.
+    fn serve(state: HTTPState) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(ServiceMaker::from(router(state))));
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn test_bind_errors() {
+        let in_use = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = in_use.local_addr().unwrap().port().to_string();
+        let state = test_state(&[("HOST", "127.0.0.1"), ("PORT", &port)], Box::leak(Box::default()));
+        let init = || init_http_server(state.delivery, state.firehose_connected);
+
+        // A port something else is listening on is an error straight away.
+        let Err(error) = init() else {
+            panic!("bound to a port which is in use");
+        };
+        assert!(matches!(&error, HttpServerError::Bind(addr, _) if *addr == state.config.http_addr), "{error:?}");
+        assert!(error.to_string().starts_with(&format!("failed to bind to 127.0.0.1:{port}: ")), "{error}");
+
+        // Once it is free, the server binds.
+        drop(in_use);
+        assert!(init().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_evict_by_did() {
+        let mut user = test_record("aabb");
+        user.did = Some("did:plc:jake".to_string());
+        let (state, store) = loaded_state(&[], user, &["red panda"]).await;
+        assert!(evict_by_did(&state, "did:plc:someone").await.is_none());
+        assert_eq!(state.tree.find_all_matches("a red panda").await.len(), 1);
+
+        // The user is gone from future matches and from the store.
+        assert!(matches!(evict_by_did(&state, "did:plc:jake").await, Some(Ok(()))));
+        assert!(store.private_keys().is_empty());
+        assert!(store.phrases(&test_key("aabb")).is_empty());
+        assert!(state.tree.find_all_matches("a red panda").await.is_empty());
+        assert!(state.dids.read().await.is_empty());
+        assert!(state.keys.read().await.is_empty());
+        assert!(evict_by_did(&state, "did:plc:jake").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_evict_by_previous_did() {
+        let mut user = test_record("aabb");
+        user.did = Some("did:plc:jake".to_string());
+        user.previous_dids = vec!["did:plc:old".to_string()];
+        let (state, store) = loaded_state(&[], user, &["red panda"]).await;
+
+        // The old DID still matches the user, but it is no longer theirs to be evicted by.
+        assert!(evict_by_did(&state, "did:plc:old").await.is_none());
+        assert_eq!(store.private_keys(), vec![test_key("aabb")]);
+        assert_eq!(state.keys.read().await.len(), 1);
+        assert!(matches!(evict_by_did(&state, "did:plc:jake").await, Some(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn test_load_user_statuses() {
+        let store: &'static MemoryStore = Box::leak(Box::default());
+        let state = test_state(&[], store);
+        store.insert(test_record("aa"), &["red panda"]);
+        store.insert(UserRecord::new(test_key("bb"), "not a url".to_string()), &["red panda"]);
+        let mut user = test_record("cc");
+        user.paused = true;
+        store.insert(user, &["red panda"]);
+        let url = serve(state.clone());
+
+        // Users who weren't loaded say why.
+        let client = reqwest::Client::new();
+        for (key, status) in [("aa", 204), ("dd", 404), ("bb", 422), ("cc", 409)] {
+            let response = client.put(format!("{url}/{}", test_key(key)))
+                .header("Authorization", &state.config.http_key)
+                .send().await.unwrap();
+            assert_eq!(response.status().as_u16(), status, "{key}");
+        }
+        assert_eq!(state.keys.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_keys() {
+        let store: &'static MemoryStore = Box::leak(Box::default());
+        let state = test_state(&[], store);
+        store.insert(test_record("aa"), &["red panda"]);
+        let url = serve(state.clone());
+        let client = reqwest::Client::new();
+
+        // Keys are only checked once the caller is authorized.
+        let response = client.get(format!("{url}/nope/status")).send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 401);
+        for path in ["nope/status", &format!("{}/phrases", "zz".repeat(32)), &format!("{}/status", "ab".repeat(33))] {
+            let response = client.get(format!("{url}/{path}")).header("Authorization", &state.config.http_key)
+                .send().await.unwrap();
+            assert_eq!(response.status().as_u16(), 400, "{path}");
+        }
+
+        // One bad key in a bulk load turns the whole load away.
+        let response = client.post(format!("{url}/bulk-load"))
+            .header("Authorization", &state.config.http_key)
+            .body(json!([test_key("aa"), "a".repeat(63)]).to_string())
+            .send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 400);
+        assert!(state.keys.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_load_user_while_disconnected() {
+        let store: &'static MemoryStore = Box::leak(Box::default());
+        let connected: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+        let state = HTTPState {
+            firehose_connected: connected,
+            ..test_state(&[("REJECT_CHANGES_WHILE_DISCONNECTED", "true")], store)
+        };
+        store.insert(test_record("aa"), &["red panda"]);
+        let url = serve(state.clone());
+        let load = || {
+            reqwest::Client::new()
+                .put(format!("{url}/{}", test_key("aa")))
+                .header("Authorization", &state.config.http_key)
+                .send()
+        };
+
+        // The user is turned away with when to try again until the firehose connects.
+        let response = load().await.unwrap();
+        assert_eq!(response.status().as_u16(), 503);
+        assert_eq!(response.headers()["Retry-After"], "5");
+        assert!(state.keys.read().await.is_empty());
+
+        connected.store(true, Ordering::Relaxed);
+        assert_eq!(load().await.unwrap().status().as_u16(), 204);
+        assert_eq!(state.keys.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_evict_did_forgets_ordered_deliveries() {
+        let mut user = test_record("aabb");
+        user.did = Some("did:plc:jake".to_string());
+        user.ordered = true;
+        let (state, store) = loaded_state(&[], user, &["red panda"]).await;
+        let user = state.keys.read().await[&test_key("aabb")].clone();
+        assert!(state.delivery_pool.spawn_ordered(user.id, 0, async {}));
+        assert!(state.delivery_pool.has_ordered(user.id));
+        let evictions = metrics::EVICTIONS.get("admin");
+
+        // Evicting over HTTP goes the same way as a broken endpoint, so the user's queue goes with them.
+        let response = reqwest::Client::new()
+            .post(format!("{}/admin/evict-did/did:plc:jake", serve(state.clone())))
+            .header("Authorization", &state.config.http_key)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 204);
+        assert!(!state.delivery_pool.has_ordered(user.id));
+        assert!(state.tree.find_all_matches("a red panda").await.is_empty());
+        assert!(store.private_keys().is_empty());
+        assert!(metrics::EVICTIONS.get("admin") > evictions);
+    }
+
+    #[tokio::test]
+    async fn test_phrases_keep_their_casing() {
+        let (state, store) = loaded_state(&[], test_record("aabb"), &["Red Panda"]).await;
+        let user = state.keys.read().await[&test_key("aabb")].clone();
+        add_phrase(state.config, state.store, state.tree, &user, "Rust").await.unwrap();
+
+        // The tree only holds the normalized phrases, but everything reported is as the user typed it.
+        assert_eq!(state.tree.find_all_matches("learning rust").await.len(), 1);
+        assert_eq!(state.tree.find_all_matches("a RED PANDA").await.len(), 1);
+        assert_eq!(user_phrases(state.keys, &test_key("aabb")).await.unwrap(), vec!["Red Panda", "Rust"]);
+        let status = user_status(state.keys, state.tree, &test_key("aabb")).await.unwrap();
+        assert_eq!(status["phrase_matches"], json!({"Red Panda": 1, "Rust": 1}));
+        assert_eq!(store.phrases(&test_key("aabb")), vec!["Red Panda", "Rust"]);
+    }
+
+    #[tokio::test]
+    async fn test_phrase_cap_is_checked_when_saving() {
+        let (state, store) = loaded_state(&[("MAX_PHRASES_PER_USER", "2")], test_record("aabb"), &["red panda"]).await;
+        let url = serve(state.clone());
+        let add = |phrase: &str| {
+            reqwest::Client::new()
+                .post(format!("{url}/{}/phrases", test_key("aabb")))
+                .header("Authorization", &state.config.http_key)
+                .body(json!({ "phrase": phrase }).to_string())
+                .send()
+        };
+
+        // Another request saved a phrase after this one was checked against our local copy, so only the store knows
+        // the user is at the cap.
+        store.add_phrase(&test_key("aabb"), "rust", Some(2)).await.unwrap();
+        let response = add("tokio").await.unwrap();
+        assert_eq!(response.status().as_u16(), 422);
+        assert_eq!(response.text().await.unwrap(), r#"{"error":"user already has the maximum of 2 phrases"}"#);
+        assert_eq!(store.phrases(&test_key("aabb")), vec!["red panda", "rust"]);
+        assert!(state.tree.find_all_matches("learning tokio").await.is_empty());
+
+        // A duplicate is still reported as one.
+        assert_eq!(add("Red Panda").await.unwrap().status().as_u16(), 409);
+    }
+
+    #[tokio::test]
+    async fn test_admin_stats() {
+        let state = test_state(&[], Box::leak(Box::default()));
+        assert_eq!(admin_stats(&state).await, json!({
+            "users": 0,
+            "dids": 0,
+            "tree": {"branches": 0, "phrases": 0, "entries": 0},
+        }));
+
+        let mut user = User::new(
+            Some("did:plc:jake".to_string()), "https://example.com".to_string(), test_key("aabb"),
+        ).unwrap();
+        user.set_phrases(vec!["red panda".to_string()]);
+        insert_user(user, state.tree, state.dids, state.keys).await;
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("ccdd")).unwrap();
+        user.set_phrases(vec!["red panda".to_string(), "bamboo".to_string()]);
+        insert_user(user, state.tree, state.dids, state.keys).await;
+        let stats = admin_stats(&state).await;
+        assert_eq!((stats["users"].as_u64(), stats["dids"].as_u64()), (Some(2), Some(1)));
+        assert_eq!((stats["tree"]["phrases"].as_u64(), stats["tree"]["entries"].as_u64()), (Some(2), Some(3)));
+
+        // Evicting takes the user out of the count.
+        assert!(evict_by_did(&state, "did:plc:jake").await.is_some());
+        let stats = admin_stats(&state).await;
+        assert_eq!((stats["users"].as_u64(), stats["dids"].as_u64()), (Some(1), Some(0)));
+        assert_eq!((stats["tree"]["phrases"].as_u64(), stats["tree"]["entries"].as_u64()), (Some(2), Some(2)));
+    }
+
+    #[test]
+    fn test_valid_private_key() {
+        assert!(valid_private_key(&"ab".repeat(32)));
+        assert!(valid_private_key(&"AB".repeat(32)));
+        assert!(!valid_private_key(""));
+        assert!(!valid_private_key(&"ab".repeat(33)));
+        assert!(!valid_private_key(&"ab".repeat(1000)));
+        assert!(!valid_private_key(&"a".repeat(63)));
+        assert!(!valid_private_key(&"zz".repeat(32)));
+        assert!(!valid_private_key(&format!("{}é", "a".repeat(62))));
+    }
 
-    // Serve the router.
-    let addr = format!("{host}:{port}").parse::<SocketAddr>().unwrap();
-    if let Err(err) = Server::bind(&addr).serve(ServiceMaker::from(router)).await {
-        panic!("Error binding to {}: {}", addr, err);
+    #[test]
+    fn test_binary_auth() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", HeaderValue::from_bytes(b"hunter\xff").unwrap());
+        assert_eq!(check_auth(&headers, "hunter2"), Some(StatusCode::BAD_REQUEST));
     }
 }
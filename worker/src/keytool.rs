@@ -0,0 +1,33 @@
+use crate::bulk_search_tree::{parse_private_key, public_key};
+
+// Runs `worker keytool <hex private key>`, which checks a private key the same way loading a user does and gives back
+// the hex public key to hand to whoever receives the user's deliveries. Nothing else in the worker is started.
+pub fn run(args: &[String]) -> Result<String, String> {
+    let [private_key] = args else {
+        return Err("usage: worker keytool <hex private key>".to_string());
+    };
+    let private_key = parse_private_key(private_key.trim()).map_err(|error| error.to_string())?;
+    Ok(hex::encode(public_key(&private_key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keytool(args: &[&str]) -> Result<String, String> {
+        run(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_keytool() {
+        // The first test vector from RFC 8032.
+        assert_eq!(
+            keytool(&["9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60"]).unwrap(),
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+        );
+        assert!(keytool(&["not hex"]).unwrap_err().contains("not valid hex"));
+        assert_eq!(keytool(&["aabb"]).unwrap_err(), "private key must be 32 bytes, got 2");
+        assert!(keytool(&[]).unwrap_err().starts_with("usage"));
+        assert!(keytool(&["aa", "bb"]).unwrap_err().starts_with("usage"));
+    }
+}
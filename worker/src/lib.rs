@@ -0,0 +1,18 @@
+// The phrase search tree the worker matches posts with. It doesn't know about users, so anything which needs to find
+// which of many phrases are in some text can use it.
+pub mod bulk_search;
+
+// The thread pool deliveries run on, so sending them never slows down reading the firehose.
+pub mod delivery_pool;
+
+// Matching posts against a tree, either from whichever task asks or from one thread which owns searching it.
+pub mod matcher;
+
+// The hook told about every delivery attempt, so metrics or alerting can be plugged into the worker's delivery path.
+pub mod observer;
+
+// Getting the text to search from a post.
+pub mod post_text;
+
+// Signing deliveries, and checking them the way receivers should.
+pub mod signature;
@@ -1,258 +1,2892 @@
 mod bulk_search_tree;
+#[cfg(feature = "firehose")]
+mod circuit_breaker;
+mod config;
+mod delivery;
+mod dns;
+#[cfg(feature = "http")]
 mod http;
+mod keytool;
+mod metrics;
+#[cfg(feature = "postgres")]
 mod postgres;
+#[cfg(feature = "firehose")]
+mod quote_cache;
+#[cfg(feature = "firehose")]
+mod rate_limit;
+#[cfg(feature = "firehose")]
+mod recent_uris;
+#[cfg(feature = "firehose")]
+mod relays;
+mod ssrf;
+mod store;
+#[cfg(feature = "firehose")]
+mod work_queue;
 
 use bulk_search_tree::{BulkSearchTree, User};
-use deadpool_postgres::Pool;
-use ed25519_dalek::ed25519::signature::SignerMut;
+#[cfg(feature = "firehose")]
+use bulk_search_tree::{Endpoint, EndpointDeath, Matcher};
+#[cfg(feature = "firehose")]
+use circuit_breaker::CircuitBreakers;
+use config::Config;
+#[cfg(feature = "firehose")]
+use dns::{HostCheck, Resolution};
+#[cfg(any(feature = "firehose", feature = "http"))]
+use delivery::EvictionReason;
+#[cfg(feature = "firehose")]
+use delivery::{DeliveryError, MetricsObserver, PayloadProfile};
+#[cfg(feature = "firehose")]
+use futures::{Sink, SinkExt as _, Stream};
 use futures::StreamExt as _;
+#[cfg(feature = "http")]
 use http::init_http_server;
-use postgres::{delete_user, init_data, init_postgres};
-use rsky_lexicon::{app::bsky::{feed::Post, richtext::Features}, com::atproto::sync::SubscribeRepos};
-use serde::Deserialize;
+#[cfg(feature = "postgres")]
+use postgres::{init_postgres, warm_up, PgStore};
+#[cfg(feature = "firehose")]
+use quote_cache::QuoteCache;
+#[cfg(feature = "firehose")]
+use rate_limit::{DeliveryLimits, RateLimiter};
+#[cfg(feature = "firehose")]
+use recent_uris::RecentUris;
+#[cfg(feature = "firehose")]
+use relays::{
+    decompress_frame, subscribe_url, ReconnectBackoff, RelayRotation, MAX_RECONNECT_DELAY, MIN_RECONNECT_DELAY,
+};
+#[cfg(feature = "firehose")]
+use rsky_lexicon::app::bsky::{feed::{Post, Repost}, richtext::Features};
+#[cfg(feature = "firehose")]
+use rsky_lexicon::com::atproto::sync::SubscribeRepos;
+#[cfg(feature = "firehose")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "firehose")]
 use serde_json::json;
+#[cfg(any(feature = "firehose", feature = "http"))]
+use store::StoreError;
+use store::{init_data, StoreKind, UserStore};
 use tokio::sync::RwLock;
-use std::{collections::{HashMap, HashSet}, fmt::Debug, io::Cursor, net::IpAddr, sync::{atomic::Ordering, Arc}, time::Duration};
-use tokio_tungstenite::tungstenite::protocol::Message;
+#[cfg(feature = "firehose")]
+use tokio::sync::mpsc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+#[cfg(feature = "firehose")]
+use std::{fmt::{Debug, Display}, future::Future, hash::Hash, sync::atomic::{AtomicUsize, Ordering}, time::Instant};
+#[cfg(any(feature = "http", feature = "firehose"))]
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "firehose")]
+use std::io::Cursor;
+#[cfg(feature = "firehose")]
+use tokio_tungstenite::tungstenite::{protocol::Message, Error as WsError};
+use tracing::{error, info, warn};
+#[cfg(feature = "firehose")]
+use tracing::{debug, trace};
+use tracing_subscriber::EnvFilter;
+#[cfg(feature = "firehose")]
+use work_queue::Turn;
+#[cfg(feature = "firehose")]
+use worker::delivery_pool::DeliveryPool;
+#[cfg(feature = "firehose")]
+use worker::observer::{DeliveryObserver, DeliveryOutcome};
+#[cfg(feature = "firehose")]
+use worker::post_text::{quoted_uri, searchable_text, tags_text, truncate_text};
 
+#[cfg(feature = "firehose")]
 #[derive(Debug, Deserialize)]
 #[serde(tag = "$type")]
 enum Lexicon {
     #[serde(rename(deserialize = "app.bsky.feed.post"))]
-    AppBskyFeedPost(Post),
+    AppBskyFeedPost(Box<Post>),
+    #[serde(rename(deserialize = "app.bsky.feed.repost"))]
+    AppBskyFeedRepost(Repost),
 }
 
-// Evicts a user if they are broken.
-async fn evict_user(user: Arc<User>, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>, pg_pool: &Pool) {
-    // Remove the user from the trees.
-    if let Some(did) = &user.did {
-        dids.write().await.remove(did);
+// Defines how payloads for matched users leave the worker. This is the HTTP sender in production, and lets tests see
+// which users would be told about what without a network or Postgres.
+#[cfg(feature = "firehose")]
+trait Delivery: Send + Sync + 'static {
+    // Queues a payload to be delivered to the user. ts_seconds is when the post was handled, and is what every endpoint
+    // and every signature gets no matter how long the delivery is queued for, so a receiver sees one timestamp per
+    // notification.
+    fn deliver(&'static self, user: Arc<User>, json: String, ts_seconds: i64) -> impl Future<Output = ()> + Send;
+}
+
+// Defines the state used to sign and send webhooks, and to evict users whose endpoints are broken.
+struct HttpDelivery {
+    config: &'static Config,
+    tree: &'static BulkSearchTree,
+    dids: &'static RwLock<HashMap<String, Arc<User>>>,
+    keys: &'static RwLock<HashMap<String, Arc<User>>>,
+    store: &'static dyn UserStore,
+    http_client: reqwest::Client,
+    #[cfg(feature = "firehose")]
+    delivery_limits: DeliveryLimits,
+    #[cfg(feature = "firehose")]
+    circuit_breakers: CircuitBreakers,
+    #[cfg(feature = "firehose")]
+    delivery_pool: DeliveryPool,
+    #[cfg(feature = "firehose")]
+    observer: &'static dyn DeliveryObserver,
+}
+
+// Creates an HTTP delivery for tests with the given settings and nothing loaded.
+#[cfg(test)]
+fn test_delivery(env: &[(&str, &str)], store: &'static dyn UserStore) -> HttpDelivery {
+    let config: &'static Config = Box::leak(Box::new(Config::for_tests(env)));
+    HttpDelivery {
+        config,
+        tree: Box::leak(Box::new(BulkSearchTree::new())),
+        dids: Box::leak(Box::new(RwLock::new(HashMap::new()))),
+        keys: Box::leak(Box::new(RwLock::new(HashMap::new()))),
+        store,
+        http_client: delivery::new_client(config),
+        #[cfg(feature = "firehose")]
+        delivery_limits: DeliveryLimits::new(None, None),
+        #[cfg(feature = "firehose")]
+        circuit_breakers: CircuitBreakers::new(config.circuit_breaker_threshold, config.circuit_breaker_cooldown),
+        #[cfg(feature = "firehose")]
+        delivery_pool: DeliveryPool::new(1, 8).unwrap(),
+        #[cfg(feature = "firehose")]
+        observer: &MetricsObserver,
+    }
+}
+
+// Defines the state used to process the firehose.
+#[cfg(feature = "firehose")]
+struct WorkerState<D: Delivery = HttpDelivery> {
+    config: &'static Config,
+    matcher: Matcher<'static>,
+    dids: &'static RwLock<HashMap<String, Arc<User>>>,
+
+    // Where the next post with too many recipients starts taking them from.
+    recipient_rotation: AtomicUsize,
+
+    quote_cache: QuoteCache,
+    recent_uris: RecentUris,
+
+    // The delivery is shared with the HTTP server, which evicts users through it too.
+    delivery: &'static D,
+}
+
+// Gets the host of a endpoint for logging and rate limiting purposes.
+#[cfg(feature = "firehose")]
+fn endpoint_host(endpoint: &str) -> String {
+    url::Url::parse(endpoint).ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+// Evicts a user, removing them from our local copy, their ordered deliveries and the store. Every eviction goes through
+// here, whether the user's endpoints broke or an operator evicted them. Users who asked to be told get a notice first,
+// unless their endpoint is internal. last_endpoint is the endpoint whose death evicted the user, which is told even
+// though it is now dead.
+#[cfg(any(feature = "firehose", feature = "http"))]
+async fn evict_user(
+    user: Arc<User>, reason: EvictionReason, last_endpoint: Option<usize>, state: &HttpDelivery,
+) -> Result<(), StoreError> {
+    warn!(user_id = user.id, did = user.did.as_deref(), reason = reason.as_str(), "Evicting user");
+    if user.notify_eviction && reason.notifies() {
+        delivery::send_eviction_notice(&state.http_client, state.config, &user, reason, last_endpoint).await;
     }
-    for phrase in &user.phrases {
-        // This can be improved, but it is so rare that its not a big deal.
-        tree.remove_item(phrase, user.clone()).await;
+    #[cfg(feature = "firehose")]
+    state.delivery_pool.forget_ordered(user.id);
+    store::evict_user(state.store, &user, state.tree, state.dids, state.keys).await
+}
+
+// Pauses a user whose endpoints have all been down for too long. They stop being matched, but are kept in the store and
+// probed every PAUSE_PROBE_INTERVAL_MS until one of their endpoints is back.
+#[cfg(feature = "firehose")]
+async fn pause_user(user: Arc<User>, state: &HttpDelivery) {
+    warn!(user_id = user.id, did = user.did.as_deref(), "Pausing user");
+    metrics::PAUSES.inc();
+    state.delivery_pool.forget_ordered(user.id);
+    let paused_until = chrono::Utc::now().timestamp_millis() + state.config.pause_probe_interval.as_millis() as i64;
+    if let Err(error) = store::pause_user(
+        state.store, &user, paused_until, state.tree, state.dids, state.keys,
+    ).await {
+        error!(user_id = user.id, %error, "Failed to save the paused user to the store");
     }
+}
+
+// Marks one of the user's endpoints as dead. If it was the last one which wasn't, the user is paused if it was downtime
+// and evicted for anything else.
+#[cfg(feature = "firehose")]
+async fn endpoint_dead(user: Arc<User>, index: usize, reason: EvictionReason, state: &HttpDelivery) {
+    match user.mark_endpoint_dead(index) {
+        EndpointDeath::AlreadyDead => {}
+        EndpointDeath::OthersLeft => {
+            metrics::DEAD_ENDPOINTS.inc(&reason.metric_label());
+            warn!(user_id = user.id, reason = reason.as_str(), "Endpoint is broken, no longer delivering to it");
+        }
 
-    // Remove the user from Postgres.
-    let reencoded_key = hex::encode(user.private_key.clone());
-    delete_user(pg_pool, &reencoded_key).await;
+        // A paused user isn't given up on, so they are only counted in bluehook_pauses_total.
+        EndpointDeath::Last if reason == EvictionReason::Downtime => pause_user(user, state).await,
+        EndpointDeath::Last => {
+            metrics::EVICTIONS.inc(&reason.metric_label());
+            let user_id = user.id;
+            if let Err(error) = evict_user(user, reason, Some(index), state).await {
+                error!(user_id, %error, "Failed to delete the evicted user from the store");
+            }
+        }
+    }
 }
 
 // Handle if the server connection failed.
-async fn server_conn_failed(user: Arc<User>, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>, pg_pool: &Pool) {
+#[cfg(feature = "firehose")]
+async fn server_conn_failed(user: Arc<User>, index: usize, state: &HttpDelivery) {
     // Parse the URL.
-    let url = match url::Url::parse(&user.endpoint) {
+    let url = match url::Url::parse(&user.endpoints[index].url) {
         Err(error) => {
             // WTF!
-            eprintln!("Error parsing the user endpoint: {error:?}");
-            evict_user(user, tree, dids, pg_pool).await;
+            error!(user_id = user.id, %error, "Error parsing the user endpoint");
+            endpoint_dead(user, index, EvictionReason::InvalidEndpoint, state).await;
             return;
         }
         Ok(url) => url,
     };
 
-    // Check if the hostname is an IP address.
-    let hostname = url.host_str().unwrap();
-    if hostname.parse::<IpAddr>().is_ok() {
-        // Don't do the DNS lookup.
-        return;
+    // Check if there is either an A or AAAA record for the hostname. Only evict if the hostname is confirmed gone,
+    // since a resolver glitch is just downtime. A endpoint without a host can never be delivered to, and there is
+    // nothing to look up for a IP address.
+    let (hostname, port) = match HostCheck::for_url(&url) {
+        HostCheck::NoHost => {
+            warn!(user_id = user.id, "User endpoint has no host");
+            endpoint_dead(user, index, EvictionReason::InvalidEndpoint, state).await;
+            return;
+        }
+        HostCheck::IpAddress => return,
+        HostCheck::Lookup(hostname, port) => (hostname, port),
+    };
+    match dns::resolve_host(&hostname, port).await {
+        Resolution::Resolved => {}
+        Resolution::NoRecords => {
+            warn!(user_id = user.id, hostname, "Hostname does not exist or has no records");
+            endpoint_dead(user, index, EvictionReason::HostnameNotFound, state).await;
+        }
+        Resolution::Transient => {
+            warn!(user_id = user.id, hostname, "Transient error looking up the hostname");
+            mark_down(user, index, state).await;
+        }
+    }
+}
+
+// Records that the endpoint is down at the given time. Returns true if it has been down for longer than the window.
+#[cfg(feature = "firehose")]
+fn record_downtime(endpoint: &Endpoint, now_ms: i64, window: Duration) -> bool {
+    // Figure out how long it has been down.
+    let dt_start = endpoint.downtime_started.load(Ordering::Relaxed);
+    if dt_start == 0 {
+        // Mark this endpoint as down.
+        endpoint.downtime_started.store(now_ms, Ordering::Relaxed);
+        return false;
+    }
+    now_ms - dt_start > window.as_millis() as i64
+}
+
+// Records a 429 with a Retry-After, holding off deliveries to the endpoint for that long. Returns true once the
+// endpoint has asked us to wait more than threshold times in a row, at which point it is treated like any other
+// eviction status.
+#[cfg(feature = "firehose")]
+fn record_retry_after(endpoint: &Endpoint, wait: Duration, now_ms: i64, threshold: u32) -> bool {
+    endpoint.retry_after_until.store(now_ms + wait.as_millis() as i64, Ordering::Relaxed);
+    endpoint.retry_after_count.fetch_add(1, Ordering::Relaxed) + 1 > threshold
+}
+
+// Checks if a delivery status counts as a success. If no statuses are configured, any 2xx status does.
+fn is_delivery_success(status: u16, success_statuses: &[u16]) -> bool {
+    if success_statuses.is_empty() {
+        (200..300).contains(&status)
+    } else {
+        success_statuses.contains(&status)
     }
+}
+
+// Marks one of the user's endpoints as down, killing it if it has been down for too long.
+#[cfg(feature = "firehose")]
+async fn mark_down(user: Arc<User>, index: usize, state: &HttpDelivery) {
+    if record_downtime(&user.endpoints[index], chrono::Utc::now().timestamp_millis(), state.config.eviction_downtime) {
+        endpoint_dead(user, index, EvictionReason::Downtime, state).await;
+    }
+}
 
-    // Check if there is either an A or AAAA record for the hostname.
-    let mut lookup = match tokio::net::lookup_host(hostname).await {
-        Ok(lookup) => lookup,
+// Sends a probe to each of a paused user's endpoints at once. Returns true if any of them answered with a success.
+async fn probe_user(user: &User, state: &HttpDelivery) -> bool {
+    let probes = user.endpoints.iter().map(|endpoint| async move {
+        if !state.config.allow_internal_endpoints && ssrf::endpoint_ip_is_internal(&endpoint.url) {
+            return false;
+        }
+        delivery::send_probe(&state.http_client, state.config, user, &endpoint.url).await
+            .is_some_and(|status| is_delivery_success(status, &state.config.success_statuses))
+    });
+    futures::future::join_all(probes).await.into_iter().any(|answered| answered)
+}
+
+// How many paused users are probed at once. Each probe can wait out the delivery timeout, so probing them one at a time
+// would hold a large backlog up, but probing them all at once would flood the delivery client.
+const PAUSED_PROBE_CONCURRENCY: usize = 16;
+
+// Probes the paused users who are due. Users with an endpoint which is back are loaded again, and everyone else is
+// left paused until the next PAUSE_PROBE_INTERVAL_MS.
+async fn probe_paused(state: &HttpDelivery) {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let users = match store::paused_users(state.config, state.store, now_ms).await {
+        Ok(users) => users,
         Err(error) => {
-            eprintln!("Error looking up the hostname: {error:?}");
-            evict_user(user, tree, dids, pg_pool).await;
+            error!(%error, "Failed to read the paused users from the store");
             return;
         }
     };
+    let paused_until = now_ms + state.config.pause_probe_interval.as_millis() as i64;
+    futures::stream::iter(users).for_each_concurrent(PAUSED_PROBE_CONCURRENCY, |(private_key, user)| async move {
+        let result = match user {
+            Ok(user) if probe_user(&user, state).await => {
+                info!(user_id = user.id, did = user.did.as_deref(), "Resuming paused user");
+                metrics::RESUMES.inc();
+                store::resume_user(state.store, user, state.tree, state.dids, state.keys).await
+            }
+            Ok(_) => state.store.set_paused(&private_key, Some(paused_until)).await,
+            Err(error) => {
+                warn!(%error, "Paused user is invalid, leaving them paused");
+                state.store.set_paused(&private_key, Some(paused_until)).await
+            }
+        };
+        if let Err(error) = result {
+            error!(%error, "Failed to save the paused user to the store");
+        }
+    }).await;
+}
 
-    // If there's nothing in the lookup, evict the user.
-    if lookup.next().is_none() {
-        evict_user(user, tree, dids, pg_pool).await;
+#[cfg(feature = "firehose")]
+impl Delivery for HttpDelivery {
+    // Queues a delivery on the delivery pool. Users who want their deliveries in order get them one at a time, without
+    // making the firehose wait on their queue.
+    async fn deliver(&'static self, user: Arc<User>, json: String, ts_seconds: i64) {
+        if user.ordered {
+            let (user_id, priority) = (user.id, user.priority);
+            if !self.delivery_pool.spawn_ordered(user_id, priority, inform_user(user, json, ts_seconds, self)) {
+                warn!(user_id, "Ordered user has too many deliveries waiting, dropping the delivery");
+                metrics::DROPPED_ORDERED_DELIVERIES.inc();
+            }
+        } else {
+            self.delivery_pool.spawn(user.priority, inform_user(user, json, ts_seconds, self)).await;
+        }
     }
 }
 
-// Inform the user about the post.
-async fn inform_user(
-    user: Arc<User>, json: String, ts_seconds: i64, http_client: reqwest::Client,
-    tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>, pg_pool: &Pool,
-) {
-    // Perform a ED25519 signature of the json including the timestamp in seconds.
-    let slice: &[u8; 32] = user.private_key.as_slice().try_into().unwrap();
-    let mut signer = ed25519_dalek::SigningKey::from_bytes(slice);
-    let ts_seconds_str = ts_seconds.to_string();
-    let new_msg_body = format!("{ts_seconds_str}{json}");
-    let signature = hex::encode(
-        signer.sign(new_msg_body.as_bytes()).to_vec()
-    );
-
-    // Send the message to the user.
-    match 
-        http_client.post(&user.endpoint).body(json)
-            .header("Content-Type", "application/json")
-            .header("X-Signature-Ed25519", signature)
-            .header("X-Signature-Timestamp", ts_seconds_str)
-            .send().await
-    {
-        Err(_) => server_conn_failed(user, tree, dids, pg_pool).await,
+// Inform the user about the post. Each endpoint gets the delivery separately, so a broken one doesn't hold the others
+// up or count against them.
+#[cfg(feature = "firehose")]
+#[tracing::instrument(skip_all, fields(user_id = user.id))]
+async fn inform_user(user: Arc<User>, json: String, ts_seconds: i64, state: &HttpDelivery) {
+    // The user's rate limit is checked once for the delivery rather than for each endpoint. A dry run doesn't count
+    // against it.
+    let limited = !state.config.dry_run;
+    if limited && !state.delivery_limits.allow_user(user.id) {
+        metrics::DROPPED_DELIVERIES.inc();
+        debug!(user_id = user.id, "Delivery dropped by the user rate limiter");
+        return;
+    }
+
+    let deliveries = (0..user.endpoints.len())
+        .filter(|&index| !user.endpoints[index].dead.load(Ordering::Relaxed))
+        .map(|index| inform_endpoint(user.clone(), index, json.clone(), ts_seconds, state));
+    let sent = futures::future::join_all(deliveries).await;
+
+    // If no endpoint got past the host limit, the delivery went nowhere and the user gets their token back.
+    if limited && !sent.contains(&true) {
+        state.delivery_limits.refund_user(user.id);
+    }
+}
+
+// How many of a user's matched phrases are logged in a dry run, and how many characters of each, so a user with a lot
+// of long phrases doesn't flood the logs.
+#[cfg(feature = "firehose")]
+const DRY_RUN_LOGGED_PHRASES: usize = 10;
+#[cfg(feature = "firehose")]
+const DRY_RUN_PHRASE_CHARS: usize = 64;
+
+// Gets how many of the user's phrases are in the text of a payload, and the first few of them cut short for the dry run
+// log. Phrases which only matched a quoted post or a tag aren't in the text, so they aren't found.
+#[cfg(feature = "firehose")]
+async fn dry_run_phrases(user: &User, json: &str, tree: &BulkSearchTree) -> (usize, Vec<String>) {
+    let payload: serde_json::Value = serde_json::from_str(json).unwrap_or_default();
+    let text = payload.pointer("/post/text").and_then(serde_json::Value::as_str).unwrap_or_default();
+    let phrases = tree.phrases_in(text, store::tree_phrases(user, tree)).await;
+    let logged = phrases.iter()
+        .take(DRY_RUN_LOGGED_PHRASES)
+        .map(|phrase| phrase.chars().take(DRY_RUN_PHRASE_CHARS).collect())
+        .collect();
+    (phrases.len(), logged)
+}
+
+// Inform one of the user's endpoints about the post. Returns true if it got past the host limit, so the delivery counts
+// against the user.
+#[cfg(feature = "firehose")]
+#[tracing::instrument(skip_all, fields(host = %endpoint_host(&user.endpoints[index].url)))]
+async fn inform_endpoint(user: Arc<User>, index: usize, json: String, ts_seconds: i64, state: &HttpDelivery) -> bool {
+    let endpoint = &user.endpoints[index];
+
+    // In a dry run, say what would have been sent and stop there, so nothing counts against the user.
+    if state.config.dry_run {
+        metrics::DRY_RUN_DELIVERIES.inc();
+        let (matched, phrases) = dry_run_phrases(&user, &json, state.tree).await;
+        info!(endpoint = endpoint.url, size = json.len(), matched, ?phrases, "Dry run, not sending the delivery");
+        return false;
+    }
+
+    // Users are checked against the allowlist when they are loaded, but never send anywhere off it.
+    if !endpoint.host_allowed(state.config.endpoint_host_allowlist.as_deref()) {
+        warn!("Endpoint host is not on ENDPOINT_HOST_ALLOWLIST");
+        return false;
+    }
+
+    // Hold off if the endpoint asked us to wait.
+    if chrono::Utc::now().timestamp_millis() < endpoint.retry_after_until.load(Ordering::Relaxed) {
+        metrics::RETRY_AFTER_DELIVERIES.inc();
+        debug!("Delivery skipped since the endpoint asked us to wait");
+        return false;
+    }
+
+    // Check the host's rate limit before doing any work.
+    if !state.delivery_limits.allow_host(&endpoint_host(&endpoint.url)) {
+        metrics::DROPPED_DELIVERIES.inc();
+        debug!("Delivery dropped by the host rate limiter");
+        return false;
+    }
+
+    send_to_endpoint(user, index, json, ts_seconds, state).await;
+    true
+}
+
+// Sends the post to one of the user's endpoints, and handles it failing.
+#[cfg(feature = "firehose")]
+async fn send_to_endpoint(user: Arc<User>, index: usize, json: String, ts_seconds: i64, state: &HttpDelivery) {
+    let endpoint = &user.endpoints[index];
+
+    // Don't bother sending if the endpoint is known to be down.
+    if !state.circuit_breakers.allow(&endpoint.url, Instant::now()) {
+        metrics::SHORT_CIRCUITED_DELIVERIES.inc();
+        debug!("Delivery skipped since the endpoint circuit is open");
+        return;
+    }
+
+    // Refuse to deliver to internal addresses so the worker can't be used to reach internal services. Hostnames are
+    // left to the delivery client's resolver, which drops internal addresses when it connects.
+    if !state.config.allow_internal_endpoints && ssrf::endpoint_ip_is_internal(&endpoint.url) {
+        warn!("Endpoint is an internal address");
+        endpoint_dead(user, index, EvictionReason::InternalAddress, state).await;
+        return;
+    }
+
+    // Sign and send the message to the user, and tell the observer how it went.
+    let started = Instant::now();
+    let result = delivery::send(&state.http_client, state.config, &user, &endpoint.url, json, ts_seconds).await;
+    let outcome = match &result {
+        Ok(resp) if is_delivery_success(resp.status().as_u16(), &state.config.success_statuses) => {
+            DeliveryOutcome::Success(resp.status().as_u16())
+        }
+        Ok(resp) => DeliveryOutcome::Failure(resp.status().as_u16()),
+        Err(DeliveryError::Http(_)) => DeliveryOutcome::Error,
+        Err(DeliveryError::Oversized(_)) => DeliveryOutcome::Oversized,
+    };
+    state.observer.observe(user.id, &endpoint.url, outcome, started.elapsed());
+    match result {
+        Err(DeliveryError::Oversized(size)) => {
+            // This is our problem rather than the endpoint's, so don't count it against them.
+            warn!(size, "Payload is over the maximum size, skipping the delivery");
+        }
+        Err(DeliveryError::Http(error)) => {
+            // The resolver refuses hostnames which only resolve to internal addresses, so look the hostname up to tell
+            // if that is why the connection failed.
+            let blocked = error.is_connect() && !state.config.allow_internal_endpoints;
+            if blocked && ssrf::endpoint_is_internal(&endpoint.url).await {
+                warn!("Endpoint resolves to an internal address");
+                endpoint_dead(user, index, EvictionReason::InternalAddress, state).await;
+                return;
+            }
+            warn!(%error, "Error sending the webhook");
+            state.circuit_breakers.record_failure(&endpoint.url, Instant::now());
+            server_conn_failed(user, index, state).await;
+        },
         Ok(resp) => {
-            if resp.status().is_success() {
-                // Make sure the user downtime is reset.
-                user.user_downtime_started.store(0, Ordering::Relaxed);
+            if outcome == DeliveryOutcome::Success(resp.status().as_u16()) {
+                // Make sure the endpoint downtime is reset and the circuit is closed.
+                endpoint.downtime_started.store(0, Ordering::Relaxed);
+                endpoint.last_success.store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+                endpoint.retry_after_count.store(0, Ordering::Relaxed);
+                state.circuit_breakers.record_success(&endpoint.url);
+
+                // The receiver can ask us to slow down in the body. This is a request rather than a failure, so it
+                // never counts towards evicting them.
+                if let Some(wait) = delivery::read_ack(resp).await {
+                    debug!(wait_ms = wait.as_millis() as u64, "Webhook acked with a wait");
+                    let now_ms = chrono::Utc::now().timestamp_millis();
+                    endpoint.retry_after_until.store(now_ms + wait.as_millis() as i64, Ordering::Relaxed);
+                }
             } else {
-                // If it is a 429 or 403, evict the user.
+                // A 429 with a Retry-After is the endpoint asking us to slow down, so wait rather than evicting unless
+                // it keeps happening.
                 let status_number = resp.status().as_u16();
-                if status_number == 429 || status_number == 403 {
-                    evict_user(user, tree, dids, pg_pool).await;
+                let retry_after = resp.headers().get(reqwest::header::RETRY_AFTER)
+                    .filter(|_| status_number == 429)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| delivery::parse_retry_after(value, chrono::Utc::now()));
+                if let Some(wait) = retry_after {
+                    warn!(wait_ms = wait.as_millis() as u64, "Webhook asked us to wait");
+                    let now_ms = chrono::Utc::now().timestamp_millis();
+                    if record_retry_after(endpoint, wait, now_ms, state.config.retry_after_eviction_threshold) {
+                        // Past the threshold it is a plain 429, so it is only evicted if 429 is a status we evict on.
+                        if state.config.eviction_statuses.contains(&status_number) {
+                            endpoint_dead(user, index, EvictionReason::Status(status_number), state).await;
+                        } else {
+                            mark_down(user, index, state).await;
+                        }
+                    }
                     return;
                 }
 
-                // If not, figure out how long they have been down.
-                let dt_start = user.user_downtime_started.load(Ordering::Relaxed);
-                if dt_start == 0 {
-                    // Mark this user as down and return.
-                    user.user_downtime_started.store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+                state.circuit_breakers.record_failure(&endpoint.url, Instant::now());
+
+                // If it is a status we evict on (by default 403), the endpoint is dead.
+                warn!(status = status_number, "Webhook returned a non-success status");
+                if state.config.eviction_statuses.contains(&status_number) {
+                    endpoint_dead(user, index, EvictionReason::Status(status_number), state).await;
                     return;
                 }
 
-                // Check if the user has been down for more than 2 hours.
-                let dt_now = chrono::Utc::now().timestamp_millis();
-                if dt_now - dt_start > 2 * 60 * 60 * 1000 {
-                    evict_user(user, tree, dids, pg_pool).await;
-                }
+                // If not, mark it as down.
+                mark_down(user, index, state).await;
             }
         },
     }
 }
 
-// Process a firehose message.
-async fn process(
-    message: Vec<u8>, tree: &'static BulkSearchTree, dids: &'static RwLock<HashMap<String, Arc<User>>>,
-    http_client: reqwest::Client, pg_pool: &'static Pool,
-) {
-    match rsky_firehose::firehose::read(&message) {
-        Ok((_header, body)) => {
-            if let SubscribeRepos::Commit(commit) = body {
-                for op in commit.ops {
-                    if let Some(cid) = op.cid {
-                        if !op.path.starts_with("app.bsky.feed.post/") {
-                            continue;
-                        }
+// The record types in Lexicon.
+#[cfg(feature = "firehose")]
+const HANDLED_RECORD_TYPES: &[&str] = &["app.bsky.feed.post", "app.bsky.feed.repost"];
 
-                        let mut car_reader = Cursor::new(&commit.blocks);
-                        let _ = rsky_firehose::car::read_header(&mut car_reader).unwrap();
-                        let car_blocks = rsky_firehose::car::read_blocks(&mut car_reader).unwrap();
-                        let record_reader = Cursor::new(car_blocks.get(&cid).unwrap());
-                        match serde_cbor::from_reader(record_reader) {
-                            Ok(Lexicon::AppBskyFeedPost(post)) => {
-                                // Get the timestamp in seconds.
-                                let ts_seconds = chrono::Utc::now().timestamp();
-
-                                // Find the search match users and inform them.
-                                let text_lower = post.text.to_lowercase();
-                                let search_match_users = tree.find_all_matches(&text_lower).await;
-                                let uri = format!("at://{}/{}", commit.repo, op.path);
-                                let cid_enc = cid.to_string();
-                                let post_uri_json = serde_json::to_string(&json!({
-                                    "cid": cid_enc,
-                                    "uri": uri,
-                                    "post": post,
-                                })).unwrap();
-                                let mut used_ids = HashSet::new();
-                                for user in search_match_users.into_iter() {
-                                    let json_clone = post_uri_json.clone();
-                                    let client_cpy = http_client.clone();
-                                    used_ids.insert(user.id);
-                                    let tree_ref = tree;
-                                    let dids_ref = dids;
-                                    tokio::spawn(async move {
-                                        inform_user(
-                                            user, json_clone, ts_seconds, client_cpy, tree_ref, dids_ref, pg_pool
-                                        ).await;
-                                    });
-                                }
-
-                                // Find any DID mentions in the post and then check if we have a user for that DID.
-                                for facet in post.facets.as_ref().unwrap_or(&vec![]).into_iter() {
-                                    let features_ref = &facet.features;
-                                    for feature in features_ref.into_iter() {
-                                        if let Features::Mention(mention) = &feature {
-                                            let lock = dids.read().await;
-                                            let user = lock.get(mention.did.as_str()).cloned();
-                                            if let Some(user) = user {
-                                                // Check if the user was already informed about this post and if not, inform them.
-                                                if !used_ids.contains(&user.id) {
-                                                    let json_clone = post_uri_json.clone();
-                                                    let client_cpy = http_client.clone();
-                                                    let tree_ref = tree;
-                                                    let dids_ref = dids;
-                                                    tokio::spawn(async move {
-                                                        inform_user(
-                                                            user, json_clone, ts_seconds, client_cpy,
-                                                            tree_ref, dids_ref, pg_pool
-                                                        ).await;
-                                                    });
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
+// Defines just the type of a record, so we can tell records we don't handle apart from broken ones.
+#[cfg(feature = "firehose")]
+#[derive(Deserialize)]
+struct RecordType {
+    #[serde(rename = "$type")]
+    record_type: String,
+}
+
+// Defines why a record could not be read.
+#[cfg(feature = "firehose")]
+#[derive(Debug)]
+enum RecordError {
+    // The CID is not in the commit blocks.
+    Missing,
+
+    // The record is fine, but it is not a type we handle.
+    Unhandled(String),
+
+    // The record could not be decoded. For records we handle, this can mean Bluesky changed the schema.
+    Malformed(serde_cbor::Error),
+}
+
+// Reads an unsigned varint from the start of the bytes, returning it and the bytes after it. Returns None if the bytes
+// end before the varint does, or if it does not fit in 64 bits.
+#[cfg(feature = "firehose")]
+fn read_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        if i == 9 && byte > 1 {
+            return None;
+        }
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+// Checks that a commit's CAR is a length prefixed header followed by length prefixed blocks, each of which fits in
+// what is left. This catches a CAR cut short before the decoder gets a length it would allocate for but never fill.
+#[cfg(feature = "firehose")]
+fn check_car_framing(mut bytes: &[u8]) -> Result<(), &'static str> {
+    let mut header = true;
+    loop {
+        let Some((length, rest)) = read_varint(bytes) else {
+            return Err(if header { "header length is cut short" } else { "block length is cut short" });
+        };
+        let Some(section) = usize::try_from(length).ok().and_then(|length| rest.get(length..)) else {
+            return Err(if header { "header is cut short" } else { "block is cut short" });
+        };
+        bytes = section;
+        header = false;
+        if bytes.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+// Reads the record for a CID out of the decoded CAR blocks.
+#[cfg(feature = "firehose")]
+fn read_record<C: Eq + Hash + Display>(car_blocks: &HashMap<C, Vec<u8>>, cid: &C) -> Result<Lexicon, RecordError> {
+    let block = car_blocks.get(cid).ok_or(RecordError::Missing)?;
+    // The paths we want hold records we handle, so the block is decoded once. Only a record that fails to decode has
+    // its type read on its own, to tell a type we don't handle apart from a broken record.
+    serde_cbor::from_slice(block).map_err(|error| match serde_cbor::from_slice::<RecordType>(block) {
+        Ok(RecordType { record_type }) if !HANDLED_RECORD_TYPES.contains(&record_type.as_str()) => {
+            RecordError::Unhandled(record_type)
+        }
+        _ => RecordError::Malformed(error),
+    })
+}
+
+// Checks if a op path is a record we handle.
+#[cfg(feature = "firehose")]
+fn is_wanted_path(path: &str) -> bool {
+    path.starts_with("app.bsky.feed.post/") || path.starts_with("app.bsky.feed.repost/")
+}
+
+// Reads the records for the ops in a commit that we handle. The CAR blocks are decoded at most once per commit, and
+// not at all if no op needs them.
+#[cfg(feature = "firehose")]
+fn read_commit_records<'a, C: Eq + Hash + Display + 'a>(
+    ops: impl IntoIterator<Item = (&'a str, Option<&'a C>)>, blocks: &[u8],
+    decode: impl FnOnce(&[u8]) -> Option<HashMap<C, Vec<u8>>>,
+) -> Vec<(&'a str, &'a C, Lexicon)> {
+    let wanted: Vec<(&str, &C)> = ops.into_iter()
+        .filter_map(|(path, cid)| Some((path, cid?)))
+        .filter(|(path, _)| is_wanted_path(path))
+        .collect();
+    if wanted.is_empty() {
+        return vec![];
+    }
+    // Tombstoned records can leave a commit with ops but no blocks, so this is normal rather than a broken commit.
+    if blocks.is_empty() {
+        debug!("Commit has ops but no blocks");
+        return vec![];
+    }
+    let Some(car_blocks) = decode(blocks) else {
+        return vec![];
+    };
+    wanted.into_iter()
+        .filter_map(|(path, cid)| match read_record(&car_blocks, cid) {
+            Ok(record) => Some((path, cid, record)),
+            Err(RecordError::Missing) => {
+                debug!(%cid, path, "CID is not present in the commit blocks, skipping the op");
+                None
+            }
+            Err(RecordError::Unhandled(record_type)) => {
+                trace!(%cid, record_type, "Ignoring a record type we don't handle");
+                None
+            }
+            Err(RecordError::Malformed(error)) => {
+                debug!(%cid, path, %error, "Failed to decode a record");
+                None
+            }
+        })
+        .collect()
+}
+
+// Defines why a user is being told about a post.
+#[cfg(feature = "firehose")]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum MatchReason {
+    Phrase,
+    Mention,
+    Quote,
+    Tag,
+}
+
+// Defines a user to tell about a post and why.
+#[cfg(feature = "firehose")]
+struct Recipient {
+    user: Arc<User>,
+    reasons: Vec<MatchReason>,
+}
+
+// Turns a record into JSON for a payload. Records come straight from the firehose, so a lexicon change could give one
+// which can't be serialized. That is logged and counted rather than panicking, so only the one record is skipped.
+#[cfg(feature = "firehose")]
+fn payload_value<T: Serialize>(uri: &str, record: &T) -> Option<serde_json::Value> {
+    match serde_json::to_value(record) {
+        Ok(value) => Some(value),
+        Err(error) => {
+            metrics::SERIALIZATION_ERRORS.inc();
+            error!(uri, %error, "Failed to serialize a record, skipping it");
+            None
+        }
+    }
+}
+
+// Builds the JSON sent to users about a post, including the reply context if it is a reply. The minimal profile only
+// keeps the parts of the post most receivers use, so they aren't tied to the shape of the whole record. Returns None if
+// the post can't be serialized.
+#[cfg(feature = "firehose")]
+fn post_payload(cid: &str, uri: &str, post: &Post, profile: PayloadProfile) -> Option<serde_json::Value> {
+    let reply = post.reply.as_ref().map(|reply| json!({
+        "root": reply.root.uri,
+        "parent": reply.parent.uri,
+    }));
+    let post = match profile {
+        PayloadProfile::Full => payload_value(uri, post)?,
+        PayloadProfile::Minimal => json!({
+            "text": post.text,
+            "createdAt": post.created_at,
+            "author": at_uri_did(uri),
+        }),
+    };
+    Some(json!({
+        "v": delivery::PAYLOAD_VERSION,
+        "bluehook": true,
+        "cid": cid,
+        "uri": uri,
+        "post": post,
+        "is_reply": reply.is_some(),
+        "reply": reply,
+    }))
+}
+
+// Adds why the user is being told about the post to the payload. This is a single string unless more than one reason
+// applies, in which case it is a list.
+#[cfg(feature = "firehose")]
+fn payload_with_reasons(payload: &serde_json::Value, reasons: &[MatchReason]) -> String {
+    let mut payload = payload.clone();
+    payload["reason"] = match reasons {
+        [reason] => json!(reason),
+        reasons => json!(reasons),
+    };
+    payload.to_string()
+}
+
+// Checks if the user wants to be told about the post. Users can opt out of replies.
+#[cfg(feature = "firehose")]
+fn wants_post(user: &User, post: &Post) -> bool {
+    user.replies || post.reply.is_none()
+}
+
+// Gets the DID of the repo a AT URI points into.
+#[cfg(feature = "firehose")]
+fn at_uri_did(uri: &str) -> Option<&str> {
+    uri.strip_prefix("at://")?.split('/').next().filter(|did| !did.is_empty())
+}
+
+// Checks a DID looks like one, as `did:<method>:<id>`. Facets come straight from the firehose, so anything can be in
+// them.
+#[cfg(feature = "firehose")]
+fn valid_did(did: &str) -> bool {
+    did.strip_prefix("did:")
+        .and_then(|rest| rest.split_once(':'))
+        .is_some_and(|(method, id)| !method.is_empty() && !id.is_empty())
+}
+
+// Gets the DIDs mentioned in the facets of a post, skipping any which aren't valid.
+#[cfg(feature = "firehose")]
+fn mentioned_dids(post: &Post) -> Vec<&str> {
+    post.facets.iter().flatten()
+        .flat_map(|facet| facet.features.iter())
+        .filter_map(|feature| match feature {
+            Features::Mention(mention) => Some(mention.did.as_str()),
+            _ => None,
+        })
+        .filter(|did| {
+            let valid = valid_did(did);
+            if !valid {
+                debug!(did, "Skipping a mention with an invalid DID");
+            }
+            valid
+        })
+        .collect()
+}
+
+// Searches the tree for the users with a phrase in the text, recording how long it took.
+#[cfg(feature = "firehose")]
+async fn find_matches(matcher: &Matcher<'_>, text: &str) -> Vec<Arc<User>> {
+    let started = Instant::now();
+    let matches = matcher.find_all_matches(text).await;
+    metrics::MATCH_DURATIONS.observe(text.len(), started.elapsed());
+    matches
+}
+
+// Finds the users who should be told about a post, either because a phrase matched, they were mentioned, a phrase
+// matched the text of the post it quotes, or a phrase matched one of its tags. Each user is only returned once, with
+// every reason that applied.
+#[cfg(feature = "firehose")]
+async fn find_post_recipients(
+    post: &Post, text: &str, quoted_text: Option<&str>, tags: &str, matcher: &Matcher<'_>,
+    dids: &RwLock<HashMap<String, Arc<User>>>,
+) -> Vec<Recipient> {
+    let mut recipients: Vec<Recipient> = vec![];
+    let mut indexes: HashMap<u64, usize> = HashMap::new();
+    let mut add = |user: Arc<User>, reason: MatchReason| {
+        if !wants_post(&user, post) {
+            return;
+        }
+        match indexes.get(&user.id) {
+            Some(&index) => {
+                let reasons = &mut recipients[index].reasons;
+                if !reasons.contains(&reason) {
+                    reasons.push(reason);
                 }
             }
+            None => {
+                indexes.insert(user.id, recipients.len());
+                recipients.push(Recipient { user, reasons: vec![reason] });
+            }
+        }
+    };
+
+    // Find the search match users. Empty text can never match.
+    let matches = if text.is_empty() { vec![] } else { find_matches(matcher, text).await };
+    for user in matches {
+        add(user, MatchReason::Phrase);
+    }
+    if let Some(quoted_text) = quoted_text.filter(|quoted_text| !quoted_text.is_empty()) {
+        for user in find_matches(matcher, quoted_text).await {
+            add(user, MatchReason::Quote);
         }
-        Err(_) => {}
     }
+    if !tags.is_empty() {
+        for user in find_matches(matcher, tags).await {
+            add(user, MatchReason::Tag);
+        }
+    }
+
+    // Find any DID mentions in the post and then check if we have a user for that DID. The lock is taken once for
+    // every mention, and dropped before anything else is done with the users.
+    let mentioned = mentioned_dids(post);
+    if mentioned.is_empty() {
+        return recipients;
+    }
+    let users: Vec<Arc<User>> = {
+        let lock = dids.read().await;
+        mentioned.into_iter().filter_map(|did| lock.get(did).cloned()).collect()
+    };
+    for user in users {
+        add(user, MatchReason::Mention);
+    }
+    recipients
 }
 
-#[tokio::main]
-async fn main() {
-    // Create the tree.
-    let tree = Box::leak(Box::new(BulkSearchTree::new()));
+// Caps how many users are told about a post. When there are too many, the users are taken from a window which moves
+// along with each capped post, so the same users aren't always the ones left out of viral posts.
+#[cfg(feature = "firehose")]
+fn cap_recipients(mut recipients: Vec<Recipient>, cap: Option<usize>, rotation: &AtomicUsize) -> Vec<Recipient> {
+    // Most posts are under the cap, so leave them alone.
+    let Some(cap) = cap.filter(|&cap| recipients.len() > cap) else {
+        return recipients;
+    };
+    let start = rotation.fetch_add(cap, Ordering::Relaxed) % recipients.len();
+    metrics::TRUNCATED_RECIPIENTS.add((recipients.len() - cap) as u64);
+    recipients.rotate_left(start);
+    recipients.truncate(cap);
+    recipients
+}
 
-    // Create the DID map.
-    let dids = Box::leak(Box::new(RwLock::new(HashMap::new())));
+// Checks if anyone could want a post, without working out who. Most posts match nobody, so this lets them be dropped
+// before the payload is built.
+#[cfg(feature = "firehose")]
+async fn anyone_might_want(
+    post: &Post, text: &str, quoted_text: Option<&str>, tags: &str, matcher: &Matcher<'_>,
+) -> bool {
+    if !mentioned_dids(post).is_empty() || matcher.any_match(text).await || matcher.any_match(tags).await {
+        return true;
+    }
+    match quoted_text {
+        Some(quoted_text) => matcher.any_match(quoted_text).await,
+        None => false,
+    }
+}
 
-    // Create the Postgres pool.
-    let pg_pool = Box::leak(Box::new(init_postgres()));
+// Handles a post, informing any users whose phrases match or who are mentioned.
+#[cfg(feature = "firehose")]
+async fn process_post<D: Delivery>(post: Post, cid: String, uri: String, turn: &Turn, state: &'static WorkerState<D>) {
+    // Get the timestamp in seconds.
+    let ts_seconds = chrono::Utc::now().timestamp();
 
-    // Initialize the data in our local copy.
-    init_data(pg_pool, tree, dids).await;
+    // Skip posts nobody wants. Quotes can only be matched on if we saw the quoted post recently. The tags are searched
+    // on their own, so they are capped on their own too.
+    let mut text = searchable_text(&post, state.config.mask_facets);
+    let mut tags = tags_text(&post);
+    let (bytes, tag_bytes) = (text.len(), tags.len());
+    let truncated_text = truncate_text(&mut text, state.config.max_post_text_bytes);
+    if truncate_text(&mut tags, state.config.max_post_text_bytes) || truncated_text {
+        metrics::TRUNCATED_POSTS.inc();
+        warn!(uri, bytes, tag_bytes, "Only matching the start of a post which is too long");
+    }
+    let text: Arc<str> = text.into();
+    let quoted_text = quoted_uri(&post).and_then(|quoted_uri| state.quote_cache.get(quoted_uri));
+    if !anyone_might_want(&post, &text, quoted_text.as_deref(), &tags, &state.matcher).await {
+        state.quote_cache.insert(uri, text);
+        return;
+    }
 
-    // Create the HTTP server.
-    tokio::spawn(async {
-        init_http_server(pg_pool, tree, dids).await;
-    });
+    // Find the users and inform them.
+    let Some(payload) = post_payload(&cid, &uri, &post, state.config.payload_profile) else {
+        state.quote_cache.insert(uri, text);
+        return;
+    };
+    let recipients = find_post_recipients(
+        &post, &text, quoted_text.as_deref(), &tags, &state.matcher, state.dids,
+    ).await;
+    state.quote_cache.insert(uri, text);
+    let recipients = cap_recipients(recipients, state.config.max_recipients_per_post, &state.recipient_rotation);
+
+    // Users who want their deliveries in order get them in the order the posts came off the firehose, even though the
+    // workers finish them in any order.
+    if recipients.iter().any(|recipient| recipient.user.ordered) {
+        turn.wait().await;
+    }
+    for recipient in recipients {
+        let json = payload_with_reasons(&payload, &recipient.reasons);
+        state.delivery.deliver(recipient.user, json, ts_seconds).await;
+    }
+}
+
+// Handles a repost, informing the author of the reposted post if they are a user.
+#[cfg(feature = "firehose")]
+async fn process_repost<D: Delivery>(
+    repost: Repost, cid: String, uri: String, turn: &Turn, state: &'static WorkerState<D>,
+) {
+    let Some(did) = at_uri_did(&repost.subject.uri) else {
+        return;
+    };
+    let user = state.dids.read().await.get(did).cloned();
+    if let Some(user) = user {
+        let ts_seconds = chrono::Utc::now().timestamp();
+        let Some(repost) = payload_value(&uri, &repost) else {
+            return;
+        };
+        let json = json!({
+            "v": delivery::PAYLOAD_VERSION,
+            "bluehook": true,
+            "cid": cid,
+            "uri": uri,
+            "repost": repost,
+        }).to_string();
+        if user.ordered {
+            turn.wait().await;
+        }
+        state.delivery.deliver(user, json, ts_seconds).await;
+    }
+}
+
+// Checks if a record was created longer ago than the max age. Records with a created_at we can't parse, or one in
+// the future because of clock skew, are never stale since we can't tell how old they really are.
+#[cfg(feature = "firehose")]
+fn is_stale(created_at: &str, now: chrono::DateTime<chrono::Utc>, max_age: Option<Duration>) -> bool {
+    let Some(max_age) = max_age else {
+        return false;
+    };
+    let Ok(created_at) = chrono::DateTime::parse_from_rfc3339(created_at) else {
+        return false;
+    };
+    now.signed_duration_since(created_at).to_std().is_ok_and(|age| age > max_age)
+}
+
+// Works out how far behind the relay a commit is being processed. The relay sets the commit time, so a time from
+// before the epoch is treated as missing, and one in the future as no lag at all.
+#[cfg(feature = "firehose")]
+fn firehose_lag(time: chrono::DateTime<chrono::Utc>, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    if time.timestamp() <= 0 {
+        return None;
+    }
+    Some((now - time).to_std().unwrap_or(Duration::ZERO))
+}
+
+// Process a firehose message.
+#[cfg(feature = "firehose")]
+#[tracing::instrument(skip_all, fields(repo = tracing::field::Empty))]
+async fn process<D: Delivery>(message: Vec<u8>, turn: Turn, state: &'static WorkerState<D>) {
+    let Some(message) = decompress_frame(&message) else {
+        warn!("Failed to decompress a firehose frame");
+        return;
+    };
+    if let Ok((_header, SubscribeRepos::Commit(commit))) = rsky_firehose::firehose::read(&message) {
+        tracing::Span::current().record("repo", commit.repo.as_str());
+        let now = chrono::Utc::now();
+        if let Some(lag) = firehose_lag(commit.time, now) {
+            metrics::FIREHOSE_LAG.set(lag.as_secs_f64());
+        }
+        let ops = commit.ops.iter().map(|op| (op.path.as_str(), op.cid.as_ref()));
+        let records = read_commit_records(ops, &commit.blocks, |blocks| {
+            // Decode the CAR blocks. Skip the commit if it is malformed.
+            if let Err(error) = check_car_framing(blocks) {
+                warn!(error, "Malformed CAR framing in commit");
+                return None;
+            }
+            let mut car_reader = Cursor::new(blocks);
+            if let Err(error) = rsky_firehose::car::read_header(&mut car_reader) {
+                warn!(?error, "Malformed CAR header in commit");
+                return None;
+            }
+            match rsky_firehose::car::read_blocks(&mut car_reader) {
+                Ok(car_blocks) => Some(car_blocks),
+                Err(error) => {
+                    warn!(?error, "Malformed CAR blocks in commit");
+                    None
+                }
+            }
+        });
+        for (path, cid, record) in records {
+            let uri = format!("at://{}/{}", commit.repo, path);
+            process_record(record, cid.to_string(), uri, now, &turn, state).await;
+        }
+    }
+}
+
+// Handles a decoded record from a commit, skipping it if it is too old.
+#[cfg(feature = "firehose")]
+async fn process_record<D: Delivery>(
+    record: Lexicon, cid: String, uri: String, now: chrono::DateTime<chrono::Utc>, turn: &Turn,
+    state: &'static WorkerState<D>,
+) {
+    let created_at = match &record {
+        Lexicon::AppBskyFeedPost(post) => &post.created_at,
+        Lexicon::AppBskyFeedRepost(repost) => &repost.created_at,
+    };
+    if is_stale(created_at, now, state.config.max_post_age) {
+        debug!(uri, created_at, "Skipping a stale record");
+        metrics::STALE_RECORDS.inc();
+        return;
+    }
+    if !state.recent_uris.first_sighting(&uri, Instant::now()) {
+        debug!(uri, "Skipping a record which was already processed");
+        metrics::DUPLICATE_RECORDS.inc();
+        return;
+    }
+    match record {
+        Lexicon::AppBskyFeedPost(post) => process_post(*post, cid, uri, turn, state).await,
+        Lexicon::AppBskyFeedRepost(repost) => process_repost(repost, cid, uri, turn, state).await,
+    }
+}
 
-    // Create the HTTP client.
-    let http_client = Box::leak(Box::new(reqwest::Client::new()));
+// Defines why we stopped reading from a relay.
+#[cfg(feature = "firehose")]
+#[derive(Debug, PartialEq)]
+enum Disconnect {
+    // The relay sent a close frame, with its code and reason if it gave them.
+    Closed(Option<(u16, String)>),
+    Error(String),
+    // The relay did not answer one of our pings in time.
+    PingTimeout,
+    // The connection ended without a close frame.
+    Ended,
+}
 
-    // Connect to the firehose.
+// Reads binary frames from a firehose socket into the queue until the relay disconnects. Sets received once the first
+// frame arrives. With a ping interval, the relay is pinged that often and has the timeout to answer, so a connection
+// which has silently died is noticed.
+#[cfg(feature = "firehose")]
+async fn read_firehose<S>(
+    socket: &mut S, queue: &mpsc::Sender<Vec<u8>>, received: &mut bool, ping_interval: Option<Duration>,
+    ping_timeout: Duration,
+) -> Disconnect
+where
+    S: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Unpin,
+{
+    let mut ping_timer = ping_interval
+        .map(|interval| tokio::time::interval_at(tokio::time::Instant::now() + interval, interval));
+    let mut pong_deadline: Option<tokio::time::Instant> = None;
     loop {
-        match tokio_tungstenite::connect_async(
-            "wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos",
-        )
-        .await
-        {
-            Ok((mut socket, _response)) => {
-                println!("Connected to the firehose. Brrrrr!");
-                while let Some(Ok(Message::Binary(message))) = socket.next().await {
-                    let client_cpy = http_client.clone();
-                    tokio::spawn(async {
-                        process(message, tree, dids, client_cpy, pg_pool).await;
-                    });
+        tokio::select! {
+            // Read anything waiting first, so a pong which arrived while the queue was full still counts.
+            biased;
+
+            message = socket.next() => match message {
+                Some(Ok(Message::Binary(message))) => {
+                    *received = true;
+                    if queue.send(message).await.is_err() {
+                        unreachable!("the firehose workers never stop");
+                    }
+                }
+                Some(Ok(Message::Ping(_))) => {
+                    // Tungstenite queues the pong when it reads the ping, but only sends it with the next write or
+                    // flush. Flush now so a relay waiting on it doesn't think we're gone while the queue is full.
+                    if let Err(error) = socket.flush().await {
+                        return Disconnect::Error(error.to_string());
+                    }
+                }
+                Some(Ok(Message::Pong(_))) => pong_deadline = None,
+                Some(Ok(Message::Close(frame))) => {
+                    return Disconnect::Closed(frame.map(|frame| (u16::from(frame.code), frame.reason.into_owned())));
                 }
+                Some(Ok(message)) => trace!(?message, "Ignoring a firehose message which is not binary"),
+                Some(Err(error)) => return Disconnect::Error(error.to_string()),
+                None => return Disconnect::Ended,
+            },
+            _ = async { tokio::time::sleep_until(pong_deadline.unwrap()).await }, if pong_deadline.is_some() => {
+                return Disconnect::PingTimeout;
             }
+            _ = async { ping_timer.as_mut().unwrap().tick().await }, if ping_timer.is_some() => {
+                // Only have one ping out at a time, so the deadline is always for the oldest one.
+                if pong_deadline.is_none() {
+                    if let Err(error) = socket.send(Message::Ping(vec![])).await {
+                        return Disconnect::Error(error.to_string());
+                    }
+                    pong_deadline = Some(tokio::time::Instant::now() + ping_timeout);
+                }
+            }
+        }
+    }
+}
+
+// Sets up logging. Filtered by RUST_LOG, and set LOG_FORMAT=json for machine readable output.
+fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if std::env::var("LOG_FORMAT").is_ok_and(|format| format == "json") {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+// Builds the runtime the firehose, HTTP server and Postgres run on. The thread counts default to the CPU count, which
+// is often wrong in containers with a CPU quota.
+fn build_runtime(config: &Config) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = config.worker_threads {
+        builder.worker_threads(threads);
+    }
+    if let Some(threads) = config.max_blocking_threads {
+        builder.max_blocking_threads(threads);
+    }
+    builder.build()
+}
+
+fn main() {
+    // The keytool only works out a public key, so it runs without any config or logging.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|command| command == "keytool") {
+        match keytool::run(&args[1..]) {
+            Ok(public_key) => println!("{public_key}"),
             Err(error) => {
-                eprintln!("Error connecting to the firehose. Waiting to reconnect: {error:?}");
-                tokio::time::sleep(Duration::from_millis(500)).await;
-                continue;
+                eprintln!("{error}");
+                std::process::exit(1);
             }
         }
+        return;
+    }
+
+    // Setup logging before anything else.
+    init_logging();
+
+    // Load the config and make sure it is all valid.
+    let config = match Config::from_env() {
+        Ok(config) => Box::leak(Box::new(config)),
+        Err(error) => {
+            error!("{error}");
+            std::process::exit(1);
+        }
+    };
+
+    // Start the runtime and run the worker on it.
+    let runtime = match build_runtime(config) {
+        Ok(runtime) => runtime,
+        Err(error) => {
+            error!(%error, "Failed to start the runtime");
+            std::process::exit(1);
+        }
+    };
+    #[cfg(feature = "firehose")]
+    runtime.block_on(run(config, &MetricsObserver));
+    #[cfg(not(feature = "firehose"))]
+    runtime.block_on(run(config));
+}
+
+// Runs the worker until it is stopped, telling the observer about every delivery attempt.
+async fn run(config: &'static Config, #[cfg(feature = "firehose")] observer: &'static dyn DeliveryObserver) {
+    // Create the tree.
+    let tree = Box::leak(Box::new(BulkSearchTree::new_with_backend(config.match_options, config.match_backend)));
+
+    // Create the DID map.
+    let dids = Box::leak(Box::new(RwLock::new(HashMap::new())));
+
+    // Create the private key map.
+    let keys = Box::leak(Box::new(RwLock::new(HashMap::new())));
+
+    // Create the store. In memory, users start from MEMORY_STORE_USERS and only live as long as the worker.
+    let store: &'static dyn UserStore = match config.store {
+        #[cfg(feature = "postgres")]
+        StoreKind::Postgres => {
+            let pg_pool = init_postgres(config);
+            if let Err(error) = warm_up(config, &pg_pool).await {
+                error!(%error, "Failed to connect to Postgres");
+                std::process::exit(1);
+            }
+            Box::leak(Box::new(PgStore::new(pg_pool)))
+        }
+        StoreKind::Memory => Box::leak(Box::new(store::MemoryStore::seeded(&config.memory_store_users))),
+    };
+
+    // Initialize the data in our local copy.
+    if let Err(error) = init_data(config, store, tree, dids, keys).await {
+        error!(%error, "Failed to load the initial data");
+        std::process::exit(1);
+    }
+
+    // Create the runtime deliveries run on, so they can't starve the firehose processing.
+    #[cfg(feature = "firehose")]
+    let delivery_pool = match DeliveryPool::new(config.delivery_threads, config.max_in_flight_deliveries) {
+        Ok(pool) => pool,
+        Err(error) => {
+            error!(%error, "Failed to start the delivery runtime");
+            std::process::exit(1);
+        }
+    };
+
+    // Create the state used to send deliveries and evict users, which the firehose and the HTTP server share.
+    let delivery: &'static HttpDelivery = Box::leak(Box::new(HttpDelivery {
+        config, tree, dids, keys, store,
+        http_client: delivery::new_client(config),
+        #[cfg(feature = "firehose")]
+        delivery_limits: DeliveryLimits::new(
+            config.user_rate_limit.map(|limit| RateLimiter::new(limit.per_second, limit.burst)),
+            config.host_rate_limit.map(|limit| RateLimiter::new(limit.per_second, limit.burst)),
+        ),
+        #[cfg(feature = "firehose")]
+        circuit_breakers: CircuitBreakers::new(config.circuit_breaker_threshold, config.circuit_breaker_cooldown),
+        #[cfg(feature = "firehose")]
+        delivery_pool,
+        #[cfg(feature = "firehose")]
+        observer,
+    }));
+
+    // Create the flag for whether the firehose is connected, which the HTTP server checks before taking changes.
+    #[cfg(any(feature = "http", feature = "firehose"))]
+    let firehose_connected = Box::leak(Box::new(AtomicBool::new(false)));
+
+    // Create the HTTP server. Without the API nothing can be loaded or changed, so the worker stops if it can't be
+    // served rather than carrying on without it.
+    #[cfg(feature = "http")]
+    {
+        let server = match init_http_server(delivery, firehose_connected) {
+            Ok(server) => server,
+            Err(error) => {
+                error!(%error, "Failed to start the HTTP server");
+                std::process::exit(1);
+            }
+        };
+        tokio::spawn(async {
+            if let Err(error) = server.await {
+                error!(%error, "The HTTP server stopped");
+                std::process::exit(1);
+            }
+        });
+    }
+
+    // Create the matcher, which is either the tree itself or the thread that owns searching it.
+    #[cfg(feature = "firehose")]
+    let matcher = match Matcher::new(config.match_mode, tree, || metrics::MATCH_ACTOR_FAILURES.inc()) {
+        Ok(matcher) => matcher,
+        Err(error) => {
+            error!(%error, "Failed to start the matcher");
+            std::process::exit(1);
+        }
+    };
+
+    // Create the state used to process the firehose.
+    #[cfg(feature = "firehose")]
+    let state: &'static WorkerState = Box::leak(Box::new(WorkerState {
+        config, matcher, dids,
+        recipient_rotation: AtomicUsize::new(0),
+        quote_cache: QuoteCache::new(config.quote_cache_size),
+        recent_uris: RecentUris::new(config.dedupe_cache_size, config.dedupe_window),
+        delivery,
+    }));
+
+    // Probe the paused users, checking for any which are due at least once a minute.
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.pause_probe_interval.min(Duration::from_secs(60)));
+        loop {
+            interval.tick().await;
+            probe_paused(delivery).await;
+        }
+    });
+
+    // Read the firehose until the worker is stopped. Without it there is nothing to read, so just keep serving.
+    #[cfg(feature = "firehose")]
+    read_relays(config, state, firehose_connected).await;
+    #[cfg(not(feature = "firehose"))]
+    std::future::pending::<()>().await;
+}
+
+// Reads the firehose into the workers which process it until the worker is stopped.
+#[cfg(feature = "firehose")]
+async fn read_relays(config: &'static Config, state: &'static WorkerState, connected: &AtomicBool) {
+    // Start the workers which process the firehose messages. Reading waits while the queue is full.
+    let queue = work_queue::spawn_workers(config.firehose_workers, config.firehose_queue_depth, |message, turn| {
+        process(message, turn, state)
+    });
+
+    // Connect to the firehose, moving to the next relay if the current one keeps failing.
+    let mut relays = RelayRotation::new(config.firehose_relays.clone(), config.firehose_relay_max_failures);
+    let mut backoff = ReconnectBackoff::new(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+    loop {
+        let relay = relays.current().to_string();
+        match tokio_tungstenite::connect_async(subscribe_url(&relay)).await {
+            Ok((mut socket, _response)) => {
+                info!(relay, "Connected to the firehose. Brrrrr!");
+                connected.store(true, Ordering::Relaxed);
+                let connected_at = Instant::now();
+                let mut received = false;
+                let disconnect = read_firehose(
+                    &mut socket, &queue, &mut received, config.firehose_ping_interval, config.firehose_ping_timeout,
+                ).await;
+                match disconnect {
+                    Disconnect::Closed(frame) => {
+                        metrics::FIREHOSE_CLOSES.inc();
+                        let (code, reason) = frame.unzip();
+                        warn!(relay, code, reason, "The relay closed the firehose. Reconnecting");
+                    }
+                    Disconnect::Error(error) => {
+                        metrics::FIREHOSE_ERRORS.inc();
+                        warn!(relay, error, "Error reading the firehose. Reconnecting");
+                    }
+                    Disconnect::PingTimeout => {
+                        metrics::FIREHOSE_ERRORS.inc();
+                        warn!(relay, "The relay did not answer a ping. Reconnecting");
+                    }
+                    Disconnect::Ended => warn!(relay, "Disconnected from the firehose. Reconnecting"),
+                }
+                connected.store(false, Ordering::Relaxed);
+                metrics::FIREHOSE_RECONNECTS.inc();
+                backoff.record_connection(connected_at.elapsed());
+
+                // A relay which hangs up before sending anything counts as a failure.
+                if received {
+                    relays.record_success();
+                } else if relays.record_failure() {
+                    warn!(from = relay, to = relays.current(), "Switching firehose relay");
+                }
+            }
+            Err(error) => {
+                error!(relay, %error, "Error connecting to the firehose. Waiting to reconnect");
+                metrics::FIREHOSE_RECONNECTS.inc();
+                if relays.record_failure() {
+                    warn!(from = relay, to = relays.current(), "Switching firehose relay");
+                }
+            }
+        }
+
+        // Wait before reconnecting, however the connection ended, so a relay which keeps hanging up isn't hammered.
+        tokio::time::sleep(backoff.next_delay()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bulk_search_tree::test_key;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_read_record_post() {
+        let post = serde_cbor::to_vec(&json!({
+            "$type": "app.bsky.feed.post",
+            "text": "hello world",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+        })).unwrap();
+        let blocks = HashMap::from([("a".to_string(), post)]);
+        match read_record(&blocks, &"a".to_string()) {
+            Ok(Lexicon::AppBskyFeedPost(post)) => assert_eq!(post.text, "hello world"),
+            _ => panic!("expected a post"),
+        }
+    }
+
+    fn reply_post() -> Post {
+        serde_json::from_value(json!({
+            "text": "hello world",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "reply": {
+                "root": {"uri": "at://did:plc:root/app.bsky.feed.post/1", "cid": "root"},
+                "parent": {"uri": "at://did:plc:parent/app.bsky.feed.post/2", "cid": "parent"},
+            },
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_read_record_repost() {
+        let repost = serde_cbor::to_vec(&json!({
+            "$type": "app.bsky.feed.repost",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "subject": {"uri": "at://did:plc:author/app.bsky.feed.post/1", "cid": "a"},
+        })).unwrap();
+        let blocks = HashMap::from([("a".to_string(), repost)]);
+        match read_record(&blocks, &"a".to_string()) {
+            Ok(Lexicon::AppBskyFeedRepost(repost)) => {
+                assert_eq!(at_uri_did(&repost.subject.uri), Some("did:plc:author"));
+            }
+            _ => panic!("expected a repost"),
+        }
+    }
+
+    #[test]
+    fn test_reply_payload() {
+        let payload = post_payload("c", "at://x/app.bsky.feed.post/3", &reply_post(), PayloadProfile::Full).unwrap();
+        assert_eq!(payload["is_reply"], true);
+        assert_eq!(payload["reply"]["root"], "at://did:plc:root/app.bsky.feed.post/1");
+        assert_eq!(payload["reply"]["parent"], "at://did:plc:parent/app.bsky.feed.post/2");
+
+        let mut post = reply_post();
+        post.reply = None;
+        let payload = post_payload("c", "at://x/app.bsky.feed.post/3", &post, PayloadProfile::Full).unwrap();
+        assert_eq!(payload["is_reply"], false);
+        assert!(payload["reply"].is_null());
+    }
+
+    #[test]
+    fn test_replies_off_filter() {
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        let reply = reply_post();
+        let mut top_level = reply_post();
+        top_level.reply = None;
+        assert!(wants_post(&user, &reply));
+
+        user.replies = false;
+        assert!(!wants_post(&user, &reply));
+        assert!(wants_post(&user, &top_level));
+    }
+
+    #[tokio::test]
+    async fn test_alt_text_matches() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "look at this",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "embed": {
+                "$type": "app.bsky.embed.images",
+                "images": [{
+                    "alt": "A Red Panda eating bamboo",
+                    "image": {
+                        "$type": "blob",
+                        "ref": {"$link": "bafkreibme22gw2h7y2h7tg2fhqotaqjucnbc24deqo72b6mkl2egezxhvy"},
+                        "mimeType": "image/jpeg",
+                        "size": 1024,
+                    },
+                }],
+            },
+        })).unwrap();
+        let tree = BulkSearchTree::new();
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        user.set_phrases(vec!["red panda".to_string()]);
+        let user = Arc::new(user);
+        tree.add_item("red panda", user.clone()).await;
+
+        let matches = tree.find_all_matches(&searchable_text(&post, false)).await;
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].id, user.id);
+    }
+
+    #[tokio::test]
+    async fn test_fields_do_not_match_across_separator() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "my red",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "embed": {
+                "$type": "app.bsky.embed.external",
+                "external": {"uri": "https://example.com", "title": "panda", "description": "Link Description"},
+            },
+        })).unwrap();
+        assert_eq!(searchable_text(&post, false), "my red\0panda\0Link Description");
+
+        let tree = BulkSearchTree::new();
+        let user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        tree.add_item("red panda", user.clone()).await;
+        tree.add_item("redpanda", user.clone()).await;
+        assert!(tree.find_all_matches(&searchable_text(&post, false)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tag_facet_matches() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "Learning Rust today",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [
+                {
+                    "index": {"byteStart": 9, "byteEnd": 13},
+                    "features": [{"$type": "app.bsky.richtext.facet#tag", "tag": "RustLang"}],
+                },
+                {
+                    "index": {"byteStart": 0, "byteEnd": 8},
+                    "features": [{"$type": "app.bsky.richtext.facet#link", "uri": "https://doc.rust-lang.org/book/"}],
+                },
+            ],
+        })).unwrap();
+        let tree = BulkSearchTree::new();
+        let tag_user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        let link_user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("bb")).unwrap());
+        tree.add_item("#rustlang", tag_user.clone()).await;
+        tree.add_item("doc.rust-lang.org", link_user.clone()).await;
+        tree.add_item("rust", tag_user.clone()).await;
+
+        let matches = tree.find_all_matches(&searchable_text(&post, false)).await;
+        let mut ids: Vec<u64> = matches.iter().map(|user| user.id).collect();
+        ids.sort();
+        let mut expected = vec![tag_user.id, link_user.id];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    fn mention(did: &str) -> serde_json::Value {
+        json!({
+            "index": {"byteStart": 0, "byteEnd": 5},
+            "features": [{"$type": "app.bsky.richtext.facet#mention", "did": did}],
+        })
+    }
+
+    // Finds the users who should be told about a post, searching all of its text and tags.
+    async fn post_recipients(
+        post: &Post, quoted_text: Option<&str>, matcher: &Matcher<'_>, dids: &RwLock<HashMap<String, Arc<User>>>,
+    ) -> Vec<Recipient> {
+        find_post_recipients(post, &searchable_text(post, false), quoted_text, &tags_text(post), matcher, dids).await
+    }
+
+    #[tokio::test]
+    async fn test_repeated_mention_delivers_once() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "@jake hey @jake",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [mention("did:plc:jake"), mention("did:plc:jake")],
+        })).unwrap();
+        let tree = BulkSearchTree::new();
+        let user = Arc::new(User::new(
+            Some("did:plc:jake".to_string()), "https://example.com".to_string(), test_key("aa"),
+        ).unwrap());
+        let dids = RwLock::new(HashMap::from([("did:plc:jake".to_string(), user.clone())]));
+
+        let recipients = post_recipients(&post, None, &Matcher::shared(&tree), &dids).await;
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].user.id, user.id);
+        assert_eq!(recipients[0].reasons, vec![MatchReason::Mention]);
+    }
+
+    #[tokio::test]
+    async fn test_previous_did_mentions() {
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let mut user = User::new(
+            Some("did:plc:jake".to_string()), "https://example.com".to_string(), test_key("aa"),
+        ).unwrap();
+        user.previous_dids = vec!["did:web:jake.example".to_string()];
+        store::insert_user(user, &tree, &dids, &RwLock::new(HashMap::new())).await;
+
+        // A mention of a DID the user had before still goes to them.
+        let post: Post = serde_json::from_value(json!({
+            "text": "@jake hey",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [mention("did:web:jake.example")],
+        })).unwrap();
+        let recipients = post_recipients(&post, None, &Matcher::shared(&tree), &dids).await;
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].user.did.as_deref(), Some("did:plc:jake"));
+        assert_eq!(recipients[0].reasons, vec![MatchReason::Mention]);
+    }
+
+    #[tokio::test]
+    async fn test_many_mentions() {
+        let dids: Vec<String> = (0..50).map(|i| format!("did:plc:user{i}")).collect();
+        let mut facets: Vec<serde_json::Value> = dids.iter().map(|did| mention(did)).collect();
+        facets.push(json!({
+            "index": {"byteStart": 0, "byteEnd": 5},
+            "features": [
+                {"$type": "app.bsky.richtext.facet#link", "uri": "https://example.com"},
+                {"$type": "app.bsky.richtext.facet#mention", "did": "did:plc:user1"},
+                {"$type": "app.bsky.richtext.facet#mention", "did": "did:plc:nobody"},
+            ],
+        }));
+        let post: Post = serde_json::from_value(json!({
+            "text": "hello everyone",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": facets,
+        })).unwrap();
+
+        // Only every other mentioned DID is a user.
+        let tree = BulkSearchTree::new();
+        let users: HashMap<String, Arc<User>> = dids.iter().enumerate().step_by(2).map(|(i, did)| {
+            let user = User::new(Some(did.clone()), "https://example.com".to_string(), test_key(&format!("{i:02x}")));
+            (did.clone(), Arc::new(user.unwrap()))
+        }).collect();
+        let expected: HashSet<u64> = users.values().map(|user| user.id).collect();
+        let dids = RwLock::new(users);
+
+        let recipients = post_recipients(&post, None, &Matcher::shared(&tree), &dids).await;
+        assert_eq!(recipients.len(), 25);
+        assert_eq!(recipients.iter().map(|recipient| recipient.user.id).collect::<HashSet<_>>(), expected);
+        assert!(recipients.iter().all(|recipient| recipient.reasons == vec![MatchReason::Mention]));
+
+        // The lock isn't held once the recipients are found.
+        assert!(dids.try_write().is_ok());
+    }
+
+    #[test]
+    fn test_valid_did() {
+        assert!(valid_did("did:plc:jake"));
+        assert!(valid_did("did:web:example.com"));
+        assert!(!valid_did(""));
+        assert!(!valid_did("did:"));
+        assert!(!valid_did("did:plc"));
+        assert!(!valid_did("did:plc:"));
+        assert!(!valid_did("did::jake"));
+        assert!(!valid_did("jake"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_mentions_are_skipped() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "@jake @someone",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [mention(""), mention("plc:jake"), mention("did:plc:jake")],
+        })).unwrap();
+        let tree = BulkSearchTree::new();
+        let user = Arc::new(User::new(
+            Some("did:plc:jake".to_string()), "https://example.com".to_string(), test_key("aa"),
+        ).unwrap());
+        let dids = RwLock::new(HashMap::from([
+            ("did:plc:jake".to_string(), user.clone()),
+            (String::new(), user.clone()),
+        ]));
+        assert_eq!(mentioned_dids(&post), vec!["did:plc:jake"]);
+        let recipients = post_recipients(&post, None, &Matcher::shared(&tree), &dids).await;
+        assert_eq!(recipients.len(), 1);
+
+        // A post whose only mention is invalid isn't wanted by anyone.
+        let post: Post = serde_json::from_value(json!({
+            "text": "@nobody",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [mention("")],
+        })).unwrap();
+        assert!(mentioned_dids(&post).is_empty());
+        let text = searchable_text(&post, false);
+        assert!(!anyone_might_want(&post, &text, None, &tags_text(&post), &Matcher::shared(&tree)).await);
+        assert!(post_recipients(&post, None, &Matcher::shared(&tree), &dids).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_match_reasons() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "@jake red pandas are great",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [mention("did:plc:jake")],
+        })).unwrap();
+        let tree = BulkSearchTree::new();
+        let phrase_user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        let mention_user = Arc::new(User::new(
+            Some("did:plc:jake".to_string()), "https://example.com".to_string(), test_key("bb"),
+        ).unwrap());
+        tree.add_item("red panda", phrase_user.clone()).await;
+        let dids = RwLock::new(HashMap::from([("did:plc:jake".to_string(), mention_user.clone())]));
+
+        let recipients = post_recipients(&post, None, &Matcher::shared(&tree), &dids).await;
+        assert_eq!(recipients.len(), 2);
+        let payload = post_payload("c", "at://x/app.bsky.feed.post/3", &post, PayloadProfile::Full).unwrap();
+        for recipient in recipients {
+            let json: serde_json::Value = serde_json::from_str(
+                &payload_with_reasons(&payload, &recipient.reasons),
+            ).unwrap();
+            if recipient.user.id == phrase_user.id {
+                assert_eq!(json["reason"], "phrase");
+            } else {
+                assert_eq!(json["reason"], "mention");
+            }
+        }
+
+        // Both a phrase and a mention only gives one recipient with both reasons.
+        tree.add_item("great", mention_user.clone()).await;
+        let recipients = post_recipients(&post, None, &Matcher::shared(&tree), &dids).await;
+        let recipient = recipients.iter().find(|recipient| recipient.user.id == mention_user.id).unwrap();
+        assert_eq!(recipients.len(), 2);
+        let json: serde_json::Value = serde_json::from_str(
+            &payload_with_reasons(&payload, &recipient.reasons),
+        ).unwrap();
+        assert_eq!(json["reason"], json!(["phrase", "mention"]));
+    }
+
+    #[tokio::test]
+    async fn test_quote_matches() {
+        let original: Post = serde_json::from_value(json!({
+            "text": "look at this red panda",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+        })).unwrap();
+        let quote: Post = serde_json::from_value(json!({
+            "text": "so cute",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "embed": {
+                "$type": "app.bsky.embed.record",
+                "record": {"uri": "at://did:plc:author/app.bsky.feed.post/1", "cid": "a"},
+            },
+        })).unwrap();
+        let tree = BulkSearchTree::new();
+        let user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        tree.add_item("red panda", user.clone()).await;
+        let dids = RwLock::new(HashMap::new());
+
+        // Without the quoted text, the quote can't match.
+        let quoted_uri = quoted_uri(&quote).unwrap();
+        let cache = QuoteCache::new(10);
+        assert_eq!(cache.get(quoted_uri), None);
+        let recipients = post_recipients(&quote, None, &Matcher::shared(&tree), &dids).await;
+        assert!(recipients.is_empty());
+
+        // Once the quoted post has been seen, it matches as a quote.
+        cache.insert(quoted_uri.to_string(), searchable_text(&original, false).into());
+        let quoted_text = cache.get(quoted_uri);
+        let recipients = post_recipients(&quote, quoted_text.as_deref(), &Matcher::shared(&tree), &dids).await;
+        assert_eq!(recipients.len(), 1);
+        let payload = post_payload("c", "at://x/app.bsky.feed.post/3", &quote, PayloadProfile::Full).unwrap();
+        let json: serde_json::Value = serde_json::from_str(
+            &payload_with_reasons(&payload, &recipients[0].reasons),
+        ).unwrap();
+        assert_eq!(json["reason"], "quote");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_posts_are_truncated() {
+        let tree = BulkSearchTree::new();
+        let user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        tree.add_item("red panda", user.clone()).await;
+        tree.add_item("bamboo", user).await;
+        let matcher = Matcher::shared(&tree);
+
+        // Only the first 64 KiB of a 10 MiB post are searched, so the phrase at the end is never reached.
+        let post: Post = serde_json::from_value(json!({
+            "text": format!("bamboo {} red panda", "a".repeat(10 << 20)),
+            "createdAt": "2024-11-20T00:00:00.000Z",
+        })).unwrap();
+        let mut text = searchable_text(&post, false);
+        assert!(truncate_text(&mut text, Some(64 << 10)));
+        assert_eq!(text.len(), 64 << 10);
+        let matches = matcher.find_all_matches(&text).await;
+        let phrases = ["bamboo".to_string(), "red panda".to_string()];
+        assert_eq!(tree.match_counts(&matches[0], phrases).await, HashMap::from([
+            ("bamboo".to_string(), 1),
+            ("red panda".to_string(), 0),
+        ]));
+
+        // Text under the max, or with no max, is left alone.
+        assert!(!truncate_text(&mut text, Some(64 << 10)));
+        assert!(!truncate_text(&mut text, None));
+        assert_eq!(text.len(), 64 << 10);
+
+        // The cut never splits a character.
+        let mut text = "red 🐼".to_string();
+        assert!(truncate_text(&mut text, Some(6)));
+        assert_eq!(text, "red ");
+    }
+
+    #[tokio::test]
+    async fn test_tag_matches() {
+        let tree = BulkSearchTree::new();
+        let user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        tree.add_item("red panda", user.clone()).await;
+        let matcher = Matcher::shared(&tree);
+        let dids = RwLock::new(HashMap::new());
+
+        // A post which only has the phrase in its tags matches as a tag.
+        let post: Post = serde_json::from_value(json!({
+            "text": "so cute",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "tags": ["", "red panda"],
+        })).unwrap();
+        let text = searchable_text(&post, false);
+        assert!(anyone_might_want(&post, &text, None, &tags_text(&post), &matcher).await);
+        let recipients = find_post_recipients(&post, &text, None, &tags_text(&post), &matcher, &dids).await;
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].reasons, vec![MatchReason::Tag]);
+        let payload = post_payload("c", "at://x/app.bsky.feed.post/3", &post, PayloadProfile::Full).unwrap();
+        let json: serde_json::Value = serde_json::from_str(
+            &payload_with_reasons(&payload, &recipients[0].reasons),
+        ).unwrap();
+        assert_eq!(json["reason"], "tag");
+
+        // When the text matches too, the user is still only told once.
+        let post: Post = serde_json::from_value(json!({
+            "text": "a red panda",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "tags": ["red panda"],
+        })).unwrap();
+        let recipients = post_recipients(&post, None, &matcher, &dids).await;
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].reasons, vec![MatchReason::Phrase, MatchReason::Tag]);
+
+        // Tags can't match across each other.
+        let post: Post = serde_json::from_value(json!({
+            "text": "so cute",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "tags": ["red", "panda"],
+        })).unwrap();
+        let text = searchable_text(&post, false);
+        assert!(!anyone_might_want(&post, &text, None, &tags_text(&post), &matcher).await);
+        assert!(find_post_recipients(&post, &text, None, &tags_text(&post), &matcher, &dids).await.is_empty());
+    }
+
+    #[test]
+    fn test_unserializable_records_are_skipped() {
+        // JSON object keys have to be strings, so a map keyed by pairs can't be serialized.
+        let record: HashMap<(u8, u8), u8> = HashMap::from([((1, 2), 3)]);
+        let before = metrics::SERIALIZATION_ERRORS.get();
+        assert_eq!(payload_value("at://x/app.bsky.feed.post/1", &record), None);
+        assert!(metrics::SERIALIZATION_ERRORS.get() > before);
+
+        let record: HashMap<String, u8> = HashMap::from([("likes".to_string(), 3)]);
+        assert_eq!(payload_value("at://x/app.bsky.feed.post/1", &record), Some(json!({"likes": 3})));
+    }
+
+    #[test]
+    fn test_payload_version() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "so cute",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+        })).unwrap();
+        let uri = "at://did:plc:author/app.bsky.feed.post/1";
+        for profile in [PayloadProfile::Full, PayloadProfile::Minimal] {
+            let payload = post_payload("c", uri, &post, profile).unwrap();
+            assert_eq!(payload["v"], 1);
+            assert_eq!(payload["bluehook"], true);
+        }
+        let test: serde_json::Value = serde_json::from_str(&delivery::test_payload()).unwrap();
+        assert_eq!(test["v"], delivery::PAYLOAD_VERSION);
+
+        // Receivers written before the version was added still read the payload, since they ignore fields they don't
+        // know.
+        #[derive(Deserialize)]
+        struct OldPayload {
+            uri: String,
+            reason: String,
+            is_reply: bool,
+        }
+        let payload = post_payload("c", uri, &post, PayloadProfile::Full).unwrap();
+        let old: OldPayload = serde_json::from_str(&payload_with_reasons(&payload, &[MatchReason::Phrase])).unwrap();
+        assert_eq!(old.uri, uri);
+        assert_eq!(old.reason, "phrase");
+        assert!(!old.is_reply);
+    }
+
+    #[test]
+    fn test_minimal_payload() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "so cute",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "langs": ["en"],
+            "embed": {
+                "$type": "app.bsky.embed.record",
+                "record": {"uri": "at://did:plc:author/app.bsky.feed.post/1", "cid": "a"},
+            },
+        })).unwrap();
+        let uri = "at://did:plc:quoter/app.bsky.feed.post/3";
+        let full = post_payload("c", uri, &post, PayloadProfile::Full).unwrap();
+        assert!(full["post"]["embed"].is_object());
+
+        let minimal = post_payload("c", uri, &post, PayloadProfile::Minimal).unwrap();
+        assert_eq!(minimal["uri"], uri);
+        assert_eq!(minimal["post"], json!({
+            "text": "so cute",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "author": "did:plc:quoter",
+        }));
+        assert_eq!(minimal["is_reply"], false);
+
+        // The signature is over exactly the body that is sent, whichever profile made it.
+        let json = payload_with_reasons(&minimal, &[MatchReason::Phrase]);
+        let user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        let signature = worker::signature::sign(&user.private_key, 1_700_000_000, &json);
+        let signature = ed25519_dalek::Signature::from_slice(&hex::decode(signature).unwrap()).unwrap();
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&user.private_key).verifying_key();
+        let signed = format!("1700000000{json}");
+        assert!(ed25519_dalek::Verifier::verify(&public_key, signed.as_bytes(), &signature).is_ok());
+        assert!(!json.contains("embed"));
+    }
+
+    // Collects the payloads which would have been delivered, by user ID.
+    #[derive(Default)]
+    struct MockDelivery {
+        delivered: std::sync::Mutex<Vec<(u64, serde_json::Value)>>,
+    }
+
+    impl Delivery for MockDelivery {
+        async fn deliver(&'static self, user: Arc<User>, json: String, _ts_seconds: i64) {
+            self.delivered.lock().unwrap().push((user.id, serde_json::from_str(&json).unwrap()));
+        }
+    }
+
+    // Creates a leaked worker state which delivers to a mock.
+    fn mock_state(
+        config: Config, tree: BulkSearchTree, dids: HashMap<String, Arc<User>>,
+    ) -> &'static WorkerState<MockDelivery> {
+        Box::leak(Box::new(WorkerState {
+            config: Box::leak(Box::new(config)),
+            matcher: Matcher::shared(Box::leak(Box::new(tree))),
+            dids: Box::leak(Box::new(RwLock::new(dids))),
+            recipient_rotation: AtomicUsize::new(0),
+            quote_cache: QuoteCache::new(10),
+            recent_uris: RecentUris::new(10, Duration::from_secs(60)),
+            delivery: Box::leak(Box::default()),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_delivers_to_matched_users() {
+        let tree = BulkSearchTree::new();
+        let phrase_user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        let mention_user = Arc::new(User::new(
+            Some("did:plc:jake".to_string()), "https://example.com".to_string(), test_key("bb"),
+        ).unwrap());
+        let other_user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("cc")).unwrap());
+        tree.add_item("red panda", phrase_user.clone()).await;
+        tree.add_item("otters", other_user.clone()).await;
+        let dids = HashMap::from([("did:plc:jake".to_string(), mention_user.clone())]);
+        let state = mock_state(Config::for_tests(&[("MAX_POST_AGE_SECONDS", "3600")]), tree, dids);
+        let now = chrono::DateTime::parse_from_rfc3339("2024-11-20T00:30:00Z").unwrap().to_utc();
+
+        // A post goes to the users it matches and nobody else.
+        let post: Post = serde_json::from_value(json!({
+            "text": "@jake red pandas are great",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [mention("did:plc:jake")],
+        })).unwrap();
+        let uri = "at://did:plc:author/app.bsky.feed.post/1";
+        process_record(
+            Lexicon::AppBskyFeedPost(Box::new(post)), "c".to_string(), uri.to_string(), now, &Turn::alone(), state,
+        ).await;
+        let mut delivered = std::mem::take(&mut *state.delivery.delivered.lock().unwrap());
+        delivered.sort_by_key(|(user_id, _)| *user_id != phrase_user.id);
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(delivered[0].0, phrase_user.id);
+        assert_eq!(delivered[0].1["reason"], "phrase");
+        assert_eq!(delivered[0].1["uri"], uri);
+        assert_eq!(delivered[1].0, mention_user.id);
+        assert_eq!(delivered[1].1["reason"], "mention");
+
+        // Reposts of a user's post go to them.
+        let repost: Repost = serde_json::from_value(json!({
+            "subject": {"uri": "at://did:plc:jake/app.bsky.feed.post/2", "cid": "a"},
+            "createdAt": "2024-11-20T00:00:00.000Z",
+        })).unwrap();
+        let (record, repost_uri) = (Lexicon::AppBskyFeedRepost(repost), "at://x/app.bsky.feed.repost/3".to_string());
+        process_record(record, "d".to_string(), repost_uri, now, &Turn::alone(), state).await;
+        let delivered = std::mem::take(&mut *state.delivery.delivered.lock().unwrap());
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].0, mention_user.id);
+        assert_eq!(delivered[0].1["repost"]["subject"]["uri"], "at://did:plc:jake/app.bsky.feed.post/2");
+        assert_eq!(delivered[0].1["v"], delivery::PAYLOAD_VERSION);
+
+        // Stale posts go to nobody.
+        let post: Post = serde_json::from_value(json!({
+            "text": "red pandas are great",
+            "createdAt": "2024-11-19T00:00:00.000Z",
+        })).unwrap();
+        let stale_uri = "at://did:plc:author/app.bsky.feed.post/4";
+        let record = Lexicon::AppBskyFeedPost(Box::new(post));
+        process_record(record, "e".to_string(), stale_uri.to_string(), now, &Turn::alone(), state).await;
+        assert!(state.delivery.delivered.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_tags_are_truncated() {
+        let tree = BulkSearchTree::new();
+        let user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        tree.add_item("red panda", user.clone()).await;
+        let state = mock_state(Config::for_tests(&[("MAX_POST_TEXT_BYTES", "64")]), tree, HashMap::new());
+        let before = metrics::TRUNCATED_POSTS.get();
+
+        // Tags are capped like the text, so a phrase past the cap in them is never reached.
+        let post = |tags: Vec<String>| -> Post {
+            serde_json::from_value(json!({"text": "so cute", "createdAt": "2024-11-20T00:00:00.000Z", "tags": tags}))
+                .unwrap()
+        };
+        let uri = "at://did:plc:author/app.bsky.feed.post/1".to_string();
+        let tags = vec!["a".repeat(100), "red panda".to_string()];
+        process_post(post(tags), "c".to_string(), uri.clone(), &Turn::alone(), state).await;
+        assert!(state.delivery.delivered.lock().unwrap().is_empty());
+        assert!(metrics::TRUNCATED_POSTS.get() > before);
+
+        // Tags under the cap still match.
+        process_post(post(vec!["red panda".to_string()]), "c".to_string(), uri, &Turn::alone(), state).await;
+        assert_eq!(state.delivery.delivered.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_ordered_users_get_posts_in_firehose_order() {
+        let tree = BulkSearchTree::new();
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        user.ordered = true;
+        tree.add_item("red panda", Arc::new(user)).await;
+        let state = mock_state(Config::for_tests(&[("FIREHOSE_WORKERS", "4")]), tree, HashMap::new());
+        let now = chrono::Utc::now();
+        let uri = |i: u64| format!("at://did:plc:author/app.bsky.feed.post/{i}");
+
+        // Earlier posts take the workers longer to get to, like commits with more to decode, so they are finished last.
+        let (done, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        let queue = work_queue::spawn_workers(state.config.firehose_workers, 16, move |i: u64, turn| {
+            let done = done.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(40 - i * 2)).await;
+                let post: Post = serde_json::from_value(json!({
+                    "text": format!("red panda number {i}"),
+                    "createdAt": now.to_rfc3339(),
+                })).unwrap();
+                process_record(
+                    Lexicon::AppBskyFeedPost(Box::new(post)), "c".to_string(), uri(i), now, &turn, state,
+                ).await;
+                drop(turn);
+                done.send(()).unwrap();
+            }
+        });
+        for i in 0..20 {
+            queue.send(i).await.unwrap();
+        }
+        for _ in 0..20 {
+            finished.recv().await.unwrap();
+        }
+        let delivered = state.delivery.delivered.lock().unwrap();
+        let delivered: Vec<&str> = delivered.iter().map(|(_, json)| json["uri"].as_str().unwrap()).collect();
+        assert_eq!(delivered, (0..20).map(uri).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_post_delivered_once() {
+        let tree = BulkSearchTree::new();
+        let user = Arc::new(User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap());
+        tree.add_item("red panda", user.clone()).await;
+        let state = mock_state(Config::for_tests(&[]), tree, HashMap::new());
+        let now = chrono::Utc::now();
+
+        // The relay sends the same post twice, but the user is only told once.
+        for _ in 0..2 {
+            let post: Post = serde_json::from_value(json!({
+                "text": "red pandas are great",
+                "createdAt": now.to_rfc3339(),
+            })).unwrap();
+            let uri = "at://did:plc:author/app.bsky.feed.post/1".to_string();
+            process_record(
+                Lexicon::AppBskyFeedPost(Box::new(post)), "c".to_string(), uri, now, &Turn::alone(), state,
+            ).await;
+        }
+        assert_eq!(state.delivery.delivered.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_firehose_ping_and_close() {
+        use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+        // The relay pings, sends a frame, then closes. It reports whether it got a pong for the ping.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let relay = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            socket.send(Message::Ping(b"hi".to_vec())).await.unwrap();
+            let pong = socket.next().await.unwrap().unwrap();
+            socket.send(Message::Text("hello".to_string())).await.unwrap();
+            socket.send(Message::Binary(b"frame".to_vec())).await.unwrap();
+            socket.close(Some(CloseFrame { code: CloseCode::Away, reason: "going away".into() })).await.unwrap();
+            pong
+        });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let (queue, mut frames) = mpsc::channel(8);
+        let mut received = false;
+        let disconnect = read_firehose(&mut socket, &queue, &mut received, None, Duration::ZERO).await;
+        assert_eq!(disconnect, Disconnect::Closed(Some((1001, "going away".to_string()))));
+        assert!(received);
+        assert_eq!(frames.recv().await, Some(b"frame".to_vec()));
+        assert!(frames.try_recv().is_err());
+        assert_eq!(relay.await.unwrap(), Message::Pong(b"hi".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_firehose_dropped_connection() {
+        // The relay goes away without a close frame.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(tokio_tungstenite::accept_async(stream).await.unwrap());
+        });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let (queue, _frames) = mpsc::channel(8);
+        let mut received = false;
+        let disconnect = read_firehose(&mut socket, &queue, &mut received, None, Duration::ZERO).await;
+        assert!(matches!(disconnect, Disconnect::Error(_) | Disconnect::Ended), "{disconnect:?}");
+        assert!(!received);
+    }
+
+    #[tokio::test]
+    async fn test_firehose_pings_answered() {
+        // The relay answers pings as it reads them, and closes once it has had three.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut pings = 0;
+            while pings < 3 {
+                if let Message::Ping(_) = socket.next().await.unwrap().unwrap() {
+                    pings += 1;
+                    socket.flush().await.unwrap();
+                }
+            }
+            socket.close(None).await.unwrap();
+        });
+
+        // Each ping has to be answered before the next is sent, so three pings means the pongs were seen.
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let (queue, _frames) = mpsc::channel(8);
+        let mut received = false;
+        let disconnect = read_firehose(
+            &mut socket, &queue, &mut received, Some(Duration::from_millis(10)), Duration::from_secs(5),
+        ).await;
+        assert_eq!(disconnect, Disconnect::Closed(None));
+    }
+
+    #[tokio::test]
+    async fn test_firehose_ping_timeout() {
+        // The relay never reads, so it never answers our pings.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let (queue, _frames) = mpsc::channel(8);
+        let mut received = false;
+        let disconnect = read_firehose(
+            &mut socket, &queue, &mut received, Some(Duration::from_millis(10)), Duration::from_millis(50),
+        ).await;
+        assert_eq!(disconnect, Disconnect::PingTimeout);
+    }
+
+    #[test]
+    fn test_stale_records() {
+        let now = chrono::DateTime::parse_from_rfc3339("2024-11-20T12:00:00Z").unwrap().to_utc();
+        let max_age = Some(Duration::from_secs(3600));
+
+        // Fresh and old posts.
+        assert!(!is_stale("2024-11-20T11:30:00.000Z", now, max_age));
+        assert!(is_stale("2024-11-20T10:00:00.000Z", now, max_age));
+        assert!(!is_stale("2024-11-20T10:00:00.000Z", now, None));
+
+        // Posts from the future or with timestamps we can't read are let through.
+        assert!(!is_stale("2024-11-20T13:00:00.000Z", now, max_age));
+        assert!(!is_stale("2999-01-01T00:00:00Z", now, max_age));
+        assert!(!is_stale("yesterday", now, max_age));
+        assert!(!is_stale("", now, max_age));
+    }
+
+    #[test]
+    fn test_firehose_lag() {
+        let now = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap().to_utc();
+        let time = chrono::DateTime::parse_from_rfc3339("2024-05-01T11:59:30.500Z").unwrap().to_utc();
+        assert_eq!(firehose_lag(time, now), Some(Duration::from_millis(29_500)));
+
+        // Commits from the future aren't behind at all, and a zero time is missing.
+        assert_eq!(firehose_lag(now + chrono::Duration::seconds(5), now), Some(Duration::ZERO));
+        assert_eq!(firehose_lag(chrono::DateTime::UNIX_EPOCH, now), None);
+    }
+
+    #[test]
+    fn test_downtime_window() {
+        let user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        let endpoint = &user.endpoints[0];
+        let window = Duration::from_millis(100);
+
+        // The first failure only marks the endpoint as down.
+        assert!(!record_downtime(endpoint, 1_000, window));
+        assert_eq!(endpoint.downtime_started.load(Ordering::Relaxed), 1_000);
+
+        // Still inside the window.
+        assert!(!record_downtime(endpoint, 1_100, window));
+
+        // Past the window.
+        assert!(record_downtime(endpoint, 1_101, window));
+    }
+
+    // Answers one request with the status, any extra header lines, and an empty body.
+    async fn respond_once(listener: &tokio::net::TcpListener, status: u16, headers: &str) -> String {
+        respond_once_with_body(listener, status, headers, "").await
+    }
+
+    // Accepts one request on the listener and answers it with the status, headers and body.
+    async fn respond_once_with_body(
+        listener: &tokio::net::TcpListener, status: u16, headers: &str, body: &str,
+    ) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // Read the whole request so the client doesn't see the connection reset.
+        let mut request = vec![];
+        let mut buf = [0; 4096];
+        loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end].lines()
+                    .find_map(|line| {
+                        line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse().unwrap())
+                    })
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    break;
+                }
+            }
+            if read == 0 {
+                break;
+            }
+        }
+        let length = body.len();
+        let response = format!(
+            "HTTP/1.1 {status} Status\r\n{headers}Content-Length: {length}\r\nConnection: close\r\n\r\n{body}",
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&request).into_owned()
+    }
+
+    // Gets the value of a header from a raw HTTP request.
+    fn request_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request.lines()
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| line.split_once(':').filter(|(key, _)| key.eq_ignore_ascii_case(name)))
+            .map(|(_, value)| value.trim())
+    }
+
+    #[tokio::test]
+    async fn test_ack_backoff() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let user = User::new(
+            None, format!("http://{}/webhook", listener.local_addr().unwrap()), test_key("aa"),
+        ).unwrap();
+        let user = Arc::new(user);
+        let state = http_delivery(&[("ALLOW_INTERNAL_ENDPOINTS", "true")]).build();
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+        let endpoint = &user.endpoints[0];
+
+        // Bodies which aren't an ack are ignored.
+        tokio::join!(
+            inform_user(user.clone(), json.clone(), 1_700_000_000, state),
+            respond_once_with_body(&listener, 200, "", "OK"),
+        );
+        assert_eq!(endpoint.retry_after_until.load(Ordering::Relaxed), 0);
+
+        // An ack asking for a wait holds off the next delivery, without counting against the endpoint.
+        let body = r#"{"next_after_ms": 60000}"#;
+        tokio::join!(
+            inform_user(user.clone(), json.clone(), 1_700_000_000, state),
+            respond_once_with_body(&listener, 200, "", body),
+        );
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        assert!(endpoint.retry_after_until.load(Ordering::Relaxed) > now_ms + 50_000);
+        assert_eq!(endpoint.retry_after_count.load(Ordering::Relaxed), 0);
+        assert!(!endpoint.dead.load(Ordering::Relaxed));
+        let skipped = tokio::time::timeout(
+            Duration::from_secs(1), inform_user(user.clone(), json, 1_700_000_000, state),
+        );
+        assert!(skipped.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queued_delivery_keeps_timestamp() {
+        let primary = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backup = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut user = User::new(
+            None, format!("http://{}/webhook", primary.local_addr().unwrap()), test_key("aa"),
+        ).unwrap();
+        user.add_endpoint(format!("http://{}/webhook", backup.local_addr().unwrap())).unwrap();
+        user.set_previous_key(Some(test_key("bb"))).unwrap();
+        let state = http_delivery(&[("ALLOW_INTERNAL_ENDPOINTS", "true")]).build();
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+
+        // The delivery waits in the pool, but is still sent with the timestamp it was queued with, to both endpoints.
+        let ts_seconds = chrono::Utc::now().timestamp() - 60;
+        let user = Arc::new(user);
+        state.deliver(user.clone(), json.clone(), ts_seconds).await;
+        let (first, second) = tokio::join!(respond_once(&primary, 200, ""), respond_once(&backup, 200, ""));
+        let max_age = Duration::from_secs(300);
+        let now_seconds = chrono::Utc::now().timestamp();
+        for request in [first, second] {
+            assert_eq!(request_header(&request, "x-signature-timestamp"), Some(ts_seconds.to_string().as_str()));
+            let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+            assert_eq!(body, json);
+            let keys = [("x-signature-ed25519", test_key("aa")), ("x-signature-ed25519-previous", test_key("bb"))];
+            for (header, key) in keys {
+                let public_key = ed25519_dalek::SigningKey::from_bytes(&hex::decode(key).unwrap().try_into().unwrap())
+                    .verifying_key().to_bytes();
+                let signature = request_header(&request, header).unwrap();
+                assert!(worker::signature::verify(&public_key, ts_seconds, body, signature, now_seconds, max_age));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_dead_endpoint_does_not_evict() {
+        let primary = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backup = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut user = User::new(
+            None, format!("http://{}/webhook", primary.local_addr().unwrap()), test_key("aa"),
+        ).unwrap();
+        user.add_endpoint(format!("http://{}/webhook", backup.local_addr().unwrap())).unwrap();
+        let state = http_delivery(&[("ALLOW_INTERNAL_ENDPOINTS", "true")]).build();
+        store::insert_user(user, state.tree, state.dids, state.keys).await;
+        let user = state.keys.read().await[&test_key("aa")].clone();
+
+        // The primary is gone for good, but the backup is fine, so the user stays and only the endpoint is counted.
+        let dead_endpoints = metrics::DEAD_ENDPOINTS.get("403");
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+        tokio::join!(
+            inform_user(user.clone(), json.clone(), 1_700_000_000, state),
+            respond_once(&primary, 403, ""), respond_once(&backup, 200, ""),
+        );
+        assert!(metrics::DEAD_ENDPOINTS.get("403") > dead_endpoints);
+        assert!(user.endpoints[0].dead.load(Ordering::Relaxed));
+        assert!(!user.endpoints[1].dead.load(Ordering::Relaxed));
+        assert!(user.endpoints[1].last_success.load(Ordering::Relaxed) > 0);
+        assert_eq!(state.keys.read().await.len(), 1);
+
+        // Only the backup gets the next delivery, and once it is gone too the user is evicted.
+        tokio::join!(inform_user(user.clone(), json, 1_700_000_000, state), respond_once(&backup, 403, ""));
+        assert!(user.endpoints[1].dead.load(Ordering::Relaxed));
+        assert!(state.keys.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_user_rate_limit_is_per_delivery() {
+        let primary = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backup = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut user = User::new(
+            None, format!("http://{}/webhook", primary.local_addr().unwrap()), test_key("aa"),
+        ).unwrap();
+        user.add_endpoint(format!("http://{}/webhook", backup.local_addr().unwrap())).unwrap();
+        let limits = DeliveryLimits::new(Some(RateLimiter::new(0.001, 1.0)), None);
+        let state = http_delivery(&[("ALLOW_INTERNAL_ENDPOINTS", "true")]).limits(limits).build();
+        store::insert_user(user, state.tree, state.dids, state.keys).await;
+        let user = state.keys.read().await[&test_key("aa")].clone();
+
+        // One token is enough for the delivery to go to both endpoints.
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+        tokio::join!(
+            inform_user(user.clone(), json.clone(), 1_700_000_000, state),
+            respond_once(&primary, 204, ""), respond_once(&backup, 204, ""),
+        );
+        assert!(user.endpoints.iter().all(|endpoint| endpoint.last_success.load(Ordering::Relaxed) > 0));
+
+        // The next delivery is over the limit, so neither endpoint is sent it.
+        let dropped = metrics::DROPPED_DELIVERIES.get();
+        inform_user(user, json, 1_700_000_000, state).await;
+        assert!(metrics::DROPPED_DELIVERIES.get() > dropped);
+    }
+
+    #[tokio::test]
+    async fn test_delivery_outcomes_are_counted() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let state = http_delivery(&[("ALLOW_INTERNAL_ENDPOINTS", "true")]).build();
+        let user = User::new(
+            None, format!("http://{}/webhook", listener.local_addr().unwrap()), test_key("aa"),
+        ).unwrap();
+        store::insert_user(user, state.tree, state.dids, state.keys).await;
+        let user = User::new(None, format!("http://{closed_addr}/webhook"), test_key("bb")).unwrap();
+        store::insert_user(user, state.tree, state.dids, state.keys).await;
+        let (user, unreachable) = {
+            let keys = state.keys.read().await;
+            (keys[&test_key("aa")].clone(), keys[&test_key("bb")].clone())
+        };
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+
+        // Other tests deliver at the same time, so only check each outcome went up.
+        for (status, label) in [(204, "2xx"), (500, "5xx"), (403, "4xx")] {
+            let before = metrics::DELIVERIES.get(label);
+            tokio::join!(
+                inform_user(user.clone(), json.clone(), 1_700_000_000, state), respond_once(&listener, status, ""),
+            );
+            assert!(metrics::DELIVERIES.get(label) > before, "{label} was not counted");
+        }
+        assert!(metrics::EVICTIONS.get("403") > 0);
+        assert!(state.keys.read().await.get(&test_key("aa")).is_none());
+
+        let before = metrics::DELIVERIES.get("error");
+        inform_user(unreachable, json, 1_700_000_000, state).await;
+        assert!(metrics::DELIVERIES.get("error") > before);
+    }
+
+    #[tokio::test]
+    async fn test_downtime_pauses_then_resumes() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/webhook", listener.local_addr().unwrap());
+        let private_key = test_key("aa");
+        let store: &'static store::MemoryStore = Box::leak(Box::new(store::MemoryStore::default()));
+        store.insert(store::UserRecord::new(private_key.clone(), endpoint.clone()), &["rust"]);
+        let env = [
+            ("ALLOW_INTERNAL_ENDPOINTS", "true"), ("ALLOW_INSECURE_ENDPOINTS", "true"), ("EVICTION_DOWNTIME_MS", "1"),
+            ("PAUSE_PROBE_INTERVAL_MS", "1"),
+        ];
+        let state = http_delivery(&env).store(store).build();
+        let mut user = User::new(None, endpoint, private_key.clone()).unwrap();
+        user.set_phrases(vec!["rust".to_string()]);
+        store::insert_user(user, state.tree, state.dids, state.keys).await;
+        let user = state.keys.read().await[&private_key].clone();
+
+        // The endpoint is down for longer than the window, so the user is paused rather than deleted.
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+        tokio::join!(inform_user(user.clone(), json.clone(), 1_700_000_000, state), respond_once(&listener, 500, ""));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        tokio::join!(inform_user(user.clone(), json, 1_700_000_000, state), respond_once(&listener, 500, ""));
+        assert!(state.keys.read().await.is_empty());
+        assert!(state.tree.find_all_matches("rust").await.is_empty());
+        let record = store.get(&private_key).unwrap();
+        assert!(record.paused);
+        assert!(store.paused_until(&private_key).is_some());
+        assert_eq!(store.phrases(&private_key), vec!["rust"]);
+
+        // While the endpoint is still down, probing leaves the user paused.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let (_, request) = tokio::join!(probe_paused(state), respond_once(&listener, 503, ""));
+        assert!(request.ends_with(&delivery::probe_payload()), "{request}");
+        assert!(store.get(&private_key).unwrap().paused);
+        assert!(state.keys.read().await.is_empty());
+
+        // Once it answers, the user is loaded again with their phrases.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        tokio::join!(probe_paused(state), respond_once(&listener, 204, ""));
+        assert!(!store.get(&private_key).unwrap().paused);
+        assert_eq!(store.paused_until(&private_key), None);
+        assert_eq!(state.tree.find_all_matches("rust").await.len(), 1);
+        assert!(!state.keys.read().await[&private_key].endpoints[0].dead.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_internal_hostname_evicted_once_connect_fails() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://localhost:{}/webhook", listener.local_addr().unwrap().port());
+        let state = http_delivery(&[("ALLOW_INSECURE_ENDPOINTS", "true")]).build();
+        let user = User::new(None, endpoint, test_key("aa")).unwrap();
+        store::insert_user(user, state.tree, state.dids, state.keys).await;
+        let user = state.keys.read().await[&test_key("aa")].clone();
+
+        // The resolver refuses to connect to localhost, and only then is the hostname looked up to give the reason.
+        let before = metrics::EVICTIONS.get("internal_address");
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+        inform_user(user, json, 1_700_000_000, state).await;
+        assert!(metrics::EVICTIONS.get("internal_address") > before);
+        assert!(state.keys.read().await.is_empty());
+    }
+
+    #[test]
+    fn test_retry_after_escalation() {
+        let user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        let endpoint = &user.endpoints[0];
+
+        // Up to the threshold, the endpoint is only told to wait.
+        for _ in 0..3 {
+            assert!(!record_retry_after(endpoint, Duration::from_secs(30), 1_000, 3));
+        }
+        assert_eq!(endpoint.retry_after_until.load(Ordering::Relaxed), 31_000);
+
+        // One more in a row is too many.
+        assert!(record_retry_after(endpoint, Duration::from_secs(30), 1_000, 3));
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_pauses_instead_of_evicting() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let user = User::new(
+            None, format!("http://{}/webhook", listener.local_addr().unwrap()), test_key("aa"),
+        ).unwrap();
+        let state = http_delivery(&[("ALLOW_INTERNAL_ENDPOINTS", "true"), ("EVICTION_STATUSES", "403,429")]).build();
+        store::insert_user(user, state.tree, state.dids, state.keys).await;
+        let user = state.keys.read().await[&test_key("aa")].clone();
+
+        // A 429 would evict here, but with a Retry-After the endpoint is paused instead.
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+        tokio::join!(
+            inform_user(user.clone(), json.clone(), 1_700_000_000, state),
+            respond_once(&listener, 429, "Retry-After: 60\r\n"),
+        );
+        let endpoint = &user.endpoints[0];
+        assert!(!endpoint.dead.load(Ordering::Relaxed));
+        assert!(endpoint.retry_after_until.load(Ordering::Relaxed) > chrono::Utc::now().timestamp_millis());
+        assert_eq!(state.keys.read().await.len(), 1);
+
+        // Nothing is sent while it is paused.
+        let skipped = metrics::RETRY_AFTER_DELIVERIES.get();
+        inform_user(user.clone(), json, 1_700_000_000, state).await;
+        assert!(metrics::RETRY_AFTER_DELIVERIES.get() > skipped);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_escalation_without_429_eviction() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/webhook", listener.local_addr().unwrap());
+        let user = User::new(None, url, test_key("aa")).unwrap();
+        let state = http_delivery(&[
+            ("ALLOW_INTERNAL_ENDPOINTS", "true"), ("EVICTION_STATUSES", ""), ("RETRY_AFTER_EVICTION_THRESHOLD", "1"),
+        ]).build();
+        store::insert_user(user, state.tree, state.dids, state.keys).await;
+        let user = state.keys.read().await[&test_key("aa")].clone();
+        let endpoint = &user.endpoints[0];
+
+        // Going past the threshold when 429 isn't an eviction status only marks the endpoint as down.
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+        for _ in 0..2 {
+            endpoint.retry_after_until.store(0, Ordering::Relaxed);
+            tokio::join!(
+                inform_user(user.clone(), json.clone(), 1_700_000_000, state),
+                respond_once(&listener, 429, "Retry-After: 60\r\n"),
+            );
+        }
+        assert!(!endpoint.dead.load(Ordering::Relaxed));
+        assert!(endpoint.downtime_started.load(Ordering::Relaxed) > 0);
+        assert_eq!(state.keys.read().await.len(), 1);
+    }
+
+    // Collects log output so tests can check what was logged.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Builds a leaked HTTP delivery for tests from test_delivery, with an empty memory store unless given another.
+    struct DeliveryBuilder(HttpDelivery);
+
+    impl DeliveryBuilder {
+        fn store(self, store: &'static dyn UserStore) -> Self {
+            Self(HttpDelivery { store, ..self.0 })
+        }
+
+        fn observer(self, observer: &'static dyn DeliveryObserver) -> Self {
+            Self(HttpDelivery { observer, ..self.0 })
+        }
+
+        fn limits(self, delivery_limits: DeliveryLimits) -> Self {
+            Self(HttpDelivery { delivery_limits, ..self.0 })
+        }
+
+        fn build(self) -> &'static HttpDelivery {
+            Box::leak(Box::new(self.0))
+        }
+    }
+
+    // Starts building an HTTP delivery with the given settings.
+    fn http_delivery(env: &[(&str, &str)]) -> DeliveryBuilder {
+        DeliveryBuilder(test_delivery(env, Box::leak(Box::new(store::MemoryStore::default()))))
+    }
+
+    #[test]
+    fn test_runtime_threads() {
+        let config = Config::for_tests(&[("WORKER_THREADS", "3"), ("MAX_BLOCKING_THREADS", "2")]);
+        let runtime = build_runtime(&config).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+        assert_eq!(runtime.block_on(async { 1 + 1 }), 2);
+    }
+
+    // Defines an observer which keeps every delivery it is told about.
+    #[derive(Default)]
+    struct RecordingObserver {
+        observed: std::sync::Mutex<Vec<(u64, String, DeliveryOutcome)>>,
+    }
+
+    impl DeliveryObserver for RecordingObserver {
+        fn observe(&self, user_id: u64, endpoint: &str, outcome: DeliveryOutcome, latency: Duration) {
+            assert!(latency < Duration::from_secs(30));
+            self.observed.lock().unwrap().push((user_id, endpoint.to_string(), outcome));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delivery_observer() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_url = format!("http://{}/webhook", closed.local_addr().unwrap());
+        drop(closed);
+        let observer: &'static RecordingObserver = Box::leak(Box::default());
+        let env = [("ALLOW_INTERNAL_ENDPOINTS", "true"), ("EVICTION_STATUSES", ""), ("MAX_DELIVERY_BYTES", "100")];
+        let state = http_delivery(&env).observer(observer).build();
+        let url = format!("http://{}/webhook", listener.local_addr().unwrap());
+        let user = Arc::new(User::new(None, url.clone(), test_key("aa")).unwrap());
+        let json = payload_with_reasons(&json!({"uri": "at://x/app.bsky.feed.post/1"}), &[MatchReason::Phrase]);
+
+        // Each response the endpoint gives is passed on as it happened.
+        for status in [204, 500, 200] {
+            tokio::join!(
+                inform_user(user.clone(), json.clone(), 1_700_000_000, state), respond_once(&listener, status, ""),
+            );
+        }
+        let unreachable = Arc::new(User::new(None, closed_url.clone(), test_key("bb")).unwrap());
+        inform_user(unreachable.clone(), json, 1_700_000_000, state).await;
+        inform_user(user.clone(), "x".repeat(101), 1_700_000_000, state).await;
+
+        assert_eq!(*observer.observed.lock().unwrap(), vec![
+            (user.id, url.clone(), DeliveryOutcome::Success(204)),
+            (user.id, url.clone(), DeliveryOutcome::Failure(500)),
+            (user.id, url.clone(), DeliveryOutcome::Success(200)),
+            (unreachable.id, closed_url, DeliveryOutcome::Error),
+            (user.id, url, DeliveryOutcome::Oversized),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_does_not_send() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/webhook", listener.local_addr().unwrap());
+        let mut user = User::new(None, endpoint, test_key("aa")).unwrap();
+        let words: Vec<String> = (0..11).map(|i| format!("word{i}")).collect();
+        let long = "a".repeat(100);
+        user.set_phrases([long.clone(), "not in the post".to_string()].into_iter().chain(words.clone()).collect());
+        let user = Arc::new(user);
+        let state = http_delivery(&[("DRY_RUN", "true"), ("ALLOW_INTERNAL_ENDPOINTS", "true")]).build();
+        let before = metrics::DRY_RUN_DELIVERIES.get();
+        let text = format!("{long} {}", words.join(" "));
+        let payload = json!({"uri": "at://x/app.bsky.feed.post/1", "post": {"text": text}});
+        let json = payload_with_reasons(&payload, &[MatchReason::Phrase]);
+        inform_user(user.clone(), json, 1_700_000_000, state).await;
+
+        // Nothing connected to the endpoint, but the delivery was logged and counted.
+        assert!(tokio::time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+        assert!(metrics::DRY_RUN_DELIVERIES.get() > before);
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Dry run, not sending the delivery"), "{logs}");
+        assert!(logs.contains(&format!("user_id={}", user.id)), "{logs}");
+
+        // Every matched phrase is counted, but only the first few are logged and each is cut short.
+        assert!(logs.contains("matched=12"), "{logs}");
+        assert!(logs.contains(&format!(r#"phrases=["{}", "word0""#, "a".repeat(64))), "{logs}");
+        assert_eq!(logs.matches(r#""word"#).count(), 9, "{logs}");
+        assert_eq!(user.endpoints[0].downtime_started.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_delivery_success() {
+        assert!(is_delivery_success(200, &[]));
+        assert!(is_delivery_success(204, &[]));
+        assert!(!is_delivery_success(302, &[]));
+        assert!(!is_delivery_success(500, &[]));
+
+        // Redirects only count if they are allowed, and then only the listed statuses count.
+        assert!(is_delivery_success(302, &[200, 302]));
+        assert!(!is_delivery_success(204, &[200, 302]));
+    }
+
+    #[test]
+    fn test_cap_recipients_spreads_users() {
+        let users: Vec<_> = (0..10u8)
+            .map(|i| Arc::new(User::new(None, "https://example.com".to_string(), hex::encode([i; 32])).unwrap()))
+            .collect();
+        let recipients = || {
+            users.iter().map(|user| Recipient { user: user.clone(), reasons: vec![MatchReason::Phrase] })
+        };
+        let rotation = AtomicUsize::new(0);
+
+        // Under the cap, everyone is told in the original order.
+        let capped = cap_recipients(recipients().collect(), Some(10), &rotation);
+        assert!(capped.iter().map(|r| r.user.id).eq(users.iter().map(|u| u.id)));
+        assert_eq!(rotation.load(Ordering::Relaxed), 0);
+
+        // Over many capped posts, every user is told equally often.
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        for _ in 0..50 {
+            for recipient in cap_recipients(recipients().collect(), Some(3), &rotation) {
+                *counts.entry(recipient.user.id).or_default() += 1;
+            }
+        }
+        assert_eq!(counts.len(), users.len());
+        assert!(counts.values().all(|&count| count == 15));
+    }
+
+    fn post_block(text: &str) -> Vec<u8> {
+        serde_cbor::to_vec(&json!({
+            "$type": "app.bsky.feed.post",
+            "text": text,
+            "createdAt": "2024-11-20T00:00:00.000Z",
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_commit_blocks_decoded_once() {
+        let blocks = HashMap::from([
+            ("a".to_string(), post_block("first")),
+            ("b".to_string(), post_block("second")),
+            ("c".to_string(), post_block("a like, really")),
+        ]);
+        let cids = ["a".to_string(), "b".to_string(), "c".to_string()];
+        let ops = [
+            ("app.bsky.feed.post/1", Some(&cids[0])),
+            ("app.bsky.feed.post/2", Some(&cids[1])),
+            ("app.bsky.feed.like/3", Some(&cids[2])),
+            ("app.bsky.feed.post/4", None),
+        ];
+
+        let mut decodes = 0;
+        let records = read_commit_records(ops, b"car", |_| {
+            decodes += 1;
+            Some(blocks.clone())
+        });
+        assert_eq!(decodes, 1);
+        let texts: Vec<_> = records.iter().map(|(path, _, record)| match record {
+            Lexicon::AppBskyFeedPost(post) => (*path, post.text.as_str()),
+            _ => panic!("expected a post"),
+        }).collect();
+        assert_eq!(texts, vec![("app.bsky.feed.post/1", "first"), ("app.bsky.feed.post/2", "second")]);
+    }
+
+    #[test]
+    fn test_commit_op_with_absent_block() {
+        let blocks = HashMap::from([("a".to_string(), post_block("first"))]);
+        let cids = ["a".to_string(), "b".to_string()];
+        let ops = [
+            ("app.bsky.feed.post/1", Some(&cids[1])),
+            ("app.bsky.feed.post/2", Some(&cids[0])),
+        ];
+
+        // The op whose block is absent is skipped without taking the rest of the commit with it.
+        let records = read_commit_records(ops, b"car", |_| Some(blocks.clone()));
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, "app.bsky.feed.post/2");
+        assert_eq!(records[0].1, "a");
+
+        // So is a commit with no blocks at all.
+        let records = read_commit_records(ops, b"car", |_| Some(HashMap::new()));
+        assert!(records.is_empty());
+    }
+
+    // Builds a CAR with an empty header and the given blocks, with their length prefixes.
+    fn car_bytes(blocks: &[&[u8]]) -> Vec<u8> {
+        // {"roots": [], "version": 1}
+        let header = b"\xa2\x65roots\x80\x67version\x01";
+        let mut car = vec![header.len() as u8];
+        car.extend_from_slice(header);
+        for block in blocks {
+            assert!(block.len() < 0x80);
+            car.push(block.len() as u8);
+            car.extend_from_slice(block);
+        }
+        car
+    }
+
+    #[test]
+    fn test_read_varint() {
+        assert_eq!(read_varint(&[0x05, 0xff]), Some((5, &[0xff][..])));
+        assert_eq!(read_varint(&[0xac, 0x02]), Some((300, &[][..])));
+        let max = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(read_varint(&max), Some((u64::MAX, &[][..])));
+        assert_eq!(read_varint(&[]), None);
+        assert_eq!(read_varint(&[0xac]), None);
+        assert_eq!(read_varint(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]), None);
+    }
+
+    #[test]
+    fn test_car_framing_cut_short() {
+        let block = post_block("hello");
+        let car = car_bytes(&[b"\x01\x02\x03", &block]);
+        assert_eq!(check_car_framing(&car), Ok(()));
+        assert_eq!(check_car_framing(&car_bytes(&[])), Ok(()));
+
+        // Cut inside the header, and before its length varint ends.
+        assert_eq!(check_car_framing(&car[..5]), Err("header is cut short"));
+        assert_eq!(check_car_framing(&[0x91]), Err("header length is cut short"));
+
+        // Cut inside a block, and inside a block length varint which claims more bytes than there are.
+        let header_end = 1 + car[0] as usize;
+        assert_eq!(check_car_framing(&car[..car.len() - 1]), Err("block is cut short"));
+        let mut cut = car[..header_end].to_vec();
+        cut.push(0x80);
+        assert_eq!(check_car_framing(&cut), Err("block length is cut short"));
+        cut.extend_from_slice(&[0x80, 0x80, 0x01]);
+        assert_eq!(check_car_framing(&cut), Err("block is cut short"));
+
+        // A commit with a cut CAR is skipped, rather than handed to the decoder.
+        let cid = "a".to_string();
+        let mut decodes = 0;
+        for cut_at in [1, 5, header_end + 1, header_end + 3, car.len() - 1] {
+            let records = read_commit_records([("app.bsky.feed.post/1", Some(&cid))], &car[..cut_at], |blocks| {
+                check_car_framing(blocks).ok()?;
+                decodes += 1;
+                Some(HashMap::from([(cid.clone(), block.clone())]))
+            });
+            assert!(records.is_empty(), "cut at {cut_at}");
+        }
+        assert_eq!(decodes, 0);
+    }
+
+    #[test]
+    fn test_commit_blocks_not_decoded_when_unneeded() {
+        let cid = "a".to_string();
+        let mut decodes = 0;
+        let records = read_commit_records([("app.bsky.feed.like/1", Some(&cid))], b"car", |_| {
+            decodes += 1;
+            None
+        });
+        assert!(records.is_empty());
+
+        // Empty blocks are never decoded.
+        let records = read_commit_records([("app.bsky.feed.post/1", Some(&cid))], b"", |_| {
+            decodes += 1;
+            None
+        });
+        assert!(records.is_empty());
+        assert_eq!(decodes, 0);
+    }
+
+    #[test]
+    fn test_read_record_missing_cid() {
+        let blocks: HashMap<String, Vec<u8>> = HashMap::new();
+        assert!(matches!(read_record(&blocks, &"a".to_string()), Err(RecordError::Missing)));
+    }
+
+    #[test]
+    fn test_read_record_truncated() {
+        let mut post = serde_cbor::to_vec(&json!({
+            "$type": "app.bsky.feed.post",
+            "text": "hello world",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+        })).unwrap();
+        post.truncate(post.len() / 2);
+        let blocks = HashMap::from([("a".to_string(), post)]);
+        assert!(matches!(read_record(&blocks, &"a".to_string()), Err(RecordError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_read_record_unhandled_type() {
+        let like = serde_cbor::to_vec(&json!({
+            "$type": "app.bsky.feed.like",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "subject": {"uri": "at://did:plc:author/app.bsky.feed.post/1", "cid": "a"},
+        })).unwrap();
+        let blocks = HashMap::from([("a".to_string(), like)]);
+        match read_record(&blocks, &"a".to_string()) {
+            Err(RecordError::Unhandled(record_type)) => assert_eq!(record_type, "app.bsky.feed.like"),
+            _ => panic!("expected an unhandled record"),
+        }
+    }
+
+    #[test]
+    fn test_read_record_post_with_bad_schema() {
+        // A post with fields that don't match the schema is malformed, not ignored.
+        let post = serde_cbor::to_vec(&json!({
+            "$type": "app.bsky.feed.post",
+            "text": 1234,
+            "createdAt": "2024-11-20T00:00:00.000Z",
+        })).unwrap();
+        let blocks = HashMap::from([("a".to_string(), post)]);
+        assert!(matches!(read_record(&blocks, &"a".to_string()), Err(RecordError::Malformed(_))));
+
+        // So is a record with no type at all.
+        let untyped = serde_cbor::to_vec(&json!({"text": "hello"})).unwrap();
+        let blocks = HashMap::from([("a".to_string(), untyped)]);
+        assert!(matches!(read_record(&blocks, &"a".to_string()), Err(RecordError::Malformed(_))));
     }
 }
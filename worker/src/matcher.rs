@@ -0,0 +1,195 @@
+use futures::FutureExt as _;
+use std::{hash::Hash, panic::AssertUnwindSafe, str::FromStr};
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+use crate::bulk_search::BulkSearchTree;
+
+// How many searches can wait for the match actor before senders wait for room.
+const ACTOR_QUEUE_DEPTH: usize = 1024;
+
+// Defines where posts are matched against the tree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchMode {
+    // Every firehose worker searches the tree itself, sharing its read lock.
+    Shared,
+    // Searches are sent to one thread which does them one after another.
+    Actor,
+}
+
+impl FromStr for MatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared" => Ok(MatchMode::Shared),
+            "actor" => Ok(MatchMode::Actor),
+            _ => Err("expected shared or actor".to_string()),
+        }
+    }
+}
+
+// Defines a search sent to the match actor, along with where the result goes.
+enum Search<T> {
+    All(String, oneshot::Sender<Vec<T>>),
+    Any(String, oneshot::Sender<bool>),
+}
+
+// Defines how posts are matched. With the actor, only one thread ever reads the tree, so the firehose workers don't
+// fight over its lock or its place in the CPU caches. The lock is still there for phrases being added and removed.
+pub struct Matcher<'a, T> {
+    tree: &'a BulkSearchTree<T>,
+    actor: Option<mpsc::Sender<Search<T>>>,
+
+    // Called each time the actor didn't answer a search, so it can be counted.
+    on_failure: fn(),
+}
+
+impl<T: Clone + Eq + Hash + Send + Sync + 'static> Matcher<'static, T> {
+    // Creates a matcher for the mode. For the actor, this starts its thread, which runs until the matcher is dropped.
+    pub fn new(mode: MatchMode, tree: &'static BulkSearchTree<T>, on_failure: fn()) -> std::io::Result<Self> {
+        if mode == MatchMode::Shared {
+            return Ok(Matcher::shared(tree));
+        }
+        let (sender, receiver) = mpsc::channel(ACTOR_QUEUE_DEPTH);
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        std::thread::Builder::new()
+            .name("matcher".to_string())
+            .spawn(move || runtime.block_on(run_actor(tree, receiver)))?;
+        Ok(Matcher { tree, actor: Some(sender), on_failure })
+    }
+}
+
+impl<'a, T: Clone + Eq + Hash> Matcher<'a, T> {
+    // Creates a matcher which searches the tree from whichever task asks.
+    pub fn shared(tree: &'a BulkSearchTree<T>) -> Self {
+        Matcher { tree, actor: None, on_failure: || {} }
+    }
+
+    // Finds all items that match within the given text.
+    pub async fn find_all_matches(&self, text: &str) -> Vec<T> {
+        if let Some(sender) = &self.actor {
+            let (result, receiver) = oneshot::channel();
+            if let Some(matches) = self.ask(sender, Search::All(text.to_string(), result), receiver).await {
+                return matches;
+            }
+        }
+        self.tree.find_all_matches(text).await
+    }
+
+    // Checks if any item matches within the given text.
+    pub async fn any_match(&self, text: &str) -> bool {
+        if let Some(sender) = &self.actor {
+            let (result, receiver) = oneshot::channel();
+            if let Some(matched) = self.ask(sender, Search::Any(text.to_string(), result), receiver).await {
+                return matched;
+            }
+        }
+        self.tree.any_match(text).await
+    }
+
+    // Sends a search to the actor and waits for the result. Returns None if the actor didn't answer, because the
+    // search panicked or the actor is gone. The caller then searches the tree itself, so a broken actor is never
+    // mistaken for nothing matching, and a search which panics does so where it would in shared mode.
+    async fn ask<R>(
+        &self, sender: &mpsc::Sender<Search<T>>, search: Search<T>, receiver: oneshot::Receiver<R>,
+    ) -> Option<R> {
+        let answer = match sender.send(search).await {
+            Ok(()) => receiver.await.ok(),
+            Err(_) => None,
+        };
+        if answer.is_none() {
+            (self.on_failure)();
+            error!("The match actor didn't answer a search, so searching here instead");
+        }
+        answer
+    }
+}
+
+// Runs searches one at a time until every sender is dropped. A search which panics is dropped without an answer, and
+// the actor carries on with the next one.
+async fn run_actor<T: Clone + Eq + Hash>(tree: &BulkSearchTree<T>, mut receiver: mpsc::Receiver<Search<T>>) {
+    while let Some(search) = receiver.recv().await {
+        // Whoever asked may have given up waiting, which is fine.
+        let searched = AssertUnwindSafe(async {
+            match search {
+                Search::All(text, result) => {
+                    let _ = result.send(tree.find_all_matches(&text).await);
+                }
+                Search::Any(text, result) => {
+                    let _ = result.send(tree.any_match(&text).await);
+                }
+            }
+        }).catch_unwind().await;
+        if searched.is_err() {
+            error!("The match actor panicked during a search");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Builds a tree of 5000 items with 3 phrases each out of 2000 made up words, and posts which each match a few.
+    async fn realistic_tree() -> (&'static BulkSearchTree<usize>, Vec<String>) {
+        let words: Vec<String> = (0..2000u32)
+            .map(|i| format!("{}{}", ["red", "blue", "rust", "sky", "pan", "crab"][i as usize % 6], i * 7919 % 10007))
+            .collect();
+        let tree: &'static BulkSearchTree<usize> = Box::leak(Box::new(BulkSearchTree::new()));
+        for i in 0..5000usize {
+            for j in 0..3 {
+                tree.add_item(&words[(i * 31 + j * 17) % words.len()], i).await;
+            }
+        }
+        let filler = "just posting about my day and the weather, nothing to see here. ";
+        let posts = (0..1000usize)
+            .map(|i| {
+                let (a, b, c) = (&words[i % 2000], &words[(i * 3) % 2000], &words[(i * 11) % 2000]);
+                format!("{filler}{a} {filler}{b} {filler}{c}")
+            })
+            .collect();
+        (tree, posts)
+    }
+
+    #[tokio::test]
+    async fn test_actor_matches_shared() {
+        let (tree, posts) = realistic_tree().await;
+        let shared = Matcher::new(MatchMode::Shared, tree, || {}).unwrap();
+        let actor = Matcher::new(MatchMode::Actor, tree, || {}).unwrap();
+        for post in posts.iter().take(50).map(String::as_str).chain(["nothing here", ""]) {
+            let mut expected = shared.find_all_matches(post).await;
+            let mut actual = actor.find_all_matches(post).await;
+            expected.sort();
+            actual.sort();
+            assert_eq!(actual, expected);
+            assert_eq!(actor.any_match(post).await, !expected.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_actor_failures_search_here() {
+        static FAILURES: AtomicUsize = AtomicUsize::new(0);
+        let tree: &'static BulkSearchTree<u32> = Box::leak(Box::new(BulkSearchTree::new()));
+        tree.add_item("red panda", 1).await;
+
+        // An actor which is gone still gives the right answers, and each search it missed is counted.
+        let (sender, receiver) = mpsc::channel(1);
+        drop(receiver);
+        let on_failure = || {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+        };
+        let matcher = Matcher { tree, actor: Some(sender), on_failure };
+        assert_eq!(matcher.find_all_matches("a red panda").await, vec![1]);
+        assert!(matcher.any_match("a red panda").await);
+        assert!(!matcher.any_match("an otter").await);
+        assert_eq!(FAILURES.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_match_mode() {
+        assert_eq!("shared".parse::<MatchMode>(), Ok(MatchMode::Shared));
+        assert_eq!("actor".parse::<MatchMode>(), Ok(MatchMode::Actor));
+        assert!("Actor".parse::<MatchMode>().is_err());
+    }
+}
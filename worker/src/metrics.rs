@@ -0,0 +1,443 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(any(feature = "firehose", feature = "http"))]
+use std::{collections::BTreeMap, sync::Mutex};
+#[cfg(feature = "firehose")]
+use std::time::Duration;
+#[cfg(feature = "http")]
+use std::fmt::Write;
+
+// Defines a counter that only goes up.
+pub struct Counter {
+    #[cfg(feature = "http")]
+    name: &'static str,
+    #[cfg(feature = "http")]
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    // The name and help text are only read when the metrics are rendered for GET /metrics.
+    #[cfg_attr(not(feature = "http"), allow(unused_variables))]
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "http")]
+            name,
+            #[cfg(feature = "http")]
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "firehose")]
+    pub fn add(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
+    #[cfg(any(test, feature = "http"))]
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    #[cfg(feature = "http")]
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        let _ = writeln!(out, "{} {}", self.name, self.get());
+    }
+}
+
+// Defines a counter split up by the value of a single label. Label values are added the first time they are counted,
+// so they should come from a small set.
+#[cfg(any(feature = "firehose", feature = "http"))]
+pub struct LabeledCounter {
+    #[cfg(feature = "http")]
+    name: &'static str,
+    #[cfg(feature = "http")]
+    help: &'static str,
+    #[cfg(feature = "http")]
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+#[cfg(any(feature = "firehose", feature = "http"))]
+impl LabeledCounter {
+    // The name and help text are only read when the metrics are rendered for GET /metrics.
+    #[cfg_attr(not(feature = "http"), allow(unused_variables))]
+    pub const fn new(name: &'static str, help: &'static str, label: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "http")]
+            name,
+            #[cfg(feature = "http")]
+            help,
+            #[cfg(feature = "http")]
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, value: &str) {
+        let mut values = self.values.lock().unwrap();
+        match values.get_mut(value) {
+            Some(count) => *count += 1,
+            None => {
+                values.insert(value.to_string(), 1);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, value: &str) -> u64 {
+        self.values.lock().unwrap().get(value).copied().unwrap_or(0)
+    }
+
+    #[cfg(feature = "http")]
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        for (value, count) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{{}=\"{value}\"}} {count}", self.name, self.label);
+        }
+    }
+}
+
+#[cfg(feature = "firehose")]
+pub static DELIVERIES: LabeledCounter = LabeledCounter::new(
+    "bluehook_webhook_deliveries_total", "Webhook deliveries sent, by status class, or error if there was no response.",
+    "status",
+);
+
+#[cfg(any(feature = "firehose", feature = "http"))]
+pub static EVICTIONS: LabeledCounter = LabeledCounter::new(
+    "bluehook_evictions_total", "Users evicted, by reason.", "reason",
+);
+
+#[cfg(feature = "firehose")]
+pub static DEAD_ENDPOINTS: LabeledCounter = LabeledCounter::new(
+    "bluehook_dead_endpoints_total", "Endpoints given up on while their user had another left, by reason.", "reason",
+);
+
+// Gets the label a delivery is counted under for the status it got back.
+#[cfg(feature = "firehose")]
+pub fn status_class(status: u16) -> &'static str {
+    match status {
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "other",
+    }
+}
+
+#[cfg(feature = "firehose")]
+pub static DROPPED_DELIVERIES: Counter = Counter::new(
+    "bluehook_dropped_deliveries_total", "Webhook deliveries dropped by the rate limiter.",
+);
+
+#[cfg(feature = "firehose")]
+pub static DROPPED_ORDERED_DELIVERIES: Counter = Counter::new(
+    "bluehook_dropped_ordered_deliveries_total",
+    "Webhook deliveries dropped because an ordered user already had too many waiting to be sent.",
+);
+
+#[cfg(feature = "firehose")]
+pub static SHORT_CIRCUITED_DELIVERIES: Counter = Counter::new(
+    "bluehook_short_circuited_deliveries_total", "Webhook deliveries skipped because the endpoint circuit was open.",
+);
+
+#[cfg(feature = "firehose")]
+pub static RETRY_AFTER_DELIVERIES: Counter = Counter::new(
+    "bluehook_retry_after_deliveries_total",
+    "Webhook deliveries skipped because the endpoint asked us to wait with Retry-After.",
+);
+
+#[cfg(feature = "firehose")]
+pub static TRUNCATED_RECIPIENTS: Counter = Counter::new(
+    "bluehook_truncated_recipients_total",
+    "Users not told about a post because it matched more than MAX_RECIPIENTS_PER_POST.",
+);
+
+#[cfg(feature = "firehose")]
+pub static TRUNCATED_POSTS: Counter = Counter::new(
+    "bluehook_truncated_posts_total", "Posts only matched up to MAX_POST_TEXT_BYTES because their text was longer.",
+);
+
+#[cfg(feature = "firehose")]
+pub static SERIALIZATION_ERRORS: Counter = Counter::new(
+    "bluehook_serialization_errors_total", "Records skipped because they could not be serialized into a payload.",
+);
+
+#[cfg(feature = "firehose")]
+pub static STALE_RECORDS: Counter = Counter::new(
+    "bluehook_stale_records_total", "Posts and reposts skipped because they were older than MAX_POST_AGE_SECONDS.",
+);
+
+#[cfg(feature = "firehose")]
+pub static DUPLICATE_RECORDS: Counter = Counter::new(
+    "bluehook_duplicate_records_total",
+    "Posts and reposts skipped because the same URI was processed within DEDUPE_WINDOW_MS.",
+);
+
+#[cfg(feature = "firehose")]
+pub static DRY_RUN_DELIVERIES: Counter = Counter::new(
+    "bluehook_dry_run_deliveries_total", "Webhook deliveries logged instead of sent because DRY_RUN is set.",
+);
+
+#[cfg(feature = "firehose")]
+pub static PAUSES: Counter = Counter::new(
+    "bluehook_pauses_total", "Users paused because all of their endpoints were down for too long.",
+);
+
+pub static RESUMES: Counter = Counter::new(
+    "bluehook_resumes_total", "Paused users loaded again after their endpoint answered a probe.",
+);
+
+#[cfg(feature = "firehose")]
+pub static MATCH_ACTOR_FAILURES: Counter = Counter::new(
+    "bluehook_match_actor_failures_total",
+    "Searches the match actor didn't answer, which were searched on the firehose worker instead.",
+);
+
+#[cfg(feature = "firehose")]
+pub static FIREHOSE_CLOSES: Counter = Counter::new(
+    "bluehook_firehose_closes_total", "Times a relay closed the firehose connection.",
+);
+
+#[cfg(feature = "firehose")]
+pub static FIREHOSE_ERRORS: Counter = Counter::new(
+    "bluehook_firehose_errors_total", "Times reading the firehose failed with an error.",
+);
+
+#[cfg(feature = "firehose")]
+pub static FIREHOSE_RECONNECTS: Counter = Counter::new(
+    "bluehook_firehose_reconnects_total", "Times the worker reconnected to the firehose.",
+);
+
+// Defines a value that can go up and down.
+#[cfg(feature = "firehose")]
+pub struct Gauge {
+    #[cfg(feature = "http")]
+    name: &'static str,
+    #[cfg(feature = "http")]
+    help: &'static str,
+    // The bits of an f64, since there is no atomic float.
+    value: AtomicU64,
+}
+
+#[cfg(feature = "firehose")]
+impl Gauge {
+    // The name and help text are only read when the metrics are rendered for GET /metrics.
+    #[cfg_attr(not(feature = "http"), allow(unused_variables))]
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "http")]
+            name,
+            #[cfg(feature = "http")]
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set(&self, value: f64) {
+        self.value.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "http")]
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.value.load(Ordering::Relaxed))
+    }
+
+    #[cfg(feature = "http")]
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} gauge", self.name);
+        let _ = writeln!(out, "{} {}", self.name, self.get());
+    }
+}
+
+#[cfg(feature = "firehose")]
+pub static FIREHOSE_LAG: Gauge = Gauge::new(
+    "bluehook_firehose_lag_seconds", "How far behind the relay's commit time the last processed commit was.",
+);
+
+// Defines the upper bounds of the duration buckets in seconds.
+#[cfg(feature = "firehose")]
+const DURATION_BUCKETS: [f64; 10] = [0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.01, 0.05];
+
+// Defines the upper bounds of the text lengths in bytes that durations are split up by. Longer text goes in its own
+// group after these.
+#[cfg(feature = "firehose")]
+const TEXT_LENGTHS: [usize; 3] = [64, 256, 1024];
+
+// Defines the durations recorded for one group of text lengths.
+#[cfg(feature = "firehose")]
+struct DurationSeries {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+#[cfg(feature = "firehose")]
+impl DurationSeries {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; DURATION_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+// Defines a histogram of how long something took, split up by the length of the text it was done on.
+#[cfg(feature = "firehose")]
+pub struct DurationHistogram {
+    #[cfg(feature = "http")]
+    name: &'static str,
+    #[cfg(feature = "http")]
+    help: &'static str,
+    series: [DurationSeries; TEXT_LENGTHS.len() + 1],
+}
+
+#[cfg(feature = "firehose")]
+impl DurationHistogram {
+    // The name and help text are only read when the metrics are rendered for GET /metrics.
+    #[cfg_attr(not(feature = "http"), allow(unused_variables))]
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "http")]
+            name,
+            #[cfg(feature = "http")]
+            help,
+            series: [const { DurationSeries::new() }; TEXT_LENGTHS.len() + 1],
+        }
+    }
+
+    // Records how long something took on text of the given length.
+    pub fn observe(&self, text_len: usize, duration: Duration) {
+        let group = TEXT_LENGTHS.iter().position(|&max| text_len <= max).unwrap_or(TEXT_LENGTHS.len());
+        let series = &self.series[group];
+        let seconds = duration.as_secs_f64();
+
+        // Buckets are cumulative, so the duration counts towards every bucket it fits in.
+        for (bucket, &max) in series.buckets.iter().zip(DURATION_BUCKETS.iter()) {
+            if seconds <= max {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        series.count.fetch_add(1, Ordering::Relaxed);
+        series.sum_nanos.fetch_add(duration.as_nanos().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "http")]
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        for (group, series) in self.series.iter().enumerate() {
+            let text_bytes = match TEXT_LENGTHS.get(group) {
+                Some(max) => format!("<={max}"),
+                None => format!(">{}", TEXT_LENGTHS[TEXT_LENGTHS.len() - 1]),
+            };
+            let count = series.count.load(Ordering::Relaxed);
+            for (bucket, max) in series.buckets.iter().zip(DURATION_BUCKETS.iter()) {
+                let bucket = bucket.load(Ordering::Relaxed);
+                let _ = writeln!(out, "{}_bucket{{text_bytes=\"{text_bytes}\",le=\"{max}\"}} {bucket}", self.name);
+            }
+            let _ = writeln!(out, "{}_bucket{{text_bytes=\"{text_bytes}\",le=\"+Inf\"}} {count}", self.name);
+            let sum = series.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(out, "{}_sum{{text_bytes=\"{text_bytes}\"}} {sum}", self.name);
+            let _ = writeln!(out, "{}_count{{text_bytes=\"{text_bytes}\"}} {count}", self.name);
+        }
+    }
+}
+
+#[cfg(feature = "firehose")]
+pub static MATCH_DURATIONS: DurationHistogram = DurationHistogram::new(
+    "bluehook_match_duration_seconds", "Time taken to search the phrase tree for a post, by text length in bytes.",
+);
+
+// Defines the counters that get rendered which only the firehose counts.
+#[cfg(all(feature = "http", feature = "firehose"))]
+static FIREHOSE_COUNTERS: &[&Counter] = &[
+    &DROPPED_DELIVERIES, &DROPPED_ORDERED_DELIVERIES, &SHORT_CIRCUITED_DELIVERIES, &RETRY_AFTER_DELIVERIES,
+    &TRUNCATED_RECIPIENTS, &TRUNCATED_POSTS, &STALE_RECORDS, &DUPLICATE_RECORDS, &SERIALIZATION_ERRORS,
+    &DRY_RUN_DELIVERIES, &PAUSES, &MATCH_ACTOR_FAILURES, &FIREHOSE_CLOSES, &FIREHOSE_ERRORS, &FIREHOSE_RECONNECTS,
+];
+
+// Renders all the metrics in the Prometheus text format.
+#[cfg(feature = "http")]
+pub fn render() -> String {
+    let mut out = String::new();
+    #[cfg(feature = "firehose")]
+    {
+        for counter in FIREHOSE_COUNTERS {
+            counter.render(&mut out);
+        }
+        DELIVERIES.render(&mut out);
+        DEAD_ENDPOINTS.render(&mut out);
+        FIREHOSE_LAG.render(&mut out);
+        MATCH_DURATIONS.render(&mut out);
+    }
+    EVICTIONS.render(&mut out);
+    RESUMES.render(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labeled_counter() {
+        let counter = LabeledCounter::new("test_deliveries_total", "Test.", "status");
+        counter.inc("5xx");
+        counter.inc("2xx");
+        counter.inc("5xx");
+        assert_eq!(counter.get("5xx"), 2);
+        assert_eq!(counter.get("4xx"), 0);
+
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert!(out.ends_with("test_deliveries_total{status=\"2xx\"} 1\ntest_deliveries_total{status=\"5xx\"} 2\n"));
+    }
+
+    #[test]
+    fn test_status_class() {
+        assert_eq!(status_class(204), "2xx");
+        assert_eq!(status_class(302), "3xx");
+        assert_eq!(status_class(429), "4xx");
+        assert_eq!(status_class(503), "5xx");
+    }
+
+    #[test]
+    fn test_gauge() {
+        let gauge = Gauge::new("test_lag_seconds", "Test.");
+        gauge.set(12.5);
+        let mut out = String::new();
+        gauge.render(&mut out);
+        assert!(out.ends_with("# TYPE test_lag_seconds gauge\ntest_lag_seconds 12.5\n"));
+    }
+
+    #[test]
+    fn test_slow_match_is_recorded() {
+        let histogram = DurationHistogram::new("test_duration_seconds", "Test.");
+        histogram.observe(100, Duration::from_millis(20));
+        histogram.observe(10, Duration::from_micros(5));
+
+        let mut out = String::new();
+        histogram.render(&mut out);
+        assert!(out.contains("# TYPE test_duration_seconds histogram"));
+
+        // The slow match only lands in the last bucket for its text length.
+        assert!(out.contains("test_duration_seconds_bucket{text_bytes=\"<=256\",le=\"0.01\"} 0\n"));
+        assert!(out.contains("test_duration_seconds_bucket{text_bytes=\"<=256\",le=\"0.05\"} 1\n"));
+        assert!(out.contains("test_duration_seconds_bucket{text_bytes=\"<=256\",le=\"+Inf\"} 1\n"));
+        assert!(out.contains("test_duration_seconds_sum{text_bytes=\"<=256\"} 0.02\n"));
+
+        // The fast one is in every bucket for its own length.
+        assert!(out.contains("test_duration_seconds_bucket{text_bytes=\"<=64\",le=\"0.00001\"} 1\n"));
+        assert!(out.contains("test_duration_seconds_count{text_bytes=\">1024\"} 0\n"));
+    }
+}
@@ -0,0 +1,21 @@
+use std::time::Duration;
+
+// Defines how an attempt to send a delivery to an endpoint went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryOutcome {
+    // The endpoint answered with one of the success statuses.
+    Success(u16),
+    // The endpoint answered with any other status.
+    Failure(u16),
+    // The endpoint could not be reached, or didn't answer in time.
+    Error,
+    // The payload was over the maximum size, so it was never sent.
+    Oversized,
+}
+
+// Defines something told about every attempt to send a delivery once it is done, with the user's ID, the endpoint, how
+// it went, and how long it took. Deliveries skipped before sending, like in a dry run or by a rate limit, aren't
+// attempts. This is kept apart from the delivery logic so other metrics or alerting can be plugged in.
+pub trait DeliveryObserver: Send + Sync {
+    fn observe(&self, user_id: u64, endpoint: &str, outcome: DeliveryOutcome, latency: Duration);
+}
@@ -0,0 +1,190 @@
+use rsky_lexicon::app::bsky::{embed::{Embeds, MediaUnion}, feed::Post, richtext::Features};
+
+// Separates the fields of a post in the searchable text. Postgres text can't contain a NUL, so no phrase can match
+// across two fields.
+pub const FIELD_SEPARATOR: char = '\0';
+
+// Adds the searchable parts of some embedded media.
+pub fn push_media_text<'a>(fields: &mut Vec<&'a str>, media: &'a MediaUnion) {
+    if let MediaUnion::Images(images) = media {
+        fields.extend(images.images.iter().map(|image| image.alt.as_str()));
+    } else if let MediaUnion::External(external) = media {
+        fields.push(&external.external.title);
+        fields.push(&external.external.description);
+    }
+}
+
+// Gets the parts of the post text outside of its facets. Ranges which are out of bounds or split a character are
+// ignored, since facets come straight from the firehose.
+pub fn unfaceted_text(post: &Post) -> Vec<&str> {
+    let text = post.text.as_str();
+    let mut ranges: Vec<(usize, usize)> = post.facets.iter().flatten()
+        .map(|facet| (facet.index.byte_start, facet.index.byte_end))
+        .filter(|&(start, end)| {
+            start < end && end <= text.len() && text.is_char_boundary(start) && text.is_char_boundary(end)
+        })
+        .collect();
+    ranges.sort_unstable();
+    let mut parts = vec![];
+    let mut cursor = 0;
+    for (start, end) in ranges {
+        if start > cursor {
+            parts.push(&text[cursor..start]);
+        }
+        cursor = cursor.max(end);
+    }
+    parts.push(&text[cursor..]);
+    parts
+}
+
+// Gets the text to search for a post. This is the post text followed by any image alt text, link card titles and
+// descriptions, and the link URIs and hashtags from the facets. The tree normalizes it once when searching. With
+// mask_facets, the mentions, links and tags in the post text are cut out and the text either side of each is its own
+// field, so a phrase can't match inside or across one.
+pub fn searchable_text(post: &Post, mask_facets: bool) -> String {
+    let mut fields = if mask_facets { unfaceted_text(post) } else { vec![post.text.as_str()] };
+    if let Some(embed) = &post.embed {
+        if let Embeds::Images(images) = embed {
+            fields.extend(images.images.iter().map(|image| image.alt.as_str()));
+        } else if let Embeds::External(external) = embed {
+            fields.push(&external.external.title);
+            fields.push(&external.external.description);
+        } else if let Embeds::RecordWithMedia(record_with_media) = embed {
+            push_media_text(&mut fields, &record_with_media.media);
+        }
+    }
+
+    // Join the fields without copying them first. Tags go in with the # they are written with.
+    let mut text = String::new();
+    let mut push_field = |prefix: &str, field: &str| {
+        if field.is_empty() {
+            return;
+        }
+        if !text.is_empty() {
+            text.push(FIELD_SEPARATOR);
+        }
+        text.push_str(prefix);
+        text.push_str(field);
+    };
+    for field in fields {
+        push_field("", field);
+    }
+    for facet in post.facets.iter().flatten() {
+        for feature in &facet.features {
+            if let Features::Link(link) = feature {
+                push_field("", &link.uri);
+            } else if let Features::Tag(tag) = feature {
+                push_field("#", &tag.tag);
+            }
+        }
+    }
+    text
+}
+
+// Cuts searchable text down to the max bytes, so a huge post from malformed firehose data can't take long to match.
+// The cut is at the character boundary before the max. Returns true if the text was cut.
+pub fn truncate_text(text: &mut String, max_bytes: Option<usize>) -> bool {
+    let Some(mut end) = max_bytes.filter(|&max_bytes| text.len() > max_bytes) else {
+        return false;
+    };
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    true
+}
+
+// Gets the text to search for the tags a post carries outside of its text, each with the # it would be written with.
+// These are searched on their own so users can be told a phrase only matched a tag.
+pub fn tags_text(post: &Post) -> String {
+    let mut text = String::new();
+    for tag in post.tags.iter().flatten().filter(|tag| !tag.is_empty()) {
+        if !text.is_empty() {
+            text.push(FIELD_SEPARATOR);
+        }
+        text.push('#');
+        text.push_str(tag);
+    }
+    text
+}
+
+// Gets the URI of the record a post quotes, if it quotes one.
+pub fn quoted_uri(post: &Post) -> Option<&str> {
+    match post.embed.as_ref()? {
+        Embeds::Record(record) => Some(&record.record.uri),
+        Embeds::RecordWithMedia(record_with_media) => Some(&record_with_media.record.record.uri),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bulk_search::{BulkSearchTree, MatchOptions};
+    use serde_json::json;
+
+    #[test]
+    fn test_searchable_text() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "Ünïcode AND ASCII",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [{
+                "index": {"byteStart": 0, "byteEnd": 3},
+                "features": [{"$type": "app.bsky.richtext.facet#tag", "tag": "Tag"}],
+            }],
+        })).unwrap();
+        assert_eq!(searchable_text(&post, false), "Ünïcode AND ASCII\0#Tag");
+        assert_eq!(MatchOptions::default().normalize(&searchable_text(&post, false)), "ünïcode and ascii\0#tag");
+
+        let empty: Post = serde_json::from_value(json!({"text": "", "createdAt": "2024-11-20T00:00:00.000Z"})).unwrap();
+        assert_eq!(searchable_text(&empty, false), "");
+    }
+
+    #[tokio::test]
+    async fn test_masked_facets() {
+        let post: Post = serde_json::from_value(json!({
+            "text": "hey @bob.test, see example.com/x #rust",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [
+                {
+                    "index": {"byteStart": 4, "byteEnd": 13},
+                    "features": [{"$type": "app.bsky.richtext.facet#mention", "did": "did:plc:bob"}],
+                },
+                {
+                    "index": {"byteStart": 19, "byteEnd": 32},
+                    "features": [{"$type": "app.bsky.richtext.facet#link", "uri": "https://example.com/x"}],
+                },
+                {
+                    "index": {"byteStart": 33, "byteEnd": 38},
+                    "features": [{"$type": "app.bsky.richtext.facet#tag", "tag": "rust"}],
+                },
+            ],
+        })).unwrap();
+        assert_eq!(
+            searchable_text(&post, false), "hey @bob.test, see example.com/x #rust\0https://example.com/x\0#rust",
+        );
+        assert_eq!(searchable_text(&post, true), "hey \0, see \0 \0https://example.com/x\0#rust");
+
+        // Phrases inside the mention only match the raw text, while links and tags still match from their facets.
+        let tree = BulkSearchTree::new();
+        tree.add_item("ob", 1).await;
+        assert_eq!(tree.find_all_matches(&searchable_text(&post, false)).await.len(), 1);
+        assert!(tree.find_all_matches(&searchable_text(&post, true)).await.is_empty());
+        tree.add_item("#rust", 1).await;
+        tree.add_item("example.com", 1).await;
+        assert_eq!(tree.find_all_matches(&searchable_text(&post, true)).await.len(), 1);
+
+        // Facets which overlap, are out of bounds, or split a character are handled.
+        let post: Post = serde_json::from_value(json!({
+            "text": "héllo @bob",
+            "createdAt": "2024-11-20T00:00:00.000Z",
+            "facets": [
+                {"index": {"byteStart": 7, "byteEnd": 11}, "features": []},
+                {"index": {"byteStart": 6, "byteEnd": 9}, "features": []},
+                {"index": {"byteStart": 2, "byteEnd": 4}, "features": []},
+                {"index": {"byteStart": 9, "byteEnd": 99}, "features": []},
+            ],
+        })).unwrap();
+        assert_eq!(searchable_text(&post, true), "héllo");
+    }
+}
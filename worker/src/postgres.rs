@@ -1,90 +1,357 @@
-use std::{collections::HashMap, sync::Arc};
-use deadpool_postgres::{Config, GenericClient, ManagerConfig, Object, Pool, RecyclingMethod, Runtime};
-use tokio::sync::RwLock;
-use crate::bulk_search_tree::{BulkSearchTree, User};
+use std::{fmt::Display, future::Future, time::Duration};
+use futures::future::BoxFuture;
+use deadpool_postgres::{
+    tokio_postgres::{types::ToSql, Row}, Config as DeadpoolConfig, ManagerConfig, Pool, PoolError, RecyclingMethod,
+    Runtime,
+};
+use tracing::warn;
+#[cfg(feature = "http")]
+use crate::store::PhraseSaved;
+use crate::{config::Config, store::{StoreError, StoredUsers, UserRecord, UserStore}};
 
-// Setup a connection pool to the Postgres database.
-pub fn init_postgres() -> Pool {
-    // Find the PG_CONNECTION_STRING environment variable.
-    let pg_connection_string = std::env::var("PG_CONNECTION_STRING").expect("PG_CONNECTION_STRING must be set");
+// How many times a query is attempted before giving up, and the delay before the first retry, which doubles each time.
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+// Defines an error from talking to Postgres.
+#[derive(Debug)]
+pub enum PgError {
+    Pool(PoolError),
+    Query(deadpool_postgres::tokio_postgres::Error),
+}
+
+impl Display for PgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PgError::Pool(error) => write!(f, "failed to get a connection: {error}"),
+            PgError::Query(error) => write!(f, "failed to run the query: {error}"),
+        }
+    }
+}
+
+impl From<PoolError> for PgError {
+    fn from(error: PoolError) -> Self {
+        PgError::Pool(error)
+    }
+}
+
+impl From<deadpool_postgres::tokio_postgres::Error> for PgError {
+    fn from(error: deadpool_postgres::tokio_postgres::Error) -> Self {
+        PgError::Query(error)
+    }
+}
+
+// Runs the function until it succeeds or we run out of attempts, backing off between each attempt.
+async fn with_retry<T, F, Fut>(mut f: F) -> Result<T, PgError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, PgError>>,
+{
+    let mut delay = RETRY_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < MAX_ATTEMPTS => {
+                warn!(%error, attempt, "Postgres operation failed. Retrying");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+// Runs a query that returns rows, retrying on failure.
+async fn query(pool: &Pool, statement: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, PgError> {
+    with_retry(|| async move {
+        let conn = pool.get().await?;
+        Ok(conn.query(statement, params).await?)
+    }).await
+}
+
+// Runs a statement that returns the number of rows modified, retrying on failure.
+async fn execute(pool: &Pool, statement: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PgError> {
+    with_retry(|| async move {
+        let conn = pool.get().await?;
+        Ok(conn.execute(statement, params).await?)
+    }).await
+}
 
+// Setup a connection pool to the Postgres database.
+pub fn init_postgres(config: &Config) -> Pool {
     // Setup a SSL pool using the certificate authorities on the system.
     let root_store = rustls::RootCertStore {
-        roots: webpki_roots::TLS_SERVER_ROOTS.iter().cloned().collect(),
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
     };
     let tls_config = rustls::ClientConfig::builder()
         .with_root_certificates(root_store)
         .with_no_client_auth();
 
     // Create the pool.
-    let mut deadpool_cfg = Config::new();
-    deadpool_cfg.url = Some(pg_connection_string);
+    let mut deadpool_cfg = DeadpoolConfig::new();
+    deadpool_cfg.url = Some(config.pg_connection_string.clone());
     deadpool_cfg.manager = Some(ManagerConfig {
         recycling_method: RecyclingMethod::Fast,
     });
+    deadpool_cfg.pool = Some(config.pg_pool);
     let tls = tokio_postgres_rustls::MakeRustlsConnect::new(tls_config);
     deadpool_cfg.create_pool(Some(Runtime::Tokio1), tls).unwrap()
 }
 
-// Delete a user from the pool by their private key.
-pub async fn delete_user(pool: &Pool, private_key: &str) {
-    let conn = pool.get().await.unwrap();
-    conn.execute(
-        "DELETE FROM users WHERE private_key = $1", &[&private_key]
-    ).await.unwrap();
-}
-
-// Internal function to load in a specific user.
-async fn load_user(
-    conn: &Object, mut user: User, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
-) {
-    let hex_s = hex::encode(&user.private_key);
-    let rows = conn.query(
-        "SELECT phrase FROM phrases WHERE private_key = $1", &[&hex_s]
-    ).await.unwrap();
-    let phrases: Vec<String> = rows.iter().map(|row| row.get::<_,String>(0)).collect();
-    user.phrases = phrases;
-    let user_arc = Arc::new(user);
-    if let Some(did) = user_arc.did.clone() {
-        dids.write().await.insert(did, user_arc.clone());
-    }
-    for phrase in user_arc.phrases.iter() {
-        tree.add_item(phrase.as_str(), user_arc.clone()).await;
-    }
-}
-
-// Initialize the data in our local copy.
-pub async fn init_data(pool: &Pool, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>) {
-    let conn = pool.get().await.unwrap();
-    let rows = conn.query(
-        "SELECT did, endpoint, private_key FROM users", &[]
-    ).await.unwrap();
-    for row in rows {
-        let did: Option<String> = row.get(0);
-        let endpoint: String = row.get(1);
-        let private_key: String = row.get(2);
-        let user = User::new(did, endpoint, private_key).unwrap();
-        load_user(&conn, user, tree, dids).await;
-    }
-}
-
-// Initialize a new user by their private key.
-pub async fn init_user(
-    pool: &Pool, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
-    private_key: &str,
-) {
-    let conn = pool.get().await.unwrap();
-    let row = match conn.query_one(
-        "SELECT did, endpoint FROM users WHERE private_key = $1", &[&private_key]
-    ).await {
-        Ok(row) => row,
-        Err(e) => {
-            eprintln!("Error fetching user: {}", e);
-            return;
+// Defines why warming up the pool failed.
+#[derive(Debug)]
+pub enum WarmupError {
+    TimedOut(Duration),
+    Pg(PgError),
+}
+
+impl Display for WarmupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WarmupError::TimedOut(timeout) => write!(f, "timed out after {}ms", timeout.as_millis()),
+            WarmupError::Pg(error) => write!(f, "{error}"),
         }
+    }
+}
+
+// Opens PG_WARMUP_CONNECTIONS connections so the first queries don't pay for them, and checks the tables have the
+// columns we read. This isn't retried, so a bad connection string or an outdated schema stops the worker at startup.
+pub async fn warm_up(config: &Config, pool: &Pool) -> Result<(), WarmupError> {
+    if config.pg_warmup_connections == 0 {
+        return Ok(());
+    }
+    let warm_up = async {
+        // Hold every connection until they are all open, otherwise the pool would hand back the same one each time.
+        let conns = futures::future::try_join_all((0..config.pg_warmup_connections).map(|_| async {
+            let conn = pool.get().await?;
+            conn.execute("SELECT 1", &[]).await?;
+            Ok::<_, PgError>(conn)
+        })).await?;
+        conns[0].execute(&format!("SELECT {USER_COLUMNS} FROM users LIMIT 0"), &[]).await?;
+        conns[0].execute("SELECT private_key, phrase FROM phrases LIMIT 0", &[]).await?;
+        Ok(())
     };
-    let did: Option<String> = row.get(0);
-    let endpoint: String = row.get(1);
-    let user = User::new(did, endpoint, private_key.to_string()).unwrap();
-    load_user(&conn, user, tree, dids).await;
+    match tokio::time::timeout(config.pg_warmup_timeout, warm_up).await {
+        Ok(result) => result.map_err(WarmupError::Pg),
+        Err(_) => Err(WarmupError::TimedOut(config.pg_warmup_timeout)),
+    }
+}
+
+// The user columns read by user_from_row.
+const USER_COLUMNS: &str =
+    "did, endpoint, private_key, replies, signing, secret, ordered, handle, notify_eviction, priority, \
+     extra_endpoints, previous_private_key, paused, previous_dids";
+
+// Reads a row of USER_COLUMNS.
+fn record_from_row(row: &Row) -> UserRecord {
+    UserRecord {
+        did: row.get(0),
+        endpoint: row.get(1),
+        private_key: row.get(2),
+        replies: row.get(3),
+        signing: row.get(4),
+        secret: row.get(5),
+        ordered: row.get(6),
+        handle: row.get(7),
+        notify_eviction: row.get(8),
+        priority: row.get(9),
+        extra_endpoints: row.get(10),
+        previous_private_key: row.get(11),
+        paused: row.get(12),
+        previous_dids: row.get(13),
+    }
+}
+
+// Reads a (private key, phrase) row.
+fn phrase_from_row(row: &Row) -> (String, String) {
+    (row.get(0), row.get(1))
+}
+
+// Defines the users and phrases kept in Postgres.
+pub struct PgStore {
+    pool: Pool,
+}
+
+impl PgStore {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+}
+
+impl UserStore for PgStore {
+    fn load_all(&self) -> BoxFuture<'_, Result<StoredUsers, StoreError>> {
+        Box::pin(async move {
+            // Load all the phrases in one go rather than one query per user.
+            let phrases = query(&self.pool, "SELECT private_key, phrase FROM phrases ORDER BY private_key", &[]).await?;
+            let users = query(&self.pool, &format!("SELECT {USER_COLUMNS} FROM users"), &[]).await?;
+            Ok(StoredUsers {
+                users: users.iter().map(record_from_row).collect(),
+                phrases: phrases.iter().map(phrase_from_row).collect(),
+            })
+        })
+    }
+
+    #[cfg(feature = "http")]
+    fn load_many<'a>(&'a self, private_keys: &'a [String]) -> BoxFuture<'a, Result<StoredUsers, StoreError>> {
+        Box::pin(async move {
+            let phrases = query(
+                &self.pool, "SELECT private_key, phrase FROM phrases WHERE private_key = ANY($1)", &[&private_keys],
+            ).await?;
+            let users = query(
+                &self.pool, &format!("SELECT {USER_COLUMNS} FROM users WHERE private_key = ANY($1)"), &[&private_keys],
+            ).await?;
+            Ok(StoredUsers {
+                users: users.iter().map(record_from_row).collect(),
+                phrases: phrases.iter().map(phrase_from_row).collect(),
+            })
+        })
+    }
+
+    fn load_paused(&self, now_ms: i64) -> BoxFuture<'_, Result<StoredUsers, StoreError>> {
+        Box::pin(async move {
+            let statement = format!("SELECT {USER_COLUMNS} FROM users WHERE paused AND paused_until <= $1");
+            let users = query(&self.pool, &statement, &[&now_ms]).await?;
+            let users: Vec<UserRecord> = users.iter().map(record_from_row).collect();
+            let private_keys: Vec<&str> = users.iter().map(|user| user.private_key.as_str()).collect();
+            let phrases = query(
+                &self.pool, "SELECT private_key, phrase FROM phrases WHERE private_key = ANY($1)", &[&private_keys],
+            ).await?;
+            Ok(StoredUsers { users, phrases: phrases.iter().map(phrase_from_row).collect() })
+        })
+    }
+
+    #[cfg(any(feature = "firehose", feature = "http"))]
+    fn delete_user<'a>(&'a self, private_key: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            execute(&self.pool, "DELETE FROM users WHERE private_key = $1", &[&private_key]).await?;
+            Ok(())
+        })
+    }
+
+    fn set_paused<'a>(
+        &'a self, private_key: &'a str, paused_until: Option<i64>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            execute(
+                &self.pool, "UPDATE users SET paused = $2, paused_until = $3 WHERE private_key = $1",
+                &[&private_key, &paused_until.is_some(), &paused_until],
+            ).await?;
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "http")]
+    fn add_phrase<'a>(
+        &'a self, private_key: &'a str, phrase: &'a str, max_phrases: Option<usize>,
+    ) -> BoxFuture<'a, Result<PhraseSaved, StoreError>> {
+        Box::pin(async move {
+            let saved = with_retry(|| async move {
+                let mut conn = self.pool.get().await?;
+                let transaction = conn.transaction().await?;
+
+                // Lock the user's row until we commit, so anyone else adding a phrase for them waits to count until
+                // this one is saved.
+                transaction.execute("SELECT 1 FROM users WHERE private_key = $1 FOR UPDATE", &[&private_key]).await?;
+                if let Some(max) = max_phrases {
+                    let row = transaction.query_one(
+                        "SELECT COUNT(*) FROM phrases WHERE private_key = $1", &[&private_key],
+                    ).await?;
+                    if row.get::<_, i64>(0) as usize >= max {
+                        return Ok(PhraseSaved::TooMany);
+                    }
+                }
+                let inserted = transaction.execute(
+                    "INSERT INTO phrases (private_key, phrase) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                    &[&private_key, &phrase],
+                ).await?;
+                transaction.commit().await?;
+                Ok(if inserted > 0 { PhraseSaved::Added } else { PhraseSaved::Duplicate })
+            }).await?;
+            Ok(saved)
+        })
+    }
+
+    #[cfg(feature = "http")]
+    fn remove_phrase<'a>(&'a self, private_key: &'a str, phrase: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            execute(
+                &self.pool, "DELETE FROM phrases WHERE private_key = $1 AND phrase = $2", &[&private_key, &phrase],
+            ).await?;
+            Ok(())
+        })
+    }
+}
+
+// Creates a pool pointed at a port nothing is listening on.
+#[cfg(test)]
+pub fn unavailable_pool() -> Pool {
+    let mut cfg = DeadpoolConfig::new();
+    cfg.url = Some("postgres://postgres@127.0.0.1:1/postgres".to_string());
+    cfg.create_pool(Some(Runtime::Tokio1), deadpool_postgres::tokio_postgres::NoTls).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_migrations_add_every_column() {
+        // Every column read is in the schema, and every one the first schema didn't have is added by a migration.
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations");
+        let migrations: String = std::fs::read_dir(dir).unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        let schema = include_str!("../schema.sql");
+        for column in USER_COLUMNS.split(", ").map(str::trim) {
+            assert!(schema.contains(&format!("    {column} ")), "{column} is not in the schema");
+            if !["did", "endpoint", "private_key"].contains(&column) {
+                let added = format!("ADD COLUMN IF NOT EXISTS {column} ");
+                assert!(migrations.contains(&added), "{column} has no migration");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pool_settings_applied() {
+        let config = Config::for_tests(&[("PG_POOL_MAX_SIZE", "7")]);
+        let pool = init_postgres(&config);
+        assert_eq!(pool.status().max_size, 7);
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_db_retries_then_fails() {
+        let pool = unavailable_pool();
+        let start = Instant::now();
+        let result = query(&pool, "SELECT 1", &[]).await;
+        assert!(matches!(result, Err(PgError::Pool(_))));
+
+        // We should have slept between each attempt.
+        assert!(start.elapsed() >= RETRY_BASE_DELAY * 3);
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_fails_fast() {
+        // Nothing is listening, so this fails straight away rather than retrying.
+        let config = Config::for_tests(&[]);
+        let start = Instant::now();
+        let result = warm_up(&config, &unavailable_pool()).await;
+        assert!(matches!(result, Err(WarmupError::Pg(PgError::Pool(_)))));
+        assert!(start.elapsed() < RETRY_BASE_DELAY);
+
+        // A server which never answers times out.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut cfg = DeadpoolConfig::new();
+        cfg.url = Some(format!("postgres://postgres@{}/postgres", listener.local_addr().unwrap()));
+        let pool = cfg.create_pool(Some(Runtime::Tokio1), deadpool_postgres::tokio_postgres::NoTls).unwrap();
+        let config = Config::for_tests(&[("PG_WARMUP_TIMEOUT_MS", "100")]);
+        let result = warm_up(&config, &pool).await;
+        assert!(matches!(result, Err(WarmupError::TimedOut(_))));
+
+        // Turning it off doesn't touch the pool.
+        let config = Config::for_tests(&[("PG_WARMUP_CONNECTIONS", "0")]);
+        assert!(warm_up(&config, &unavailable_pool()).await.is_ok());
+    }
 }
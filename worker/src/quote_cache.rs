@@ -0,0 +1,68 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+
+// Defines the cached posts and the order they were added in.
+#[derive(Default)]
+struct CachedPosts {
+    texts: HashMap<String, Arc<str>>,
+    order: VecDeque<String>,
+}
+
+// Defines a cache of the searchable text of recent posts by URI. Quote embeds on the firehose only reference the quoted
+// post, so this is the only way to see what a quote is quoting. Once full, the oldest post is dropped.
+pub struct QuoteCache {
+    capacity: usize,
+    posts: Mutex<CachedPosts>,
+}
+
+impl QuoteCache {
+    // Creates a cache holding up to capacity posts. A capacity of 0 turns the cache off.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, posts: Mutex::new(CachedPosts::default()) }
+    }
+
+    // Adds the text of a post, dropping the oldest post if the cache is full.
+    pub fn insert(&self, uri: String, text: Arc<str>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut posts = self.posts.lock().unwrap();
+        if posts.texts.insert(uri.clone(), text).is_some() {
+            return;
+        }
+        posts.order.push_back(uri);
+        if posts.order.len() > self.capacity {
+            if let Some(oldest) = posts.order.pop_front() {
+                posts.texts.remove(&oldest);
+            }
+        }
+    }
+
+    // Gets the text of a post if it is still cached.
+    pub fn get(&self, uri: &str) -> Option<Arc<str>> {
+        self.posts.lock().unwrap().texts.get(uri).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_is_dropped() {
+        let cache = QuoteCache::new(2);
+        cache.insert("a".to_string(), "first".into());
+        cache.insert("b".to_string(), "second".into());
+        cache.insert("a".to_string(), "first again".into());
+        cache.insert("c".to_string(), "third".into());
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b").as_deref(), Some("second"));
+        assert_eq!(cache.get("c").as_deref(), Some("third"));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let cache = QuoteCache::new(0);
+        cache.insert("a".to_string(), "first".into());
+        assert_eq!(cache.get("a"), None);
+    }
+}
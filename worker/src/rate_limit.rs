@@ -0,0 +1,167 @@
+use std::{collections::HashMap, hash::Hash, sync::Mutex, time::Instant};
+
+// How many buckets we let build up before dropping the ones that have fully refilled.
+const PRUNE_THRESHOLD: usize = 4096;
+
+// Defines a bucket of tokens that refills over time.
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// Defines the buckets and when we should next prune them.
+struct Buckets<K> {
+    buckets: HashMap<K, TokenBucket>,
+    next_prune: usize,
+}
+
+// Defines a token bucket rate limiter for each key.
+pub struct RateLimiter<K> {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<Buckets<K>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(per_second: f64, burst: f64) -> Self {
+        Self {
+            per_second, burst,
+            buckets: Mutex::new(Buckets { buckets: HashMap::new(), next_prune: PRUNE_THRESHOLD }),
+        }
+    }
+
+    // Takes a token for the key if one is available. Returns false if the key is being rate limited.
+    pub fn try_acquire(&self, key: K, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Drop any buckets which have fully refilled. This is lossless since a new bucket starts full.
+        if buckets.buckets.len() >= buckets.next_prune {
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+                bucket.tokens + elapsed * per_second < burst
+            });
+            buckets.next_prune = PRUNE_THRESHOLD.max(buckets.buckets.len() * 2);
+        }
+
+        // Refill the bucket based on how long it has been since we last touched it.
+        let bucket = buckets.buckets.entry(key).or_insert(TokenBucket { tokens: self.burst, last_refill: now });
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.last_refill = now;
+
+        // Take a token if we can.
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Gives back a token taken for the key, for when whatever it was taken for didn't go ahead.
+    pub fn refund(&self, key: &K) {
+        if let Some(bucket) = self.buckets.lock().unwrap().buckets.get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(self.burst);
+        }
+    }
+}
+
+// Defines the rate limits applied to webhook deliveries. A limiter is None if that limit is disabled.
+pub struct DeliveryLimits {
+    users: Option<RateLimiter<u64>>,
+    hosts: Option<RateLimiter<String>>,
+}
+
+impl DeliveryLimits {
+    pub fn new(users: Option<RateLimiter<u64>>, hosts: Option<RateLimiter<String>>) -> Self {
+        Self { users, hosts }
+    }
+
+    // Checks if a delivery to the user is allowed right now. This is checked once per delivery, however many endpoints
+    // the user has.
+    pub fn allow_user(&self, user_id: u64) -> bool {
+        self.users.as_ref().is_none_or(|users| users.try_acquire(user_id, Instant::now()))
+    }
+
+    // Gives back the user's token for a delivery which wasn't sent anywhere, so a delivery the host limit drops doesn't
+    // count against the user.
+    pub fn refund_user(&self, user_id: u64) {
+        if let Some(users) = &self.users {
+            users.refund(&user_id);
+        }
+    }
+
+    // Checks if a delivery to the host is allowed right now.
+    pub fn allow_host(&self, host: &str) -> bool {
+        self.hosts.as_ref().is_none_or(|hosts| hosts.try_acquire(host.to_string(), Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_burst_is_shed() {
+        let limiter = RateLimiter::new(10.0, 5.0);
+        let now = Instant::now();
+        let allowed = (0..20).filter(|_| limiter.try_acquire(1u64, now)).count();
+        assert_eq!(allowed, 5);
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let limiter = RateLimiter::new(10.0, 5.0);
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(limiter.try_acquire(1u64, now));
+        }
+        assert!(!limiter.try_acquire(1u64, now));
+
+        // 100ms at 10 per second should give us one more token.
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.try_acquire(1u64, later));
+        assert!(!limiter.try_acquire(1u64, later));
+    }
+
+    #[test]
+    fn test_keys_are_independent() {
+        let limiter = RateLimiter::new(1.0, 1.0);
+        let now = Instant::now();
+        assert!(limiter.try_acquire(1u64, now));
+        assert!(!limiter.try_acquire(1u64, now));
+        assert!(limiter.try_acquire(2u64, now));
+    }
+
+    #[test]
+    fn test_host_limit_applies_across_users() {
+        let limits = DeliveryLimits::new(None, Some(RateLimiter::new(1.0, 2.0)));
+        assert!(limits.allow_host("example.com"));
+        assert!(limits.allow_host("example.com"));
+        assert!(!limits.allow_host("example.com"));
+        assert!(limits.allow_host("example.org"));
+    }
+
+    #[test]
+    fn test_refund_leaves_user_bucket_alone() {
+        let limits = DeliveryLimits::new(Some(RateLimiter::new(0.001, 2.0)), Some(RateLimiter::new(0.001, 1.0)));
+        assert!(limits.allow_user(1));
+        assert!(limits.allow_host("example.com"));
+
+        // The host is out of tokens, so the user gets theirs back for another delivery.
+        assert!(limits.allow_user(1));
+        assert!(!limits.allow_host("example.com"));
+        limits.refund_user(1);
+        assert!(limits.allow_user(1));
+        assert!(!limits.allow_user(1));
+    }
+
+    #[test]
+    fn test_no_limits() {
+        let limits = DeliveryLimits::new(None, None);
+        assert!((0..1000).all(|i| limits.allow_user(i) && limits.allow_host("example.com")));
+        limits.refund_user(1);
+    }
+}
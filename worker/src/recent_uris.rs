@@ -0,0 +1,82 @@
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, time::{Duration, Instant}};
+
+// Defines the URIs which have been seen, when they were seen, and the order they were added in.
+#[derive(Default)]
+struct SeenUris {
+    times: HashMap<String, Instant>,
+    order: VecDeque<String>,
+}
+
+// Defines a record of the URIs processed recently. Relays can send the same commit twice, and without this every
+// matched user would be told about it twice. Once full, the oldest URI is forgotten.
+pub struct RecentUris {
+    capacity: usize,
+    window: Duration,
+    seen: Mutex<SeenUris>,
+}
+
+impl RecentUris {
+    // Creates a record holding up to capacity URIs for the window. A capacity or window of 0 turns it off.
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        Self { capacity, window, seen: Mutex::new(SeenUris::default()) }
+    }
+
+    // Records the URI as seen. Returns false if it was already seen within the window.
+    pub fn first_sighting(&self, uri: &str, now: Instant) -> bool {
+        if self.capacity == 0 || self.window.is_zero() {
+            return true;
+        }
+        let mut seen = self.seen.lock().unwrap();
+
+        // Forget the URIs which have fallen out of the window, since they can't be duplicates any more.
+        while let Some(oldest) = seen.order.front() {
+            if seen.times.get(oldest).is_some_and(|&time| now.duration_since(time) < self.window) {
+                break;
+            }
+            let oldest = seen.order.pop_front().unwrap();
+            seen.times.remove(&oldest);
+        }
+
+        if seen.times.contains_key(uri) {
+            return false;
+        }
+        seen.times.insert(uri.to_string(), now);
+        seen.order.push_back(uri.to_string());
+        if seen.order.len() > self.capacity {
+            if let Some(oldest) = seen.order.pop_front() {
+                seen.times.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates_within_window() {
+        let recent = RecentUris::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(recent.first_sighting("a", now));
+        assert!(!recent.first_sighting("a", now + Duration::from_secs(30)));
+
+        // Once the window has passed, the URI is new again.
+        assert!(recent.first_sighting("a", now + Duration::from_secs(61)));
+
+        // The oldest URI is forgotten once the record is full.
+        let now = now + Duration::from_secs(61);
+        assert!(recent.first_sighting("b", now));
+        assert!(recent.first_sighting("c", now));
+        assert!(recent.first_sighting("a", now));
+        assert!(!recent.first_sighting("c", now));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let recent = RecentUris::new(0, Duration::from_secs(60));
+        assert!(recent.first_sighting("a", Instant::now()));
+        assert!(recent.first_sighting("a", Instant::now()));
+    }
+}
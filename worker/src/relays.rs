@@ -0,0 +1,209 @@
+use std::{borrow::Cow, io::Read, time::Duration};
+
+// The bytes every zstd frame starts with. Firehose frames start with a CBOR map, so they can never start with these.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// The most a compressed frame can decompress to, so a small frame can't be used to exhaust our memory.
+const MAX_DECOMPRESSED_FRAME_BYTES: u64 = 16 * 1024 * 1024;
+
+// Defines which firehose relay we are connected to, moving on to the next one after repeated failures.
+pub struct RelayRotation {
+    relays: Vec<String>,
+    current: usize,
+    failures: u32,
+    max_failures: u32,
+}
+
+impl RelayRotation {
+    pub fn new(relays: Vec<String>, max_failures: u32) -> Self {
+        assert!(!relays.is_empty(), "at least one relay is required");
+        Self { relays, current: 0, failures: 0, max_failures }
+    }
+
+    // Gets the relay we should connect to.
+    pub fn current(&self) -> &str {
+        &self.relays[self.current]
+    }
+
+    // Records a failure on the current relay. Returns true if this moved us to the next relay.
+    pub fn record_failure(&mut self) -> bool {
+        self.failures += 1;
+        if self.failures < self.max_failures || self.relays.len() == 1 {
+            return false;
+        }
+        self.failures = 0;
+        self.current = (self.current + 1) % self.relays.len();
+        true
+    }
+
+    // Records that the current relay is working.
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+    }
+}
+
+// How long to wait before reconnecting to the firehose the first time, doubling each time it goes down again quickly.
+pub const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// How long a connection has to stay up before the reconnect delay starts again from the minimum. A relay which accepts
+// the connection and then hangs up straight away is backed off the same as one which refuses it.
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+
+// Defines how long to wait before reconnecting to the firehose.
+pub struct ReconnectBackoff {
+    next: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl ReconnectBackoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self { next: min, min, max }
+    }
+
+    // Gets how long to wait before the next reconnect, doubling the wait for the one after.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    // Records how long a connection stayed up. The wait only starts again from the minimum if it stayed up long enough.
+    pub fn record_connection(&mut self, uptime: Duration) {
+        if uptime >= STABLE_CONNECTION {
+            self.next = self.min;
+        }
+    }
+}
+
+// Gets the subscribeRepos URL for a relay. Relays can be given as a base URL or the full URL.
+pub fn subscribe_url(relay: &str) -> String {
+    if relay.contains("/xrpc/") {
+        relay.to_string()
+    } else {
+        format!("{}/xrpc/com.atproto.sync.subscribeRepos", relay.trim_end_matches('/'))
+    }
+}
+
+// Decompresses a firehose frame if it is zstd compressed. Uncompressed frames are passed through without copying.
+// Returns None if the frame is compressed but can't be decompressed, or is too big once decompressed.
+pub fn decompress_frame(frame: &[u8]) -> Option<Cow<'_, [u8]>> {
+    if !frame.starts_with(&ZSTD_MAGIC) {
+        return Some(Cow::Borrowed(frame));
+    }
+    let decoder = zstd::stream::Decoder::new(frame).ok()?;
+    let mut decompressed = Vec::new();
+    decoder.take(MAX_DECOMPRESSED_FRAME_BYTES + 1).read_to_end(&mut decompressed).ok()?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_FRAME_BYTES {
+        return None;
+    }
+    Some(Cow::Owned(decompressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotation(max_failures: u32) -> RelayRotation {
+        RelayRotation::new(vec!["wss://a.example".to_string(), "wss://b.example".to_string()], max_failures)
+    }
+
+    #[test]
+    fn test_rotates_after_repeated_failures() {
+        let mut relays = rotation(3);
+        assert_eq!(relays.current(), "wss://a.example");
+        assert!(!relays.record_failure());
+        assert!(!relays.record_failure());
+        assert!(relays.record_failure());
+        assert_eq!(relays.current(), "wss://b.example");
+
+        // Wraps back around to the first relay.
+        for _ in 0..2 {
+            assert!(!relays.record_failure());
+        }
+        assert!(relays.record_failure());
+        assert_eq!(relays.current(), "wss://a.example");
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let mut relays = rotation(2);
+        assert!(!relays.record_failure());
+        relays.record_success();
+        assert!(!relays.record_failure());
+        assert_eq!(relays.current(), "wss://a.example");
+    }
+
+    #[test]
+    fn test_single_relay_never_rotates() {
+        let mut relays = RelayRotation::new(vec!["wss://a.example".to_string()], 1);
+        assert!(!relays.record_failure());
+        assert!(!relays.record_failure());
+        assert_eq!(relays.current(), "wss://a.example");
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let mut backoff = ReconnectBackoff::new(Duration::from_millis(500), Duration::from_secs(2));
+        let delays: Vec<_> = (0..4).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, vec![500, 1000, 2000, 2000]);
+
+        // A connection which drops straight away keeps the wait up, but one which stayed up starts it again.
+        backoff.record_connection(Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        backoff.record_connection(STABLE_CONNECTION);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_subscribe_url() {
+        assert_eq!(subscribe_url("wss://bsky.network"), "wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos");
+        assert_eq!(subscribe_url("wss://bsky.network/"), "wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos");
+        assert_eq!(
+            subscribe_url("wss://relay.example/xrpc/com.atproto.sync.subscribeRepos"),
+            "wss://relay.example/xrpc/com.atproto.sync.subscribeRepos",
+        );
+    }
+
+    // Builds a frame like the firehose sends, a CBOR header followed by a CBOR body.
+    fn commit_frame() -> Vec<u8> {
+        let mut frame = serde_cbor::to_vec(&serde_json::json!({"op": 1, "t": "#commit"})).unwrap();
+        frame.extend(serde_cbor::to_vec(&serde_json::json!({
+            "seq": 1234,
+            "repo": "did:plc:jake",
+            "ops": [{"action": "create", "path": "app.bsky.feed.post/1"}],
+        })).unwrap());
+        frame
+    }
+
+    #[test]
+    fn test_compressed_frame_matches_uncompressed() {
+        let frame = commit_frame();
+        let compressed = zstd::encode_all(frame.as_slice(), 3).unwrap();
+        assert!(compressed.starts_with(&ZSTD_MAGIC));
+
+        // Both decode to the same header and body.
+        let decompressed = decompress_frame(&compressed).unwrap();
+        assert!(matches!(decompressed, Cow::Owned(_)));
+        let mut events = serde_cbor::Deserializer::from_slice(&decompressed).into_iter::<serde_cbor::Value>();
+        let mut twin_events = serde_cbor::Deserializer::from_slice(&frame).into_iter::<serde_cbor::Value>();
+        for _ in 0..2 {
+            assert_eq!(events.next().unwrap().unwrap(), twin_events.next().unwrap().unwrap());
+        }
+        assert!(events.next().is_none());
+
+        // Uncompressed frames are not copied.
+        assert!(matches!(decompress_frame(&frame), Some(Cow::Borrowed(bytes)) if bytes == frame.as_slice()));
+    }
+
+    #[test]
+    fn test_bad_compressed_frames() {
+        let mut truncated = zstd::encode_all(commit_frame().as_slice(), 3).unwrap();
+        truncated.truncate(truncated.len() / 2);
+        assert!(decompress_frame(&truncated).is_none());
+
+        let bomb = zstd::encode_all(vec![0u8; MAX_DECOMPRESSED_FRAME_BYTES as usize + 1].as_slice(), 3).unwrap();
+        assert!(decompress_frame(&bomb).is_none());
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+use crypto::{hmac::Hmac, mac::Mac, sha2::Sha256, util::fixed_time_eq};
+use ed25519_dalek::ed25519::signature::SignerMut;
+
+// Performs a ED25519 signature of the json including the timestamp in seconds. Returns the hex encoded signature.
+pub fn sign(private_key: &[u8; 32], ts_seconds: i64, json: &str) -> String {
+    let mut signer = ed25519_dalek::SigningKey::from_bytes(private_key);
+    let new_msg_body = format!("{ts_seconds}{json}");
+    hex::encode(signer.sign(new_msg_body.as_bytes()).to_vec())
+}
+
+// Computes the hex encoded HMAC-SHA256 of the json including the timestamp in seconds, using the shared secret.
+pub fn sign_hmac(secret: &str, ts_seconds: i64, json: &str) -> String {
+    let mut mac = Hmac::new(Sha256::new(), secret.as_bytes());
+    mac.input(ts_seconds.to_string().as_bytes());
+    mac.input(json.as_bytes());
+    hex::encode(mac.result().code())
+}
+
+// Checks a HMAC signature from the X-Signature-HMAC header in constant time.
+pub fn verify_hmac(secret: &str, ts_seconds: i64, json: &str, signature: &str) -> bool {
+    let expected = sign_hmac(secret, ts_seconds, json);
+    fixed_time_eq(expected.as_bytes(), signature.to_ascii_lowercase().as_bytes())
+}
+
+// Checks the signature timestamp is within max_age of now in either direction, so a receiver with a clock a little
+// ahead of or behind the worker still accepts fresh deliveries, but replayed old ones are rejected.
+pub fn signature_fresh(ts_seconds: i64, now_seconds: i64, max_age: Duration) -> bool {
+    now_seconds.abs_diff(ts_seconds) <= max_age.as_secs()
+}
+
+// Checks a hex encoded Ed25519 signature from the X-Signature-Ed25519 header, and that it isn't older than max_age.
+pub fn verify(
+    public_key: &[u8; 32], ts_seconds: i64, json: &str, signature: &str, now_seconds: i64, max_age: Duration,
+) -> bool {
+    if !signature_fresh(ts_seconds, now_seconds, max_age) {
+        return false;
+    }
+    let Ok(public_key) = ed25519_dalek::VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+    let signature = hex::decode(signature).ok().and_then(|bytes| ed25519_dalek::Signature::from_slice(&bytes).ok());
+    let Some(signature) = signature else {
+        return false;
+    };
+    public_key.verify_strict(format!("{ts_seconds}{json}").as_bytes(), &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let private_key = [7u8; 32];
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&private_key).verifying_key().to_bytes();
+        let json = r#"{"test":true}"#;
+        let signature = sign(&private_key, 1_700_000_000, json);
+        let max_age = Duration::from_secs(300);
+        assert!(verify(&public_key, 1_700_000_000, json, &signature, 1_700_000_000, max_age));
+
+        // Clocks a little out in either direction are fine, but anything past the max age isn't.
+        assert!(verify(&public_key, 1_700_000_000, json, &signature, 1_700_000_300, max_age));
+        assert!(verify(&public_key, 1_700_000_000, json, &signature, 1_699_999_700, max_age));
+        assert!(!verify(&public_key, 1_700_000_000, json, &signature, 1_700_000_301, max_age));
+        assert!(!verify(&public_key, 1_700_000_000, json, &signature, 1_699_999_699, max_age));
+
+        // The timestamp, body, and key are all covered by the signature.
+        assert!(!verify(&public_key, 1_700_000_001, json, &signature, 1_700_000_000, max_age));
+        assert!(!verify(&public_key, 1_700_000_000, r#"{"test":false}"#, &signature, 1_700_000_000, max_age));
+        let other_key = ed25519_dalek::SigningKey::from_bytes(&[8u8; 32]).verifying_key().to_bytes();
+        assert!(!verify(&other_key, 1_700_000_000, json, &signature, 1_700_000_000, max_age));
+        assert!(!verify(&public_key, 1_700_000_000, json, "not hex", 1_700_000_000, max_age));
+    }
+
+    #[test]
+    fn test_hmac_round_trip() {
+        let json = r#"{"test":true}"#;
+        let signature = sign_hmac("hunter2", 1_700_000_000, json);
+        assert!(verify_hmac("hunter2", 1_700_000_000, json, &signature));
+        assert!(verify_hmac("hunter2", 1_700_000_000, json, &signature.to_uppercase()));
+        assert!(!verify_hmac("hunter3", 1_700_000_000, json, &signature));
+        assert!(!verify_hmac("hunter2", 1_700_000_001, json, &signature));
+        assert!(!verify_hmac("hunter2", 1_700_000_000, &format!("{json} "), &signature));
+    }
+}
@@ -0,0 +1,159 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use url::{Host, Url};
+
+// Checks if a IPv4 address is private, loopback, link-local, or otherwise not on the public internet.
+fn is_internal_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+        || ip.is_documentation() || ip.is_multicast()
+        // 0.0.0.0/8 ("this network").
+        || a == 0
+        // 100.64.0.0/10 (carrier-grade NAT).
+        || (a == 100 && (b & 0xc0) == 64)
+        // 192.0.0.0/24 (IETF protocol assignments).
+        || (a == 192 && b == 0 && c == 0)
+        // 198.18.0.0/15 (benchmarking).
+        || (a == 198 && (b & 0xfe) == 18)
+        // 240.0.0.0/4 (reserved).
+        || a >= 240
+}
+
+// Pulls out the IPv4 address embedded in an IPv6 one, for the ranges which hand traffic on to IPv4.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        // ::ffff:0:0/96 (IPv4-mapped) and ::/96 (IPv4-compatible).
+        [0, 0, 0, 0, 0, 0 | 0xffff, _, _] => ip.to_ipv4(),
+        // 64:ff9b::/96 (NAT64), where the IPv4 address is the last 32 bits.
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(Ipv4Addr::from(u128::from(ip) as u32)),
+        // 2002::/16 (6to4), where the IPv4 address is the next 32 bits.
+        [0x2002, high, low, ..] => Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+        _ => None,
+    }
+}
+
+// Checks if a IPv6 address is loopback, unique local, link-local, or otherwise not on the public internet.
+fn is_internal_ipv6(ip: Ipv6Addr) -> bool {
+    if ip.is_loopback() || ip.is_unspecified() {
+        return true;
+    }
+    if let Some(ipv4) = embedded_ipv4(ip) {
+        return is_internal_ipv4(ipv4);
+    }
+    let first = ip.segments()[0];
+    ip.is_multicast()
+        // fc00::/7 (unique local).
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10 (link-local).
+        || (first & 0xffc0) == 0xfe80
+        // fec0::/10 (deprecated site-local).
+        || (first & 0xffc0) == 0xfec0
+        // 2001:db8::/32 (documentation).
+        || (first == 0x2001 && ip.segments()[1] == 0xdb8)
+}
+
+// Checks if a IP address should never be delivered to.
+pub fn is_internal_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_ipv4(ip),
+        IpAddr::V6(ip) => is_internal_ipv6(ip),
+    }
+}
+
+// Checks if the endpoint's host is an internal IP address, without resolving hostnames. The delivery client's resolver
+// only filters the addresses hostnames resolve to, so this is what keeps deliveries off IP literals.
+pub fn endpoint_ip_is_internal(endpoint: &str) -> bool {
+    match Url::parse(endpoint).ok().as_ref().and_then(Url::host) {
+        Some(Host::Ipv4(ip)) => is_internal_ipv4(ip),
+        Some(Host::Ipv6(ip)) => is_internal_ipv6(ip),
+        _ => false,
+    }
+}
+
+// Checks if the endpoint points at an internal address, resolving the hostname if needed. If the hostname does not
+// resolve, this returns false and the delivery failure is handled as usual. This costs a lookup, so deliveries only
+// call it once they have failed to connect, to tell if the failure should evict the user with its own reason.
+#[cfg(any(feature = "firehose", feature = "http"))]
+pub async fn endpoint_is_internal(endpoint: &str) -> bool {
+    let Ok(url) = Url::parse(endpoint) else {
+        return false;
+    };
+    let port = url.port_or_known_default().unwrap_or(443);
+    match url.host() {
+        Some(Host::Domain(hostname)) => match crate::dns::lookup(hostname, port).await {
+            Ok(addrs) => addrs.iter().any(|addr| is_internal_ip(addr.ip())),
+            Err(_) => false,
+        },
+        _ => endpoint_ip_is_internal(endpoint),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(ip: &str) -> bool {
+        is_internal_ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_internal_ranges() {
+        assert!(internal("127.0.0.1"));
+        assert!(internal("10.0.0.5"));
+        assert!(internal("169.254.169.254"));
+        assert!(internal("192.168.1.1"));
+        assert!(internal("100.64.0.1"));
+        assert!(internal("::1"));
+        assert!(internal("fd00::1"));
+        assert!(internal("fe80::1"));
+        assert!(internal("::ffff:127.0.0.1"));
+    }
+
+    #[test]
+    fn test_public_addresses() {
+        assert!(!internal("1.1.1.1"));
+        assert!(!internal("8.8.8.8"));
+        assert!(!internal("2606:4700:4700::1111"));
+    }
+
+    #[test]
+    fn test_ipv4_compatible() {
+        assert!(internal("::7f00:1"));
+        assert!(internal("::a00:5"));
+        assert!(!internal("::101:101"));
+    }
+
+    #[test]
+    fn test_nat64() {
+        assert!(internal("64:ff9b::7f00:1"));
+        assert!(internal("64:ff9b::a9fe:a9fe"));
+        assert!(!internal("64:ff9b::808:808"));
+    }
+
+    #[test]
+    fn test_6to4() {
+        assert!(internal("2002:7f00:1::"));
+        assert!(internal("2002:c0a8:101::1"));
+        assert!(!internal("2002:808:808::1"));
+    }
+
+    #[test]
+    fn test_site_local() {
+        assert!(internal("fec0::1"));
+        assert!(internal("feff::1"));
+    }
+
+    #[test]
+    fn test_endpoint_ip_skips_hostnames() {
+        assert!(endpoint_ip_is_internal("http://[::ffff:10.0.0.1]/"));
+        assert!(endpoint_ip_is_internal("http://127.0.0.1:8080/"));
+        assert!(!endpoint_ip_is_internal("http://localhost/"));
+        assert!(!endpoint_ip_is_internal("not a url"));
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_with_ip_literal() {
+        assert!(endpoint_is_internal("http://169.254.169.254/latest/meta-data").await);
+        assert!(endpoint_is_internal("http://[::1]:8080/").await);
+        assert!(!endpoint_is_internal("https://1.1.1.1/webhook").await);
+    }
+}
@@ -0,0 +1,1234 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr, sync::Arc};
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+#[cfg(feature = "http")]
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+#[cfg(feature = "postgres")]
+use crate::postgres::PgError;
+use crate::{bulk_search_tree::{BulkSearchTree, TreeBatch, User, UserError}, config::Config};
+
+// Defines a user as it is kept in the store, before it has been checked. Read from MEMORY_STORE_USERS, fields which
+// are left out get the same defaults as a new row in the users table.
+#[derive(Clone, Debug, Deserialize)]
+pub struct UserRecord {
+    pub private_key: String,
+    #[serde(default)]
+    pub did: Option<String>,
+    #[serde(default)]
+    pub previous_dids: Vec<String>,
+    pub endpoint: String,
+    #[serde(default = "default_replies")]
+    pub replies: bool,
+    #[serde(default = "default_signing")]
+    pub signing: String,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub ordered: bool,
+    #[serde(default)]
+    pub handle: Option<String>,
+    #[serde(default)]
+    pub notify_eviction: bool,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub extra_endpoints: Vec<String>,
+    #[serde(default)]
+    pub previous_private_key: Option<String>,
+
+    // Paused users aren't loaded until their endpoint answers a probe.
+    #[serde(default)]
+    pub paused: bool,
+}
+
+fn default_replies() -> bool {
+    true
+}
+
+fn default_signing() -> String {
+    "ed25519".to_string()
+}
+
+// Defines a user the memory store starts with, along with their phrases.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SeedUser {
+    #[serde(flatten)]
+    pub user: UserRecord,
+    #[serde(default)]
+    pub phrases: Vec<String>,
+}
+
+impl UserRecord {
+    // Creates a record with the same defaults as a new row in the users table.
+    #[cfg(test)]
+    pub fn new(private_key: String, endpoint: String) -> Self {
+        Self {
+            private_key, did: None, previous_dids: vec![], endpoint, replies: default_replies(),
+            signing: default_signing(), secret: None, ordered: false, handle: None, notify_eviction: false, priority: 0,
+            extra_endpoints: vec![], previous_private_key: None, paused: false,
+        }
+    }
+
+    // Builds the user, checking everything the store doesn't.
+    pub fn into_user(self, config: &Config) -> Result<User, UserError> {
+        let mut user = User::new(self.did, self.endpoint, self.private_key)?;
+        user.previous_dids = self.previous_dids;
+        user.replies = self.replies;
+        user.set_signing(self.signing.parse()?, self.secret)?;
+        user.ordered = self.ordered;
+        user.set_handle(self.handle);
+        user.notify_eviction = self.notify_eviction;
+        user.priority = self.priority;
+        for endpoint in self.extra_endpoints {
+            user.add_endpoint(endpoint)?;
+        }
+        user.set_previous_key(self.previous_private_key)?;
+        user.require_https(config.allow_insecure_endpoints)?;
+        user.require_allowed_host(config.endpoint_host_allowlist.as_deref())?;
+        Ok(user)
+    }
+}
+
+// Defines users read from the store along with their phrases, as (private key, phrase) pairs.
+#[derive(Default)]
+pub struct StoredUsers {
+    pub users: Vec<UserRecord>,
+    pub phrases: Vec<(String, String)>,
+}
+
+// Defines which store the worker keeps users and their phrases in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StoreKind {
+    #[cfg(feature = "postgres")]
+    Postgres,
+    // Users only live as long as the worker, so this is for trying the worker out and for tests.
+    Memory,
+}
+
+impl Default for StoreKind {
+    fn default() -> Self {
+        #[cfg(feature = "postgres")]
+        return StoreKind::Postgres;
+        #[cfg(not(feature = "postgres"))]
+        return StoreKind::Memory;
+    }
+}
+
+impl FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            #[cfg(feature = "postgres")]
+            "postgres" => Ok(StoreKind::Postgres),
+            #[cfg(not(feature = "postgres"))]
+            "postgres" => Err("the worker was built without the postgres feature".to_string()),
+            "memory" => Ok(StoreKind::Memory),
+            _ => Err("expected postgres or memory".to_string()),
+        }
+    }
+}
+
+// Defines an error from a store.
+#[derive(Debug)]
+pub enum StoreError {
+    #[cfg(feature = "postgres")]
+    Pg(PgError),
+    // The memory store was made unavailable by a test.
+    #[cfg(test)]
+    Unavailable,
+}
+
+impl Display for StoreError {
+    #[cfg_attr(not(any(test, feature = "postgres")), allow(unused_variables))]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            #[cfg(feature = "postgres")]
+            StoreError::Pg(ref error) => write!(f, "{error}"),
+            #[cfg(test)]
+            StoreError::Unavailable => write!(f, "the store is unavailable"),
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<PgError> for StoreError {
+    fn from(error: PgError) -> Self {
+        StoreError::Pg(error)
+    }
+}
+
+// Defines what happened when saving a phrase for a user.
+#[cfg(feature = "http")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PhraseSaved {
+    Added,
+    Duplicate,
+    TooMany,
+}
+
+// Defines where users and their phrases are kept.
+pub trait UserStore: Send + Sync {
+    // Reads every user and all of their phrases.
+    fn load_all(&self) -> BoxFuture<'_, Result<StoredUsers, StoreError>>;
+
+    // Reads the users with the given private keys and their phrases. Keys with no user are left out.
+    #[cfg(feature = "http")]
+    fn load_many<'a>(&'a self, private_keys: &'a [String]) -> BoxFuture<'a, Result<StoredUsers, StoreError>>;
+
+    // Reads the paused users who are due to be probed at the given time, in milliseconds since the epoch, and their
+    // phrases.
+    fn load_paused(&self, now_ms: i64) -> BoxFuture<'_, Result<StoredUsers, StoreError>>;
+
+    // Deletes a user and their phrases.
+    #[cfg(any(feature = "firehose", feature = "http"))]
+    fn delete_user<'a>(&'a self, private_key: &'a str) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    // Pauses a user until the given time, or unpauses them if it is None.
+    fn set_paused<'a>(
+        &'a self, private_key: &'a str, paused_until: Option<i64>,
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    // Saves a phrase for a user unless they already have it or it would take them over the cap. The cap is checked in
+    // the same step as the phrase is saved, so two phrases added at once can't both fit under it.
+    #[cfg(feature = "http")]
+    fn add_phrase<'a>(
+        &'a self, private_key: &'a str, phrase: &'a str, max_phrases: Option<usize>,
+    ) -> BoxFuture<'a, Result<PhraseSaved, StoreError>>;
+
+    // Deletes a phrase from a user.
+    #[cfg(feature = "http")]
+    fn remove_phrase<'a>(&'a self, private_key: &'a str, phrase: &'a str) -> BoxFuture<'a, Result<(), StoreError>>;
+}
+
+// Defines a store which only lives in memory. This is the store with STORE=memory, which starts with the users in
+// MEMORY_STORE_USERS, and lets the loading and eviction paths be tested without a database.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: std::sync::Mutex<StoredUsers>,
+
+    // When each paused user is next due to be probed.
+    paused_until: std::sync::Mutex<std::collections::HashMap<String, i64>>,
+
+    // Makes every call fail, like a database which can't be reached.
+    #[cfg(test)]
+    unavailable: AtomicBool,
+}
+
+impl MemoryStore {
+    // Creates a store where every call fails.
+    #[cfg(test)]
+    pub fn unavailable() -> Self {
+        Self { unavailable: AtomicBool::new(true), ..Self::default() }
+    }
+
+    // Fails if a test made the store unavailable.
+    fn check_available(&self) -> Result<(), StoreError> {
+        #[cfg(test)]
+        if self.unavailable.load(Ordering::Relaxed) {
+            return Err(StoreError::Unavailable);
+        }
+        Ok(())
+    }
+
+    // Creates a store holding the given users with their phrases.
+    pub fn seeded(users: &[SeedUser]) -> Self {
+        let store = Self::default();
+        for seed in users {
+            store.insert(seed.user.clone(), &seed.phrases);
+        }
+        store
+    }
+
+    // Adds a user with their phrases.
+    pub fn insert(&self, user: UserRecord, phrases: &[impl AsRef<str>]) {
+        let mut data = self.data.lock().unwrap();
+        data.phrases.extend(phrases.iter().map(|phrase| (user.private_key.clone(), phrase.as_ref().to_string())));
+        data.users.push(user);
+    }
+
+    // Gets the private keys of the users in the store.
+    #[cfg(test)]
+    pub fn private_keys(&self) -> Vec<String> {
+        self.data.lock().unwrap().users.iter().map(|user| user.private_key.clone()).collect()
+    }
+
+    // Gets the phrases in the store for a user.
+    #[cfg(test)]
+    pub fn phrases(&self, private_key: &str) -> Vec<String> {
+        let data = self.data.lock().unwrap();
+        data.phrases.iter().filter(|(key, _)| key == private_key).map(|(_, phrase)| phrase.clone()).collect()
+    }
+
+    // Gets a copy of a user in the store.
+    #[cfg(test)]
+    pub fn get(&self, private_key: &str) -> Option<UserRecord> {
+        self.data.lock().unwrap().users.iter().find(|user| user.private_key == private_key).cloned()
+    }
+
+    // Gets when a paused user is next due to be probed.
+    pub fn paused_until(&self, private_key: &str) -> Option<i64> {
+        self.paused_until.lock().unwrap().get(private_key).copied()
+    }
+
+    // Copies out the users which pass the check, along with their phrases.
+    fn load(&self, wanted: impl Fn(&UserRecord) -> bool) -> StoredUsers {
+        let data = self.data.lock().unwrap();
+        let users: Vec<UserRecord> = data.users.iter().filter(|user| wanted(user)).cloned().collect();
+        let phrases = data.phrases.iter()
+            .filter(|(private_key, _)| users.iter().any(|user| &user.private_key == private_key))
+            .cloned()
+            .collect();
+        StoredUsers { users, phrases }
+    }
+}
+
+impl UserStore for MemoryStore {
+    fn load_all(&self) -> BoxFuture<'_, Result<StoredUsers, StoreError>> {
+        Box::pin(async move {
+            self.check_available()?;
+            Ok(self.load(|_| true))
+        })
+    }
+
+    #[cfg(feature = "http")]
+    fn load_many<'a>(&'a self, private_keys: &'a [String]) -> BoxFuture<'a, Result<StoredUsers, StoreError>> {
+        Box::pin(async move {
+            self.check_available()?;
+            Ok(self.load(|user| private_keys.contains(&user.private_key)))
+        })
+    }
+
+    fn load_paused(&self, now_ms: i64) -> BoxFuture<'_, Result<StoredUsers, StoreError>> {
+        Box::pin(async move {
+            self.check_available()?;
+            let due = |user: &UserRecord| {
+                user.paused && self.paused_until(&user.private_key).is_some_and(|until| until <= now_ms)
+            };
+            Ok(self.load(due))
+        })
+    }
+
+    #[cfg(any(feature = "firehose", feature = "http"))]
+    fn delete_user<'a>(&'a self, private_key: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.check_available()?;
+            let mut data = self.data.lock().unwrap();
+            data.users.retain(|user| user.private_key != private_key);
+            data.phrases.retain(|(key, _)| key != private_key);
+            Ok(())
+        })
+    }
+
+    fn set_paused<'a>(
+        &'a self, private_key: &'a str, paused_until: Option<i64>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.check_available()?;
+            let mut data = self.data.lock().unwrap();
+            if let Some(user) = data.users.iter_mut().find(|user| user.private_key == private_key) {
+                user.paused = paused_until.is_some();
+                let mut paused = self.paused_until.lock().unwrap();
+                match paused_until {
+                    Some(until) => paused.insert(private_key.to_string(), until),
+                    None => paused.remove(private_key),
+                };
+            }
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "http")]
+    fn add_phrase<'a>(
+        &'a self, private_key: &'a str, phrase: &'a str, max_phrases: Option<usize>,
+    ) -> BoxFuture<'a, Result<PhraseSaved, StoreError>> {
+        Box::pin(async move {
+            self.check_available()?;
+            let mut data = self.data.lock().unwrap();
+            let existing = data.phrases.iter().filter(|(key, _)| key == private_key).map(|(_, existing)| existing);
+            if existing.clone().any(|existing| existing == phrase) {
+                return Ok(PhraseSaved::Duplicate);
+            }
+            if max_phrases.is_some_and(|max| existing.count() >= max) {
+                return Ok(PhraseSaved::TooMany);
+            }
+            data.phrases.push((private_key.to_string(), phrase.to_string()));
+            Ok(PhraseSaved::Added)
+        })
+    }
+
+    #[cfg(feature = "http")]
+    fn remove_phrase<'a>(&'a self, private_key: &'a str, phrase: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.check_available()?;
+            self.data.lock().unwrap().phrases.retain(|(key, existing)| key != private_key || existing != phrase);
+            Ok(())
+        })
+    }
+}
+
+// Inserts a user with their phrases into our local copy, replacing any copy of them which is already loaded. Phrases
+// the tree won't accept are dropped from the user.
+pub async fn insert_user(
+    user: User, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
+    keys: &RwLock<HashMap<String, Arc<User>>>,
+) {
+    insert_users([user], tree, dids, keys).await;
+}
+
+// Inserts users like insert_user, in one batch of changes to the tree. The automaton backend is only built once for
+// the batch rather than for every phrase, so loads of many users should go through here.
+pub async fn insert_users(
+    users: impl IntoIterator<Item = User>, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
+    keys: &RwLock<HashMap<String, Arc<User>>>,
+) {
+    let mut batch = tree.batch().await;
+    for user in users {
+        insert_user_into(user, tree, &mut batch, dids, keys).await;
+    }
+}
+
+// Inserts a user with their phrases as part of a batch of changes to the tree.
+async fn insert_user_into(
+    mut user: User, tree: &BulkSearchTree, batch: &mut TreeBatch<'_>, dids: &RwLock<HashMap<String, Arc<User>>>,
+    keys: &RwLock<HashMap<String, Arc<User>>>,
+) {
+    let user_id = user.id;
+    user.retain_phrases(|phrase| {
+        let accepted = tree.accepts(phrase);
+        if !accepted {
+            warn!(user_id, phrase, "Ignoring a phrase which is too short or too long");
+        }
+        accepted
+    });
+    let user_arc = Arc::new(user);
+
+    // The new copy takes over the phrases of any old one before the phrases it no longer has are removed, so a post
+    // matching a phrase the user kept is never missed while they are reloaded.
+    let phrases = tree_phrases(&user_arc, tree);
+    for phrase in &phrases {
+        batch.replace_item(phrase, user_arc.clone());
+    }
+    let existing = keys.write().await.insert(hex::encode(user_arc.private_key), user_arc.clone());
+    register_dids(&user_arc, dids).await;
+    let Some(existing) = existing else {
+        return;
+    };
+    for phrase in tree_phrases(&existing, tree) {
+        if !phrases.iter().any(|kept| tree.same_phrase(kept, &phrase)) {
+            batch.remove_item(&phrase, &existing);
+        }
+    }
+
+    // Leave the DIDs the new copy has alone.
+    release_dids(&existing, existing.dids().filter(|&did| !user_arc.dids().any(|kept| kept == did)), dids).await;
+}
+
+// Gets every phrase the user is in the tree for. Plain text mentions of their handle are matched like a phrase, but it
+// is kept apart from their own phrases, so it isn't stored, listed, deleted or counted towards MAX_PHRASES_PER_USER.
+pub fn tree_phrases(user: &User, tree: &BulkSearchTree) -> Vec<String> {
+    let mut phrases = user.phrases();
+    phrases.extend(user.handle_phrase().filter(|phrase| tree.accepts(phrase)));
+    phrases
+}
+
+// Points each of the user's DIDs at them. A previous DID is never taken from a user who has it as their current DID.
+async fn register_dids(user: &Arc<User>, dids: &RwLock<HashMap<String, Arc<User>>>) {
+    let mut dids = dids.write().await;
+    if let Some(did) = &user.did {
+        dids.insert(did.clone(), user.clone());
+    }
+    for did in &user.previous_dids {
+        if !dids.get(did).is_some_and(|current| current.id != user.id && current.did.as_ref() == Some(did)) {
+            dids.insert(did.clone(), user.clone());
+        }
+    }
+}
+
+// Removes the DIDs which still point at the user, leaving alone any another user has taken since.
+async fn release_dids<'a>(
+    user: &User, released: impl Iterator<Item = &'a str>, dids: &RwLock<HashMap<String, Arc<User>>>,
+) {
+    let mut dids = dids.write().await;
+    for did in released {
+        if dids.get(did).is_some_and(|current| current.id == user.id) {
+            dids.remove(did);
+        }
+    }
+}
+
+// Removes a user and their phrases from our local copy.
+#[cfg(any(feature = "firehose", feature = "http"))]
+async fn remove_user(
+    user: &Arc<User>, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
+    keys: &RwLock<HashMap<String, Arc<User>>>,
+) {
+    release_dids(user, user.dids(), dids).await;
+    keys.write().await.remove(&hex::encode(user.private_key));
+    for phrase in tree_phrases(user, tree) {
+        // This can be improved, but it is so rare that its not a big deal.
+        tree.remove_item(&phrase, user).await;
+    }
+}
+
+// Defines why a phrase could not be added to a user.
+#[cfg(feature = "http")]
+#[derive(Debug)]
+pub enum PhraseError {
+    // The phrase is blank or shorter than the minimum length once normalized.
+    TooShort,
+    // The phrase is over the maximum number of bytes once normalized.
+    TooLong(usize),
+    // The user already has the most phrases they are allowed.
+    TooMany(usize),
+    // The user already has the phrase.
+    Duplicate,
+    Store(StoreError),
+}
+
+#[cfg(feature = "http")]
+impl Display for PhraseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhraseError::TooShort => write!(f, "phrase is too short"),
+            PhraseError::TooLong(max) => write!(f, "phrase is over the maximum of {max} bytes"),
+            PhraseError::TooMany(max) => write!(f, "user already has the maximum of {max} phrases"),
+            PhraseError::Duplicate => write!(f, "user already has the phrase"),
+            PhraseError::Store(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<StoreError> for PhraseError {
+    fn from(error: StoreError) -> Self {
+        PhraseError::Store(error)
+    }
+}
+
+// Checks a phrase can be added to a loaded user. Phrases which normalize to one the user already has are duplicates,
+// since they would be the same place in the tree.
+#[cfg(feature = "http")]
+fn check_new_phrase(
+    user: &User, tree: &BulkSearchTree, phrase: &str, max_phrases: Option<usize>,
+) -> Result<(), PhraseError> {
+    if tree.too_long(phrase) {
+        return Err(PhraseError::TooLong(tree.max_bytes()));
+    }
+    if !tree.accepts(phrase) {
+        return Err(PhraseError::TooShort);
+    }
+    let phrases = user.phrases();
+    if phrases.iter().any(|existing| tree.same_phrase(existing, phrase)) {
+        return Err(PhraseError::Duplicate);
+    }
+    match max_phrases {
+        Some(max) if phrases.len() >= max => Err(PhraseError::TooMany(max)),
+        _ => Ok(()),
+    }
+}
+
+// Adds a phrase to a loaded user in our local copy.
+#[cfg(feature = "http")]
+async fn insert_phrase(user: &Arc<User>, tree: &BulkSearchTree, phrase: &str) {
+    user.add_phrase(phrase);
+    tree.add_item(phrase, user.clone()).await;
+}
+
+// Adds a phrase to a loaded user, saving it to the store before it is matched on so the two stay in step.
+#[cfg(feature = "http")]
+pub async fn add_phrase(
+    config: &Config, store: &dyn UserStore, tree: &BulkSearchTree, user: &Arc<User>, phrase: &str,
+) -> Result<(), PhraseError> {
+    let max_phrases = config.max_phrases_per_user;
+    check_new_phrase(user, tree, phrase, max_phrases)?;
+
+    // The store checks the cap again, since another request may have added a phrase since we checked.
+    match store.add_phrase(&hex::encode(user.private_key), phrase, max_phrases).await? {
+        PhraseSaved::Added => {}
+        PhraseSaved::Duplicate => return Err(PhraseError::Duplicate),
+        PhraseSaved::TooMany => return Err(PhraseError::TooMany(max_phrases.unwrap_or_default())),
+    }
+    insert_phrase(user, tree, phrase).await;
+    Ok(())
+}
+
+// Finds the phrase a loaded user has which is the same as the given one once normalized, as it is stored.
+#[cfg(feature = "http")]
+fn find_phrase(user: &User, tree: &BulkSearchTree, phrase: &str) -> Option<String> {
+    user.phrases().into_iter().find(|existing| tree.same_phrase(existing, phrase))
+}
+
+// Removes a phrase from a loaded user in our local copy. The user stays in the tree if they have another phrase which
+// normalizes to the same thing, or it is the mention of their handle.
+#[cfg(feature = "http")]
+async fn drop_phrase(user: &Arc<User>, tree: &BulkSearchTree, phrase: &str) {
+    user.remove_phrase(phrase);
+    if !tree_phrases(user, tree).iter().any(|existing| tree.same_phrase(existing, phrase)) {
+        tree.remove_item(phrase, user).await;
+    }
+}
+
+// Removes a phrase from a loaded user, deleting it from the store first so the two stay in step. Returns false if the
+// user does not have the phrase.
+#[cfg(feature = "http")]
+pub async fn remove_phrase(
+    store: &dyn UserStore, tree: &BulkSearchTree, user: &Arc<User>, phrase: &str,
+) -> Result<bool, StoreError> {
+    let Some(phrase) = find_phrase(user, tree, phrase) else {
+        return Ok(false);
+    };
+    store.remove_phrase(&hex::encode(user.private_key), &phrase).await?;
+    drop_phrase(user, tree, &phrase).await;
+    Ok(true)
+}
+
+// Evicts a user, removing them from our local copy and then from the store.
+#[cfg(any(feature = "firehose", feature = "http"))]
+pub async fn evict_user(
+    store: &dyn UserStore, user: &Arc<User>, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
+    keys: &RwLock<HashMap<String, Arc<User>>>,
+) -> Result<(), StoreError> {
+    remove_user(user, tree, dids, keys).await;
+    store.delete_user(&hex::encode(user.private_key)).await
+}
+
+// Pauses a user, removing them from our local copy but keeping them in the store to be probed from paused_until.
+#[cfg(feature = "firehose")]
+pub async fn pause_user(
+    store: &dyn UserStore, user: &Arc<User>, paused_until: i64, tree: &BulkSearchTree,
+    dids: &RwLock<HashMap<String, Arc<User>>>, keys: &RwLock<HashMap<String, Arc<User>>>,
+) -> Result<(), StoreError> {
+    remove_user(user, tree, dids, keys).await;
+    store.set_paused(&hex::encode(user.private_key), Some(paused_until)).await
+}
+
+// Unpauses a user and loads them back into our local copy, updating the store first so the two stay in step.
+pub async fn resume_user(
+    store: &dyn UserStore, user: User, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
+    keys: &RwLock<HashMap<String, Arc<User>>>,
+) -> Result<(), StoreError> {
+    store.set_paused(&hex::encode(user.private_key), None).await?;
+    insert_user(user, tree, dids, keys).await;
+    Ok(())
+}
+
+// Reads and builds the paused users who are due to be probed, with their phrases.
+pub async fn paused_users(
+    config: &Config, store: &dyn UserStore, now_ms: i64,
+) -> Result<Vec<(String, Result<User, UserError>)>, StoreError> {
+    let stored = store.load_paused(now_ms).await?;
+    let mut phrases = group_phrases(stored.phrases);
+    let mut users = users_from_records(config, stored.users);
+    for (private_key, user) in &mut users {
+        if let Ok(user) = user {
+            user.set_phrases(phrases.remove(private_key).unwrap_or_default());
+        }
+    }
+    Ok(users)
+}
+
+// Groups (private key, phrase) pairs into a map of private key to phrases.
+fn group_phrases(rows: impl IntoIterator<Item = (String, String)>) -> HashMap<String, Vec<String>> {
+    let mut phrases: HashMap<String, Vec<String>> = HashMap::new();
+    for (private_key, phrase) in rows {
+        phrases.entry(private_key).or_default().push(phrase);
+    }
+    phrases
+}
+
+// Checks and builds the users read from the store, keeping their private keys so their phrases can be found.
+fn users_from_records(config: &Config, records: Vec<UserRecord>) -> Vec<(String, Result<User, UserError>)> {
+    records.into_iter().map(|record| (record.private_key.clone(), record.into_user(config))).collect()
+}
+
+// Initialize the data in our local copy.
+pub async fn init_data(
+    config: &Config, store: &dyn UserStore, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
+    keys: &RwLock<HashMap<String, Arc<User>>>,
+) -> Result<(), StoreError> {
+    let stored = store.load_all().await?;
+    let mut phrases = group_phrases(stored.phrases);
+    let (paused, records): (Vec<UserRecord>, Vec<UserRecord>) = stored.users.into_iter().partition(|user| user.paused);
+    let users = users_from_records(config, records);
+    let (loaded, skipped) = insert_initial_users(users, &mut phrases, tree, dids, keys).await;
+    info!(loaded, skipped, paused = paused.len(), "Loaded the users");
+    Ok(())
+}
+
+// Inserts the users read at startup with their phrases. Invalid users are skipped so one bad row can't stop everyone
+// else from loading. Returns how many users were loaded and how many were skipped.
+async fn insert_initial_users(
+    users: Vec<(String, Result<User, UserError>)>, phrases: &mut HashMap<String, Vec<String>>, tree: &BulkSearchTree,
+    dids: &RwLock<HashMap<String, Arc<User>>>, keys: &RwLock<HashMap<String, Arc<User>>>,
+) -> (usize, usize) {
+    let mut skipped = 0;
+    let mut valid = vec![];
+    for (private_key, user) in users {
+        let user_phrases = phrases.remove(&private_key).unwrap_or_default();
+        let mut user = match user {
+            Ok(user) => user,
+            Err(error) => {
+                warn!(%error, "Skipping invalid user");
+                skipped += 1;
+                continue;
+            }
+        };
+        user.set_phrases(user_phrases);
+        valid.push(user);
+    }
+    let loaded = valid.len();
+    insert_users(valid, tree, dids, keys).await;
+    (loaded, skipped)
+}
+
+// Initialize a new user by their private key. Returns what happened to them.
+#[cfg(feature = "http")]
+pub async fn init_user(
+    config: &Config, store: &dyn UserStore, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
+    keys: &RwLock<HashMap<String, Arc<User>>>, private_key: &str,
+) -> Result<LoadResult, StoreError> {
+    let stored = store.load_many(&[private_key.to_string()]).await?;
+    let Some(record) = stored.users.into_iter().next() else {
+        warn!("User to initialize was not found");
+        return Ok(LoadResult::NotFound);
+    };
+    if record.paused {
+        info!("User to initialize is paused");
+        return Ok(LoadResult::Paused);
+    }
+    let mut user = match record.into_user(config) {
+        Ok(user) => user,
+        Err(error) => {
+            warn!(%error, "Rejecting invalid user");
+            return Ok(LoadResult::Invalid);
+        }
+    };
+    user.set_phrases(stored.phrases.into_iter().map(|(_, phrase)| phrase).collect());
+    insert_user(user, tree, dids, keys).await;
+    Ok(LoadResult::Loaded)
+}
+
+// Defines what happened to one of the users in a bulk load.
+#[cfg(feature = "http")]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadResult {
+    Loaded,
+    NotFound,
+    Invalid,
+    Paused,
+}
+
+// Inserts the users read for a bulk load with their phrases, and works out what happened to each requested key.
+#[cfg(feature = "http")]
+async fn insert_bulk_users(
+    requested: &[String], users: Vec<(String, Result<User, UserError>)>, mut phrases: HashMap<String, Vec<String>>,
+    tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>, keys: &RwLock<HashMap<String, Arc<User>>>,
+) -> HashMap<String, LoadResult> {
+    let mut results: HashMap<String, LoadResult> = requested.iter()
+        .map(|private_key| (private_key.clone(), LoadResult::NotFound))
+        .collect();
+    let mut valid = vec![];
+    for (private_key, user) in users {
+        let result = match user {
+            Ok(mut user) => {
+                user.set_phrases(phrases.remove(&private_key).unwrap_or_default());
+                valid.push(user);
+                LoadResult::Loaded
+            }
+            Err(error) => {
+                warn!(%error, "Rejecting invalid user");
+                LoadResult::Invalid
+            }
+        };
+        results.insert(private_key, result);
+    }
+    insert_users(valid, tree, dids, keys).await;
+    results
+}
+
+// Initialize many users by their private keys in one go. Returns what happened to each key.
+#[cfg(feature = "http")]
+pub async fn init_users(
+    config: &Config, store: &dyn UserStore, tree: &BulkSearchTree, dids: &RwLock<HashMap<String, Arc<User>>>,
+    keys: &RwLock<HashMap<String, Arc<User>>>, private_keys: &[String],
+) -> Result<HashMap<String, LoadResult>, StoreError> {
+    let stored = store.load_many(private_keys).await?;
+    let phrases = group_phrases(stored.phrases);
+    let (paused, records): (Vec<UserRecord>, Vec<UserRecord>) = stored.users.into_iter().partition(|user| user.paused);
+    let users = users_from_records(config, records);
+    let mut results = insert_bulk_users(private_keys, users, phrases, tree, dids, keys).await;
+    for record in paused {
+        results.insert(record.private_key, LoadResult::Paused);
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bulk_search_tree::{test_key, MatchOptions, SearchBackend};
+
+    #[test]
+    fn test_group_phrases() {
+        let grouped = group_phrases(vec![
+            (test_key("aa"), "hello".to_string()),
+            (test_key("aa"), "world".to_string()),
+            (test_key("bb"), "rust".to_string()),
+        ]);
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[&test_key("aa")], vec!["hello", "world"]);
+        assert_eq!(grouped[&test_key("bb")], vec!["rust"]);
+    }
+
+    #[tokio::test]
+    async fn test_batched_load_matches_per_user_load() {
+        let rows = vec![
+            (test_key("aa"), "hello".to_string()),
+            (test_key("aa"), "world".to_string()),
+            (test_key("bb"), "or".to_string()),
+        ];
+        let mut grouped = group_phrases(rows.clone());
+
+        // Load the users from the batched phrases.
+        let batched_tree = BulkSearchTree::new();
+        let batched_dids = RwLock::new(HashMap::new());
+        for key in [test_key("aa"), test_key("bb")] {
+            let mut user = User::new(None, "https://example.com".to_string(), key.clone()).unwrap();
+            user.set_phrases(grouped.remove(&key).unwrap());
+            insert_user(user, &batched_tree, &batched_dids, &RwLock::new(HashMap::new())).await;
+        }
+
+        // Load the users one at a time like the old path did.
+        let single_tree = BulkSearchTree::new();
+        let single_dids = RwLock::new(HashMap::new());
+        for key in [test_key("aa"), test_key("bb")] {
+            let mut user = User::new(None, "https://example.com".to_string(), key.clone()).unwrap();
+            user.set_phrases(rows.iter().filter(|(k, _)| *k == key).map(|(_, p)| p.clone()).collect());
+            insert_user(user, &single_tree, &single_dids, &RwLock::new(HashMap::new())).await;
+        }
+
+        for text in ["hello", "world", "hello world", "nothing"] {
+            let batched = batched_tree.find_all_matches(text).await;
+            let single = single_tree.find_all_matches(text).await;
+            let mut batched: Vec<_> = batched.iter().map(|u| u.phrases()).collect();
+            let mut single: Vec<_> = single.iter().map(|u| u.phrases()).collect();
+            batched.sort();
+            single.sort();
+            assert_eq!(batched, single);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_mention_matches() {
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        user.set_handle(Some("@Alice.bsky.social".to_string()));
+        user.set_phrases(vec!["rust".to_string()]);
+        insert_user(user, &tree, &dids, &RwLock::new(HashMap::new())).await;
+
+        // The mention is matched, but it isn't one of the user's phrases.
+        let matches = tree.find_all_matches("thanks @alice.bsky.social for the help").await;
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].phrases(), vec!["rust"]);
+        assert_eq!(tree_phrases(&matches[0], &tree), vec!["rust", "@Alice.bsky.social"]);
+
+        // The handle alone, without the "@", is not a mention.
+        assert!(tree.find_all_matches("alice.bsky.social").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handle_is_not_a_stored_phrase() {
+        let config = Config::for_tests(&[("MAX_PHRASES_PER_USER", "1")]);
+        let store = MemoryStore::default();
+        let mut record = UserRecord::new(test_key("aa"), "https://example.com".to_string());
+        record.handle = Some("alice.bsky.social".to_string());
+        store.insert(record, &[] as &[&str]);
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        init_user(&config, &store, &tree, &dids, &keys, &test_key("aa")).await.unwrap();
+        let user = keys.read().await[&test_key("aa")].clone();
+        assert!(user.phrases().is_empty());
+
+        // The handle can't be deleted like a phrase, and doesn't use up the user's only phrase.
+        assert!(!remove_phrase(&store, &tree, &user, "@alice.bsky.social").await.unwrap());
+        add_phrase(&config, &store, &tree, &user, "rust").await.unwrap();
+        assert_eq!(store.phrases(&test_key("aa")), vec!["rust"]);
+
+        // Having it as a phrase too and then deleting that leaves the mention matched.
+        assert!(remove_phrase(&store, &tree, &user, "rust").await.unwrap());
+        add_phrase(&config, &store, &tree, &user, "@Alice.bsky.social").await.unwrap();
+        assert!(remove_phrase(&store, &tree, &user, "@alice.bsky.social").await.unwrap());
+        assert_eq!(tree.find_all_matches("hi @alice.bsky.social").await.len(), 1);
+
+        // Removing the user takes the mention with them.
+        remove_user(&user, &tree, &dids, &keys).await;
+        assert!(tree.find_all_matches("hi @alice.bsky.social").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reloading_user_replaces_them() {
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        for (did, phrase) in [("did:plc:old", "hello"), ("did:plc:new", "world")] {
+            let mut user = User::new(Some(did.to_string()), "https://example.com".to_string(), test_key("aa")).unwrap();
+            user.set_phrases(vec![phrase.to_string(), "both".to_string()]);
+            insert_user(user, &tree, &dids, &keys).await;
+        }
+
+        // Only the second copy is left.
+        assert!(tree.find_all_matches("hello").await.is_empty());
+        let matches = tree.find_all_matches("both world").await;
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].phrases(), vec!["world", "both"]);
+        assert!(Arc::ptr_eq(&matches[0], &keys.read().await[&test_key("aa")]));
+        let dids = dids.read().await;
+        assert_eq!(dids.len(), 1);
+        assert!(Arc::ptr_eq(&matches[0], &dids["did:plc:new"]));
+    }
+
+    #[tokio::test]
+    async fn test_previous_dids() {
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        let mut bob = User::new(
+            Some("did:plc:bob".to_string()), "https://example.com".to_string(), test_key("bb"),
+        ).unwrap();
+        bob.previous_dids = vec!["did:plc:shared".to_string()];
+        insert_user(bob, &tree, &dids, &keys).await;
+        let mut alice = User::new(
+            Some("did:plc:shared".to_string()), "https://example.com".to_string(), test_key("aa"),
+        ).unwrap();
+        alice.previous_dids = vec!["did:plc:old".to_string(), "did:plc:older".to_string()];
+        insert_user(alice, &tree, &dids, &keys).await;
+        let alice = keys.read().await[&test_key("aa")].clone();
+        assert_eq!(alice.dids().collect::<Vec<_>>(), vec!["did:plc:shared", "did:plc:old", "did:plc:older"]);
+
+        // A previous DID can't take over someone's current DID, whichever is loaded first.
+        let mut bob = User::new(
+            Some("did:plc:bob".to_string()), "https://example.com".to_string(), test_key("bb"),
+        ).unwrap();
+        bob.previous_dids = vec!["did:plc:shared".to_string()];
+        insert_user(bob, &tree, &dids, &keys).await;
+        assert_eq!(dids.read().await["did:plc:shared"].id, alice.id);
+        assert_eq!(dids.read().await["did:plc:old"].id, alice.id);
+
+        // Reloading without a previous DID drops it, and removing the user drops the rest.
+        let mut alice = User::new(
+            Some("did:plc:shared".to_string()), "https://example.com".to_string(), test_key("aa"),
+        ).unwrap();
+        alice.previous_dids = vec!["did:plc:old".to_string()];
+        insert_user(alice, &tree, &dids, &keys).await;
+        assert!(!dids.read().await.contains_key("did:plc:older"));
+        let alice = keys.read().await[&test_key("aa")].clone();
+        assert!(Arc::ptr_eq(&dids.read().await["did:plc:old"], &alice));
+        remove_user(&alice, &tree, &dids, &keys).await;
+        let mut remaining: Vec<String> = dids.read().await.keys().cloned().collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["did:plc:bob"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_reloading_never_drops_kept_phrases() {
+        let tree: &'static BulkSearchTree = Box::leak(Box::new(BulkSearchTree::new()));
+        let dids: &'static RwLock<HashMap<String, Arc<User>>> = Box::leak(Box::new(RwLock::new(HashMap::new())));
+        let keys: &'static RwLock<HashMap<String, Arc<User>>> = Box::leak(Box::new(RwLock::new(HashMap::new())));
+        let reload = |other: &str| {
+            let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+            user.set_phrases(vec!["red panda".to_string(), other.to_string()]);
+            insert_user(user, tree, dids, keys)
+        };
+        reload("apple").await;
+
+        // Search while the user is reloaded with their other phrase swapped back and forth. The phrase they kept
+        // always matches, and so does one of the two they swap between.
+        let done: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+        let searches: Vec<_> = ["red panda", "apple banana"].into_iter().map(|text| tokio::spawn(async move {
+            let mut searches = 0;
+            while !done.load(Ordering::Relaxed) {
+                assert_eq!(tree.find_all_matches(text).await.len(), 1, "{text}");
+                searches += 1;
+                tokio::task::yield_now().await;
+            }
+            searches
+        })).collect();
+        for i in 0..500 {
+            reload(if i % 2 == 0 { "banana" } else { "apple" }).await;
+            tokio::task::yield_now().await;
+        }
+        done.store(true, Ordering::Relaxed);
+        for search in searches {
+            assert!(search.await.unwrap() > 0);
+        }
+
+        // Only the last set of phrases is left.
+        assert!(tree.find_all_matches("banana").await.is_empty());
+        assert_eq!(tree.find_all_matches("apple").await.len(), 1);
+        assert_eq!(tree.stats().await.entries, 2);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_users_are_skipped_at_startup() {
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        let users = vec![
+            (test_key("aa"), User::new(None, "https://example.com".to_string(), test_key("aa"))),
+            ("not hex".to_string(), User::new(None, "https://example.com".to_string(), "not hex".to_string())),
+            (test_key("bb"), User::new(None, "not a url".to_string(), test_key("bb"))),
+            (test_key("cc"), User::new(None, "https://example.com".to_string(), test_key("cc"))),
+        ];
+        let mut phrases = group_phrases(vec![
+            (test_key("aa"), "hello".to_string()),
+            (test_key("bb"), "hello".to_string()),
+            (test_key("cc"), "world".to_string()),
+        ]);
+
+        // The users either side of the bad ones still load with their phrases.
+        assert_eq!(insert_initial_users(users, &mut phrases, &tree, &dids, &keys).await, (2, 2));
+        assert_eq!(keys.read().await.len(), 2);
+        assert_eq!(tree.find_all_matches("hello world").await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_load_results() {
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        let requested = [test_key("aa"), test_key("bb"), test_key("cc"), test_key("dd")];
+        let users = vec![
+            (test_key("aa"), User::new(None, "https://example.com".to_string(), test_key("aa"))),
+            (test_key("bb"), User::new(None, "not a url".to_string(), test_key("bb"))),
+            (test_key("cc"), User::new(None, "https://example.com".to_string(), test_key("cc"))),
+        ];
+        let phrases = group_phrases(vec![
+            (test_key("aa"), "hello".to_string()),
+            (test_key("bb"), "hello".to_string()),
+            (test_key("cc"), "world".to_string()),
+        ]);
+
+        let results = insert_bulk_users(&requested, users, phrases, &tree, &dids, &keys).await;
+        assert_eq!(results, HashMap::from([
+            (test_key("aa"), LoadResult::Loaded),
+            (test_key("bb"), LoadResult::Invalid),
+            (test_key("cc"), LoadResult::Loaded),
+            (test_key("dd"), LoadResult::NotFound),
+        ]));
+        assert_eq!(keys.read().await.len(), 2);
+        assert_eq!(tree.find_all_matches("hello world").await.len(), 2);
+        assert_eq!(serde_json::to_value(LoadResult::NotFound).unwrap(), "not_found");
+
+        // Without a store, nothing is loaded.
+        let config = Config::for_tests(&[]);
+        let result = init_users(&config, &MemoryStore::unavailable(), &tree, &dids, &keys, &requested).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_added_phrase_matches() {
+        let tree = BulkSearchTree::new();
+        let keys = RwLock::new(HashMap::new());
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        user.set_phrases(vec!["red panda".to_string()]);
+        insert_user(user, &tree, &RwLock::new(HashMap::new()), &keys).await;
+        let user = keys.read().await[&test_key("aa")].clone();
+        assert!(tree.find_all_matches("look at this otter").await.is_empty());
+
+        // The phrase is matched on as soon as it is added.
+        check_new_phrase(&user, &tree, "Otter", Some(2)).unwrap();
+        insert_phrase(&user, &tree, "Otter").await;
+        assert_eq!(tree.find_all_matches("look at this otter").await.len(), 1);
+        assert_eq!(user.phrases(), vec!["red panda", "Otter"]);
+
+        // Duplicates, short phrases, and phrases over the cap are rejected.
+        assert!(matches!(check_new_phrase(&user, &tree, "otter", None), Err(PhraseError::Duplicate)));
+        assert!(matches!(check_new_phrase(&user, &tree, "", None), Err(PhraseError::TooShort)));
+        assert!(matches!(check_new_phrase(&user, &tree, &"a".repeat(513), None), Err(PhraseError::TooLong(512))));
+        check_new_phrase(&user, &tree, &"a".repeat(512), None).unwrap();
+        assert!(matches!(check_new_phrase(&user, &tree, "bamboo", Some(2)), Err(PhraseError::TooMany(2))));
+        check_new_phrase(&user, &tree, "bamboo", None).unwrap();
+
+        // Nothing is matched on if it can't be saved.
+        let config = Config::for_tests(&[]);
+        let result = add_phrase(&config, &MemoryStore::unavailable(), &tree, &user, "bamboo").await;
+        assert!(matches!(result, Err(PhraseError::Store(_))));
+        assert!(tree.find_all_matches("bamboo").await.is_empty());
+        assert_eq!(user.phrase_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_removed_phrase_stops_matching() {
+        let tree = BulkSearchTree::new();
+        let keys = RwLock::new(HashMap::new());
+        let mut user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        user.set_phrases(vec!["Red Panda".to_string(), "otter".to_string(), "Otter".to_string()]);
+        insert_user(user, &tree, &RwLock::new(HashMap::new()), &keys).await;
+        let user = keys.read().await[&test_key("aa")].clone();
+
+        // The phrase is found however it is cased, and the user's other phrases still match once it is gone.
+        let phrase = find_phrase(&user, &tree, "red panda").unwrap();
+        assert_eq!(phrase, "Red Panda");
+        drop_phrase(&user, &tree, &phrase).await;
+        assert!(tree.find_all_matches("a red panda").await.is_empty());
+        assert_eq!(tree.find_all_matches("an otter").await.len(), 1);
+        assert_eq!(user.phrases(), vec!["otter", "Otter"]);
+        assert_eq!(find_phrase(&user, &tree, "red panda"), None);
+
+        // Phrases which normalize to one still held keep matching.
+        drop_phrase(&user, &tree, "otter").await;
+        assert_eq!(tree.find_all_matches("an otter").await.len(), 1);
+        drop_phrase(&user, &tree, "Otter").await;
+        assert!(tree.find_all_matches("an otter").await.is_empty());
+
+        // Phrases the user doesn't have are not found without touching the store.
+        assert!(!remove_phrase(&MemoryStore::unavailable(), &tree, &user, "bamboo").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_evict_user_reports_store_errors() {
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        let user = User::new(None, "https://example.com".to_string(), test_key("aa")).unwrap();
+        insert_user(user, &tree, &dids, &keys).await;
+        let user = keys.read().await[&test_key("aa")].clone();
+        let result = evict_user(&MemoryStore::unavailable(), &user, &tree, &dids, &keys).await;
+        assert!(matches!(result, Err(StoreError::Unavailable)));
+    }
+
+    #[tokio::test]
+    async fn test_users_load_from_store() {
+        let config = Config::for_tests(&[]);
+        let store = MemoryStore::default();
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        let mut user = UserRecord::new(test_key("aa"), "https://example.com".to_string());
+        user.did = Some("did:plc:alice".to_string());
+        store.insert(user, &["red panda"]);
+        store.insert(UserRecord::new(test_key("bb"), "not a url".to_string()), &["red panda"]);
+
+        // Everyone valid is loaded at startup.
+        init_data(&config, &store, &tree, &dids, &keys).await.unwrap();
+        assert_eq!(keys.read().await.len(), 1);
+        assert_eq!(dids.read().await.len(), 1);
+        assert_eq!(tree.find_all_matches("a red panda").await.len(), 1);
+
+        // Users added later are picked up one at a time or in bulk.
+        let mut user = UserRecord::new(test_key("cc"), "https://example.com".to_string());
+        user.replies = false;
+        store.insert(user, &["otter"]);
+        assert_eq!(init_user(&config, &store, &tree, &dids, &keys, &test_key("cc")).await.unwrap(), LoadResult::Loaded);
+        assert!(!keys.read().await[&test_key("cc")].replies);
+        store.insert(UserRecord::new(test_key("dd"), "https://example.com".to_string()), &["otter"]);
+        let results = init_users(
+            &config, &store, &tree, &dids, &keys, &[test_key("dd"), test_key("ee")],
+        ).await.unwrap();
+        assert_eq!(results[&test_key("dd")], LoadResult::Loaded);
+        assert_eq!(results[&test_key("ee")], LoadResult::NotFound);
+        assert_eq!(tree.find_all_matches("an otter").await.len(), 2);
+
+        // Phrases are saved to the store as they are matched on.
+        let user = keys.read().await[&test_key("cc")].clone();
+        add_phrase(&config, &store, &tree, &user, "bamboo").await.unwrap();
+        assert_eq!(store.phrases(&test_key("cc")), vec!["otter", "bamboo"]);
+        assert!(remove_phrase(&store, &tree, &user, "Otter").await.unwrap());
+        assert_eq!(store.phrases(&test_key("cc")), vec!["bamboo"]);
+        assert_eq!(tree.find_all_matches("an otter").await.len(), 1);
+
+        // Evicted users are deleted from the store, so they don't come back on a restart.
+        evict_user(&store, &user, &tree, &dids, &keys).await.unwrap();
+        assert_eq!(store.private_keys(), vec![test_key("aa"), test_key("bb"), test_key("dd")]);
+        assert!(store.phrases(&test_key("cc")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_many_users_load_with_the_automaton() {
+        // Building the automaton for every phrase would take minutes for this many, so this only finishes if the load
+        // builds it once.
+        let config = Config::for_tests(&[]);
+        let store = MemoryStore::default();
+        for i in 0..5_000u32 {
+            let phrases = [0, 1, 2, 3].map(|j| format!("<{i}.{j}>"));
+            let key = hex::encode(i.to_be_bytes().repeat(8));
+            store.insert(UserRecord::new(key, "https://example.com".to_string()), &phrases);
+        }
+        let tree = BulkSearchTree::new_with_backend(MatchOptions::default(), SearchBackend::AhoCorasick);
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        init_data(&config, &store, &tree, &dids, &keys).await.unwrap();
+        assert_eq!(keys.read().await.len(), 5_000);
+        assert_eq!(tree.stats().await.phrases, 20_000);
+        assert_eq!(tree.find_all_matches("<1234.2> and <4321.0>").await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_seeded_users_load_at_startup() {
+        let config = Config::for_tests(&[]);
+        let seed: Vec<SeedUser> = serde_json::from_value(serde_json::json!([
+            {"private_key": test_key("aa"), "endpoint": "https://example.com", "phrases": ["red panda", "otter"]},
+            {"private_key": test_key("bb"), "endpoint": "https://example.com", "replies": false},
+        ])).unwrap();
+        let store = MemoryStore::seeded(&seed);
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        init_data(&config, &store, &tree, &dids, &keys).await.unwrap();
+        assert_eq!(keys.read().await.len(), 2);
+        assert!(!keys.read().await[&test_key("bb")].replies);
+        assert_eq!(tree.find_all_matches("a red panda and an otter").await.len(), 1);
+
+        // They can be reloaded and have phrases added like any other user.
+        assert_eq!(init_user(&config, &store, &tree, &dids, &keys, &test_key("bb")).await.unwrap(), LoadResult::Loaded);
+        let user = keys.read().await[&test_key("bb")].clone();
+        add_phrase(&config, &store, &tree, &user, "bamboo").await.unwrap();
+        assert_eq!(store.phrases(&test_key("bb")), vec!["bamboo"]);
+    }
+
+    #[tokio::test]
+    async fn test_paused_users_are_not_loaded() {
+        let config = Config::for_tests(&[]);
+        let store = MemoryStore::default();
+        let tree = BulkSearchTree::new();
+        let dids = RwLock::new(HashMap::new());
+        let keys = RwLock::new(HashMap::new());
+        store.insert(UserRecord::new(test_key("aa"), "https://example.com".to_string()), &["red panda"]);
+        store.insert(UserRecord::new(test_key("bb"), "https://example.com".to_string()), &["otter"]);
+        init_data(&config, &store, &tree, &dids, &keys).await.unwrap();
+
+        // Pausing keeps the user and their phrases in the store, but stops them being matched.
+        let user = keys.read().await[&test_key("aa")].clone();
+        pause_user(&store, &user, 1_000, &tree, &dids, &keys).await.unwrap();
+        assert!(tree.find_all_matches("a red panda").await.is_empty());
+        assert_eq!(store.private_keys(), vec![test_key("aa"), test_key("bb")]);
+        assert_eq!(store.phrases(&test_key("aa")), vec!["red panda"]);
+
+        // They aren't loaded at startup or when asked for, and are only due to be probed from paused_until.
+        let keys = RwLock::new(HashMap::new());
+        init_data(&config, &store, &tree, &dids, &keys).await.unwrap();
+        assert_eq!(init_user(&config, &store, &tree, &dids, &keys, &test_key("aa")).await.unwrap(), LoadResult::Paused);
+        assert!(!keys.read().await.contains_key(&test_key("aa")));
+        let results = init_users(&config, &store, &tree, &dids, &keys, &[test_key("aa")]).await.unwrap();
+        assert_eq!(results[&test_key("aa")], LoadResult::Paused);
+        assert!(paused_users(&config, &store, 999).await.unwrap().is_empty());
+        let mut due = paused_users(&config, &store, 1_000).await.unwrap();
+        assert_eq!(due.len(), 1);
+
+        // Resuming loads them again with their phrases.
+        let (private_key, user) = due.remove(0);
+        assert_eq!(private_key, test_key("aa"));
+        resume_user(&store, user.unwrap(), &tree, &dids, &keys).await.unwrap();
+        assert_eq!(tree.find_all_matches("a red panda").await.len(), 1);
+        assert!(!store.get(&test_key("aa")).unwrap().paused);
+        assert!(paused_users(&config, &store, i64::MAX).await.unwrap().is_empty());
+    }
+}
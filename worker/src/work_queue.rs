@@ -0,0 +1,191 @@
+use futures::FutureExt as _;
+use std::{collections::BTreeSet, future::Future, panic::AssertUnwindSafe, sync::Arc};
+use tokio::sync::{mpsc, watch, Mutex};
+use tracing::error;
+
+// Defines which items have been handled, so handlers can wait for the items queued before theirs.
+struct QueueOrder {
+    // How many items from the start of the queue have all been handled.
+    handled: watch::Sender<u64>,
+
+    // Items which were handled before some item queued ahead of them.
+    handled_early: std::sync::Mutex<BTreeSet<u64>>,
+}
+
+impl QueueOrder {
+    fn new() -> Arc<Self> {
+        Arc::new(QueueOrder { handled: watch::channel(0).0, handled_early: Default::default() })
+    }
+
+    // Marks the item at the position as handled.
+    fn finish(&self, position: u64) {
+        let mut handled_early = self.handled_early.lock().unwrap();
+        handled_early.insert(position);
+        let mut handled = *self.handled.borrow();
+        while handled_early.remove(&handled) {
+            handled += 1;
+        }
+        self.handled.send_if_modified(|current| std::mem::replace(current, handled) != handled);
+    }
+}
+
+// Defines where an item is in the queue. The workers handle items at the same time, so they finish in any order, but a
+// handler can wait for every item queued before its own to be handled first. The item counts as handled once this is
+// dropped.
+pub struct Turn {
+    position: u64,
+    order: Arc<QueueOrder>,
+}
+
+impl Turn {
+    // Creates a turn for an item which isn't in a queue, so it never waits.
+    #[cfg(test)]
+    pub fn alone() -> Self {
+        Turn { position: 0, order: QueueOrder::new() }
+    }
+
+    // Waits until every item queued before this one has been handled.
+    pub async fn wait(&self) {
+        let mut handled = self.order.handled.subscribe();
+        // The sender lives as long as this turn, so this can't fail.
+        let _ = handled.wait_for(|&handled| handled >= self.position).await;
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.order.finish(self.position);
+    }
+}
+
+// Starts a fixed number of workers which handle items from a queue holding at most depth items. Sending to the returned
+// queue waits while it is full, so a producer that is faster than the workers is slowed down rather than piling up
+// work in memory. Each item is handled along with its turn in the queue.
+pub fn spawn_workers<T, F, Fut>(workers: usize, depth: usize, handler: F) -> mpsc::Sender<T>
+where
+    T: Send + 'static,
+    F: Fn(T, Turn) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let (sender, receiver) = mpsc::channel(depth);
+    let receiver = Arc::new(Mutex::new((receiver, 0)));
+    let order = QueueOrder::new();
+    let handler = Arc::new(handler);
+    for _ in 0..workers {
+        let receiver = receiver.clone();
+        let order = order.clone();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            loop {
+                // Only hold the lock while waiting for an item so the other workers can take the next one. Items are
+                // numbered as they are taken, which is the order they were queued in.
+                let (item, position) = {
+                    let mut receiver = receiver.lock().await;
+                    let Some(item) = receiver.0.recv().await else {
+                        return;
+                    };
+                    receiver.1 += 1;
+                    (item, receiver.1 - 1)
+                };
+                let turn = Turn { position, order: order.clone() };
+
+                // Don't let a panic handling one item take the worker down with it. The turn is dropped either way, so
+                // the items after it don't wait forever.
+                if AssertUnwindSafe(handler(item, turn)).catch_unwind().await.is_err() {
+                    error!("Worker panicked handling an item");
+                }
+            }
+        });
+    }
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+
+    // Counts how many items are alive, and the most there have ever been at once.
+    struct Tracked(Arc<(AtomicUsize, AtomicUsize)>);
+
+    impl Tracked {
+        fn new(counts: &Arc<(AtomicUsize, AtomicUsize)>) -> Self {
+            let alive = counts.0.fetch_add(1, Ordering::SeqCst) + 1;
+            counts.1.fetch_max(alive, Ordering::SeqCst);
+            Self(counts.clone())
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0 .0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fast_producer_is_bounded() {
+        let counts = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let handled = Arc::new(AtomicUsize::new(0));
+        let worker_handled = handled.clone();
+        let queue = spawn_workers(2, 8, move |item: Tracked, _turn| {
+            let handled = worker_handled.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                handled.fetch_add(1, Ordering::SeqCst);
+                drop(item);
+            }
+        });
+
+        // Each item is created right before it is sent, so at most the queue depth, one per worker, and the one being
+        // sent are alive at once.
+        for _ in 0..200 {
+            assert!(queue.send(Tracked::new(&counts)).await.is_ok());
+        }
+        drop(queue);
+        while handled.load(Ordering::SeqCst) < 200 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(counts.1.load(Ordering::SeqCst) <= 8 + 2 + 1);
+    }
+
+    #[tokio::test]
+    async fn test_worker_survives_panic() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let queue = spawn_workers(1, 1, move |item: u32, _turn| {
+            let tx = tx.clone();
+            async move {
+                assert_ne!(item, 1, "boom");
+                tx.send(item).unwrap();
+            }
+        });
+        queue.send(1).await.unwrap();
+        queue.send(2).await.unwrap();
+        assert_eq!(rx.recv().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_waiting_for_turn() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let queue = spawn_workers(4, 4, move |item: u64, turn: Turn| {
+            let tx = tx.clone();
+            async move {
+                // Later items are quicker to handle, so they would be done first without waiting.
+                tokio::time::sleep(Duration::from_millis(20 - item % 4 * 5)).await;
+                assert_ne!(item, 5, "boom");
+                turn.wait().await;
+                tx.send(item).unwrap();
+            }
+        });
+        for item in 0..20 {
+            queue.send(item).await.unwrap();
+        }
+
+        // Even with a panic in the middle, nothing is stuck behind it.
+        let mut handled = vec![];
+        for _ in 0..19 {
+            handled.push(rx.recv().await.unwrap());
+        }
+        assert_eq!(handled, (0..20).filter(|&item| item != 5).collect::<Vec<_>>());
+        assert!(Turn::alone().wait().now_or_never().is_some());
+    }
+}